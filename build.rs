@@ -9,6 +9,11 @@ pub fn main() {
     println!("cargo:rerun-if-changed=usermode/example_app/main.c");
     println!("cargo:rerun-if-changed=usermode/example_app/Makefile");
 
+    // RAM (and so the kernel) starts at 1GiB on QEMU's `virt` machine
+    if env::var_os("CARGO_FEATURE_BOARD_VIRT").is_some() {
+        println!("cargo:rustc-link-arg=--defsym=__ram_base=0x40000000");
+    }
+
     let out_dir = env::var_os("OUT_DIR").unwrap();
 
     Command::new("make")
@@ -0,0 +1,122 @@
+#!/usr/bin/env python3
+# Writes the kernel's function symbols into its `.ksyms` section, for symbolized backtraces.
+# Usage: embed_symbols.py <kernel elf>
+# The layout must match `SymbolTable` in src/arch/aarch64/backtrace.rs
+
+import re
+import struct
+import sys
+
+MAGIC = b'BOLDSYMS'
+SHT_SYMTAB = 2
+STT_FUNC = 2
+
+RUST_ESCAPES = {
+    '$SP$': '@', '$BP$': '*', '$RF$': '&', '$LT$': '<', '$GT$': '>', '$LP$': '(', '$RP$': ')',
+    '$C$': ',',
+}
+
+
+def demangle(name):
+    """Demangles legacy Rust symbols (`_ZN...17h<hash>E`), leaves anything else as is"""
+    # LTO may add suffixes to local copies, e.g. `_ZN...E.1036`
+    name = re.sub(r'(\.\d+)+$', '', name)
+    if not name.startswith('_ZN') or not name.endswith('E'):
+        return name
+
+    parts = []
+    rest = name[3:-1]
+    while rest:
+        match = re.match(r'(\d+)', rest)
+        if not match:
+            return name
+        start = len(match.group(1))
+        length = int(match.group(1))
+        parts.append(rest[start:start + length])
+        rest = rest[start + length:]
+
+    if parts and re.fullmatch(r'h[0-9a-f]{16}', parts[-1]):
+        parts.pop()
+
+    result = []
+    for part in parts:
+        if part.startswith('_$'):
+            part = part[1:]
+        for escape, char in RUST_ESCAPES.items():
+            part = part.replace(escape, char)
+        part = re.sub(r'\$u([0-9a-f]{2})\$', lambda m: chr(int(m.group(1), 16)), part)
+        result.append(part.replace('..', '::'))
+    return '::'.join(result)
+
+
+def read_sections(elf):
+    if elf[:4] != b'\x7fELF' or elf[4] != 2 or elf[5] != 1:
+        sys.exit('Not a little-endian ELF64 file')
+    shoff, = struct.unpack_from('<Q', elf, 0x28)
+    shentsize, shnum, shstrndx = struct.unpack_from('<HHH', elf, 0x3a)
+
+    sections = []
+    for i in range(shnum):
+        name, sh_type, _, addr, offset, size, link, _, _, entsize = struct.unpack_from(
+            '<IIQQQQIIQQ', elf, shoff + i * shentsize)
+        sections.append({'name': name, 'type': sh_type, 'addr': addr, 'offset': offset,
+                         'size': size, 'link': link, 'entsize': entsize})
+
+    names = sections[shstrndx]
+    for section in sections:
+        start = names['offset'] + section['name']
+        section['name'] = elf[start:elf.index(b'\0', start)].decode()
+    return sections
+
+
+def read_functions(elf, sections):
+    symtab = next(s for s in sections if s['type'] == SHT_SYMTAB)
+    strtab = sections[symtab['link']]
+
+    functions = {}
+    for offset in range(symtab['offset'], symtab['offset'] + symtab['size'], symtab['entsize']):
+        name, info, _, _, value, _ = struct.unpack_from('<IBBHQQ', elf, offset)
+        if info & 0xf != STT_FUNC or value == 0:
+            continue
+        start = strtab['offset'] + name
+        name = elf[start:elf.index(b'\0', start)].decode(errors='replace')
+        # Keep one name per address
+        functions.setdefault(value, demangle(name))
+    return sorted(functions.items())
+
+
+def build_table(functions):
+    header_len = 16
+    entries = b''
+    strings = b''
+    for addr, name in functions:
+        encoded = name.encode()
+        entries += struct.pack('<QII', addr, len(strings), len(encoded))
+        strings += encoded
+    header = MAGIC + struct.pack('<II', len(functions), header_len + len(entries))
+    return header + entries + strings
+
+
+def main():
+    path = sys.argv[1]
+    with open(path, 'rb') as f:
+        elf = bytearray(f.read())
+
+    sections = read_sections(elf)
+    ksyms = next((s for s in sections if s['name'] == '.ksyms'), None)
+    if ksyms is None:
+        sys.exit('No .ksyms section in ' + path)
+
+    table = build_table(read_functions(elf, sections))
+    if len(table) > ksyms['size']:
+        sys.exit('Symbol table is 0x{:x} bytes, but .ksyms only has room for 0x{:x}, '
+                 'increase it in linker.ld'.format(len(table), ksyms['size']))
+
+    table += bytes(ksyms['size'] - len(table))
+    elf[ksyms['offset']:ksyms['offset'] + ksyms['size']] = table
+    with open(path, 'wb') as f:
+        f.write(elf)
+
+
+if __name__ == '__main__':
+    main()
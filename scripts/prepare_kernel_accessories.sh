@@ -16,13 +16,17 @@ pushd "$INITRD_DIR"
 tar -cf ../initrd.tar ./*
 popd
 
-# Make disk image
+# Make disk image, partitioned like an SD card: one FAT32 partition from 1MiB
 DISK_IMG="$BUILD_DIR/disk.img"
 rm -f "$DISK_IMG"
 fallocate "$DISK_IMG" -l 64MiB
-mkfs.vfat -F 32 -n 'BOLD SYSTEM' "$DISK_IMG"
-mcopy -i "$DISK_IMG" "$INITRD_DIR"/hello ::hello
-mcopy -i "$DISK_IMG" "$INITRD_DIR"/world ::world
+echo 'start=2048, type=c' | sfdisk --quiet "$DISK_IMG"
+mkfs.vfat -F 32 -n 'BOLD SYSTEM' --offset 2048 "$DISK_IMG" $((63 * 1024))
+mcopy -i "$DISK_IMG@@1M" "$INITRD_DIR"/hello ::hello
+mcopy -i "$DISK_IMG@@1M" "$INITRD_DIR"/world ::world
+
+# Embed symbol table for backtraces
+./scripts/embed_symbols.py "$KERNEL_ELF"
 
 # Convert kernel to bin file
 KERNEL_BIN="$KERNEL_ELF".bin
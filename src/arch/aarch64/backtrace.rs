@@ -0,0 +1,172 @@
+use crate::arch::aarch64::board;
+use crate::prelude::*;
+use core::fmt;
+use core::ptr::slice_from_raw_parts;
+
+extern "C" {
+    static __ksyms_start: u8;
+    static __ksyms_end: u8;
+}
+
+/// Written by `scripts/embed_symbols.py` after linking
+const KSYMS_MAGIC: &[u8; 8] = b"BOLDSYMS";
+const KSYMS_HEADER_LEN: usize = 16;
+const KSYMS_ENTRY_LEN: usize = 16;
+
+const MAX_FRAMES: usize = 32;
+
+/// Frame records can only live in the kernel's linear map of RAM
+const KERNEL_MAP: core::ops::Range<u64> =
+    0xffffff8000000000 + board::RAM_BASE as u64..0xffffff8040000000 + board::RAM_BASE as u64;
+
+/// The symbol table embedded in the `.ksyms` section, laid out as:
+/// - Header: magic, `u32` symbol count, `u32` offset of the string table
+/// - Entries, sorted by address: `u64` address, `u32` name offset, `u32` name length
+/// - String table
+struct SymbolTable {
+    data: &'static [u8],
+    count: usize,
+    strings: usize,
+}
+
+impl SymbolTable {
+    fn get() -> Option<SymbolTable> {
+        let data = unsafe {
+            let start = &__ksyms_start as *const u8;
+            let end = &__ksyms_end as *const u8;
+            &*slice_from_raw_parts(start, end.offset_from(start) as usize)
+        };
+        if data.len() < KSYMS_HEADER_LEN || &data[..8] != KSYMS_MAGIC {
+            return None;
+        }
+        let count = read_u32(data, 8)? as usize;
+        let strings = read_u32(data, 12)? as usize;
+        if KSYMS_HEADER_LEN + count * KSYMS_ENTRY_LEN > strings.min(data.len()) {
+            return None;
+        }
+
+        Some(SymbolTable {
+            data,
+            count,
+            strings,
+        })
+    }
+
+    fn entry(&self, i: usize) -> Option<(u64, &'static str)> {
+        let offset = KSYMS_HEADER_LEN + i * KSYMS_ENTRY_LEN;
+        let addr =
+            read_u32(self.data, offset)? as u64 | (read_u32(self.data, offset + 4)? as u64) << 32;
+        let name_start = self.strings + read_u32(self.data, offset + 8)? as usize;
+        let name_len = read_u32(self.data, offset + 12)? as usize;
+        let name = self.data.get(name_start..name_start + name_len)?;
+        Some((addr, core::str::from_utf8(name).ok()?))
+    }
+
+    /// Finds the symbol containing `addr`, returns its name and the offset into it
+    fn lookup(&self, addr: u64) -> Option<(&'static str, u64)> {
+        // Find the last symbol that starts at or before `addr`
+        let (mut low, mut high) = (0, self.count);
+        while low < high {
+            let mid = (low + high) / 2;
+            if self.entry(mid)?.0 <= addr {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        let (sym_addr, name) = self.entry(low.checked_sub(1)?)?;
+        Some((name, addr - sym_addr))
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Formats a code address as `0x... function+0x...`, or just the address if it's unknown
+pub struct Symbolized(pub u64);
+
+impl fmt::Display for Symbolized {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "0x{:016x}", self.0)?;
+        if let Some((name, offset)) = SymbolTable::get().and_then(|table| table.lookup(self.0)) {
+            write!(f, " {}+0x{:x}", name, offset)?;
+        }
+        Ok(())
+    }
+}
+
+/// Walks the frame records starting at `fp` (x29), calling `f` with each return address.
+/// Stops at the first record that doesn't look valid.
+///
+/// # Safety
+///
+/// `fp` must be a frame pointer of kernel code, i.e. frame records must be mapped
+pub unsafe fn walk<F: FnMut(u64)>(mut fp: u64, mut f: F) {
+    for _ in 0..MAX_FRAMES {
+        if !KERNEL_MAP.contains(&fp) || fp % 16 != 0 {
+            break;
+        }
+        // A frame record is the caller's x29 followed by the return address
+        let record = fp as *const u64;
+        let lr = *record.add(1);
+        if lr == 0 {
+            break;
+        }
+        // Point at the branch instruction rather than after it
+        f(lr - 4);
+
+        // The stack grows down, so callers' frames are above ours
+        let next = *record;
+        if next <= fp {
+            break;
+        }
+        fp = next;
+    }
+}
+
+/// Returns the current frame pointer
+#[inline(always)]
+pub fn current_fp() -> u64 {
+    let fp: u64;
+    unsafe {
+        asm!("mov {}, x29", out(reg) fp, options(nomem, nostack));
+    }
+    fp
+}
+
+/// Writes the frames above `fp` one per line
+///
+/// # Safety
+///
+/// See `walk`
+pub unsafe fn write_backtrace(w: &mut dyn fmt::Write, fp: u64) -> fmt::Result {
+    let mut i = 0;
+    let mut result = Ok(());
+    walk(fp, |addr| {
+        if result.is_ok() {
+            result = writeln!(w, "#{} {}", i, Symbolized(addr));
+            i += 1;
+        }
+    });
+    result
+}
+
+struct PrintWriter;
+
+impl fmt::Write for PrintWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        print!("{}", s);
+        Ok(())
+    }
+}
+
+/// Prints the frames above `fp` to the console
+///
+/// # Safety
+///
+/// See `walk`
+pub unsafe fn print_backtrace(fp: u64) {
+    let _ = write_backtrace(&mut PrintWriter, fp);
+}
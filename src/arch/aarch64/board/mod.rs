@@ -0,0 +1,65 @@
+//! Everything that differs between the machines we run on.
+//!
+//! The board is picked at build time (the `board-virt` feature), since it decides where the
+//! kernel is linked. Each board module provides the same set of items, re-exported from here.
+
+use crate::arch::aarch64::dtb;
+use crate::prelude::*;
+use futures::future::BoxFuture;
+
+#[cfg(not(feature = "board-virt"))]
+mod raspi3;
+#[cfg(not(feature = "board-virt"))]
+pub use raspi3::*;
+
+#[cfg(feature = "board-virt")]
+mod virt;
+#[cfg(feature = "board-virt")]
+pub use virt::*;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Board {
+    RaspberryPi3,
+    QemuVirt,
+}
+
+/// A console usable before the driver manager is up
+pub struct EarlyConsole {
+    /// Value of `earlycon=`
+    pub name: &'static [u8],
+    pub driver_name: &'static [u8],
+    pub init: fn(),
+}
+
+/// A file in `/sys/board`, generated each time it's read
+pub struct InfoFile {
+    pub name: &'static [u8],
+    pub read: fn() -> BoxFuture<'static, Vec<u8>>,
+}
+
+/// Formats one value per line, or `error` if the firmware didn't answer
+fn info_text(value: Result<impl core::fmt::Display, ()>) -> Vec<u8> {
+    match value {
+        Ok(value) => alloc::format!("{}\n", value).into_bytes(),
+        Err(()) => b"error\n".to_vec(),
+    }
+}
+
+/// Warns if the device tree we were given describes a different machine than we were built for
+pub fn check_device_tree() {
+    let compatible = match dtb::property(&[], "compatible") {
+        Some(compatible) => compatible,
+        None => return,
+    };
+    // A list of NUL-terminated strings
+    if !compatible
+        .split(|c| *c == 0)
+        .any(|name| name == DTB_COMPATIBLE.as_bytes())
+    {
+        println!(
+            "[WARN] Built for {:?}, but the device tree says \"{}\"",
+            BOARD,
+            AsciiStr(compatible)
+        );
+    }
+}
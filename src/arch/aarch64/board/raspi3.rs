@@ -0,0 +1,461 @@
+use super::{info_text, Board, EarlyConsole, InfoFile};
+use crate::arch::aarch64::dtb::MemoryLayout;
+use crate::arch::aarch64::exceptions::ExceptionContext;
+use crate::arch::aarch64::gpio::{self, Function, Pull};
+use crate::arch::aarch64::mmio::{
+    core_irq_source, core_mbox0_rdclr, core_mbox0_set, core_mbox_int_ctrl, core_timer_int_ctrl,
+    mmio_read, mmio_write, CORE_IRQ_SOURCE_CNTPNS, CORE_IRQ_SOURCE_GPU, CORE_IRQ_SOURCE_MBOX0,
+    CORE_MBOX_INT_CTRL_MBOX0, CORE_TIMER_INT_CTRL_CNTPNS, DISABLE_BASIC_IRQS, DISABLE_IRQS_1,
+    DISABLE_IRQS_2, ENABLE_BASIC_IRQS, ENABLE_IRQS_1, ENABLE_IRQS_2, IRQ_BASIC_BASE,
+    IRQ_BASIC_PENDING, IRQ_LOCAL_CNTPNS, IRQ_LOCAL_MBOX0, IRQ_PENDING_1, IRQ_PENDING_2,
+    IRQ_PL011_UART0, MMIO_BASE, PM_PASSWORD, PM_RSTC, PM_RSTC_WRCFG_CLR, PM_RSTC_WRCFG_FULL_RESET,
+    PM_RSTS, PM_RSTS_PARTITION_CLR, PM_WDOG, UART0_BASE,
+};
+use crate::arch::aarch64::pl011::{self, Pl011};
+use crate::arch::aarch64::uart1::{init_uart1, write_uart1};
+use crate::arch::aarch64::{irq, mailbox, mailbox_methods, mmio, mmu};
+use crate::ipc::well_known;
+use crate::prelude::*;
+use crate::threads::current_core;
+use crate::{boot_options, driver_manager, fi};
+
+pub const BOARD: Board = Board::RaspberryPi3;
+pub const DTB_COMPATIBLE: &str = "brcm,bcm2837";
+
+/// Physical start of RAM, must match `__ram_base` in the linker script
+pub const RAM_BASE: usize = 0;
+/// Used when there's no device tree to tell us better
+pub const FALLBACK_MEMORY: PhySlice = PhySlice {
+    base: PhyAddr(0),
+    len: 256 * 1024 * 1024,
+};
+/// The peripherals share the first GiB with RAM, everything from here on is device memory
+pub const RAM_IOMEM_START: usize = mmio::MMIO_BASE as usize;
+/// Other 1GiB blocks to map as device memory: The local peripherals (core mailboxes, per-core
+/// IRQ sources)
+pub const DEVICE_BLOCKS: [usize; 1] = [mmio::LOCAL_BASE as usize];
+
+pub const EARLY_CONSOLES: [EarlyConsole; 2] = [
+    EarlyConsole {
+        name: b"uart1",
+        driver_name: b"Raspberry Pi 3 UART1",
+        init: init_uart1,
+    },
+    EarlyConsole {
+        name: b"uart0",
+        driver_name: b"Raspberry Pi 3 UART0",
+        init: init_uart0,
+    },
+];
+/// The UART the kernel shell runs on, its directory has an input and an output queue
+pub const SHELL_UART: [u64; 3] = [
+    well_known::ROOT_DEVICES,
+    well_known::DEVICES_RPI_UART,
+    well_known::RPI_UART1,
+];
+pub const SHELL_UART_IN: u64 = well_known::RPI_UART_IN;
+pub const SHELL_UART_OUT: u64 = well_known::RPI_UART_OUT;
+pub const HAS_FRAMEBUFFER: bool = true;
+
+/// Spin-table release addresses of cores 1-3, polled by the firmware stub
+const SPIN_TABLE: [usize; 3] = [0xe0, 0xe8, 0xf0];
+
+// ----- UART0 -----
+
+/// The firmware's default, in case the mailbox doesn't tell us the actual clock
+const UART0_CLOCK_HZ: u32 = 48_000_000;
+/// `mailbox_methods::CLOCK_IDS`
+const UART_CLOCK_ID: u32 = 2;
+
+static UART0: pl011::Device = pl011::Device::new(Pl011::new(
+    UART0_BASE as usize,
+    UART0_CLOCK_HZ,
+    IRQ_PL011_UART0,
+));
+
+static mut UART0_DRIVER: pl011::Driver = pl011::Driver::new(
+    b"Raspberry Pi 3 UART0",
+    BOARD,
+    &UART0,
+    fi::FileInterface {
+        sync_read: Some(&UART0),
+        read: Some(&UART0),
+        sync_write: Some(&UART0),
+        write: Some(&UART0),
+        ctrl: None,
+    },
+    [
+        well_known::ROOT_DEVICES,
+        well_known::DEVICES_RPI_UART,
+        well_known::RPI_UART0,
+    ],
+    uart0_irq,
+    init_uart0,
+);
+
+#[link_section = ".drivers"]
+#[used]
+static mut UART0_DRIVER_REF: &dyn driver_manager::Driver = unsafe { &UART0_DRIVER };
+
+/// The header's GPIO 14 and 15 only go to UART0 if it's the early console, otherwise they stay
+/// with UART1 and UART0 keeps the firmware's routing (Bluetooth)
+fn init_uart0() {
+    if let Ok(clock) = mailbox_methods::get_clock_rate_sync(UART_CLOCK_ID) {
+        UART0.uart.set_clock_hz(clock.0 as u32);
+    }
+    UART0.uart.init(&boot_options::get().uart0);
+
+    if boot_options::get().earlycon == b"uart0" {
+        // `claim` warns if they're taken
+        let _ = gpio::setup(&[14, 15], b"UART0", Function::Alt0, Pull::Off);
+    }
+}
+
+unsafe fn uart0_irq(_e: &mut ExceptionContext) {
+    UART0.handle_irq();
+}
+
+/// Writes to the console before any driver is up
+pub fn early_write(buf: &[u8]) {
+    if UART0.uart.is_initialized() {
+        UART0.uart.write_sync(buf);
+    } else {
+        let _ = write_uart1(buf);
+    }
+}
+
+/// Moves the peripherals to wherever the device tree says they are
+///
+/// # Safety
+///
+/// This function assumes it runs once, on core 0, before any driver is initialized
+pub unsafe fn map_peripherals(layout: Option<&MemoryLayout>) {
+    if let Some(mmio_base) = layout.and_then(|l| l.mmio_base()) {
+        if mmu::map_iomem(mmio_base).is_ok() {
+            mmio::set_mmio_base(mmio_base);
+            UART0
+                .uart
+                .set_base(PhyAddr(mmio_base.0 + (UART0_BASE - MMIO_BASE) as usize));
+        }
+    }
+}
+
+/// Fills `cmdline` with the kernel command line given by the firmware
+pub fn firmware_cmdline<const CAP: usize>(cmdline: &mut ArrayVec<u8, CAP>) {
+    if let Ok(args) = mailbox_methods::get_kernel_args_sync() {
+        let _ = cmdline.try_extend_from_slice(&args);
+    }
+}
+
+/// Releases `core` from the spin table, making it jump to `entry`
+///
+/// # Safety
+///
+/// This function assumes `entry` is the physical address of code that's fine with the MMU off
+pub unsafe fn start_core(core: usize, entry: usize) -> Result<(), ()> {
+    let spin_addr = *SPIN_TABLE.get(core.checked_sub(1).ok_or(())?).ok_or(())?;
+    let spin_slot = PhyAddr(spin_addr).virt_mut() as *mut usize;
+    *spin_slot = entry;
+    asm!("dc civac, {:x}", in(reg) spin_slot, options(nostack));
+
+    // Wake the cores waiting on the spin table
+    asm!("dsb sy", "sev", options(nostack));
+    Ok(())
+}
+
+// ----- Power -----
+
+/// Firmware partition that halts instead of booting
+const PM_PARTITION_HALT: u32 = 63;
+
+/// Resets the board with the PM watchdog, the firmware then boots `partition`
+fn pm_reset(partition: u32) -> ! {
+    unsafe {
+        // Spread the partition number over the even bits
+        let partition_bits = (0..6).fold(0, |bits, i| bits | (partition & (1 << i)) << i);
+        let rsts = mmio_read(PM_RSTS) & PM_RSTS_PARTITION_CLR;
+        mmio_write(PM_RSTS, PM_PASSWORD | rsts | partition_bits);
+
+        // Fire in 10 watchdog ticks (~150us)
+        mmio_write(PM_WDOG, PM_PASSWORD | 10);
+        let rstc = mmio_read(PM_RSTC) & PM_RSTC_WRCFG_CLR;
+        mmio_write(PM_RSTC, PM_PASSWORD | rstc | PM_RSTC_WRCFG_FULL_RESET);
+
+        loop {
+            asm!("wfi");
+        }
+    }
+}
+
+pub fn reboot() -> ! {
+    pm_reset(0)
+}
+
+pub fn halt() -> ! {
+    pm_reset(PM_PARTITION_HALT)
+}
+
+/// Nothing to do, `halt` resets every core along with the rest of the SoC
+pub unsafe fn check_halting() {}
+
+/// The Pi 3 can't cut its own power, the best it can do is halt
+pub fn poweroff() -> ! {
+    halt()
+}
+
+// ----- Board info -----
+
+pub const INFO_FILES: [InfoFile; 10] = [
+    InfoFile {
+        name: b"model",
+        read: || {
+            Box::pin(async {
+                info_text(mailbox_methods::get_board_revision().await.map(model_name))
+            })
+        },
+    },
+    InfoFile {
+        name: b"revision",
+        read: || {
+            Box::pin(async {
+                info_text(
+                    mailbox_methods::get_board_revision()
+                        .await
+                        .map(|r| alloc::format!("{:#x}", r)),
+                )
+            })
+        },
+    },
+    InfoFile {
+        name: b"serial",
+        read: || {
+            Box::pin(async {
+                info_text(
+                    mailbox_methods::get_board_serial()
+                        .await
+                        .map(|s| alloc::format!("{:016x}", s)),
+                )
+            })
+        },
+    },
+    InfoFile {
+        name: b"arm_memory",
+        read: || {
+            Box::pin(async { info_text(mailbox_methods::get_arm_memory().await.map(MemoryRange)) })
+        },
+    },
+    InfoFile {
+        name: b"vc_memory",
+        read: || {
+            Box::pin(async { info_text(mailbox_methods::get_vc_memory().await.map(MemoryRange)) })
+        },
+    },
+    InfoFile {
+        name: b"temperature",
+        read: || {
+            Box::pin(async { info_text(mailbox_methods::get_temperature().await.map(Celsius)) })
+        },
+    },
+    InfoFile {
+        name: b"max_temperature",
+        read: || {
+            Box::pin(async { info_text(mailbox_methods::get_max_temperature().await.map(Celsius)) })
+        },
+    },
+    InfoFile {
+        name: b"voltages",
+        read: || {
+            Box::pin(async {
+                let mut text = Vec::new();
+                for (id, name) in mailbox_methods::VOLTAGE_IDS {
+                    text.extend(
+                        match mailbox_methods::get_voltage(id).await {
+                            Ok(uv) => alloc::format!(
+                                "{}: {}.{:04} V\n",
+                                name,
+                                uv / 1000000,
+                                uv % 1000000 / 100
+                            ),
+                            Err(()) => alloc::format!("{}: error\n", name),
+                        }
+                        .bytes(),
+                    );
+                }
+                text
+            })
+        },
+    },
+    InfoFile {
+        name: b"clocks",
+        read: || {
+            Box::pin(async {
+                let mut text = Vec::new();
+                for (id, name) in mailbox_methods::CLOCK_IDS {
+                    let state = mailbox_methods::get_clock_state(id).await;
+                    let rate = mailbox_methods::get_clock_rate(id).await;
+                    text.extend(
+                        match (state, rate) {
+                            (Ok(Some(on)), Ok(rate)) => {
+                                alloc::format!("{}: {}, {}\n", name, OnOff(on), rate)
+                            }
+                            (Ok(None), _) => alloc::format!("{}: missing\n", name),
+                            _ => alloc::format!("{}: error\n", name),
+                        }
+                        .bytes(),
+                    );
+                }
+                text
+            })
+        },
+    },
+    InfoFile {
+        name: b"power",
+        read: || {
+            Box::pin(async {
+                let mut text = Vec::new();
+                for (id, name) in mailbox_methods::POWER_DOMAIN_IDS {
+                    text.extend(
+                        match mailbox_methods::get_power_state(id).await {
+                            Ok(Some(on)) => alloc::format!("{}: {}\n", name, OnOff(on)),
+                            Ok(None) => alloc::format!("{}: missing\n", name),
+                            Err(()) => alloc::format!("{}: error\n", name),
+                        }
+                        .bytes(),
+                    );
+                }
+                text
+            })
+        },
+    },
+];
+
+/// Decodes a new-style revision code, e.g. 0xa02082 is a "Raspberry Pi 3 Model B Rev 1.2"
+fn model_name(revision: u32) -> alloc::string::String {
+    if revision & (1 << 23) == 0 {
+        return alloc::format!("Raspberry Pi (old-style revision {:#x})", revision);
+    }
+    let model = match (revision >> 4) & 0xff {
+        0x04 => "2 Model B",
+        0x08 => "3 Model B",
+        0x0a => "Compute Module 3",
+        0x0d => "3 Model B+",
+        0x0e => "3 Model A+",
+        0x10 => "Compute Module 3+",
+        0x12 => "Zero 2 W",
+        _ => "(unknown model)",
+    };
+    alloc::format!("Raspberry Pi {} Rev 1.{}", model, revision & 0xf)
+}
+
+struct MemoryRange(PhySlice);
+
+impl core::fmt::Display for MemoryRange {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:#010x}-{:#010x} ({} MiB)",
+            self.0.base.0,
+            self.0.base.0 + self.0.len,
+            self.0.len / 1024 / 1024
+        )
+    }
+}
+
+/// Thousandths of a degree
+struct Celsius(u32);
+
+impl core::fmt::Display for Celsius {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}.{} C", self.0 / 1000, self.0 % 1000 / 100)
+    }
+}
+
+struct OnOff(bool);
+
+impl core::fmt::Display for OnOff {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(if self.0 { "on" } else { "off" })
+    }
+}
+
+// ----- Interrupts -----
+
+/// See `mmio::IRQ_BASIC_BASE` for the numbering
+pub const IRQ_COUNT: usize = 128;
+pub const IRQ_TIMER: u32 = IRQ_LOCAL_CNTPNS;
+pub const IRQ_IPI: u32 = IRQ_LOCAL_MBOX0;
+
+pub unsafe fn init_irqs() {
+    init_irqs_secondary();
+    mailbox::enable_irq();
+}
+
+/// Routes the current core's generic timer and IPI mailbox to its IRQ line
+pub unsafe fn init_irqs_secondary() {
+    let core = current_core();
+    mmio_write(core_timer_int_ctrl(core), CORE_TIMER_INT_CTRL_CNTPNS);
+    mmio_write(core_mbox_int_ctrl(core), CORE_MBOX_INT_CTRL_MBOX0);
+}
+
+pub unsafe fn enable_irq(irq: u32) {
+    match irq {
+        0..=31 => mmio_write(ENABLE_IRQS_1, 1 << irq),
+        32..=63 => mmio_write(ENABLE_IRQS_2, 1 << (irq - 32)),
+        64..=71 => mmio_write(ENABLE_BASIC_IRQS, 1 << (irq - IRQ_BASIC_BASE)),
+        // Core-local interrupts are enabled on every core by `init_irqs_secondary`
+        _ => {}
+    }
+}
+
+pub unsafe fn disable_irq(irq: u32) {
+    match irq {
+        0..=31 => mmio_write(DISABLE_IRQS_1, 1 << irq),
+        32..=63 => mmio_write(DISABLE_IRQS_2, 1 << (irq - 32)),
+        64..=71 => mmio_write(DISABLE_BASIC_IRQS, 1 << (irq - IRQ_BASIC_BASE)),
+        _ => {}
+    }
+}
+
+pub unsafe fn send_ipi(core: usize) {
+    mmio_write(core_mbox0_set(core), 1);
+}
+
+/// Dispatches every pending bit of `pending`, numbered from `base`
+unsafe fn dispatch_pending(base: u32, mut pending: u32, e: &mut ExceptionContext) {
+    while pending != 0 {
+        let bit = pending.trailing_zeros();
+        pending &= pending - 1;
+        irq::dispatch(base + bit, e);
+    }
+}
+
+pub unsafe fn handle_irq(e: &mut ExceptionContext) {
+    let source = mmio_read(core_irq_source(current_core()));
+
+    if source & CORE_IRQ_SOURCE_MBOX0 != 0 {
+        // Ack interrupt
+        mmio_write(core_mbox0_rdclr(current_core()), 0xffffffff);
+        irq::dispatch(IRQ_LOCAL_MBOX0, e);
+    }
+
+    if source & CORE_IRQ_SOURCE_CNTPNS != 0 {
+        irq::dispatch(IRQ_LOCAL_CNTPNS, e);
+    }
+
+    if source & CORE_IRQ_SOURCE_GPU != 0 {
+        // Bits 8+ of the basic register summarize the banks, read the banks themselves instead
+        let basic = mmio_read(IRQ_BASIC_PENDING) & 0xff;
+        let bank1 = mmio_read(IRQ_PENDING_1);
+        let bank2 = mmio_read(IRQ_PENDING_2);
+        if basic | bank1 | bank2 == 0 {
+            irq::spurious();
+        }
+        dispatch_pending(IRQ_BASIC_BASE, basic, e);
+        dispatch_pending(0, bank1, e);
+        dispatch_pending(32, bank2, e);
+    }
+
+    if source & (CORE_IRQ_SOURCE_MBOX0 | CORE_IRQ_SOURCE_CNTPNS | CORE_IRQ_SOURCE_GPU) == 0 {
+        irq::spurious();
+    }
+}
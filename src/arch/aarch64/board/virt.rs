@@ -0,0 +1,255 @@
+use super::{info_text, Board, EarlyConsole, InfoFile};
+use crate::arch::aarch64::dtb::{self, MemoryLayout};
+use crate::arch::aarch64::exceptions::ExceptionContext;
+use crate::arch::aarch64::gic::{self, Gic};
+use crate::arch::aarch64::pl011::{self, Pl011};
+use crate::arch::aarch64::{interrupts, irq};
+use crate::boot_options;
+use crate::ipc::well_known;
+use crate::prelude::*;
+use crate::{driver_manager, fi, threads};
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+pub const BOARD: Board = Board::QemuVirt;
+pub const DTB_COMPATIBLE: &str = "linux,dummy-virt";
+
+/// Physical start of RAM, must match `__ram_base` in the linker script
+pub const RAM_BASE: usize = 0x40000000;
+/// Used when there's no device tree to tell us better
+pub const FALLBACK_MEMORY: PhySlice = PhySlice {
+    base: PhyAddr(RAM_BASE),
+    len: 128 * 1024 * 1024,
+};
+/// No peripherals share RAM's GiB, all of it is normal memory
+pub const RAM_IOMEM_START: usize = RAM_BASE + 0x40000000;
+/// Other 1GiB blocks to map as device memory: Everything below RAM (GIC, PL011, virtio, ...)
+pub const DEVICE_BLOCKS: [usize; 1] = [0];
+
+pub const EARLY_CONSOLES: [EarlyConsole; 1] = [EarlyConsole {
+    name: b"pl011",
+    driver_name: b"QEMU virt PL011 UART",
+    init: init_uart0,
+}];
+/// The UART the kernel shell runs on, its directory has an input and an output queue
+pub const SHELL_UART: [u64; 3] = [
+    well_known::ROOT_DEVICES,
+    well_known::DEVICES_PL011,
+    well_known::PL011_UART0,
+];
+pub const SHELL_UART_IN: u64 = well_known::PL011_IN;
+pub const SHELL_UART_OUT: u64 = well_known::PL011_OUT;
+pub const HAS_FRAMEBUFFER: bool = false;
+
+static GIC: Gic = Gic::new(0x08000000, 0x08010000);
+
+const PSCI_CPU_OFF: u64 = 0x84000002;
+const PSCI_CPU_ON: u64 = 0xC4000003;
+const PSCI_SYSTEM_OFF: u64 = 0x84000008;
+const PSCI_SYSTEM_RESET: u64 = 0x84000009;
+
+// ----- UART -----
+
+static UART0: pl011::Device = pl011::Device::new(Pl011::new(0x09000000, 24_000_000, IRQ_UART0));
+
+static mut DRIVER: pl011::Driver = pl011::Driver::new(
+    b"QEMU virt PL011 UART",
+    BOARD,
+    &UART0,
+    fi::FileInterface {
+        sync_read: Some(&UART0),
+        read: Some(&UART0),
+        sync_write: Some(&UART0),
+        write: Some(&UART0),
+        ctrl: None,
+    },
+    SHELL_UART,
+    uart0_irq,
+    init_uart0,
+);
+
+#[link_section = ".drivers"]
+#[used]
+static mut DRIVER_REF: &dyn driver_manager::Driver = unsafe { &DRIVER };
+
+fn init_uart0() {
+    UART0.uart.init(&boot_options::get().uart0);
+}
+
+unsafe fn uart0_irq(_e: &mut ExceptionContext) {
+    UART0.handle_irq();
+}
+
+/// Writes to the console before any driver is up
+pub fn early_write(buf: &[u8]) {
+    UART0.uart.write_sync(buf);
+}
+
+/// The peripherals are always in the same place
+///
+/// # Safety
+///
+/// This function assumes it runs once, on core 0, before any driver is initialized
+pub unsafe fn map_peripherals(_layout: Option<&MemoryLayout>) {}
+
+/// QEMU puts the command line in the device tree, there's nowhere else to look
+pub fn firmware_cmdline<const CAP: usize>(_cmdline: &mut ArrayVec<u8, CAP>) {}
+
+/// Calls the PSCI firmware, which is either behind `hvc` or `smc` depending on the device tree
+unsafe fn psci_call(function: u64, arg1: u64, arg2: u64) -> u64 {
+    let mut result = function;
+    match dtb::property(&["psci"], "method") {
+        Some(b"smc\0") => asm!(
+            "smc #0",
+            inout("x0") result,
+            in("x1") arg1,
+            in("x2") arg2,
+            in("x3") 0,
+            options(nostack)
+        ),
+        _ => asm!(
+            "hvc #0",
+            inout("x0") result,
+            in("x1") arg1,
+            in("x2") arg2,
+            in("x3") 0,
+            options(nostack)
+        ),
+    }
+    result
+}
+
+/// Powers on `core` with PSCI, making it jump to `entry`
+///
+/// # Safety
+///
+/// This function assumes `entry` is the physical address of code that's fine with the MMU off
+pub unsafe fn start_core(core: usize, entry: usize) -> Result<(), ()> {
+    // The MPIDR of core N has Aff0 = N
+    let result = psci_call(PSCI_CPU_ON, core as u64, entry as u64);
+    if result == 0 {
+        Ok(())
+    } else {
+        println!(
+            "[WARN] PSCI CPU_ON for core #{} failed: {}",
+            core, result as i64
+        );
+        Err(())
+    }
+}
+
+// ----- Power -----
+
+pub fn reboot() -> ! {
+    unsafe { psci_call(PSCI_SYSTEM_RESET, 0, 0) };
+    halt()
+}
+
+/// Set by `halt`, cores getting an IPI turn themselves off when they see it
+static HALTING: AtomicBool = AtomicBool::new(false);
+
+/// Turns every core off with PSCI CPU_OFF: The others through an IPI, then this one
+pub fn halt() -> ! {
+    unsafe {
+        interrupts::disable();
+        HALTING.store(true, Ordering::SeqCst);
+        let current_core = threads::current_core();
+        for core in (0..threads::CORE_COUNT).filter(|core| *core != current_core) {
+            send_ipi(core);
+        }
+        cpu_off()
+    }
+}
+
+/// Called on IPIs, turns the current core off if the machine is halting
+pub unsafe fn check_halting() {
+    if HALTING.load(Ordering::SeqCst) {
+        cpu_off()
+    }
+}
+
+unsafe fn cpu_off() -> ! {
+    interrupts::disable();
+    psci_call(PSCI_CPU_OFF, 0, 0);
+    // Only returns if the firmware refused
+    loop {
+        asm!("wfi");
+    }
+}
+
+pub fn poweroff() -> ! {
+    unsafe { psci_call(PSCI_SYSTEM_OFF, 0, 0) };
+    halt()
+}
+
+// ----- Board info -----
+
+pub const INFO_FILES: [InfoFile; 2] = [
+    InfoFile {
+        name: b"model",
+        read: || Box::pin(async { info_text(Ok("QEMU virt")) }),
+    },
+    InfoFile {
+        name: b"memory",
+        read: || {
+            Box::pin(async {
+                info_text(dtb::memory_layout().map(|layout| alloc::format!("{:?}", layout.memory)))
+            })
+        },
+    },
+];
+
+// ----- Interrupts -----
+
+/// GIC interrupt ids: SGIs, then PPIs from 16, then SPIs from 32
+pub const IRQ_COUNT: usize = 256;
+/// EL1 physical timer PPI
+pub const IRQ_TIMER: u32 = 30;
+/// Software generated interrupt used for IPIs
+pub const IRQ_IPI: u32 = 0;
+/// SPI 1
+const IRQ_UART0: u32 = 33;
+
+pub unsafe fn init_irqs() {
+    GIC.init_distributor();
+    init_irqs_secondary();
+}
+
+/// Enables the IPI and timer interrupts on a secondary core
+pub unsafe fn init_irqs_secondary() {
+    GIC.init_cpu_interface();
+    // SGIs and PPIs are banked, every core enables its own
+    GIC.enable(IRQ_IPI);
+    GIC.enable(IRQ_TIMER);
+}
+
+pub unsafe fn enable_irq(irq: u32) {
+    GIC.enable(irq);
+}
+
+pub unsafe fn disable_irq(irq: u32) {
+    GIC.disable(irq);
+}
+
+pub unsafe fn send_ipi(core: usize) {
+    GIC.send_sgi(core, IRQ_IPI);
+}
+
+pub unsafe fn handle_irq(e: &mut ExceptionContext) {
+    let mut handled = false;
+    loop {
+        let iar = GIC.acknowledge();
+        let irq = iar & 0x3ff;
+        if irq >= gic::SPURIOUS_IRQ {
+            if !handled {
+                irq::spurious();
+            }
+            break;
+        }
+
+        irq::dispatch(irq, e);
+        handled = true;
+
+        GIC.end_of_interrupt(iar);
+    }
+}
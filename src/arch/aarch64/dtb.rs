@@ -1,7 +1,161 @@
+use crate::arch::aarch64::mmio;
 use crate::prelude::*;
 
+use core::sync::atomic::{AtomicUsize, Ordering};
 use dtb::StructItem;
 
+/// Physical address of the device tree blob given by the bootloader, 0 if there is none
+static DTB_ADDR: AtomicUsize = AtomicUsize::new(0);
+
+const MAX_DEPTH: usize = 16;
+
+/// A single entry of a `ranges` property, maps addresses on a child bus to its parent bus
+#[derive(Debug, Copy, Clone)]
+pub struct BusRange {
+    pub child: u64,
+    pub parent: u64,
+    pub len: u64,
+}
+
+impl BusRange {
+    pub fn translate(&self, child_addr: u64) -> Option<u64> {
+        if child_addr >= self.child && child_addr - self.child < self.len {
+            Some(child_addr - self.child + self.parent)
+        } else {
+            None
+        }
+    }
+}
+
+/// The physical memory map described by the device tree
+#[derive(Debug)]
+pub struct MemoryLayout {
+    /// Usable RAM, from the `/memory` nodes
+    pub memory: ArrayVec<PhySlice, 8>,
+    /// Ranges from `/memreserve/`, `/reserved-memory`, the initrd and the blob itself
+    pub reserved: ArrayVec<PhySlice, 32>,
+    /// Address translation from the peripheral bus, from `/soc/ranges`
+    pub soc_ranges: ArrayVec<BusRange, 8>,
+}
+
+impl MemoryLayout {
+    /// Physical address of the main peripherals (the ones at `mmio::MMIO_BUS_BASE` on the bus)
+    pub fn mmio_base(&self) -> Option<PhyAddr> {
+        self.soc_ranges
+            .iter()
+            .find_map(|range| range.translate(mmio::MMIO_BUS_BASE as u64))
+            .map(|addr| PhyAddr(addr as usize))
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Node<'a> {
+    name: &'a str,
+    address_cells: u32,
+    size_cells: u32,
+}
+
+impl<'a> Node<'a> {
+    fn node_name(&self) -> &'a str {
+        self.name.split('@').next().unwrap()
+    }
+}
+
+/// Reads a big-endian number made of `cells` 32bit cells from the start of `value`
+fn read_cells(value: &[u8], cells: u32) -> Option<(u64, &[u8])> {
+    let len = cells as usize * 4;
+    if cells > 2 || value.len() < len {
+        return None;
+    }
+    let num = value[..len]
+        .iter()
+        .fold(0u64, |acc, byte| (acc << 8) | *byte as u64);
+    Some((num, &value[len..]))
+}
+
+fn read_u32(value: &[u8]) -> Option<u32> {
+    read_cells(value, 1).map(|(num, _)| num as u32)
+}
+
+/// Parses a `reg` property into `out`, using the cell sizes of the node's parent
+fn read_reg<const CAP: usize>(
+    mut value: &[u8],
+    parent: &Node,
+    out: &mut ArrayVec<PhySlice, CAP>,
+) -> Result<(), ()> {
+    while !value.is_empty() {
+        let (base, rest) = read_cells(value, parent.address_cells).ok_or(())?;
+        let (len, rest) = read_cells(rest, parent.size_cells).ok_or(())?;
+        value = rest;
+        if len != 0 {
+            out.try_push(PhySlice {
+                base: PhyAddr(base as usize),
+                len: len as usize,
+            })
+            .map_err(|_| ())?;
+        }
+    }
+    Ok(())
+}
+
+/// Parses a `ranges` property of `node`, whose parent is `parent`
+fn read_ranges<const CAP: usize>(
+    mut value: &[u8],
+    node: &Node,
+    parent: &Node,
+    out: &mut ArrayVec<BusRange, CAP>,
+) -> Result<(), ()> {
+    while !value.is_empty() {
+        let (child, rest) = read_cells(value, node.address_cells).ok_or(())?;
+        let (parent_addr, rest) = read_cells(rest, parent.address_cells).ok_or(())?;
+        let (len, rest) = read_cells(rest, node.size_cells).ok_or(())?;
+        value = rest;
+        out.try_push(BusRange {
+            child,
+            parent: parent_addr,
+            len,
+        })
+        .map_err(|_| ())?;
+    }
+    Ok(())
+}
+
+/// Calls `f` with every node's path (root first) and its properties.
+/// A node's `#address-cells` and `#size-cells` are applied as soon as they're seen, so they're
+/// always in place by the time `f` sees the node's children.
+fn for_each_property<'a, F: FnMut(&[Node<'a>], &'a str, &'a [u8]) -> Result<(), ()>>(
+    reader: &dtb::Reader<'a>,
+    mut f: F,
+) -> Result<(), ()> {
+    let mut path: ArrayVec<Node, MAX_DEPTH> = ArrayVec::new();
+    for item in reader.struct_items() {
+        match item {
+            StructItem::BeginNode { name } => {
+                // Default cell sizes, as defined by the devicetree spec
+                path.try_push(Node {
+                    name,
+                    address_cells: 2,
+                    size_cells: 1,
+                })
+                .map_err(|_| ())?;
+            }
+            StructItem::Property { name, value } => {
+                let node = path.last_mut().ok_or(())?;
+                match name {
+                    "#address-cells" => node.address_cells = read_u32(value).ok_or(())?,
+                    "#size-cells" => node.size_cells = read_u32(value).ok_or(())?,
+                    _ => {}
+                }
+                f(&path, name, value)?;
+            }
+            StructItem::EndNode => {
+                path.pop().ok_or(())?;
+            }
+        }
+    }
+    Ok(())
+}
+
 fn dtb_tree(reader: &dtb::Reader) {
     let mut depth = 0;
     for si in reader.struct_items() {
@@ -20,15 +174,138 @@ fn dtb_tree(reader: &dtb::Reader) {
     }
 }
 
-pub unsafe fn parse(dtb: *const u8) {
-    // let dtb = dtb::Reader::read(dtb).unwrap();
-    let dtb = dtb::Reader::read_from_address(dtb as usize).unwrap();
-    println!(
-        "{:?}",
-        dtb.reserved_mem_entries().collect::<ArrayVec<_, 64>>()
-    );
-    // for si in dtb.struct_items() {
-    //     println!("- {:?}", si);
-    // }
-    // dtb_tree(&dtb);
+/// # Safety
+///
+/// This function assumes `dtb_addr` is either a valid dtb or null, and that it's mapped
+pub unsafe fn init(dtb_addr: PhyAddr) -> Result<(), ()> {
+    if dtb_addr.0 == 0 {
+        return Err(());
+    }
+    dtb::Reader::read_from_address(dtb_addr.virt() as usize).map_err(|_| ())?;
+    DTB_ADDR.store(dtb_addr.0, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Returns a reader for the device tree, if we were given one
+pub fn reader() -> Option<dtb::Reader<'static>> {
+    let addr = DTB_ADDR.load(Ordering::SeqCst);
+    if addr == 0 {
+        return None;
+    }
+    // SAFETY: `init` verified the blob, and nobody writes to it
+    unsafe { dtb::Reader::read_from_address(PhyAddr(addr).virt() as usize).ok() }
+}
+
+/// The physical range occupied by the device tree blob
+pub fn blob() -> Option<PhySlice> {
+    let addr = DTB_ADDR.load(Ordering::SeqCst);
+    if addr == 0 {
+        return None;
+    }
+    // SAFETY: `init` verified the blob, the header starts with the magic and the total size
+    let total_size = unsafe { u32::from_be(*(PhyAddr(addr).virt() as *const u32).add(1)) };
+    Some(PhySlice {
+        base: PhyAddr(addr),
+        len: total_size as usize,
+    })
+}
+
+pub fn memory_layout() -> Result<MemoryLayout, ()> {
+    let reader = reader().ok_or(())?;
+    let mut layout = MemoryLayout {
+        memory: ArrayVec::new(),
+        reserved: ArrayVec::new(),
+        soc_ranges: ArrayVec::new(),
+    };
+
+    for entry in reader.reserved_mem_entries() {
+        layout
+            .reserved
+            .try_push(PhySlice {
+                base: PhyAddr(entry.address as usize),
+                len: entry.size as usize,
+            })
+            .map_err(|_| ())?;
+    }
+    layout
+        .reserved
+        .try_push(blob().ok_or(())?)
+        .map_err(|_| ())?;
+    if let Some(initrd) = initrd() {
+        layout.reserved.try_push(initrd).map_err(|_| ())?;
+    }
+
+    // The cell sizes of `/soc` may come after its `ranges`, so only decode them at the end
+    let mut soc: Option<(Node, Node, &[u8])> = None;
+
+    for_each_property(&reader, |path, name, value| match (path, name) {
+        ([root, node], "reg") if node.node_name() == "memory" => {
+            read_reg(value, root, &mut layout.memory)
+        }
+        ([_, parent, _], "reg") if parent.node_name() == "reserved-memory" => {
+            read_reg(value, parent, &mut layout.reserved)
+        }
+        ([root, node], _) if node.node_name() == "soc" => {
+            let ranges = if name == "ranges" {
+                value
+            } else {
+                soc.map(|(_, _, ranges)| ranges).unwrap_or(&[])
+            };
+            soc = Some((*root, *node, ranges));
+            Ok(())
+        }
+        _ => Ok(()),
+    })?;
+
+    if let Some((root, node, ranges)) = soc {
+        read_ranges(ranges, &node, &root, &mut layout.soc_ranges)?;
+    }
+
+    Ok(layout)
+}
+
+/// Finds a property by the path of its node, e.g. `property(&["chosen"], "bootargs")`
+pub fn property(node_path: &[&str], name: &str) -> Option<&'static [u8]> {
+    let reader = reader()?;
+    let mut found = None;
+
+    for_each_property(&reader, |path, prop_name, value| {
+        // The first node is the root, it has no name
+        if prop_name == name
+            && path.len() == node_path.len() + 1
+            && path[1..]
+                .iter()
+                .zip(node_path)
+                .all(|(node, wanted)| node.name == *wanted)
+        {
+            found = Some(value);
+        }
+        Ok(())
+    })
+    .ok()?;
+
+    found
+}
+
+/// The initial ramdisk loaded by the bootloader, from `/chosen`
+pub fn initrd() -> Option<PhySlice> {
+    // These are either 32 or 64 bit, regardless of `#address-cells`
+    let read_addr = |value: &[u8]| read_cells(value, (value.len() / 4) as u32).map(|(n, _)| n);
+    let start = read_addr(property(&["chosen"], "linux,initrd-start")?)?;
+    let end = read_addr(property(&["chosen"], "linux,initrd-end")?)?;
+
+    if end > start {
+        Some(PhySlice {
+            base: PhyAddr(start as usize),
+            len: (end - start) as usize,
+        })
+    } else {
+        None
+    }
+}
+
+pub fn print_tree() {
+    if let Some(reader) = reader() {
+        dtb_tree(&reader);
+    }
 }
@@ -1,18 +1,122 @@
+use crate::arch::aarch64::board::Board;
 use crate::arch::aarch64::mmio::{
-    mmio_read, mmio_write, RNG_CTRL, RNG_DATA, RNG_INT_MASK, RNG_STATUS,
+    mmio_read, mmio_write, sleep_us, RNG_CTRL, RNG_DATA, RNG_INT_MASK, RNG_STATUS,
 };
+use crate::driver_manager::{DeviceType, DriverInfo};
+use crate::prelude::*;
+use crate::{driver_manager, fi, random};
+use core::cell::UnsafeCell;
+use spin::RwLock;
 
-pub unsafe fn init() {
-    mmio_write(RNG_STATUS, 0x40000);
+/// Words read from the RNG to seed the CSPRNG, and every time it's reseeded
+const SEED_WORDS: usize = 8;
+const RESEED_INTERVAL_US: u64 = 60 * 1000 * 1000;
 
-    // mask interrupt
-    mmio_write(RNG_INT_MASK, mmio_read(RNG_INT_MASK) | 1);
+// ----- Driver -----
 
-    // enable
-    mmio_write(RNG_CTRL, mmio_read(RNG_CTRL) | 1);
+#[derive(Debug)]
+struct Driver {
+    info: UnsafeCell<DriverInfo>,
 }
 
-pub unsafe fn get() -> u32 {
-    while ((mmio_read(RNG_STATUS)) >> 24) == 0 {}
-    mmio_read(RNG_DATA)
+impl driver_manager::Driver for Driver {
+    fn init(&self) -> Result<(), ()> {
+        unsafe {
+            // Discard the first numbers, they're not random yet
+            mmio_write(RNG_STATUS, 0x40000);
+
+            // Mask the interrupt, we poll
+            mmio_write(RNG_INT_MASK, mmio_read(RNG_INT_MASK) | 1);
+
+            mmio_write(RNG_CTRL, mmio_read(RNG_CTRL) | 1);
+
+            // FIXME: Vulnerability
+            (*self.info.get()).initialized = true;
+        }
+
+        spawn_task!(b"RNG.seed", {
+            let mut buf = [0u8; SEED_WORDS * 4];
+            loop {
+                if fi::Read::read_exact(&DEVICE, &mut buf).await.is_ok() {
+                    random::add_entropy(&buf, buf.len() as u64 * 8);
+                }
+                sleep_us(RESEED_INTERVAL_US).await;
+            }
+        });
+
+        Ok(())
+    }
+
+    fn info(&'static self) -> &'static DriverInfo {
+        // FIXME: Vulnerability
+        unsafe { self.info.get().as_ref().unwrap() }
+    }
+
+    fn board(&self) -> Option<Board> {
+        Some(Board::RaspberryPi3)
+    }
 }
+
+static mut DRIVER: Driver = Driver {
+    info: UnsafeCell::new(DriverInfo {
+        name: b"Raspberry Pi 3 RNG",
+        initialized: false,
+        devices: RwLock::new([driver_manager::Device {
+            device_type: DeviceType::Entropy,
+            interface: fi::FileInterface {
+                sync_read: Some(&DEVICE),
+                read: Some(&DEVICE),
+                sync_write: None,
+                write: None,
+                ctrl: None,
+            },
+        }]),
+    }),
+};
+
+#[link_section = ".drivers"]
+#[used]
+static mut DRIVER_REF: &dyn driver_manager::Driver = unsafe { &DRIVER };
+
+// ----- Device -----
+
+#[derive(Debug)]
+struct Device;
+
+/// Words waiting in the RNG's FIFO
+fn available_words() -> u32 {
+    unsafe { mmio_read(RNG_STATUS) >> 24 }
+}
+
+/// Reads whole words, so a short `buf` wastes the rest of one
+fn read_word(buf: &mut [u8]) -> usize {
+    let word = unsafe { mmio_read(RNG_DATA) }.to_le_bytes();
+    let len = buf.len().min(word.len());
+    buf[..len].copy_from_slice(&word[..len]);
+    len
+}
+
+#[async_trait]
+impl fi::Read for Device {
+    async fn read(&self, buf: &mut [u8]) -> IoResult<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        while available_words() == 0 {
+            yield_now().await;
+        }
+        Ok(read_word(buf))
+    }
+}
+
+impl fi::SyncRead for Device {
+    fn read(&self, buf: &mut [u8]) -> IoResult<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        while available_words() == 0 {}
+        Ok(read_word(buf))
+    }
+}
+
+static DEVICE: Device = Device;
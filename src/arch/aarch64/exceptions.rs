@@ -1,3 +1,4 @@
+use crate::arch::aarch64::backtrace::{self, Symbolized};
 use crate::prelude::*;
 use core::convert::TryFrom;
 
@@ -29,24 +30,125 @@ pub struct ExceptionContext {
     pub sp: u64,
 }
 
-#[allow(dead_code, unused_variables)]
-unsafe fn print_stacktrace(e: &mut ExceptionContext) {
-    println!("@@@@");
-    let sp = get_msr!(sp_el0);
-    println!("- PC: 0x{:x} ", e.pc);
-    println!("- LR: 0x{:x} ", e.lr);
-    for i in 0..128 {
-        let val = *(sp as *const u64).offset(i);
-        if (0xffffff8000080000..=0xffffff8000080000 + 0x03f400).contains(&val) {
-            println!("- {}: 0x{:x} ", i, val);
+/// Synchronous exception classes, from `ESR_EL1.EC`
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ExceptionClass {
+    Unknown,
+    TrappedFp,
+    IllegalState,
+    Svc64,
+    TrappedMsr,
+    InstructionAbortLower,
+    InstructionAbortSame,
+    PcAlignment,
+    DataAbortLower,
+    DataAbortSame,
+    SpAlignment,
+    FpException,
+    Breakpoint,
+    Other(u8),
+}
+
+impl ExceptionClass {
+    pub fn from_esr(esr: u64) -> ExceptionClass {
+        match (esr >> 26) as u8 & 0x3f {
+            0x00 => ExceptionClass::Unknown,
+            0x07 => ExceptionClass::TrappedFp,
+            0x0e => ExceptionClass::IllegalState,
+            0x15 => ExceptionClass::Svc64,
+            0x18 => ExceptionClass::TrappedMsr,
+            0x20 => ExceptionClass::InstructionAbortLower,
+            0x21 => ExceptionClass::InstructionAbortSame,
+            0x22 => ExceptionClass::PcAlignment,
+            0x24 => ExceptionClass::DataAbortLower,
+            0x25 => ExceptionClass::DataAbortSame,
+            0x26 => ExceptionClass::SpAlignment,
+            0x2c => ExceptionClass::FpException,
+            0x3c => ExceptionClass::Breakpoint,
+            ec => ExceptionClass::Other(ec),
+        }
+    }
+
+    pub fn is_abort(&self) -> bool {
+        matches!(
+            self,
+            ExceptionClass::InstructionAbortLower
+                | ExceptionClass::InstructionAbortSame
+                | ExceptionClass::DataAbortLower
+                | ExceptionClass::DataAbortSame
+        )
+    }
+}
+
+/// Describes the fault status code (`ESR_EL1.ISS.xFSC`) of an instruction or data abort
+fn describe_fault_status(esr: u64) -> &'static str {
+    match esr & 0x3f {
+        0b000000..=0b000011 => "address size fault",
+        0b000100..=0b000111 => "translation fault",
+        0b001000..=0b001011 => "access flag fault",
+        0b001100..=0b001111 => "permission fault",
+        0b010000 => "external abort",
+        0b100001 => "alignment fault",
+        0b110000 => "TLB conflict",
+        _ => "unknown fault",
+    }
+}
+
+unsafe fn dump_registers(e: &ExceptionContext, esr: u64, far: u64) {
+    println!("-------------------------------------------");
+    println!("Registers:");
+    for reg in e.gpr {
+        print!("{:016x} ", reg);
+    }
+    println!();
+    println!("Exception reason: 0x{:x}", esr);
+    println!("FAR (Address accessed): 0x{:x}", far);
+    println!("PC: 0x{:x}", e.pc);
+    println!("LR: 0x{:x}", e.lr);
+    println!("SP: 0x{:016x}", e.sp);
+    println!("SPSR: 0x{:x}", e.spsr);
+    println!("-------------------------------------------");
+}
+
+/// Kills the current thread because of a fault it caused, then switches to the next one
+unsafe fn kill_faulting_thread(
+    e: &mut ExceptionContext,
+    class: ExceptionClass,
+    esr: u64,
+    far: u64,
+) {
+    let executor = &crate::threads::EXECUTORS.get().unwrap()[crate::threads::current_core()];
+    let thread = match executor.current_thread() {
+        Some(thread) => thread,
+        None => panic!("Usermode exception without a thread: {:?}", class),
+    };
+
+    {
+        let thread = thread.read();
+        print!(
+            "[WARN] Thread #{} \"{}\" crashed: {:?}",
+            thread.id(),
+            AsciiStr(thread.name()),
+            class
+        );
+        if class.is_abort() {
+            print!(" ({}) at 0x{:x}", describe_fault_status(esr), far);
         }
+        println!(", PC=0x{:x}", e.pc);
     }
-    println!("@@@@");
+    dump_registers(e, esr, far);
+
+    thread.read().kill();
+    executor.switch(e);
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn exception_handler2(e: &mut ExceptionContext) {
-    if get_msr!(esr_el1) == 0x56000000 {
+    let esr = get_msr!(esr_el1);
+    let far = get_msr!(far_el1);
+    let class = ExceptionClass::from_esr(esr);
+
+    if class == ExceptionClass::Svc64 {
         match crate::syscalls::Syscall::try_from(e.gpr[8]) {
             Ok(syscall_no) => {
                 crate::syscalls::handle_syscall(e, syscall_no);
@@ -60,25 +162,26 @@ pub unsafe extern "C" fn exception_handler2(e: &mut ExceptionContext) {
         return;
     }
 
-    println!("-------------------------------------------");
-    // let sp = (e as *const ExceptionContext as *const u8)
-    //     .offset(size_of::<ExceptionContext>() as isize) as *const u64;
-    println!("Registers:");
-    for reg in e.gpr {
-        print!("{:016x} ", reg);
+    // SPSR.M == EL0t, the fault came from usermode, only the thread has to go
+    if e.spsr & 0b1111 == 0 {
+        kill_faulting_thread(e, class, esr, far);
+        return;
     }
-    println!();
-    println!("Exception reason: 0x{:x}", get_msr!(esr_el1));
-    println!("FAR (Address accessed): 0x{:x}", get_msr!(far_el1));
-    println!("PC: 0x{:x}", e.pc);
-    println!("LR: 0x{:x}", e.lr);
-    println!("SP: 0x{:016x}", e.sp);
-    println!("SPSR: 0x{:x}", e.spsr);
-    println!("-------------------------------------------");
-    print_stacktrace(e);
 
-    loop {
-        asm!("wfi");
+    dump_registers(e, esr, far);
+    println!("PC: {}", Symbolized(e.pc));
+    println!("Backtrace:");
+    backtrace::print_backtrace(e.gpr[29]);
+    if class.is_abort() {
+        panic!(
+            "Kernel exception: {:?} ({}) at 0x{:x}\nPC={}",
+            class,
+            describe_fault_status(esr),
+            far,
+            Symbolized(e.pc)
+        );
+    } else {
+        panic!("Kernel exception: {:?}\nPC={}", class, Symbolized(e.pc));
     }
 }
 
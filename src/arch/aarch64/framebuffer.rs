@@ -1,3 +1,4 @@
+use crate::arch::aarch64::board::Board;
 use crate::arch::aarch64::mailbox::_send_fb_property_tags;
 use crate::prelude::*;
 
@@ -85,6 +86,10 @@ impl driver_manager::Driver for Driver {
         // FIXME: Vulnerability
         unsafe { self.info.get().as_ref().unwrap() }
     }
+
+    fn board(&self) -> Option<Board> {
+        Some(Board::RaspberryPi3)
+    }
 }
 
 static mut DRIVER: Driver = Driver {
@@ -406,16 +411,29 @@ pub unsafe fn panic(message: &[u8]) {
             }
         }
 
-        // Draw message
-        for (i, c) in message.iter().enumerate() {
-            draw_char(
-                fb,
-                pitch,
-                crate::fonts::TERMINUS.get(),
-                if *c == b'\n' { b' ' } else { *c },
-                3 + i / 70,
-                5 + i % 70,
-            );
+        // Draw message, wrapping long lines
+        const COLUMNS: usize = 70;
+        let rows = (height as usize / 16).saturating_sub(6);
+        let (mut row, mut col) = (0, 0);
+        for c in message {
+            if *c == b'\n' || col == COLUMNS {
+                row += 1;
+                col = 0;
+            }
+            if row >= rows {
+                break;
+            }
+            if *c != b'\n' {
+                draw_char(
+                    fb,
+                    pitch,
+                    crate::fonts::TERMINUS.get(),
+                    *c,
+                    3 + row,
+                    5 + col,
+                );
+                col += 1;
+            }
         }
     }
 }
@@ -0,0 +1,98 @@
+//! ARM Generic Interrupt Controller, version 2
+
+use crate::prelude::*;
+
+// Distributor registers
+const GICD_CTLR: usize = 0x000;
+const GICD_ISENABLER: usize = 0x100;
+const GICD_ICENABLER: usize = 0x180;
+const GICD_IPRIORITYR: usize = 0x400;
+const GICD_ITARGETSR: usize = 0x800;
+const GICD_SGIR: usize = 0xF00;
+
+// CPU interface registers
+const GICC_CTLR: usize = 0x000;
+const GICC_PMR: usize = 0x004;
+const GICC_IAR: usize = 0x00C;
+const GICC_EOIR: usize = 0x010;
+
+/// Interrupt ids from here on mean there's nothing (more) to handle
+pub const SPURIOUS_IRQ: u32 = 1020;
+/// SGIs and PPIs are banked per core
+const PRIVATE_IRQS: u32 = 32;
+const DEFAULT_PRIORITY: u8 = 0xa0;
+
+pub struct Gic {
+    /// Physical address of the distributor
+    dist_base: usize,
+    /// Physical address of the CPU interface
+    cpu_base: usize,
+}
+
+impl Gic {
+    pub const fn new(dist_base: usize, cpu_base: usize) -> Self {
+        Gic {
+            dist_base,
+            cpu_base,
+        }
+    }
+
+    unsafe fn dist(&self, reg: usize) -> *mut u32 {
+        PhyAddr(self.dist_base + reg).virt_mut() as *mut u32
+    }
+
+    unsafe fn cpu(&self, reg: usize) -> *mut u32 {
+        PhyAddr(self.cpu_base + reg).virt_mut() as *mut u32
+    }
+
+    /// Turns on the distributor, once for the whole system
+    pub unsafe fn init_distributor(&self) {
+        self.dist(GICD_CTLR).write_volatile(1);
+    }
+
+    /// Turns on the current core's CPU interface, letting every priority through
+    pub unsafe fn init_cpu_interface(&self) {
+        self.cpu(GICC_PMR).write_volatile(0xff);
+        self.cpu(GICC_CTLR).write_volatile(1);
+    }
+
+    /// Enables `irq`, shared interrupts are routed to core 0
+    pub unsafe fn enable(&self, irq: u32) {
+        let irq = irq as usize;
+        (self.dist(GICD_IPRIORITYR) as *mut u8)
+            .add(irq)
+            .write_volatile(DEFAULT_PRIORITY);
+        if irq >= PRIVATE_IRQS as usize {
+            (self.dist(GICD_ITARGETSR) as *mut u8)
+                .add(irq)
+                .write_volatile(1);
+        }
+        self.dist(GICD_ISENABLER)
+            .add(irq / 32)
+            .write_volatile(1 << (irq % 32));
+    }
+
+    pub unsafe fn disable(&self, irq: u32) {
+        let irq = irq as usize;
+        self.dist(GICD_ICENABLER)
+            .add(irq / 32)
+            .write_volatile(1 << (irq % 32));
+    }
+
+    /// Sends software generated interrupt `sgi` to `core`
+    pub unsafe fn send_sgi(&self, core: usize, sgi: u32) {
+        self.dist(GICD_SGIR)
+            .write_volatile((1 << (16 + core)) | (sgi & 0xf));
+    }
+
+    /// Returns the id of the highest priority pending interrupt, which is then active until
+    /// passed to `end_of_interrupt`
+    pub unsafe fn acknowledge(&self) -> u32 {
+        self.cpu(GICC_IAR).read_volatile()
+    }
+
+    /// Takes the raw value returned by `acknowledge`
+    pub unsafe fn end_of_interrupt(&self, iar: u32) {
+        self.cpu(GICC_EOIR).write_volatile(iar);
+    }
+}
@@ -0,0 +1,495 @@
+//! The BCM2837's GPIO pins: function selection, pull-up/down, claims by drivers, and edge/level
+//! events delivered to async waiters
+
+use crate::arch::aarch64::board::Board;
+use crate::arch::aarch64::exceptions::ExceptionContext;
+use crate::arch::aarch64::irq;
+use crate::arch::aarch64::mmio::{
+    delay, mmio_read, mmio_write, GPCLR0, GPEDS0, GPFEN0, GPFSEL0, GPHEN0, GPLEN0, GPLEV0, GPPUD,
+    GPPUDCLK0, GPREN0, GPSET0, IRQ_GPIO,
+};
+use crate::driver_manager::{DeviceType, DriverInfo};
+use crate::ipc::{IpcDir, IpcNode, IpcRef};
+use crate::prelude::*;
+use crate::{driver_manager, fi, ipc};
+
+use core::cell::UnsafeCell;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use futures::prelude::stream::BoxStream;
+use spin::{Mutex, RwLock};
+
+pub const PIN_COUNT: u32 = 54;
+
+/// Owner of the pins configured through the IPC tree
+const IPC_OWNER: &[u8] = b"ipc";
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Function {
+    Input,
+    Output,
+    Alt0,
+    Alt1,
+    Alt2,
+    Alt3,
+    Alt4,
+    Alt5,
+}
+
+impl Function {
+    fn bits(self) -> u32 {
+        match self {
+            Function::Input => 0b000,
+            Function::Output => 0b001,
+            Function::Alt0 => 0b100,
+            Function::Alt1 => 0b101,
+            Function::Alt2 => 0b110,
+            Function::Alt3 => 0b111,
+            Function::Alt4 => 0b011,
+            Function::Alt5 => 0b010,
+        }
+    }
+
+    fn from_bits(bits: u32) -> Function {
+        match bits & 0b111 {
+            0b000 => Function::Input,
+            0b001 => Function::Output,
+            0b100 => Function::Alt0,
+            0b101 => Function::Alt1,
+            0b110 => Function::Alt2,
+            0b111 => Function::Alt3,
+            0b011 => Function::Alt4,
+            _ => Function::Alt5,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Function::Input => "in",
+            Function::Output => "out",
+            Function::Alt0 => "alt0",
+            Function::Alt1 => "alt1",
+            Function::Alt2 => "alt2",
+            Function::Alt3 => "alt3",
+            Function::Alt4 => "alt4",
+            Function::Alt5 => "alt5",
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Pull {
+    Off = 0,
+    Down = 1,
+    Up = 2,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Trigger {
+    Rising,
+    Falling,
+    /// Either edge
+    Change,
+    High,
+    Low,
+}
+
+/// Who claimed each pin
+static OWNERS: Mutex<[Option<&'static [u8]>; PIN_COUNT as usize]> =
+    Mutex::new([None; PIN_COUNT as usize]);
+/// Serializes read-modify-writes of registers shared by several pins. Also taken by the IRQ
+/// handler, so only lock it with interrupts disabled
+static REGS: Mutex<()> = Mutex::new(());
+
+struct Waiter {
+    /// Event detection is on for this pin
+    armed: bool,
+    fired: bool,
+    waker: Option<Waker>,
+}
+
+const NO_WAITER: Waiter = Waiter {
+    armed: false,
+    fired: false,
+    waker: None,
+};
+/// Locked before `REGS`, only with interrupts disabled
+static WAITERS: Mutex<[Waiter; PIN_COUNT as usize]> = Mutex::new([NO_WAITER; PIN_COUNT as usize]);
+
+/// The register for `pin` in the bank starting at `reg0`, and the pin's bit in it
+fn bank(reg0: u32, pin: u32) -> (u32, u32) {
+    assert!(pin < PIN_COUNT, "No GPIO pin {}", pin);
+    (reg0 + 4 * (pin / 32), 1 << (pin % 32))
+}
+
+// ----- Claims -----
+
+/// Claims all of `pins` for `owner`, or none of them if one belongs to someone else
+pub fn claim(pins: &[u32], owner: &'static [u8]) -> Result<(), ()> {
+    let mut owners = OWNERS.lock();
+    for pin in pins {
+        match *owners.get(*pin as usize).ok_or(())? {
+            Some(other) if other != owner => {
+                println!(
+                    "[WARN] GPIO {} belongs to \"{}\", \"{}\" can't have it",
+                    pin,
+                    AsciiStr(other),
+                    AsciiStr(owner)
+                );
+                return Err(());
+            }
+            _ => {}
+        }
+    }
+    for pin in pins {
+        owners[*pin as usize] = Some(owner);
+    }
+    Ok(())
+}
+
+/// Gives back the `pins` that belong to `owner`
+pub fn release(pins: &[u32], owner: &'static [u8]) {
+    let mut owners = OWNERS.lock();
+    for pin in pins {
+        if let Some(slot) = owners.get_mut(*pin as usize) {
+            if *slot == Some(owner) {
+                *slot = None;
+            }
+        }
+    }
+}
+
+pub fn owner(pin: u32) -> Option<&'static [u8]> {
+    OWNERS.lock().get(pin as usize).copied().flatten()
+}
+
+/// Claims `pins`, then sets their function and pull
+pub fn setup(pins: &[u32], owner: &'static [u8], function: Function, pull: Pull) -> Result<(), ()> {
+    claim(pins, owner)?;
+    for pin in pins {
+        set_function(*pin, function);
+        set_pull(*pin, pull);
+    }
+    Ok(())
+}
+
+// ----- Configuration -----
+
+pub fn set_function(pin: u32, function: Function) {
+    assert!(pin < PIN_COUNT, "No GPIO pin {}", pin);
+    let reg = GPFSEL0 + 4 * (pin / 10);
+    let shift = (pin % 10) * 3;
+
+    let _irq_lock = irq_lock();
+    let _regs = REGS.lock();
+    unsafe {
+        mmio_write(
+            reg,
+            mmio_read(reg) & !(0b111 << shift) | function.bits() << shift,
+        )
+    };
+}
+
+pub fn function(pin: u32) -> Function {
+    assert!(pin < PIN_COUNT, "No GPIO pin {}", pin);
+    let reg = GPFSEL0 + 4 * (pin / 10);
+    Function::from_bits(unsafe { mmio_read(reg) } >> ((pin % 10) * 3))
+}
+
+/// The pull can't be read back, the hardware only latches it
+pub fn set_pull(pin: u32, pull: Pull) {
+    let (clk_reg, bit) = bank(GPPUDCLK0, pin);
+
+    let _irq_lock = irq_lock();
+    let _regs = REGS.lock();
+    unsafe {
+        // The control signal needs 150 cycles to settle, then 150 more clocked into the pin
+        mmio_write(GPPUD, pull as u32);
+        delay(150);
+        mmio_write(clk_reg, bit);
+        delay(150);
+        mmio_write(GPPUD, 0);
+        mmio_write(clk_reg, 0);
+    }
+}
+
+/// Drives an output pin high or low
+pub fn write(pin: u32, high: bool) {
+    let (reg, bit) = bank(if high { GPSET0 } else { GPCLR0 }, pin);
+    unsafe { mmio_write(reg, bit) };
+}
+
+/// The pin's level, whatever its function
+pub fn read(pin: u32) -> bool {
+    let (reg, bit) = bank(GPLEV0, pin);
+    unsafe { mmio_read(reg) & bit != 0 }
+}
+
+// ----- Events -----
+
+/// Turns event detection for `pin` on for `trigger`, or off. `REGS` must be locked
+unsafe fn set_detect(pin: u32, trigger: Option<Trigger>) {
+    let rising = matches!(trigger, Some(Trigger::Rising) | Some(Trigger::Change));
+    let falling = matches!(trigger, Some(Trigger::Falling) | Some(Trigger::Change));
+    let high = trigger == Some(Trigger::High);
+    let low = trigger == Some(Trigger::Low);
+    for (reg0, on) in [
+        (GPREN0, rising),
+        (GPFEN0, falling),
+        (GPHEN0, high),
+        (GPLEN0, low),
+    ] {
+        let (reg, bit) = bank(reg0, pin);
+        let value = mmio_read(reg);
+        mmio_write(reg, if on { value | bit } else { value & !bit });
+    }
+}
+
+/// Waits for `trigger` on `pin`. Detection is only on while someone waits, so events in between
+/// are missed. Fails if the pin doesn't exist or someone else is already waiting on it
+pub async fn wait_for(pin: u32, trigger: Trigger) -> Result<(), ()> {
+    if pin >= PIN_COUNT {
+        return Err(());
+    }
+    {
+        let _irq_lock = irq_lock();
+        let mut waiters = WAITERS.lock();
+        let waiter = &mut waiters[pin as usize];
+        if waiter.armed {
+            return Err(());
+        }
+        *waiter = Waiter {
+            armed: true,
+            fired: false,
+            waker: None,
+        };
+        let _regs = REGS.lock();
+        unsafe { set_detect(pin, Some(trigger)) };
+    }
+    Event(pin).await;
+    Ok(())
+}
+
+/// Turns detection back off if it's dropped before the event
+struct Event(u32);
+
+impl Future for Event {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let _irq_lock = irq_lock();
+        let mut waiters = WAITERS.lock();
+        let waiter = &mut waiters[self.0 as usize];
+        if waiter.fired {
+            waiter.armed = false;
+            Poll::Ready(())
+        } else {
+            waiter.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for Event {
+    fn drop(&mut self) {
+        let _irq_lock = irq_lock();
+        let mut waiters = WAITERS.lock();
+        let waiter = &mut waiters[self.0 as usize];
+        if waiter.armed {
+            let _regs = REGS.lock();
+            unsafe { set_detect(self.0, None) };
+            *waiter = NO_WAITER;
+        }
+    }
+}
+
+unsafe fn handle_irq(_e: &mut ExceptionContext) {
+    let mut waiters = WAITERS.lock();
+    let _regs = REGS.lock();
+    for bank_start in (0..PIN_COUNT).step_by(32) {
+        let (status_reg, _) = bank(GPEDS0, bank_start);
+        let events = mmio_read(status_reg);
+        for pin in (bank_start..PIN_COUNT.min(bank_start + 32))
+            .filter(|pin| events & (1 << (pin % 32)) != 0)
+        {
+            // Level events would fire again right away
+            set_detect(pin, None);
+            let waiter = &mut waiters[pin as usize];
+            waiter.fired = true;
+            if let Some(waker) = waiter.waker.take() {
+                waker.wake();
+            }
+        }
+        mmio_write(status_reg, events);
+    }
+}
+
+// ----- IPC -----
+
+/// A pin in the IPC tree. Reading it gives its level, reading it as a queue waits for the level to
+/// change first. Writing `0` or `1` drives it as an output, `up`, `down` and `off` set its pull,
+/// and `in` makes it an input and gives it back
+pub struct IpcGpioPin {
+    pin: u32,
+    name: ArrayVec<u8, 8>,
+}
+
+impl IpcGpioPin {
+    pub fn new(pin: u32) -> Arc<Self> {
+        let mut name = ArrayVec::new();
+        name.try_extend_from_slice(b"gpio").unwrap();
+        if pin >= 10 {
+            name.push(b'0' + (pin / 10) as u8);
+        }
+        name.push(b'0' + (pin % 10) as u8);
+        Arc::new(IpcGpioPin { pin, name })
+    }
+
+    fn level_text(&self) -> [u8; 2] {
+        [b'0' + read(self.pin) as u8, b'\n']
+    }
+}
+
+fn copy_from(contents: &[u8], offset: usize, dest: &mut [u8]) -> usize {
+    if offset >= contents.len() {
+        return 0;
+    }
+    let len = dest.len().min(contents.len() - offset);
+    dest[..len].copy_from_slice(&contents[offset..offset + len]);
+    len
+}
+
+#[async_trait]
+impl IpcNode for IpcGpioPin {
+    fn dir_list<'a>(self: Arc<Self>) -> Option<BoxStream<'a, IpcRef>> {
+        None
+    }
+
+    async fn dir_get(self: Arc<Self>, _id: u64) -> Option<IpcRef> {
+        None
+    }
+
+    async fn dir_create(self: Arc<Self>, _id: u64) -> Option<IpcRef> {
+        None
+    }
+
+    async fn dir_link(
+        self: Arc<Self>,
+        _id: u64,
+        _node: Arc<dyn IpcNode + Send + Sync>,
+    ) -> Option<IpcRef> {
+        None
+    }
+
+    /// Fails if a driver owns the pin or the command is unknown
+    fn queue_write(self: Arc<Self>, data: &[u8]) -> Result<usize, ()> {
+        let command = data.strip_suffix(b"\n").unwrap_or(data);
+        if !matches!(command, b"0" | b"1" | b"up" | b"down" | b"off" | b"in") {
+            return Err(());
+        }
+        claim(&[self.pin], IPC_OWNER)?;
+        match command {
+            b"0" | b"1" => {
+                // Set the level first, so it doesn't glitch
+                write(self.pin, command == b"1");
+                set_function(self.pin, Function::Output);
+            }
+            b"up" => set_pull(self.pin, Pull::Up),
+            b"down" => set_pull(self.pin, Pull::Down),
+            b"off" => set_pull(self.pin, Pull::Off),
+            _ => {
+                set_function(self.pin, Function::Input);
+                release(&[self.pin], IPC_OWNER);
+            }
+        }
+        Ok(data.len())
+    }
+
+    /// Waits for the level to change, `None` if someone else is already waiting
+    async fn queue_read(self: Arc<Self>, dest: &mut [u8]) -> Option<usize> {
+        wait_for(self.pin, Trigger::Change).await.ok()?;
+        Some(copy_from(&self.level_text(), 0, dest))
+    }
+
+    async fn read_at(self: Arc<Self>, offset: u64, dest: &mut [u8]) -> Option<usize> {
+        Some(copy_from(&self.level_text(), offset as usize, dest))
+    }
+
+    fn describe(&self) -> [u8; 4] {
+        *b"GPIO"
+    }
+
+    fn name(&self) -> Option<&[u8]> {
+        Some(&self.name)
+    }
+}
+
+// ----- Driver -----
+
+#[derive(Debug)]
+struct Driver {
+    info: UnsafeCell<DriverInfo>,
+}
+
+impl driver_manager::Driver for Driver {
+    fn init(&self) -> Result<(), ()> {
+        // FIXME: Vulnerability
+        unsafe {
+            (*self.info.get()).initialized = true;
+        }
+
+        irq::register(IRQ_GPIO, b"GPIO", handle_irq)?;
+
+        spawn_task!(b"GPIO.ipc", {
+            let pins = (0..PIN_COUNT)
+                .map(|pin| IpcRef {
+                    id: pin as u64,
+                    inner: IpcGpioPin::new(pin),
+                })
+                .collect();
+            let root = ipc::ROOT.read().as_ref().unwrap().clone();
+            root.dir_get(ipc::well_known::ROOT_DEVICES)
+                .await
+                .unwrap()
+                .dir_link(
+                    ipc::well_known::DEVICES_GPIO,
+                    IpcDir::new_named(b"gpio", pins),
+                )
+                .await
+                .unwrap();
+        });
+
+        Ok(())
+    }
+
+    fn info(&'static self) -> &'static DriverInfo {
+        // FIXME: Vulnerability
+        unsafe { self.info.get().as_ref().unwrap() }
+    }
+
+    fn board(&self) -> Option<Board> {
+        Some(Board::RaspberryPi3)
+    }
+}
+
+static mut DRIVER: Driver = Driver {
+    info: UnsafeCell::new(DriverInfo {
+        name: b"Raspberry Pi 3 GPIO",
+        initialized: false,
+        devices: RwLock::new([driver_manager::Device {
+            device_type: DeviceType::Gpio,
+            interface: fi::FileInterface {
+                sync_read: None,
+                read: None,
+                sync_write: None,
+                write: None,
+                ctrl: None,
+            },
+        }]),
+    }),
+};
+
+#[link_section = ".drivers"]
+#[used]
+static mut DRIVER_REF: &dyn driver_manager::Driver = unsafe { &DRIVER };
@@ -1,14 +1,156 @@
+use crate::arch::aarch64::{board, interrupts, mmu, phymem};
 use crate::prelude::*;
+use crate::threads;
 
-#[naked]
-unsafe extern "C" fn _park_core() -> ! {
-    asm!("1: wfi", "b 1b", options(noreturn))
+/// Physical address of the top of each core's boot stack, read by `_secondary_start`
+#[no_mangle]
+static mut SECONDARY_STACKS: [usize; threads::CORE_COUNT] = [0; threads::CORE_COUNT];
+
+/// Clean and invalidate the cache line containing `addr`, so cores with their MMU (and thus
+/// their caches) still off see what we wrote
+unsafe fn flush_dcache_line(addr: *const ()) {
+    asm!("dc civac, {:x}", in(reg) addr, options(nostack));
 }
 
+/// Allocates a boot stack for each secondary core and has the board start it
+///
+/// # Safety
+///
+/// This function assumes it runs only once, on core 0, after the physical memory allocator and
+/// page tables are initialized
 pub unsafe fn init_multicore() {
-    *(PhyAddr(0xe0).virt_mut() as *mut usize) = _park_core as usize & 0xffffffff;
-    *(PhyAddr(0xe8).virt_mut() as *mut usize) = _park_core as usize & 0xffffffff;
-    *(PhyAddr(0xf0).virt_mut() as *mut usize) = _park_core as usize & 0xffffffff;
+    for (core, stack_top) in SECONDARY_STACKS.iter_mut().enumerate().skip(1) {
+        let stack = {
+            let mut phymem = phymem::PHYMEM_FREE_LIST.lock();
+            phymem
+                .alloc_pages(64) // 256KiB
+                .expect("Failed to allocate secondary core stack")
+        };
+        *stack_top = stack.base.0 + stack.len;
+        flush_dcache_line(stack_top as *const usize as *const ());
+
+        if board::start_core(core, _secondary_start as usize & 0xffffffff).is_err() {
+            println!("[WARN] Failed to start core #{}", core);
+        }
+    }
+}
+
+/// # Safety
+///
+/// This function assumes:
+/// - It runs once per secondary core, from low memory
+/// - `core` is the current core number, and its boot stack is in `SECONDARY_STACKS`
+#[no_mangle]
+unsafe extern "C" fn secondary_kmain(core: usize) -> ! {
+    mmu::init_secondary().unwrap();
+
+    let real_kmain_addr = PhyAddr(secondary_kmain_mmu as *const () as usize).virt();
+    let real_kmain_addr: unsafe extern "C" fn(usize) -> ! = core::mem::transmute(real_kmain_addr);
+    (real_kmain_addr)(core)
+}
+
+/// # Safety
+///
+/// Same as `secondary_kmain`, but the MMU is initialized with both low-half and high-half
+/// pointing to kernel memory
+#[no_mangle]
+unsafe extern "C" fn secondary_kmain_mmu(core: usize) -> ! {
+    asm!(
+        "mov sp, {:x}",
+        "mov x0, {:x}",
+        "b secondary_kmain_on_stack",
+        in(reg) PhyAddr(SECONDARY_STACKS[core]).virt() as usize,
+        in(reg) core,
+        options(noreturn)
+    )
+}
+
+/// # Safety
+///
+/// Same as `secondary_kmain_mmu`, but the stack was changed to the high-half address of the
+/// core's boot stack
+#[no_mangle]
+unsafe extern "C" fn secondary_kmain_on_stack(core: usize) -> ! {
+    mmu::eject_lowmem();
+    println!("[INFO] Core #{} online", core);
+
+    // The scheduler may not be up yet, it will send us a tick once it is
+    threads::EXECUTORS.wait();
+    interrupts::init_secondary();
+
+    loop {
+        asm!("wfi");
+    }
+}
+
+#[naked]
+unsafe extern "C" fn _secondary_start() -> ! {
+    asm!(
+        "    // read cpu affinity, x0 is kept as the argument for `secondary_kmain`",
+        "    mrs     x0, mpidr_el1",
+        "    and     x0, x0, #3",
+        "",
+        "    // get the boot stack for this core",
+        "    ldr     x1, =SECONDARY_STACKS",
+        "    mov     w1, w1", // FIXME: Truncate SECONDARY_STACKS to 32bit, ignoring highmem
+        "    ldr     x1, [x1, x0, lsl #3]",
+        "",
+        "    // set up EL1",
+        "    mrs     x3, CurrentEL",
+        "    and     x3, x3, #12       // clear reserved bits",
+        "",
+        "    // running at EL3?",
+        "    cmp     x3, #12",
+        "    bne     5f",
+        "    // should never be executed, just for completeness",
+        "    mov     x2, #0x5b1",
+        "    msr     scr_el3, x2",
+        "    mov     x2, #0x3c9",
+        "    msr     spsr_el3, x2",
+        "    adr     x2, 5f",
+        "    msr     elr_el3, x2",
+        "    eret",
+        "",
+        "5:",
+        "    // running at EL2?",
+        "    cmp     x3, #4",
+        "    beq     5f",
+        "    msr     sp_el1, x1",
+        "    // enable CNTP for EL1",
+        "    mrs     x3, cnthctl_el2",
+        "    orr     x3, x3, #3",
+        "    msr     cnthctl_el2, x3",
+        "    msr     cntvoff_el2, xzr",
+        "    // enable AArch64 in EL1",
+        "    mov     x3, #(1 << 31)    // AArch64",
+        "    orr     x3, x3, #(1 << 1)   // SWIO hardwired on Pi3",
+        "    msr     hcr_el2, x3",
+        "    mrs     x3, hcr_el2",
+        "    // Setup SCTLR access",
+        "    mov     x2, #0x0800",
+        "    movk    x2, #0x30d0, lsl #16",
+        "    msr     sctlr_el1, x2",
+        "// set up exception handlers",
+        "    ldr     x2, =_vectors",
+        "    mov     w2, w2", // FIXME: Truncate _vectors to 32bit, ignoring highmem
+        "    msr     vbar_el1, x2",
+        "    // change execution level to EL1",
+        "    mov     x2, #0x3c4",
+        "    msr     spsr_el2, x2",
+        "    adr     x2, 5f",
+        "    msr     elr_el2, x2",
+        "    eret",
+        "",
+        "5:",
+        "    mov     sp, x1",
+        "",
+        "    // jump to secondary_kmain, which shouldn't return. halt if it does",
+        "    bl      secondary_kmain",
+        "1:",
+        "    wfi",
+        "    b       1b",
+        options(noreturn)
+    )
 }
 
 #[naked]
@@ -0,0 +1,114 @@
+//! Interrupt handlers registered per IRQ line, with statistics. Line numbers are the board's,
+//! below `board::IRQ_COUNT`
+
+use crate::arch::aarch64::board;
+use crate::arch::aarch64::exceptions::ExceptionContext;
+use crate::prelude::*;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::RwLock;
+
+pub type IrqHandler = unsafe fn(&mut ExceptionContext);
+
+#[derive(Copy, Clone)]
+struct IrqAction {
+    name: &'static [u8],
+    handler: IrqHandler,
+}
+
+/// Also read by the IRQ handler, so only write it with interrupts disabled
+static ACTIONS: RwLock<[Option<IrqAction>; board::IRQ_COUNT]> =
+    RwLock::new([None; board::IRQ_COUNT]);
+
+// Only used to initialize `COUNTS`
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+static COUNTS: [AtomicU64; board::IRQ_COUNT] = [ZERO; board::IRQ_COUNT];
+static SPURIOUS_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// Calls `handler` whenever `irq` fires, and enables it. Fails if it's taken or out of range
+pub fn register(irq: u32, name: &'static [u8], handler: IrqHandler) -> Result<(), ()> {
+    let _irq_lock = irq_lock();
+    let mut actions = ACTIONS.write();
+    let action = actions.get_mut(irq as usize).ok_or(())?;
+    if action.is_some() {
+        println!(
+            "[WARN] IRQ {} is already taken, can't give it to \"{}\"",
+            irq,
+            AsciiStr(name)
+        );
+        return Err(());
+    }
+    *action = Some(IrqAction { name, handler });
+    unsafe { board::enable_irq(irq) };
+    Ok(())
+}
+
+/// Disables `irq` and forgets its handler
+pub fn unregister(irq: u32) {
+    let _irq_lock = irq_lock();
+    if let Some(action) = ACTIONS.write().get_mut(irq as usize) {
+        unsafe { board::disable_irq(irq) };
+        *action = None;
+    }
+}
+
+/// Unmasks a registered `irq` after `disable`
+pub fn enable(irq: u32) {
+    if (irq as usize) < board::IRQ_COUNT {
+        unsafe { board::enable_irq(irq) };
+    }
+}
+
+/// Masks `irq` without forgetting its handler
+pub fn disable(irq: u32) {
+    if (irq as usize) < board::IRQ_COUNT {
+        unsafe { board::disable_irq(irq) };
+    }
+}
+
+/// Called by the board's IRQ handler for every pending line
+pub unsafe fn dispatch(irq: u32, e: &mut ExceptionContext) {
+    let action = ACTIONS.read().get(irq as usize).copied().flatten();
+    match action {
+        Some(action) => {
+            COUNTS[irq as usize].fetch_add(1, Ordering::Relaxed);
+            (action.handler)(e);
+        }
+        None => {
+            // Nobody will acknowledge it, so it'd fire forever
+            println!("[WARN] Unhandled IRQ {}, disabling it", irq);
+            disable(irq);
+        }
+    }
+}
+
+/// Called by the board's IRQ handler when the controller had nothing pending
+pub fn spurious() {
+    SPURIOUS_COUNT.fetch_add(1, Ordering::Relaxed);
+}
+
+pub struct IrqStats {
+    pub irq: u32,
+    pub name: &'static [u8],
+    pub count: u64,
+}
+
+/// Statistics of the registered IRQs
+pub fn stats() -> Vec<IrqStats> {
+    ACTIONS
+        .read()
+        .iter()
+        .zip(0..)
+        .filter_map(|(action, irq)| {
+            action.map(|action| IrqStats {
+                irq,
+                name: action.name,
+                count: COUNTS[irq as usize].load(Ordering::Relaxed),
+            })
+        })
+        .collect()
+}
+
+pub fn spurious_count() -> u64 {
+    SPURIOUS_COUNT.load(Ordering::Relaxed)
+}
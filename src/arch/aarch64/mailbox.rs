@@ -1,12 +1,18 @@
+use crate::arch::aarch64::exceptions::ExceptionContext;
 use crate::arch::aarch64::framebuffer::FramebufferInfo;
+use crate::arch::aarch64::irq;
 use crate::arch::aarch64::mmio::{
-    delay_us_sync, mmio_read, mmio_write, MBOX_READ, MBOX_STATUS, MBOX_WRITE,
+    delay_us_sync, mmio_read, mmio_write, IRQ_ARM_MAILBOX, MBOX_CONFIG, MBOX_CONFIG_DATA_IRQ,
+    MBOX_READ, MBOX_STATUS, MBOX_WRITE,
 };
 use crate::prelude::*;
 use core::cmp::{max, min};
-use core::mem::size_of;
+use core::future::Future;
+use core::mem::{self, size_of};
 use core::ops::{Deref, DerefMut};
+use core::pin::Pin;
 use core::ptr::slice_from_raw_parts;
+use core::task::{Context, Poll, Waker};
 use spin::Mutex;
 
 /// This bit is set in the status register if there is no space to write into the mailbox
@@ -21,6 +27,12 @@ pub const MBOX_TAG_LAST: u32 = 0;
 
 pub const MBOX_RESPONSE: u32 = 0x80000000;
 
+/// The property tags channel
+const MBOX_CHANNEL_PROPERTY: u32 = 8;
+
+/// Requests that can be in flight at once
+const SLOT_COUNT: usize = 4;
+
 #[repr(align(16), C)]
 #[derive(Debug)]
 struct MailboxMessage {
@@ -29,41 +41,177 @@ struct MailboxMessage {
     rest: [u32; 50],
 }
 
-static MAILBOX_LOCK: Mutex<()> = Mutex::new(());
-
-#[link_section = ".dma"]
-#[used]
-static mut MAILBOX_MSG: MailboxMessage = MailboxMessage {
+const EMPTY_MESSAGE: MailboxMessage = MailboxMessage {
     size: 0,
     magic: 0,
     rest: [0; 50],
 };
 
-pub unsafe fn write_raw(data: u32) {
-    while mmio_read(MBOX_STATUS) & MAIL_FULL != 0 {}
-    mmio_write(MBOX_WRITE, data);
+#[link_section = ".dma"]
+#[used]
+static mut MAILBOX_MSGS: [MailboxMessage; SLOT_COUNT] = [EMPTY_MESSAGE; SLOT_COUNT];
+
+enum SlotState {
+    Free,
+    /// Taken, the message is being written or the reply read
+    Owned,
+    /// Sent, the waker is called when the reply arrives
+    InFlight(Option<Waker>),
+    /// Replied, the owner can read the reply
+    Done,
+    /// The owner gave up, freed once the VideoCore is done with the buffer
+    Abandoned,
+}
+
+const FREE_SLOT: SlotState = SlotState::Free;
+
+/// Also taken by the IRQ handler, so only lock it through `with_slots`
+static SLOTS: Mutex<[SlotState; SLOT_COUNT]> = Mutex::new([FREE_SLOT; SLOT_COUNT]);
+
+fn with_slots<R>(f: impl FnOnce(&mut [SlotState; SLOT_COUNT]) -> R) -> R {
+    let _irq_lock = irq_lock();
+    f(&mut *SLOTS.lock())
 }
 
-pub unsafe fn read_raw() -> u32 {
-    let mut response = false;
-    for _ in 0..1000 {
-        if (mmio_read(MBOX_STATUS) & MAIL_EMPTY) == 0 {
-            response = true;
-            break;
-        } else {
-            delay_us_sync(100);
+/// The mail the VideoCore sends back when it's done with the slot's buffer
+fn slot_mail(slot: usize) -> u32 {
+    let message = unsafe { &MAILBOX_MSGS[slot] } as *const MailboxMessage;
+    ((message as usize as u32) & !0xF) | MBOX_CHANNEL_PROPERTY
+}
+
+/// Takes all replies out of the mailbox and wakes whoever waits for them. Called by the IRQ
+/// handler, and by waiters in case interrupts aren't enabled yet
+fn receive_replies() {
+    with_slots(|slots| unsafe {
+        while mmio_read(MBOX_STATUS) & MAIL_EMPTY == 0 {
+            let mail = mmio_read(MBOX_READ);
+            // Replies on other channels aren't ours
+            if let Some(slot) = (0..SLOT_COUNT).find(|slot| slot_mail(*slot) == mail) {
+                match mem::replace(&mut slots[slot], SlotState::Done) {
+                    SlotState::InFlight(Some(waker)) => waker.wake(),
+                    SlotState::Abandoned => slots[slot] = SlotState::Free,
+                    _ => {}
+                }
+            }
         }
+    });
+}
+
+/// A message buffer owned by one request
+struct Slot(usize);
+
+impl Slot {
+    fn try_acquire() -> Option<Slot> {
+        with_slots(|slots| {
+            let slot = slots
+                .iter()
+                .position(|state| matches!(state, SlotState::Free))?;
+            slots[slot] = SlotState::Owned;
+            Some(Slot(slot))
+        })
+    }
+
+    async fn acquire() -> Slot {
+        loop {
+            if let Some(slot) = Slot::try_acquire() {
+                return slot;
+            }
+            yield_now().await;
+        }
+    }
+
+    fn acquire_sync() -> Slot {
+        loop {
+            if let Some(slot) = Slot::try_acquire() {
+                return slot;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    fn message(&mut self) -> &mut MailboxMessage {
+        unsafe { &mut MAILBOX_MSGS[self.0] }
+    }
+
+    fn is_done(&self) -> bool {
+        with_slots(|slots| matches!(slots[self.0], SlotState::Done))
     }
-    if !response {
+
+    fn send(&mut self) {
+        with_slots(|slots| slots[self.0] = SlotState::InFlight(None));
+        unsafe {
+            while mmio_read(MBOX_STATUS) & MAIL_FULL != 0 {}
+            mmio_write(MBOX_WRITE, slot_mail(self.0));
+        }
+    }
+
+    /// Sends the message and waits for the reply to be written over it
+    async fn call(&mut self) {
+        self.send();
+        Reply(self.0).await
+    }
+
+    /// Like `call`, but spins
+    fn call_sync(&mut self) {
+        self.send();
+        for _ in 0..1000 {
+            receive_replies();
+            if self.is_done() {
+                return;
+            }
+            delay_us_sync(100);
+        }
         panic!("No response from mailbox");
     }
-    mmio_read(MBOX_READ)
 }
 
-pub unsafe fn call_raw(dst: *mut u8) {
-    let mbox_addr = ((dst as usize as u32) & !0xF) | 8;
-    write_raw(mbox_addr);
-    while read_raw() != mbox_addr {}
+impl Drop for Slot {
+    fn drop(&mut self) {
+        with_slots(|slots| {
+            slots[self.0] = match slots[self.0] {
+                SlotState::InFlight(_) => SlotState::Abandoned,
+                _ => SlotState::Free,
+            }
+        });
+    }
+}
+
+struct Reply(usize);
+
+impl Future for Reply {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        receive_replies();
+        with_slots(|slots| match &mut slots[self.0] {
+            SlotState::Done => Poll::Ready(()),
+            state => {
+                *state = SlotState::InFlight(Some(cx.waker().clone()));
+                Poll::Pending
+            }
+        })
+    }
+}
+
+/// Enables the "data available" interrupt of the VideoCore's mailbox
+pub unsafe fn enable_irq() {
+    mmio_write(MBOX_CONFIG, MBOX_CONFIG_DATA_IRQ);
+    irq::register(IRQ_ARM_MAILBOX, b"VideoCore mailbox", handle_irq).warn();
+}
+
+unsafe fn handle_irq(_e: &mut ExceptionContext) {
+    receive_replies();
+}
+
+fn check_response(mailbox: &MailboxMessage) -> Result<(), ()> {
+    if mailbox.magic != MBOX_RESPONSE {
+        println!(
+            "[EROR] No mailbox response (0x{:x} vs expected 0x{:x})",
+            mailbox.magic, MBOX_RESPONSE
+        );
+        return Err(());
+    }
+    Ok(())
 }
 
 // FIXME: HACK
@@ -71,9 +219,8 @@ pub(crate) unsafe fn _send_fb_property_tags(
     width: u32,
     height: u32,
 ) -> Result<FramebufferInfo, ()> {
-    // Get reference to mailbox in device memory
-    let _lock = MAILBOX_LOCK.lock();
-    let mut mailbox = &mut MAILBOX_MSG;
+    let mut slot = Slot::acquire_sync();
+    let mailbox = slot.message();
 
     // Tag list header
     mailbox.size = 35 * 4;
@@ -115,15 +262,10 @@ pub(crate) unsafe fn _send_fb_property_tags(
     mailbox.rest[32] = MBOX_TAG_LAST;
 
     // Send the tags
-    call_raw(mailbox.deref_mut() as *mut MailboxMessage as *mut u8);
+    slot.call_sync();
 
-    if mailbox.magic != MBOX_RESPONSE {
-        println!(
-            "[EROR] No mailbox response (0x{:x} vs expected 0x{:x})",
-            mailbox.magic, MBOX_RESPONSE
-        );
-        return Err(());
-    }
+    let mailbox = slot.message();
+    check_response(mailbox)?;
 
     assert_eq!(mailbox.rest[18], 32);
     assert_ne!(mailbox.rest[26], 0);
@@ -142,15 +284,12 @@ pub(crate) unsafe fn _send_fb_property_tags(
     })
 }
 
-unsafe fn _send_property_tag(
+fn fill_property_tag(
+    mailbox: &mut MailboxMessage,
     ident: u32,
     tag_capacity: u32,
     tag_data: &[u32],
-) -> Result<TrimmedArray<u32, 26>, ()> {
-    // Get reference to mailbox in device memory
-    let _lock = MAILBOX_LOCK.lock();
-    let mut mailbox = &mut MAILBOX_MSG;
-
+) {
     // Tag list header
     mailbox.size = tag_capacity + 6 * 4;
     mailbox.magic = MBOX_REQUEST;
@@ -167,17 +306,10 @@ unsafe fn _send_property_tag(
     mailbox.rest[47] = MBOX_TAG_LAST;
     mailbox.rest[48] = 0;
     mailbox.rest[49] = 0;
+}
 
-    // Send the tags
-    call_raw(mailbox.deref_mut() as *mut MailboxMessage as *mut u8);
-
-    if mailbox.magic != MBOX_RESPONSE {
-        println!(
-            "[EROR] No mailbox response (0x{:x} vs expected 0x{:x})",
-            mailbox.magic, MBOX_RESPONSE
-        );
-        return Err(());
-    }
+fn parse_property_tag(mailbox: &MailboxMessage, ident: u32) -> Result<TrimmedArray<u32, 26>, ()> {
+    check_response(mailbox)?;
 
     // Find response tag
     let mut i = 0;
@@ -213,28 +345,77 @@ unsafe fn _send_property_tag(
     }
 }
 
-pub(crate) unsafe fn send_property_tag<REQ: Copy, RES: Copy>(
+async fn _send_property_tag(
+    ident: u32,
+    tag_capacity: u32,
+    tag_data: &[u32],
+) -> Result<TrimmedArray<u32, 26>, ()> {
+    let mut slot = Slot::acquire().await;
+    fill_property_tag(slot.message(), ident, tag_capacity, tag_data);
+    slot.call().await;
+    parse_property_tag(slot.message(), ident)
+}
+
+fn _send_property_tag_sync(
+    ident: u32,
+    tag_capacity: u32,
+    tag_data: &[u32],
+) -> Result<TrimmedArray<u32, 26>, ()> {
+    let mut slot = Slot::acquire_sync();
+    fill_property_tag(slot.message(), ident, tag_capacity, tag_data);
+    slot.call_sync();
+    parse_property_tag(slot.message(), ident)
+}
+
+unsafe fn req_words<REQ: Copy>(req: &REQ) -> &[u32] {
+    &*slice_from_raw_parts(req as *const REQ as *const u32, size_of::<REQ>() / 4)
+}
+
+fn tag_capacity<REQ, RES>() -> u32 {
+    max(size_of::<REQ>() as u32, size_of::<RES>() as u32)
+}
+
+pub(crate) async unsafe fn send_property_tag<REQ: Copy, RES: Copy>(
     ident: u32,
     req: REQ,
 ) -> Result<RES, ()> {
-    let req = &*slice_from_raw_parts(&req as *const REQ as *const u32, size_of::<REQ>() / 4);
-    let res = _send_property_tag(
-        ident,
-        max(size_of::<REQ>() as u32, size_of::<RES>() as u32),
-        req,
-    )?;
+    let res = _send_property_tag(ident, tag_capacity::<REQ, RES>(), req_words(&req)).await?;
+    let res = res.deref().as_ptr() as *const RES;
+    Ok(*res)
+}
+
+pub(crate) unsafe fn send_property_tag_sync<REQ: Copy, RES: Copy>(
+    ident: u32,
+    req: REQ,
+) -> Result<RES, ()> {
+    let res = _send_property_tag_sync(ident, tag_capacity::<REQ, RES>(), req_words(&req))?;
     let res = res.deref().as_ptr() as *const RES;
     Ok(*res)
 }
 
-pub(crate) unsafe fn send_property_tag_raw<REQ: Copy>(
+pub(crate) async unsafe fn send_property_tag_raw<REQ: Copy>(
     ident: u32,
     req: REQ,
     capacity: usize,
 ) -> Result<TrimmedArray<u32, 26>, ()> {
-    let req = &*slice_from_raw_parts(&req as *const REQ as *const u32, size_of::<REQ>() / 4);
-    let res = _send_property_tag(ident, max(size_of::<REQ>() as u32, capacity as u32), req)?;
-    Ok(res)
+    _send_property_tag(
+        ident,
+        max(size_of::<REQ>() as u32, capacity as u32),
+        req_words(&req),
+    )
+    .await
+}
+
+pub(crate) unsafe fn send_property_tag_raw_sync<REQ: Copy>(
+    ident: u32,
+    req: REQ,
+    capacity: usize,
+) -> Result<TrimmedArray<u32, 26>, ()> {
+    _send_property_tag_sync(
+        ident,
+        max(size_of::<REQ>() as u32, capacity as u32),
+        req_words(&req),
+    )
 }
 
 pub struct TrimmedArray<T, const LEN: usize> {
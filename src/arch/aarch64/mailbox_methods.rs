@@ -1,30 +1,45 @@
-use crate::arch::aarch64::mailbox::{send_property_tag, send_property_tag_raw, TrimmedArray};
+use crate::arch::aarch64::mailbox::{
+    send_property_tag, send_property_tag_raw, send_property_tag_raw_sync, send_property_tag_sync,
+    TrimmedArray,
+};
 use crate::console::Freq;
 use crate::prelude::*;
 use core::ops::Deref;
 use core::ptr::slice_from_raw_parts;
 
-pub fn get_clock_rate(clock_id: u32) -> Result<Freq, ()> {
-    #[repr(C)]
-    #[derive(Copy, Clone)]
-    pub struct GetClockRateReq {
-        clock_id: u32,
-    }
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct GetClockRateReq {
+    clock_id: u32,
+}
 
-    #[repr(C)]
-    #[derive(Copy, Clone)]
-    pub struct GetClockRateRes {
-        clock_id: u32,
-        rate: u32,
-    }
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct GetClockRateRes {
+    clock_id: u32,
+    rate: u32,
+}
 
+pub async fn get_clock_rate(clock_id: u32) -> Result<Freq, ()> {
     let res: GetClockRateRes =
-        unsafe { send_property_tag(0x00030002, GetClockRateReq { clock_id })? };
+        unsafe { send_property_tag(0x00030002, GetClockRateReq { clock_id }).await? };
 
     Ok(Freq(res.rate as u64))
 }
 
-pub fn set_clock_rate(clock_id: u32, rate: u32, skip_setting_turbo: bool) -> Result<Freq, ()> {
+/// Returns the clock's rate, for use before the kernel tasks run
+pub fn get_clock_rate_sync(clock_id: u32) -> Result<Freq, ()> {
+    let res: GetClockRateRes =
+        unsafe { send_property_tag_sync(0x00030002, GetClockRateReq { clock_id })? };
+
+    Ok(Freq(res.rate as u64))
+}
+
+pub async fn set_clock_rate(
+    clock_id: u32,
+    rate: u32,
+    skip_setting_turbo: bool,
+) -> Result<Freq, ()> {
     #[repr(C)]
     #[derive(Copy, Clone)]
     pub struct SetClockRateReq {
@@ -48,13 +63,14 @@ pub fn set_clock_rate(clock_id: u32, rate: u32, skip_setting_turbo: bool) -> Res
                 rate,
                 skip_setting_turbo: skip_setting_turbo as u32,
             },
-        )?
+        )
+        .await?
     };
 
     Ok(Freq(res.rate as u64))
 }
 
-pub fn get_framebuffer_phy_size() -> Result<(u32, u32), ()> {
+pub async fn get_framebuffer_phy_size() -> Result<(u32, u32), ()> {
     #[repr(C)]
     #[derive(Copy, Clone)]
     pub struct GetFbPhySizeReq;
@@ -66,12 +82,12 @@ pub fn get_framebuffer_phy_size() -> Result<(u32, u32), ()> {
         height: u32,
     }
 
-    let res: GetFbPhySizeRes = unsafe { send_property_tag(0x00040003, GetFbPhySizeReq)? };
+    let res: GetFbPhySizeRes = unsafe { send_property_tag(0x00040003, GetFbPhySizeReq).await? };
 
     Ok((res.width, res.height))
 }
 
-pub fn set_framebuffer_phy_size(width: u32, height: u32) -> Result<(u32, u32), ()> {
+pub async fn set_framebuffer_phy_size(width: u32, height: u32) -> Result<(u32, u32), ()> {
     #[repr(C)]
     #[derive(Copy, Clone)]
     pub struct SetFbPhySizeReq {
@@ -87,12 +103,12 @@ pub fn set_framebuffer_phy_size(width: u32, height: u32) -> Result<(u32, u32), (
     }
 
     let res: SetFbPhySizeRes =
-        unsafe { send_property_tag(0x00048003, SetFbPhySizeReq { width, height })? };
+        unsafe { send_property_tag(0x00048003, SetFbPhySizeReq { width, height }).await? };
 
     Ok((res.width, res.height))
 }
 
-pub fn set_framebuffer_virt_size(width: u32, height: u32) -> Result<(u32, u32), ()> {
+pub async fn set_framebuffer_virt_size(width: u32, height: u32) -> Result<(u32, u32), ()> {
     #[repr(C)]
     #[derive(Copy, Clone)]
     pub struct SetFbVirtSizeReq {
@@ -108,12 +124,12 @@ pub fn set_framebuffer_virt_size(width: u32, height: u32) -> Result<(u32, u32),
     }
 
     let res: SetFbVirtSizeRes =
-        unsafe { send_property_tag(0x00048004, SetFbVirtSizeReq { width, height })? };
+        unsafe { send_property_tag(0x00048004, SetFbVirtSizeReq { width, height }).await? };
 
     Ok((res.width, res.height))
 }
 
-pub fn set_framebuffer_virt_offset(x: u32, y: u32) -> Result<(u32, u32), ()> {
+pub async fn set_framebuffer_virt_offset(x: u32, y: u32) -> Result<(u32, u32), ()> {
     #[repr(C)]
     #[derive(Copy, Clone)]
     pub struct SetFbVirtOffsetReq {
@@ -129,12 +145,12 @@ pub fn set_framebuffer_virt_offset(x: u32, y: u32) -> Result<(u32, u32), ()> {
     }
 
     let res: SetFbVirtOffsetRes =
-        unsafe { send_property_tag(0x00048009, SetFbVirtOffsetReq { x, y })? };
+        unsafe { send_property_tag(0x00048009, SetFbVirtOffsetReq { x, y }).await? };
 
     Ok((res.x, res.y))
 }
 
-pub fn set_framebuffer_depth(depth: u32) -> Result<u32, ()> {
+pub async fn set_framebuffer_depth(depth: u32) -> Result<u32, ()> {
     #[repr(C)]
     #[derive(Copy, Clone)]
     pub struct SetFbDepthReq {
@@ -147,12 +163,13 @@ pub fn set_framebuffer_depth(depth: u32) -> Result<u32, ()> {
         depth: u32,
     }
 
-    let res: SetFbDepthRes = unsafe { send_property_tag(0x00048005, SetFbDepthReq { depth })? };
+    let res: SetFbDepthRes =
+        unsafe { send_property_tag(0x00048005, SetFbDepthReq { depth }).await? };
 
     Ok(res.depth)
 }
 
-pub fn set_framebuffer_pixel_order(order: u32) -> Result<u32, ()> {
+pub async fn set_framebuffer_pixel_order(order: u32) -> Result<u32, ()> {
     #[repr(C)]
     #[derive(Copy, Clone)]
     pub struct SetFbPixelOrderReq {
@@ -166,12 +183,12 @@ pub fn set_framebuffer_pixel_order(order: u32) -> Result<u32, ()> {
     }
 
     let res: SetFbPixelOrderRes =
-        unsafe { send_property_tag(0x00040006, SetFbPixelOrderReq { order })? };
+        unsafe { send_property_tag(0x00040006, SetFbPixelOrderReq { order }).await? };
 
     Ok(res.order)
 }
 
-pub fn alloc_framebuffer(alignment: u32) -> Result<PhySlice, ()> {
+pub async fn alloc_framebuffer(alignment: u32) -> Result<PhySlice, ()> {
     #[repr(C)]
     #[derive(Copy, Clone)]
     pub struct AllocFbReq {
@@ -185,7 +202,7 @@ pub fn alloc_framebuffer(alignment: u32) -> Result<PhySlice, ()> {
         size: u32,
     }
 
-    let res: AllocFbRes = unsafe { send_property_tag(0x00040001, AllocFbReq { alignment })? };
+    let res: AllocFbRes = unsafe { send_property_tag(0x00040001, AllocFbReq { alignment }).await? };
 
     Ok(PhySlice {
         base: PhyAddr((res.addr & 0x3fffffff) as usize),
@@ -193,7 +210,7 @@ pub fn alloc_framebuffer(alignment: u32) -> Result<PhySlice, ()> {
     })
 }
 
-pub fn free_framebuffer() -> Result<(), ()> {
+pub async fn free_framebuffer() -> Result<(), ()> {
     #[repr(C)]
     #[derive(Copy, Clone)]
     pub struct FreeFbReq;
@@ -202,12 +219,12 @@ pub fn free_framebuffer() -> Result<(), ()> {
     #[derive(Copy, Clone)]
     pub struct FreeFbRes;
 
-    let _res: FreeFbRes = unsafe { send_property_tag(0x00048001, FreeFbReq)? };
+    let _res: FreeFbRes = unsafe { send_property_tag(0x00048001, FreeFbReq).await? };
 
     Ok(())
 }
 
-pub fn get_framebuffer_pitch() -> Result<u32, ()> {
+pub async fn get_framebuffer_pitch() -> Result<u32, ()> {
     #[repr(C)]
     #[derive(Copy, Clone)]
     pub struct GetFbPitchReq;
@@ -218,13 +235,13 @@ pub fn get_framebuffer_pitch() -> Result<u32, ()> {
         pitch: u32,
     }
 
-    let res: GetFbPitchRes = unsafe { send_property_tag(0x00040008, GetFbPitchReq)? };
+    let res: GetFbPitchRes = unsafe { send_property_tag(0x00040008, GetFbPitchReq).await? };
 
     Ok(res.pitch)
 }
 
 /// Returns uptime in microseconds
-pub fn get_stc() -> Result<u32, ()> {
+pub async fn get_stc() -> Result<u32, ()> {
     #[repr(C)]
     #[derive(Copy, Clone)]
     pub struct GetStcReq;
@@ -236,13 +253,13 @@ pub fn get_stc() -> Result<u32, ()> {
         unused: u32,
     }
 
-    let res: GetStcRes = unsafe { send_property_tag(0x0003000b, GetStcReq)? };
+    let res: GetStcRes = unsafe { send_property_tag(0x0003000b, GetStcReq).await? };
 
     Ok(res.time)
 }
 
 /// Returns MAC address
-pub fn get_nic_mac() -> Result<[u8; 6], ()> {
+pub async fn get_nic_mac() -> Result<[u8; 6], ()> {
     #[repr(C)]
     #[derive(Copy, Clone)]
     pub struct GetNicMacReq;
@@ -253,29 +270,272 @@ pub fn get_nic_mac() -> Result<[u8; 6], ()> {
         mac: [u8; 6],
     }
 
-    let res: GetNicMacRes = unsafe { send_property_tag(0x00010003, GetNicMacReq)? };
+    let res: GetNicMacRes = unsafe { send_property_tag(0x00010003, GetNicMacReq).await? };
 
     Ok(res.mac)
 }
 
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct GetKernelArgsReq;
+
+fn kernel_args_bytes(res: TrimmedArray<u32, 26>) -> TrimmedArray<u8, 104> {
+    let transmuted =
+        unsafe { &*slice_from_raw_parts(res.deref().as_ptr() as *const u8, res.len() * 4) };
+
+    let mut new_res_data = [0u8; 104];
+    new_res_data[0..transmuted.len()].copy_from_slice(transmuted);
+
+    TrimmedArray::new(new_res_data, transmuted.len())
+}
+
 /// Returns kernel args
-pub fn get_kernel_args() -> Result<TrimmedArray<u8, 104>, ()> {
+pub async fn get_kernel_args() -> Result<TrimmedArray<u8, 104>, ()> {
+    let res = unsafe { send_property_tag_raw(0x00050001, GetKernelArgsReq, 104).await? };
+    Ok(kernel_args_bytes(res))
+}
+
+/// Returns kernel args, for use before the kernel tasks run
+pub fn get_kernel_args_sync() -> Result<TrimmedArray<u8, 104>, ()> {
+    let res = unsafe { send_property_tag_raw_sync(0x00050001, GetKernelArgsReq, 104)? };
+    Ok(kernel_args_bytes(res))
+}
+
+/// Returns the board model, usually 0 on modern boards, see `get_board_revision`
+pub async fn get_board_model() -> Result<u32, ()> {
     #[repr(C)]
     #[derive(Copy, Clone)]
-    pub struct GetKernelArgsReq;
+    pub struct GetBoardModelReq;
 
     #[repr(C)]
     #[derive(Copy, Clone)]
-    pub struct GetKernelArgsRes {
-        data: [u8; 96],
+    pub struct GetBoardModelRes {
+        model: u32,
     }
 
-    let res = unsafe { send_property_tag_raw(0x00050001, GetKernelArgsReq, 104)? };
-    let transmuted =
-        unsafe { &*slice_from_raw_parts(res.deref().as_ptr() as *const u8, res.len() * 4) };
+    let res: GetBoardModelRes = unsafe { send_property_tag(0x00010001, GetBoardModelReq).await? };
 
-    let mut new_res_data = [0u8; 104];
-    new_res_data[0..transmuted.len()].copy_from_slice(transmuted);
+    Ok(res.model)
+}
+
+/// Returns the board revision code, which encodes the model, memory size and manufacturer
+pub async fn get_board_revision() -> Result<u32, ()> {
+    #[repr(C)]
+    #[derive(Copy, Clone)]
+    pub struct GetBoardRevisionReq;
+
+    #[repr(C)]
+    #[derive(Copy, Clone)]
+    pub struct GetBoardRevisionRes {
+        revision: u32,
+    }
+
+    let res: GetBoardRevisionRes =
+        unsafe { send_property_tag(0x00010002, GetBoardRevisionReq).await? };
+
+    Ok(res.revision)
+}
+
+pub async fn get_board_serial() -> Result<u64, ()> {
+    #[repr(C)]
+    #[derive(Copy, Clone)]
+    pub struct GetBoardSerialReq;
+
+    #[repr(C)]
+    #[derive(Copy, Clone)]
+    pub struct GetBoardSerialRes {
+        low: u32,
+        high: u32,
+    }
+
+    let res: GetBoardSerialRes = unsafe { send_property_tag(0x00010004, GetBoardSerialReq).await? };
+
+    Ok((res.high as u64) << 32 | res.low as u64)
+}
+
+/// Returns the memory given to the ARM cores
+pub async fn get_arm_memory() -> Result<PhySlice, ()> {
+    #[repr(C)]
+    #[derive(Copy, Clone)]
+    pub struct GetArmMemoryReq;
+
+    #[repr(C)]
+    #[derive(Copy, Clone)]
+    pub struct GetArmMemoryRes {
+        base: u32,
+        size: u32,
+    }
+
+    let res: GetArmMemoryRes = unsafe { send_property_tag(0x00010005, GetArmMemoryReq).await? };
+
+    Ok(PhySlice {
+        base: PhyAddr(res.base as usize),
+        len: res.size as usize,
+    })
+}
+
+/// Returns the memory kept by the VideoCore GPU
+pub async fn get_vc_memory() -> Result<PhySlice, ()> {
+    #[repr(C)]
+    #[derive(Copy, Clone)]
+    pub struct GetVcMemoryReq;
 
-    Ok(TrimmedArray::new(new_res_data, transmuted.len()))
+    #[repr(C)]
+    #[derive(Copy, Clone)]
+    pub struct GetVcMemoryRes {
+        base: u32,
+        size: u32,
+    }
+
+    let res: GetVcMemoryRes = unsafe { send_property_tag(0x00010006, GetVcMemoryReq).await? };
+
+    Ok(PhySlice {
+        base: PhyAddr(res.base as usize),
+        len: res.size as usize,
+    })
+}
+
+/// Returns the SoC temperature in thousandths of a degree Celsius
+pub async fn get_temperature() -> Result<u32, ()> {
+    #[repr(C)]
+    #[derive(Copy, Clone)]
+    pub struct GetTemperatureReq {
+        temperature_id: u32,
+    }
+
+    #[repr(C)]
+    #[derive(Copy, Clone)]
+    pub struct GetTemperatureRes {
+        temperature_id: u32,
+        value: u32,
+    }
+
+    let res: GetTemperatureRes =
+        unsafe { send_property_tag(0x00030006, GetTemperatureReq { temperature_id: 0 }).await? };
+
+    Ok(res.value)
+}
+
+/// Returns the temperature the firmware throttles at, in thousandths of a degree Celsius
+pub async fn get_max_temperature() -> Result<u32, ()> {
+    #[repr(C)]
+    #[derive(Copy, Clone)]
+    pub struct GetMaxTemperatureReq {
+        temperature_id: u32,
+    }
+
+    #[repr(C)]
+    #[derive(Copy, Clone)]
+    pub struct GetMaxTemperatureRes {
+        temperature_id: u32,
+        value: u32,
+    }
+
+    let res: GetMaxTemperatureRes =
+        unsafe { send_property_tag(0x0003000a, GetMaxTemperatureReq { temperature_id: 0 }).await? };
+
+    Ok(res.value)
+}
+
+/// Voltage ids for `get_voltage`
+pub const VOLTAGE_IDS: [(u32, &str); 4] =
+    [(1, "core"), (2, "sdram_c"), (3, "sdram_p"), (4, "sdram_i")];
+
+/// Returns the voltage in microvolts
+pub async fn get_voltage(voltage_id: u32) -> Result<u32, ()> {
+    #[repr(C)]
+    #[derive(Copy, Clone)]
+    pub struct GetVoltageReq {
+        voltage_id: u32,
+    }
+
+    #[repr(C)]
+    #[derive(Copy, Clone)]
+    pub struct GetVoltageRes {
+        voltage_id: u32,
+        value: u32,
+    }
+
+    let res: GetVoltageRes =
+        unsafe { send_property_tag(0x00030003, GetVoltageReq { voltage_id }).await? };
+
+    Ok(res.value)
+}
+
+/// Clock ids for `get_clock_state` and `get_clock_rate`
+pub const CLOCK_IDS: [(u32, &str); 10] = [
+    (1, "emmc"),
+    (2, "uart"),
+    (3, "arm"),
+    (4, "core"),
+    (5, "v3d"),
+    (6, "h264"),
+    (7, "isp"),
+    (8, "sdram"),
+    (9, "pixel"),
+    (10, "pwm"),
+];
+
+/// Returns whether the clock is on, or `None` if there's no such clock
+pub async fn get_clock_state(clock_id: u32) -> Result<Option<bool>, ()> {
+    #[repr(C)]
+    #[derive(Copy, Clone)]
+    pub struct GetClockStateReq {
+        clock_id: u32,
+    }
+
+    #[repr(C)]
+    #[derive(Copy, Clone)]
+    pub struct GetClockStateRes {
+        clock_id: u32,
+        state: u32,
+    }
+
+    let res: GetClockStateRes =
+        unsafe { send_property_tag(0x00030001, GetClockStateReq { clock_id }).await? };
+
+    Ok(decode_state(res.state))
+}
+
+/// Power domain ids for `get_power_state`
+pub const POWER_DOMAIN_IDS: [(u32, &str); 9] = [
+    (0, "sd_card"),
+    (1, "uart0"),
+    (2, "uart1"),
+    (3, "usb_hcd"),
+    (4, "i2c0"),
+    (5, "i2c1"),
+    (6, "i2c2"),
+    (7, "spi"),
+    (8, "ccp2tx"),
+];
+
+/// Returns whether the power domain is on, or `None` if there's no such domain
+pub async fn get_power_state(device_id: u32) -> Result<Option<bool>, ()> {
+    #[repr(C)]
+    #[derive(Copy, Clone)]
+    pub struct GetPowerStateReq {
+        device_id: u32,
+    }
+
+    #[repr(C)]
+    #[derive(Copy, Clone)]
+    pub struct GetPowerStateRes {
+        device_id: u32,
+        state: u32,
+    }
+
+    let res: GetPowerStateRes =
+        unsafe { send_property_tag(0x00020001, GetPowerStateReq { device_id }).await? };
+
+    Ok(decode_state(res.state))
+}
+
+/// Bit 0 is on/off, bit 1 is set if the clock or power domain doesn't exist
+fn decode_state(state: u32) -> Option<bool> {
+    if state & 2 != 0 {
+        None
+    } else {
+        Some(state & 1 != 0)
+    }
 }
@@ -5,6 +5,7 @@ use crate::prelude::*;
 
 use core::future::Future;
 use core::pin::Pin;
+use core::sync::atomic::{AtomicU32, Ordering};
 use core::task::{Context, Poll};
 
 /// board type, raspi3
@@ -15,8 +16,16 @@ MMIO_BASE = 0x3F000000, // for raspi2 & 3
 MMIO_BASE = 0xFE000000, // for raspi4
 MMIO_BASE = 0x20000000, // for raspi1, raspi zero etc.
 */
-/// The MMIO area base address.
+/// The MMIO area base address, as found on the raspi3.
+/// The register addresses below are relative to it, and get relocated to wherever the device tree
+/// says the peripherals are by `mmio_read` / `mmio_write`
 pub const MMIO_BASE: u32 = 0x3F000000;
+pub const MMIO_LEN: u32 = 0x01000000;
+
+/// The MMIO area base address as seen from the VideoCore bus, which the device tree uses
+pub const MMIO_BUS_BASE: u32 = 0x7E000000;
+
+static MMIO_ACTUAL_BASE: AtomicU32 = AtomicU32::new(MMIO_BASE);
 
 /// The offsets for reach register.
 pub const GPIO_BASE: u32 = MMIO_BASE + 0x200000;
@@ -32,6 +41,7 @@ pub const GPSET0: u32 = GPIO_BASE + 0x1c;
 pub const GPSET1: u32 = GPIO_BASE + 0x20;
 
 pub const GPCLR0: u32 = GPIO_BASE + 0x28;
+pub const GPCLR1: u32 = GPIO_BASE + 0x2c;
 
 pub const GPLEV0: u32 = GPIO_BASE + 0x34;
 pub const GPLEV1: u32 = GPIO_BASE + 0x38;
@@ -39,9 +49,18 @@ pub const GPLEV1: u32 = GPIO_BASE + 0x38;
 pub const GPEDS0: u32 = GPIO_BASE + 0x40;
 pub const GPEDS1: u32 = GPIO_BASE + 0x44;
 
+pub const GPREN0: u32 = GPIO_BASE + 0x4c;
+pub const GPREN1: u32 = GPIO_BASE + 0x50;
+
+pub const GPFEN0: u32 = GPIO_BASE + 0x58;
+pub const GPFEN1: u32 = GPIO_BASE + 0x5c;
+
 pub const GPHEN0: u32 = GPIO_BASE + 0x64;
 pub const GPHEN1: u32 = GPIO_BASE + 0x68;
 
+pub const GPLEN0: u32 = GPIO_BASE + 0x70;
+pub const GPLEN1: u32 = GPIO_BASE + 0x74;
+
 /// Controls actuation of pull up/down to ALL GPIO pins.
 pub const GPPUD: u32 = GPIO_BASE + 0x94;
 
@@ -75,7 +94,21 @@ pub const UART0_ITIP: u32 = UART0_BASE + 0x84;
 pub const UART0_ITOP: u32 = UART0_BASE + 0x88;
 pub const UART0_TDR: u32 = UART0_BASE + 0x8C;
 
+/// The power management block, its watchdog resets the board
+pub const PM_BASE: u32 = MMIO_BASE + 0x100000;
+pub const PM_RSTC: u32 = PM_BASE + 0x1c;
+pub const PM_RSTS: u32 = PM_BASE + 0x20;
+pub const PM_WDOG: u32 = PM_BASE + 0x24;
+
+/// Writes to the PM registers are ignored without it
+pub const PM_PASSWORD: u32 = 0x5a000000;
+pub const PM_RSTC_WRCFG_CLR: u32 = 0xffffffcf;
+pub const PM_RSTC_WRCFG_FULL_RESET: u32 = 0x00000020;
+/// The firmware boots the partition stored in every other bit of RSTS, 63 means halt
+pub const PM_RSTS_PARTITION_CLR: u32 = 0xfffffaaa;
+
 // The offsets for each register for the UART1.
+pub const AUX_IRQ: u32 = MMIO_BASE + 0x00215000;
 pub const UART1_ENABLE: u32 = MMIO_BASE + 0x00215004;
 pub const UART1_MU_IO: u32 = MMIO_BASE + 0x00215040;
 pub const UART1_MU_IER: u32 = MMIO_BASE + 0x00215044;
@@ -89,12 +122,25 @@ pub const UART1_MU_CNTL: u32 = MMIO_BASE + 0x00215060;
 pub const UART1_MU_STAT: u32 = MMIO_BASE + 0x00215064;
 pub const UART1_MU_BAUD: u32 = MMIO_BASE + 0x00215068;
 
+/// In `AUX_IRQ`, the mini UART has an interrupt pending (the SPIs share the line)
+pub const AUX_IRQ_MINI_UART: u32 = 1 << 0;
+pub const UART1_MU_IER_RX: u32 = 1 << 0;
+pub const UART1_MU_IER_TX: u32 = 1 << 1;
+/// Documented as don't care, but RX interrupts don't fire without them
+pub const UART1_MU_IER_REQUIRED: u32 = 0b11 << 2;
+pub const UART1_MU_LSR_DATA_READY: u32 = 1 << 0;
+pub const UART1_MU_LSR_TX_EMPTY: u32 = 1 << 5;
+
 /// The offsets for Mailbox registers
 pub const MBOX_BASE: u32 = MMIO_BASE + 0xB880;
 pub const MBOX_READ: u32 = MBOX_BASE + 0x00;
 pub const MBOX_STATUS: u32 = MBOX_BASE + 0x18;
+pub const MBOX_CONFIG: u32 = MBOX_BASE + 0x1C;
 pub const MBOX_WRITE: u32 = MBOX_BASE + 0x20;
 
+/// Interrupt when the VideoCore's mailbox has replies to read
+pub const MBOX_CONFIG_DATA_IRQ: u32 = 1 << 0;
+
 pub const SYSTMR_LO: u32 = MBOX_BASE + 0x3004;
 pub const SYSTMR_HI: u32 = MBOX_BASE + 0x3008;
 
@@ -118,7 +164,56 @@ pub const SYSTEM_TIMER_IRQ_0: u32 = 1 << 0;
 pub const SYSTEM_TIMER_IRQ_1: u32 = 1 << 1;
 pub const SYSTEM_TIMER_IRQ_2: u32 = 1 << 2;
 pub const SYSTEM_TIMER_IRQ_3: u32 = 1 << 3;
-pub const UART_IRQ: u32 = 1 << (57 - 32);
+
+/// IRQ numbers: 0-63 are the GPU's banks 1 and 2, 64-71 the ARM's basic interrupts and 96+ the
+/// core-local interrupts (bits of `CORE0_IRQ_SOURCE`)
+pub const IRQ_BASIC_BASE: u32 = 64;
+pub const IRQ_LOCAL_BASE: u32 = 96;
+pub const IRQ_AUX: u32 = 29;
+/// `gpio_int[3]`, raised for events on any pin
+pub const IRQ_GPIO: u32 = 52;
+pub const IRQ_PL011_UART0: u32 = 57;
+/// The Arasan SD host controller (EMMC)
+pub const IRQ_EMMC: u32 = 62;
+pub const IRQ_ARM_MAILBOX: u32 = IRQ_BASIC_BASE + 1;
+pub const IRQ_LOCAL_CNTPNS: u32 = IRQ_LOCAL_BASE + 1;
+pub const IRQ_LOCAL_MBOX0: u32 = IRQ_LOCAL_BASE + 4;
+
+/// The base address for the ARM local peripherals (BCM2836 and up)
+pub const LOCAL_BASE: u32 = 0x40000000;
+
+/// Per-core registers, indexed by core number
+pub const CORE0_TIMER_INT_CTRL: u32 = LOCAL_BASE + 0x40;
+pub const CORE0_MBOX_INT_CTRL: u32 = LOCAL_BASE + 0x50;
+pub const CORE0_IRQ_SOURCE: u32 = LOCAL_BASE + 0x60;
+pub const CORE0_MBOX0_SET: u32 = LOCAL_BASE + 0x80;
+pub const CORE0_MBOX0_RDCLR: u32 = LOCAL_BASE + 0xC0;
+
+pub const CORE_TIMER_INT_CTRL_CNTPNS: u32 = 1 << 1;
+pub const CORE_MBOX_INT_CTRL_MBOX0: u32 = 1 << 0;
+pub const CORE_IRQ_SOURCE_CNTPNS: u32 = 1 << 1;
+pub const CORE_IRQ_SOURCE_MBOX0: u32 = 1 << 4;
+pub const CORE_IRQ_SOURCE_GPU: u32 = 1 << 8;
+
+pub const fn core_timer_int_ctrl(core: usize) -> u32 {
+    CORE0_TIMER_INT_CTRL + 4 * core as u32
+}
+
+pub const fn core_mbox_int_ctrl(core: usize) -> u32 {
+    CORE0_MBOX_INT_CTRL + 4 * core as u32
+}
+
+pub const fn core_irq_source(core: usize) -> u32 {
+    CORE0_IRQ_SOURCE + 4 * core as u32
+}
+
+pub const fn core_mbox0_set(core: usize) -> u32 {
+    CORE0_MBOX0_SET + 16 * core as u32
+}
+
+pub const fn core_mbox0_rdclr(core: usize) -> u32 {
+    CORE0_MBOX0_RDCLR + 16 * core as u32
+}
 
 pub const TIMER_CS: u32 = MMIO_BASE + 0x00003000;
 pub const TIMER_CLO: u32 = MMIO_BASE + 0x00003004;
@@ -133,12 +228,34 @@ pub const TIMER_CS_M1: u32 = 1 << 1;
 pub const TIMER_CS_M2: u32 = 1 << 2;
 pub const TIMER_CS_M3: u32 = 1 << 3;
 
+/// Relocate all MMIO accesses to the peripherals at `base`
+///
+/// # Safety
+///
+/// This function assumes `base` is mapped as device memory, and that no driver is mid-access
+pub unsafe fn set_mmio_base(base: PhyAddr) {
+    MMIO_ACTUAL_BASE.store(base.0 as u32, Ordering::SeqCst);
+}
+
+pub fn mmio_base() -> PhyAddr {
+    PhyAddr(MMIO_ACTUAL_BASE.load(Ordering::Relaxed) as usize)
+}
+
+#[inline(always)]
+fn relocate(addr: u32) -> u32 {
+    if (MMIO_BASE..MMIO_BASE + MMIO_LEN).contains(&addr) {
+        addr - MMIO_BASE + MMIO_ACTUAL_BASE.load(Ordering::Relaxed)
+    } else {
+        addr
+    }
+}
+
 pub unsafe fn mmio_read(addr: u32) -> u32 {
-    (PhyAddr(addr as usize).virt() as *const u32).read_volatile()
+    (PhyAddr(relocate(addr) as usize).virt() as *const u32).read_volatile()
 }
 
 pub unsafe fn mmio_write(addr: u32, value: u32) {
-    (PhyAddr(addr as usize).virt_mut() as *mut u32).write_volatile(value);
+    (PhyAddr(relocate(addr) as usize).virt_mut() as *mut u32).write_volatile(value);
 }
 
 #[inline(always)]
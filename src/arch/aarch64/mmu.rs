@@ -1,4 +1,4 @@
-use crate::arch::aarch64::mmio;
+use crate::arch::aarch64::board;
 use crate::prelude::*;
 use core::mem::size_of;
 
@@ -14,6 +14,8 @@ pub const PT_USER: u64 = 1 << 6;
 pub const PT_RW: u64 = 0 << 7;
 pub const PT_RO: u64 = 1 << 7;
 pub const PT_AF: u64 = 1 << 10;
+/// Not global: Only matches TLB entries tagged with the current ASID
+pub const PT_NG: u64 = 1 << 11;
 pub const PT_NX: u64 = 1 << 54;
 
 // Share-ability
@@ -69,7 +71,7 @@ impl PageTable {
 unsafe fn drop_pagetable(pt: &mut PageTable) {
     let pt_addr = pt as *const PageTable as usize;
     let paging_addr = &PAGING as *const PageTables as usize;
-    let paging_end = paging_addr + size_of::<PageTable>();
+    let paging_end = paging_addr + size_of::<PageTables>();
     if pt_addr < paging_addr || pt_addr >= paging_end {
         drop(Box::from_raw(pt));
     }
@@ -109,7 +111,7 @@ pub unsafe fn init() -> Result<(), ()> {
         &mut *((&mut PAGING as *mut PageTables as usize & 0xffffffff) as *mut PageTables);
 
     // Identity map user area, L1 Table
-    paging.user_l1.0[0] = {
+    paging.user_l1.0[board::RAM_BASE >> 30] = {
         (paging.user_l2.0.as_ptr() as u64) | // Physical address
             PT_PAGE |     // it has the "Present" flag, which must be set, and we have area in it mapped by pages
             PT_AF |       // accessed flag. Without this we're going to have a Data Abort exception
@@ -118,6 +120,19 @@ pub unsafe fn init() -> Result<(), ()> {
             PT_MEM // normal memory
     };
 
+    // Identity map the board's other peripherals, L1 blocks
+    for base in board::DEVICE_BLOCKS {
+        paging.user_l1.0[base >> 30] = {
+            ((base as u64) & !0x3fffffff) | // Physical address
+                PT_BLOCK |    // map 1G block
+                PT_AF |       // accessed flag
+                PT_NX |       // no execute
+                PT_KERNEL |     // non-privileged
+                PT_OSH |      // outer shareable
+                PT_DEV // device memory
+        };
+    }
+
     // Identity map user area, L2 Table, first block
     paging.user_l2.0[0] = {
         (paging.user_l3.0.as_ptr() as u64) | // Physical address
@@ -129,32 +144,22 @@ pub unsafe fn init() -> Result<(), ()> {
     };
 
     // Identity map user area, L2 Table
-    let iomem_cutoff = (mmio::MMIO_BASE >> 21) as usize;
-    let data_cutoff = (((&__data_start) as *const u8 as u64 & 0xffffffff) / PAGE_SIZE) as usize;
-    let dma_start = (((&__dma_start) as *const u8 as u64 & 0xffffffff) / PAGE_SIZE) as usize;
-    let dma_end = (((&__dma_end) as *const u8 as u64 & 0xffffffff) / PAGE_SIZE) as usize;
+    let iomem_cutoff = ((board::RAM_IOMEM_START - board::RAM_BASE) >> 21).min(512);
+    let ram_page = |addr: *const u8| {
+        ((addr as u64 & 0xffffffff) - board::RAM_BASE as u64) as usize / PAGE_SIZE as usize
+    };
+    let data_cutoff = ram_page(&__data_start);
+    let dma_start = ram_page(&__dma_start);
+    let dma_end = ram_page(&__dma_end);
     // println!("dma_start = 0x{:x}, dma_end = 0x{:x}", dma_start, dma_end);
     for (i, tbl) in paging.user_l2.0.iter_mut().enumerate().skip(1) {
-        *tbl = {
-            (i << 21) as u64 | // Physical address
-                PT_BLOCK |    // map 2M block
-                PT_AF |       // accessed flag
-                PT_NX |       // no execute
-                PT_KERNEL |     // non-privileged
-                // different attributes for device memory
-                if i >= iomem_cutoff {
-                    // println!("Defining 0x{:x} as DMA memory", i << 21);
-                    PT_OSH | PT_DEV
-                } else {
-                    PT_ISH | PT_MEM
-                }
-        };
+        *tbl = l2_block(i, iomem_cutoff);
     }
 
     // User L3 table
     for (i, tbl) in paging.user_l3.0.iter_mut().enumerate() {
         *tbl = {
-            (i as u64 * PAGE_SIZE) | // Physical address
+            (board::RAM_BASE as u64 + i as u64 * PAGE_SIZE) | // Physical address
                 PT_PAGE |     // map 4k
                 PT_AF |       // accessed flag
                 PT_KERNEL |     // non-privileged
@@ -169,6 +174,71 @@ pub unsafe fn init() -> Result<(), ()> {
         };
     }
 
+    enable()
+}
+
+fn l2_block(i: usize, iomem_cutoff: usize) -> u64 {
+    (board::RAM_BASE + (i << 21)) as u64 | // Physical address
+        PT_BLOCK |    // map 2M block
+        PT_AF |       // accessed flag
+        PT_NX |       // no execute
+        PT_KERNEL |     // non-privileged
+        // different attributes for device memory
+        if i >= iomem_cutoff {
+            // println!("Defining 0x{:x} as DMA memory", i << 21);
+            PT_OSH | PT_DEV
+        } else {
+            PT_ISH | PT_MEM
+        }
+}
+
+/// Map everything from `base` to the end of RAM's GiB as device memory, and everything below it
+/// as normal memory. `init` assumes the peripherals are at `board::RAM_IOMEM_START`
+///
+/// # Safety
+///
+/// This function assumes it runs before the other cores are started, and that nothing is using
+/// memory whose attributes change
+pub unsafe fn map_iomem(base: PhyAddr) -> Result<(), ()> {
+    if !(board::RAM_BASE..board::RAM_BASE + 0x40000000).contains(&base.0) {
+        println!(
+            "[WARN] Peripherals at {:?} are outside of the mapped area",
+            base
+        );
+        return Err(());
+    }
+    let iomem_cutoff = (base.0 - board::RAM_BASE) >> 21;
+    let l2 = &mut PAGING.user_l2.0;
+
+    // Break before make
+    for (i, tbl) in l2.iter_mut().enumerate().skip(1) {
+        if *tbl != l2_block(i, iomem_cutoff) {
+            *tbl = 0;
+        }
+    }
+    asm!("dsb ishst", "tlbi vmalle1is", "dsb ish", "isb");
+    for (i, tbl) in l2.iter_mut().enumerate().skip(1) {
+        if *tbl == 0 {
+            *tbl = l2_block(i, iomem_cutoff);
+        }
+    }
+    asm!("dsb ishst", "isb");
+
+    Ok(())
+}
+
+/// Points both halves at the kernel page tables and turns on translation for the current core
+///
+/// # Safety
+///
+/// This function assumes it runs from low memory, after `init` built the page tables
+pub unsafe fn init_secondary() -> Result<(), ()> {
+    enable()
+}
+
+unsafe fn enable() -> Result<(), ()> {
+    let paging = &*((&PAGING as *const PageTables as usize & 0xffffffff) as *const PageTables);
+
     // Verify MMU is capable
     let id_aa64mmfr0_el1 = get_msr!(id_aa64mmfr0_el1);
     let tgran4_supp = id_aa64mmfr0_el1 & (0xF << 28) == 0;
@@ -186,7 +256,8 @@ pub unsafe fn init() -> Result<(), ()> {
         (0x44 << 16) // AttrIdx=2: non cacheable
     });
 
-    // Specify mapping characteristics in translate control register
+    // Specify mapping characteristics in translate control register. AS and A1 are left at 0:
+    // 8-bit ASIDs, taken from ttbr0
     #[allow(clippy::identity_op)]
     {
         set_msr!(tcr_el1, {
@@ -301,6 +372,46 @@ pub unsafe fn virt2phy(vaddr: usize) -> Option<PhyAddr> {
     virt2pte(vaddr).map(|(pte, offset)| PhyAddr(((pte as usize) & 0x7FFFFFF000) + offset))
 }
 
+/// The lvl3 entry mapping `vaddr` under `page_table`, if there's one. Huge pages aren't looked at
+pub unsafe fn page_entry(page_table: &PageTable, vaddr: usize) -> Option<u64> {
+    let mut res = None;
+    page_table.use_child((vaddr >> 30) % 512, |lvl2| match lvl2 {
+        Some((raw1, lvl2)) if *raw1 & PT_PAGE == PT_PAGE => {
+            lvl2.use_child((vaddr >> 21) % 512, |lvl3| match lvl3 {
+                Some((raw2, lvl3)) if *raw2 & PT_PAGE == PT_PAGE => {
+                    res = Some(lvl3.0[(vaddr >> 12) % 512]).filter(|pte| *pte != 0);
+                }
+                _ => {}
+            });
+        }
+        _ => {}
+    });
+    res
+}
+
+/// Whether usermode could read, or also write with `write`, all of `vaddr..vaddr + len` through
+/// the current ttbr0
+pub unsafe fn user_accessible(vaddr: usize, len: usize, write: bool) -> bool {
+    let end = match vaddr.checked_add(len) {
+        Some(end) if end <= 0x80_0000_0000 => end,
+        _ => return false,
+    };
+    let mut page = vaddr & !(PAGE_SIZE as usize - 1);
+    while page < end {
+        // Translates like an EL0 access would, the result lands in par_el1
+        if write {
+            asm!("at s1e0w, {:x}", "isb", in(reg) page);
+        } else {
+            asm!("at s1e0r, {:x}", "isb", in(reg) page);
+        }
+        if get_msr!(par_el1) & 1 != 0 {
+            return false;
+        }
+        page += PAGE_SIZE as usize;
+    }
+    true
+}
+
 pub unsafe fn vmap(vaddr: usize, paddr: PhyAddr, attrs: u64) -> Result<(), ()> {
     // TODO: Only supports ttbr0 for now
     vmap_to(&mut PAGING.user_l1, vaddr, paddr, attrs)
@@ -313,7 +424,6 @@ pub unsafe fn vmap_to(
     paddr: PhyAddr,
     attrs: u64,
 ) -> Result<(), ()> {
-    // TODO: Doesn't ever free lvl2,3 tables if empty
     if vaddr % (PAGE_SIZE as usize) != 0 {
         return Err(());
     }
@@ -388,28 +498,122 @@ pub unsafe fn vmap_to(
     res
 }
 
-/// Gnarly code ahead
 pub unsafe fn vunmap(vaddr: usize) -> Result<(), ()> {
     // TODO: Only supports ttbr0 for now
-    // TODO: Doesn't ever free lvl2,3 tables if empty
+    vunmap_from(&mut PAGING.user_l1, vaddr).map(|_| ())
+}
+
+/// Gnarly code ahead
+///
+/// Returns what was mapped at `vaddr`. The lvl2,3 tables left empty are freed
+pub unsafe fn vunmap_from(page_table: &mut PageTable, vaddr: usize) -> Result<PhyAddr, ()> {
     // TODO: Frees whole huge pages
     if vaddr % (PAGE_SIZE as usize) != 0 {
         return Err(());
     }
+    let vaddr = vaddr & 0x7fffffffff;
 
     let mut res = Err(());
+    let mut empty_tables: ArrayVec<*mut PageTable, 2> = ArrayVec::new();
     println!("[DBUG] VMAP: Unmapping 0x{:x}", vaddr);
 
-    virt2pte_mut(vaddr, |pte| {
-        if let Some((pte, _)) = pte {
+    let mut unmap = |pte: &mut u64| {
+        if *pte != 0 {
+            res = Ok(PhyAddr((*pte & 0x7FFFFFF000) as usize));
             *pte = 0;
-            res = Ok(());
-        } else {
-            println!("[WARN] VMAP: Double vunmap of 0x{:x}", vaddr);
         }
+    };
+    page_table.use_child_mut(vaddr >> 30, |lvl2| match lvl2 {
+        Some((raw1, _)) if *raw1 & PT_PAGE != PT_PAGE => {
+            // Huge page
+            unmap(raw1);
+        }
+        Some((raw1, lvl2)) => {
+            lvl2.use_child_mut((vaddr >> 21) % 512, |lvl3| match lvl3 {
+                Some((raw2, _)) if *raw2 & PT_PAGE != PT_PAGE => {
+                    // Huge page
+                    unmap(raw2);
+                }
+                Some((raw2, lvl3)) => {
+                    unmap(&mut lvl3.0[(vaddr >> 12) % 512]);
+                    if lvl3.0.iter().all(|pte| *pte == 0) {
+                        *raw2 = 0;
+                        empty_tables.push(lvl3);
+                    }
+                }
+                None => {}
+            });
+            if lvl2.0.iter().all(|pte| *pte == 0) {
+                *raw1 = 0;
+                empty_tables.push(lvl2);
+            }
+        }
+        None => {}
     });
     if res.is_ok() {
         asm!("dsb ishst", "tlbi vmalle1is", "dsb ish", "isb"); // taken from linux
+    } else {
+        println!("[WARN] VMAP: Double vunmap of 0x{:x}", vaddr);
+    }
+    // Nothing can walk through them anymore
+    for table in empty_tables {
+        drop_pagetable(&mut *table);
     }
     res
 }
+
+/// Frees every lvl2,3 table under `page_table`, leaving it empty. What they mapped isn't freed
+///
+/// # Safety
+///
+/// Nothing can be using `page_table`
+pub unsafe fn free_tables(page_table: &mut PageTable) {
+    let mut tables = Vec::new();
+    for idx in 0..512 {
+        page_table.use_child_mut(idx, |lvl2| {
+            if let Some((raw1, lvl2)) = lvl2 {
+                if *raw1 & PT_PAGE == PT_PAGE {
+                    for idx in 0..512 {
+                        lvl2.use_child_mut(idx, |lvl3| {
+                            if let Some((raw2, lvl3)) = lvl3 {
+                                if *raw2 & PT_PAGE == PT_PAGE {
+                                    tables.push(lvl3 as *mut PageTable);
+                                }
+                            }
+                        });
+                    }
+                    tables.push(lvl2 as *mut PageTable);
+                }
+                *raw1 = 0;
+            }
+        });
+    }
+    asm!("dsb ishst", "tlbi vmalle1is", "dsb ish", "isb"); // taken from linux
+    for table in tables {
+        drop_pagetable(&mut *table);
+    }
+}
+
+/// How many lvl2,3 tables there are under `page_table`
+pub unsafe fn count_tables(page_table: &PageTable) -> usize {
+    let mut count = 0;
+    for idx in 0..512 {
+        page_table.use_child(idx, |lvl2| {
+            if let Some((raw1, lvl2)) = lvl2 {
+                if *raw1 & PT_PAGE == PT_PAGE {
+                    count += 1;
+                    for idx in 0..512 {
+                        lvl2.use_child(idx, |lvl3| {
+                            if let Some((raw2, _)) = lvl3 {
+                                if *raw2 & PT_PAGE == PT_PAGE {
+                                    count += 1;
+                                }
+                            }
+                        });
+                    }
+                }
+            }
+        });
+    }
+    count
+}
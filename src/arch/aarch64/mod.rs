@@ -1,17 +1,24 @@
+pub(crate) mod backtrace;
+pub(crate) mod board;
 pub(crate) mod entropy;
 pub(crate) mod exceptions;
 pub(crate) mod framebuffer;
+pub(crate) mod gic;
+pub(crate) mod gpio;
 pub(crate) mod init;
 pub(crate) mod mailbox;
 pub(crate) mod mailbox_methods;
 pub(crate) mod mmio;
 pub(crate) mod mmu;
 pub(crate) mod phymem;
+pub(crate) mod pl011;
+pub(crate) mod power;
 // pub(crate) mod qemu_uart;
-pub(crate) mod sdhc;
-// pub(crate) mod uart;
 pub(crate) mod dtb;
 pub(crate) mod interrupts;
+pub(crate) mod irq;
+pub(crate) mod sdhc;
+pub(crate) mod timer;
 pub(crate) mod uart1;
 pub(crate) mod virtmem;
 
@@ -10,8 +10,9 @@ extern "C" {
     static mut __ram_end: u8;
 }
 
-const PAGE_SIZE: usize = 4096;
-const RAM_SIZE: usize = 256 * 1024 * 1024;
+pub const PAGE_SIZE: usize = 4096;
+/// Each freed page that isn't next to the head range takes an entry, so be generous
+const MAX_FREE_RANGES: usize = 0x10000;
 pub static PHYMEM_FREE_LIST: Mutex<FreeList> = Mutex::new(unsafe { FreeList::new() });
 
 #[repr(C)]
@@ -117,7 +118,7 @@ impl ops::Sub for FreeRange {
 pub struct FreeList {
     head: u32,
     free_count: u32,
-    data: [FreeRange; RAM_SIZE / PAGE_SIZE],
+    data: [FreeRange; MAX_FREE_RANGES],
 }
 
 impl FreeList {
@@ -125,29 +126,61 @@ impl FreeList {
         FreeList {
             head: 0,
             free_count: 0,
-            data: [FreeRange { base: 0, len: 0 }; RAM_SIZE / PAGE_SIZE],
+            data: [FreeRange { base: 0, len: 0 }; MAX_FREE_RANGES],
         }
     }
 
-    pub unsafe fn init(&mut self) {
-        // FIXME: For framebuffer, etc
-        const END_RESERVE: usize = 0xf000000;
-        const END_RESERVE_PAGES: usize = END_RESERVE / PAGE_SIZE;
-
+    /// Fill the free list with `memory`, minus the kernel image and `reserved`
+    pub unsafe fn init(&mut self, memory: &[PhySlice], reserved: &[PhySlice]) {
         let ram_start = &__ram_start as *const u8 as usize & 0xffffffff;
         let ram_end = &__ram_end as *const u8 as usize & 0xffffffff;
-        let ram_start_pages = (&__ram_start as *const u8 as usize & 0xffffffff) / PAGE_SIZE;
-        let ram_end_pages = (&__ram_end as *const u8 as usize & 0xffffffff) / PAGE_SIZE;
-        println!(
-            "[DBUG] Phymem range: 0p{:x}..0p{:x}",
-            ram_start,
-            ram_end - END_RESERVE
-        );
-        self.free_count = ((ram_end_pages - END_RESERVE_PAGES) - ram_start_pages) as u32;
-        self.data[0] = FreeRange {
-            base: ram_start_pages as u32,
-            len: self.free_count,
-        };
+
+        self.head = 0;
+        self.data[0] = FreeRange { base: 0, len: 0 };
+        for region in memory {
+            // Only the area up to `__ram_end` is mapped
+            let start = region.base.0;
+            let end = (region.base.0 + region.len).min(ram_end);
+            if start >= end {
+                println!("[WARN] Ignoring unmapped memory {:?}", region);
+                continue;
+            }
+            println!("[DBUG] Phymem range: 0p{:x}..0p{:x}", start, end);
+
+            let range = FreeRange {
+                base: ((start + PAGE_SIZE - 1) / PAGE_SIZE) as u32,
+                len: (end / PAGE_SIZE - (start + PAGE_SIZE - 1) / PAGE_SIZE) as u32,
+            };
+            if self.data[0].len == 0 {
+                self.data[0] = range;
+            } else {
+                self.head += 1;
+                self.data[self.head as usize] = range;
+            }
+        }
+        self.update_free_count();
+
+        // The kernel image, including everything below it
+        self.reserve_range(PhySlice {
+            base: PhyAddr(0),
+            len: ram_start,
+        })
+        .unwrap();
+        for range in reserved {
+            self.reserve_range(*range).unwrap();
+        }
+    }
+
+    fn update_free_count(&mut self) {
+        self.free_count = self.data[0..=self.head as usize]
+            .iter()
+            .map(|range| range.len)
+            .sum();
+    }
+
+    /// Pages left to allocate
+    pub fn free_count(&self) -> usize {
+        self.free_count as usize
     }
 
     pub unsafe fn alloc_page(&mut self) -> Option<PhyAddr> {
@@ -176,8 +209,12 @@ impl FreeList {
         let page = (addr.0 / PAGE_SIZE) as u32;
 
         let head = &mut self.data[self.head as usize];
-        if page == head.base - 1 {
+        if head.len == 0 {
+            head.base = page;
+            head.len = 1;
+        } else if page + 1 == head.base {
             head.base -= 1;
+            head.len += 1;
         } else if page == head.base + head.len {
             head.len += 1;
         } else {
@@ -194,8 +231,10 @@ impl FreeList {
 
     pub unsafe fn reserve_range(&mut self, range: PhySlice) -> Result<(), ()> {
         println!("[INFO] Reserving range {:?}", range);
+        // Round outwards, partially reserved pages are reserved
         let base_pages = (range.base.0 / PAGE_SIZE) as u32;
-        let len_pages = (range.len / PAGE_SIZE) as u32;
+        let len_pages =
+            ((range.base.0 + range.len + PAGE_SIZE - 1) / PAGE_SIZE) as u32 - base_pages;
         let mut added_ranges: ArrayVec<_, 8> = ArrayVec::new();
         for range in &mut self.data[0..=self.head as usize] {
             let (left, right) = *range
@@ -220,11 +259,16 @@ impl FreeList {
         (&mut self.data[self.head as usize + 1..self.head as usize + 1 + added_ranges.len()])
             .copy_from_slice(&added_ranges);
         self.head += added_ranges.len() as u32;
+        self.update_free_count();
 
         Ok(())
     }
 
-    // TODO: free_pages
+    pub unsafe fn free_pages(&mut self, slice: PhySlice) {
+        for page in (slice.base.0..slice.base.0 + slice.len).step_by(PAGE_SIZE) {
+            self.free_page(PhyAddr(page));
+        }
+    }
 }
 
 pub unsafe fn reserve(range: PhySlice) -> Result<(), ()> {
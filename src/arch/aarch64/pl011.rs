@@ -0,0 +1,466 @@
+use crate::arch::aarch64::board::Board;
+use crate::arch::aarch64::interrupts;
+use crate::arch::aarch64::irq::{self, IrqHandler};
+use crate::driver_manager::{DeviceType, DriverInfo};
+use crate::ipc;
+use crate::ipc::spsc_queue::SpscQueue;
+use crate::prelude::*;
+
+use crate::{driver_manager, fi, ktask};
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use spin::{Mutex, Once, RwLock};
+
+// Register offsets
+const DR: usize = 0x00;
+const FR: usize = 0x18;
+const IBRD: usize = 0x24;
+const FBRD: usize = 0x28;
+const LCRH: usize = 0x2C;
+const CR: usize = 0x30;
+const IFLS: usize = 0x34;
+const IMSC: usize = 0x38;
+const MIS: usize = 0x40;
+const ICR: usize = 0x44;
+
+const DR_ERRORS: u32 = 0xf << 8;
+const FR_RXFE: u32 = 1 << 4;
+const FR_TXFF: u32 = 1 << 5;
+const LCRH_PEN: u32 = 1 << 1;
+const LCRH_EPS: u32 = 1 << 2;
+const LCRH_STP2: u32 = 1 << 3;
+const LCRH_FEN: u32 = 1 << 4;
+const LCRH_WLEN_SHIFT: u32 = 5;
+const CR_UARTEN: u32 = 1 << 0;
+const CR_TXE: u32 = 1 << 8;
+const CR_RXE: u32 = 1 << 9;
+/// Interrupt when the receive FIFO is 1/2 full, or the transmit FIFO 1/4 full
+const IFLS_RX_1_2_TX_1_4: u32 = (0b010 << 3) | 0b001;
+const INT_RX: u32 = 1 << 4;
+const INT_TX: u32 = 1 << 5;
+/// Receive timeout, for what's left in the FIFO below the threshold
+const INT_RT: u32 = 1 << 6;
+const INT_ALL: u32 = 0x7ff;
+
+const TX_BUFFER_SIZE: usize = 4096;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+/// Baud rate and frame format
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct LineConfig {
+    pub baud: u32,
+    /// 5 to 8
+    pub data_bits: u8,
+    pub parity: Parity,
+    /// 1 or 2
+    pub stop_bits: u8,
+}
+
+impl LineConfig {
+    /// 115200 baud, 8N1
+    pub const DEFAULT: LineConfig = LineConfig {
+        baud: 115200,
+        data_bits: 8,
+        parity: Parity::None,
+        stop_bits: 1,
+    };
+
+    /// Parses `<BAUD>[,<DATA BITS><PARITY><STOP BITS>]`, e.g. `115200` or `9600,7e1`
+    pub fn parse(value: &[u8]) -> Option<LineConfig> {
+        let mut parts = value.splitn(2, |c| *c == b',');
+        let baud: u32 = core::str::from_utf8(parts.next()?).ok()?.parse().ok()?;
+        if baud == 0 {
+            return None;
+        }
+        let mut config = LineConfig {
+            baud,
+            ..LineConfig::DEFAULT
+        };
+
+        if let Some(format) = parts.next() {
+            let (data_bits, parity, stop_bits) = match *format {
+                [data_bits, parity, stop_bits] => (data_bits, parity, stop_bits),
+                _ => return None,
+            };
+            config.data_bits = match data_bits {
+                b'5'..=b'8' => data_bits - b'0',
+                _ => return None,
+            };
+            config.parity = match parity.to_ascii_lowercase() {
+                b'n' => Parity::None,
+                b'e' => Parity::Even,
+                b'o' => Parity::Odd,
+                _ => return None,
+            };
+            config.stop_bits = match stop_bits {
+                b'1' | b'2' => stop_bits - b'0',
+                _ => return None,
+            };
+        }
+        Some(config)
+    }
+
+    fn lcrh(&self) -> u32 {
+        let mut lcrh = LCRH_FEN | ((self.data_bits as u32 - 5) << LCRH_WLEN_SHIFT);
+        match self.parity {
+            Parity::None => {}
+            Parity::Even => lcrh |= LCRH_PEN | LCRH_EPS,
+            Parity::Odd => lcrh |= LCRH_PEN,
+        }
+        if self.stop_bits == 2 {
+            lcrh |= LCRH_STP2;
+        }
+        lcrh
+    }
+}
+
+/// An ARM PrimeCell UART, e.g. QEMU `virt`'s console or the Raspberry Pi's UART0
+#[derive(Debug)]
+pub struct Pl011 {
+    /// Physical address of the registers, can move with the rest of the peripherals
+    base: AtomicUsize,
+    /// Reference clock, used to calculate the baud rate divisor
+    clock_hz: AtomicU32,
+    /// The board's IRQ line
+    irq: u32,
+    initialized: AtomicBool,
+}
+
+impl Pl011 {
+    pub const fn new(base: usize, clock_hz: u32, irq: u32) -> Self {
+        Pl011 {
+            base: AtomicUsize::new(base),
+            clock_hz: AtomicU32::new(clock_hz),
+            irq,
+            initialized: AtomicBool::new(false),
+        }
+    }
+
+    unsafe fn read(&self, reg: usize) -> u32 {
+        (PhyAddr(self.base.load(Ordering::Relaxed) + reg).virt() as *const u32).read_volatile()
+    }
+
+    unsafe fn write(&self, reg: usize, value: u32) {
+        (PhyAddr(self.base.load(Ordering::Relaxed) + reg).virt_mut() as *mut u32)
+            .write_volatile(value);
+    }
+
+    pub fn set_base(&self, base: PhyAddr) {
+        self.base.store(base.0, Ordering::Relaxed);
+    }
+
+    /// Takes effect on the next `init`
+    pub fn set_clock_hz(&self, clock_hz: u32) {
+        self.clock_hz.store(clock_hz, Ordering::Relaxed);
+    }
+
+    /// Applies `config`, with interrupts masked until the driver is up
+    pub fn init(&self, config: &LineConfig) {
+        unsafe {
+            self.write(CR, 0);
+            self.write(ICR, INT_ALL);
+            self.write(IMSC, 0);
+
+            // Divisor = clock / (16 * baud), the fraction is in 64ths
+            let clock_hz = self.clock_hz.load(Ordering::Relaxed);
+            let divisor_x64 = (clock_hz as u64 * 4 / config.baud as u64) as u32;
+            self.write(IBRD, divisor_x64 >> 6);
+            self.write(FBRD, divisor_x64 & 0x3f);
+
+            self.write(LCRH, config.lcrh());
+            self.write(IFLS, IFLS_RX_1_2_TX_1_4);
+            self.write(CR, CR_UARTEN | CR_TXE | CR_RXE);
+        }
+        self.initialized.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_initialized(&self) -> bool {
+        self.initialized.load(Ordering::Relaxed)
+    }
+
+    pub fn can_write(&self) -> bool {
+        unsafe { self.read(FR) & FR_TXFF == 0 }
+    }
+
+    pub fn can_read(&self) -> bool {
+        unsafe { self.read(FR) & FR_RXFE == 0 }
+    }
+
+    pub fn write_sync(&self, buf: &[u8]) {
+        for c in buf {
+            // Wait for UART to become ready to transmit.
+            while !self.can_write() {}
+            unsafe { self.write(DR, *c as u32) };
+        }
+    }
+
+    /// Reads a byte if one is waiting, bytes with framing or parity errors are dropped
+    pub fn try_read(&self) -> Option<u8> {
+        while self.can_read() {
+            let data = unsafe { self.read(DR) };
+            if data & DR_ERRORS == 0 {
+                return Some(data as u8);
+            }
+        }
+        None
+    }
+}
+
+// ----- Driver -----
+
+pub struct Driver {
+    info: UnsafeCell<DriverInfo>,
+    board: Board,
+    device: &'static Device,
+    /// The IPC directory the input and output queues are created in. `PL011_IN`/`PL011_OUT` have
+    /// the same ids as `RPI_UART_IN`/`RPI_UART_OUT`
+    ipc_dir: [u64; 3],
+    /// Calls `device.handle_irq`, the board provides it since IRQ handlers take no context
+    irq_handler: IrqHandler,
+    /// Sets up the hardware if it isn't already the early console
+    init_hardware: fn(),
+}
+
+impl driver_manager::Driver for Driver {
+    fn init(&self) -> Result<(), ()> {
+        // FIXME: Vulnerability
+        unsafe {
+            (*self.info.get()).initialized = true;
+        }
+        let device = self.device;
+        let ipc_dir = self.ipc_dir;
+
+        if !device.uart.is_initialized() {
+            (self.init_hardware)();
+        }
+        let name = unsafe { (*self.info.get()).name };
+        irq::register(device.uart.irq, name, self.irq_handler)?;
+        device.irq_ready.store(true, Ordering::Relaxed);
+
+        spawn_task!(b"PL011.input", {
+            let input_queue = create_queue(&ipc_dir, ipc::well_known::PL011_IN).await;
+
+            // The IRQ handler fills it from now on
+            device.rx_queue.call_once(|| input_queue);
+            let _irq_lock = irq_lock();
+            let tx_buffer = device.tx_buffer.lock();
+            device.set_irqs(tx_buffer.is_readable());
+        });
+
+        spawn_task!(b"PL011.output", {
+            let output_queue = create_queue(&ipc_dir, ipc::well_known::PL011_OUT).await;
+
+            // Write to it forever
+            let mut buf = [0u8; 512];
+            loop {
+                if let Some(count) = output_queue.queue_read(&mut buf).await {
+                    if count != 0 {
+                        fi::SyncWrite::write_all(device, &buf[0..count]).unwrap();
+                    }
+                }
+                ktask::yield_now().await;
+            }
+        });
+
+        Ok(())
+    }
+
+    fn info(&'static self) -> &'static DriverInfo {
+        // FIXME: Vulnerability
+        unsafe { self.info.get().as_ref().unwrap() }
+    }
+
+    fn board(&self) -> Option<Board> {
+        Some(self.board)
+    }
+}
+
+async fn create_queue(dir: &[u64], id: u64) -> ipc::IpcRef {
+    let mut node = ipc::ROOT.read().as_ref().unwrap().clone();
+    for part in dir {
+        node = node.dir_get(*part).await.unwrap();
+    }
+    node.dir_link(id, ipc::IpcSpscQueue::new()).await.unwrap()
+}
+
+impl Driver {
+    /// `interface` must point at `device`, it can't be built here since this is a `const fn`
+    pub const fn new(
+        name: &'static [u8],
+        board: Board,
+        device: &'static Device,
+        interface: fi::FileInterface,
+        ipc_dir: [u64; 3],
+        irq_handler: IrqHandler,
+        init_hardware: fn(),
+    ) -> Self {
+        Driver {
+            info: UnsafeCell::new(DriverInfo {
+                name,
+                initialized: false,
+                devices: RwLock::new([driver_manager::Device {
+                    device_type: DeviceType::Console,
+                    interface,
+                }]),
+            }),
+            board,
+            device,
+            ipc_dir,
+            irq_handler,
+            init_hardware,
+        }
+    }
+}
+
+// ----- Device -----
+
+pub struct Device {
+    pub uart: Pl011,
+    /// Bytes waiting for the transmit interrupt, only locked with interrupts disabled
+    tx_buffer: Mutex<SpscQueue<TX_BUFFER_SIZE>>,
+    /// Set once the IRQ handler is registered, writes are synchronous until then
+    irq_ready: AtomicBool,
+    /// The IRQ handler pushes received bytes here
+    rx_queue: Once<ipc::IpcRef>,
+}
+
+impl Device {
+    pub const fn new(uart: Pl011) -> Self {
+        Device {
+            uart,
+            tx_buffer: Mutex::new(SpscQueue::new()),
+            irq_ready: AtomicBool::new(false),
+            rx_queue: Once::new(),
+        }
+    }
+
+    /// Moves buffered bytes into the UART's FIFO until either is full or empty
+    fn drain_tx(&self, buffer: &mut SpscQueue<TX_BUFFER_SIZE>) {
+        while self.uart.can_write() {
+            match buffer.read(1).first().copied() {
+                Some(c) => unsafe { self.uart.write(DR, c as u32) },
+                None => break,
+            }
+        }
+    }
+
+    /// Unmasks the receive interrupts once there's a queue for them, and the transmit one if `tx`
+    fn set_irqs(&self, tx: bool) {
+        let mut imsc = 0;
+        if self.rx_queue.get().is_some() {
+            imsc |= INT_RX | INT_RT;
+        }
+        if tx {
+            imsc |= INT_TX;
+        }
+        unsafe { self.uart.write(IMSC, imsc) };
+    }
+
+    /// Buffers `buf` for the transmit interrupt to send. Spins instead when nothing would drain
+    /// the buffer: before the driver is up, in IRQ handlers and after panics
+    fn write_buffered(&self, mut buf: &[u8]) -> usize {
+        let len = buf.len();
+        let buffered = self.irq_ready.load(Ordering::Relaxed) && interrupts::enabled();
+
+        let _irq_lock = irq_lock();
+        let mut buffer = self.tx_buffer.lock();
+
+        if !buffered {
+            // Keep the order, what's already buffered goes first
+            while buffer.is_readable() {
+                self.drain_tx(&mut buffer);
+            }
+            self.uart.write_sync(buf);
+            return len;
+        }
+
+        loop {
+            self.drain_tx(&mut buffer);
+            let written = buffer.write(buf);
+            buf = &buf[written..];
+            if buf.is_empty() {
+                break;
+            }
+            // Full, wait for the UART to make room
+            core::hint::spin_loop();
+        }
+        // The transmit interrupt only fires when the FIFO drains past the threshold, which it
+        // will since it was just filled
+        self.set_irqs(buffer.is_readable());
+        len
+    }
+
+    /// Called by the board's IRQ handler for this UART
+    pub fn handle_irq(&self) {
+        let pending = unsafe { self.uart.read(MIS) };
+        unsafe { self.uart.write(ICR, pending) };
+
+        // Empty the receive FIFO
+        let mut received = ArrayVec::<u8, 32>::new();
+        while !received.is_full() {
+            match self.uart.try_read() {
+                Some(c) => received.push(c),
+                None => break,
+            }
+        }
+        if let Some(queue) = self.rx_queue.get() {
+            // Dropped if the reader is too slow
+            let _ = queue.queue_write(&received);
+        }
+
+        // Refill the transmit FIFO
+        let mut buffer = self.tx_buffer.lock();
+        self.drain_tx(&mut buffer);
+        self.set_irqs(buffer.is_readable());
+    }
+}
+
+#[async_trait]
+impl fi::Write for Device {
+    async fn write(&self, buf: &[u8]) -> IoResult<usize> {
+        Ok(self.write_buffered(buf))
+    }
+}
+
+#[async_trait]
+impl fi::Read for Device {
+    /// Waits for the IRQ handler to receive something
+    async fn read(&self, buf: &mut [u8]) -> IoResult<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            if let Some(queue) = self.rx_queue.get() {
+                return queue.queue_read(buf).await.ok_or(());
+            }
+            ktask::yield_now().await;
+        }
+    }
+}
+
+impl fi::SyncWrite for Device {
+    fn write(&self, buf: &[u8]) -> IoResult<usize> {
+        Ok(self.write_buffered(buf))
+    }
+}
+
+impl fi::SyncRead for Device {
+    /// Polls the UART directly, only useful while the IRQ handler can't steal the bytes
+    fn read(&self, buf: &mut [u8]) -> IoResult<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            if let Some(c) = self.uart.try_read() {
+                buf[0] = c;
+                return Ok(1);
+            }
+        }
+    }
+}
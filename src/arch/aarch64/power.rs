@@ -0,0 +1,57 @@
+//! Restarting and stopping the machine
+
+use crate::arch::aarch64::mmio::delay_us_sync;
+use crate::arch::aarch64::{board, interrupts};
+use crate::boot_options;
+use crate::prelude::*;
+use qemu_exit::QEMUExit;
+
+/// QEMU's exit status after `halt` and `poweroff`
+const EXIT_SUCCESS: u32 = 0;
+/// QEMU's exit status after a kernel panic
+const EXIT_PANIC: u32 = 1;
+
+/// With `qemu_exit=on`, makes QEMU exit with `code`. Uses semihosting, so it's only safe under
+/// QEMU's `-semihosting`, real hardware would take an undefined instruction exception.
+fn exit_qemu(code: u32) {
+    if boot_options::get().qemu_exit {
+        qemu_exit::AArch64::new().exit(code);
+    }
+}
+
+pub fn reboot() -> ! {
+    println!("[INFO] Rebooting...");
+    unsafe { interrupts::disable() };
+    board::reboot()
+}
+
+pub fn halt() -> ! {
+    println!("[INFO] Halting...");
+    unsafe { interrupts::disable() };
+    exit_qemu(EXIT_SUCCESS);
+    board::halt()
+}
+
+pub fn poweroff() -> ! {
+    println!("[INFO] Powering off...");
+    unsafe { interrupts::disable() };
+    exit_qemu(EXIT_SUCCESS);
+    board::poweroff()
+}
+
+/// Called by the panic handler once the message is out: Exits QEMU, reboots after the delay set
+/// by `panic_reboot=`, or hangs
+pub fn after_panic() -> ! {
+    unsafe { interrupts::disable() };
+    exit_qemu(EXIT_PANIC);
+
+    if let Some(seconds) = boot_options::get().panic_reboot {
+        println!("[INFO] Rebooting in {} seconds...", seconds);
+        delay_us_sync(seconds.saturating_mul(1000 * 1000));
+        board::reboot()
+    }
+
+    loop {
+        unsafe { asm!("wfi") };
+    }
+}
@@ -1,13 +1,77 @@
+//! The Arasan SD host controller ("EMMC") behind the Pi's SD card slot. Commands and transfers
+//! wait for its interrupt, so the card is only used from ktasks
+
+use crate::arch::aarch64::board::Board;
+use crate::arch::aarch64::exceptions::ExceptionContext;
+use crate::arch::aarch64::gpio::{self, Function, Pull};
+use crate::arch::aarch64::irq;
 use crate::arch::aarch64::mmio::{
-    delay, delay_us_sync, mmio_read, mmio_write, GPFSEL4, GPFSEL5, GPHEN1, GPPUD, GPPUDCLK1,
+    delay_us, get_uptime_us, mmio_read, mmio_write, sleep_us, IRQ_EMMC,
 };
+use crate::block::{self, BlockDevice};
+use crate::driver_manager::{DeviceType, DriverInfo};
 use crate::prelude::*;
+use crate::{driver_manager, fi, ktask};
+
+use core::cell::UnsafeCell;
+use core::convert::{TryFrom, TryInto};
+use core::fmt;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::task::{Context, Poll, Waker};
+use spin::{Mutex, RwLock};
+
+pub const BLOCK_SIZE: usize = 512;
+/// The block count in `EMMC_BLKSIZECNT` is 16 bits wide
+const MAX_BLOCKS_PER_TRANSFER: usize = 0xffff;
+
+const STATUS_TIMEOUT_US: u64 = 500_000;
+/// How long the card may take to power up
+const OP_COND_TIMEOUT_US: u64 = 1_000_000;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SdhcError {
+    /// No card, or it isn't initialized yet
+    NoCard,
+    /// The card's pins belong to another driver
+    PinsTaken,
+    /// The host controller didn't come out of reset
+    Reset,
+    /// The SD clock didn't stabilize
+    Clock,
+    /// The command or data lines stayed busy
+    Busy,
+    /// The card didn't answer a command
+    CommandTimeout,
+    /// The card stopped sending or taking data
+    DataTimeout,
+    /// Other error bits of `EMMC_INTERRUPT` (CRC, end bit, index...)
+    Interrupt(u32),
+    /// Error bits of the card's status
+    Card(u32),
+    /// The card doesn't take our voltage, or never finished powering up
+    Unsupported,
+    /// The buffer isn't a whole number of blocks, or they're past what the card can address
+    InvalidBuffer,
+}
 
-pub struct Sdhc {
-    sd_scr: [u32; 2],
-    sd_ocr: u32, // TODO: remove?
-    sd_rca: u32,
-    sd_hv: u32,
+impl fmt::Display for SdhcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SdhcError::NoCard => write!(f, "no SD card"),
+            SdhcError::PinsTaken => write!(f, "the SD card pins are taken"),
+            SdhcError::Reset => write!(f, "controller reset failed"),
+            SdhcError::Clock => write!(f, "SD clock isn't stable"),
+            SdhcError::Busy => write!(f, "controller busy"),
+            SdhcError::CommandTimeout => write!(f, "command timed out"),
+            SdhcError::DataTimeout => write!(f, "data timed out"),
+            SdhcError::Interrupt(flags) => write!(f, "controller error 0x{:x}", flags),
+            SdhcError::Card(status) => write!(f, "card error 0x{:x}", status),
+            SdhcError::Unsupported => write!(f, "unsupported card"),
+            SdhcError::InvalidBuffer => write!(f, "invalid buffer"),
+        }
+    }
 }
 
 mod consts {
@@ -42,6 +106,7 @@ mod consts {
     pub const CMD_GO_IDLE: u32 = 0x00000000;
     pub const CMD_ALL_SEND_CID: u32 = 0x02010000;
     pub const CMD_SEND_REL_ADDR: u32 = 0x03020000;
+    pub const CMD_SEND_CSD: u32 = 0x09010000;
     pub const CMD_CARD_SELECT: u32 = 0x07030000;
     pub const CMD_SEND_IF_COND: u32 = 0x08020000;
     pub const CMD_STOP_TRANS: u32 = 0x0C030000;
@@ -71,6 +136,11 @@ mod consts {
     pub const INT_CMD_DONE: u32 = 0x00000001;
 
     pub const INT_ERROR_MASK: u32 = 0x017E8000;
+    /// Everything that ends a wait with an error
+    pub const INT_ERRORS: u32 = INT_ERROR_MASK | INT_CMD_TIMEOUT | INT_DATA_TIMEOUT;
+    /// What the controller signals to the ARM
+    pub const INT_ENABLED: u32 =
+        INT_CMD_DONE | INT_DATA_DONE | INT_WRITE_RDY | INT_READ_RDY | INT_ERRORS;
 
     // CONTROL register settings
     pub const C0_SPI_MODE_EN: u32 = 0x00100000;
@@ -97,429 +167,424 @@ mod consts {
     // SCR flags
     pub const SCR_SD_BUS_WIDTH_4: u32 = 0x00000400;
     pub const SCR_SUPP_SET_BLKCNT: u32 = 0x02000000;
-    // added by my driver
-    pub const SCR_SUPP_CCS: u32 = 0x00000001;
 
     pub const ACMD41_VOLTAGE: u32 = 0x00ff8000;
     pub const ACMD41_CMD_COMPLETE: u32 = 0x80000000;
     pub const ACMD41_CMD_CCS: u32 = 0x40000000;
     pub const ACMD41_ARG_HC: u32 = 0x51ff8000;
+
+    // CSD fields, as the controller stores the response: without the CRC, in RESP0-3
+    pub const CSD_STRUCTURE_V2: u32 = 1;
 }
 
 use consts::*;
 
-pub struct SdhcCmdError(pub u32);
+// ----- Interrupts -----
 
-impl From<SdhcCmdError> for () {
-    fn from(e: SdhcCmdError) -> Self {
-        println!("[WARN] ERROR: Cmd returned error 0x{:x}", e.0);
+/// `EMMC_INTERRUPT` flags the IRQ handler acknowledged, until a wait takes them
+static PENDING: AtomicU32 = AtomicU32::new(0);
+/// Only locked with interrupts disabled
+static WAKER: Mutex<Option<Waker>> = Mutex::new(None);
+
+unsafe fn handle_irq(_e: &mut ExceptionContext) {
+    let flags = mmio_read(EMMC_INTERRUPT);
+    mmio_write(EMMC_INTERRUPT, flags);
+    PENDING.fetch_or(flags, Ordering::SeqCst);
+    if let Some(waker) = WAKER.lock().take() {
+        waker.wake();
     }
 }
 
-impl Sdhc {
-    pub unsafe fn init() -> Result<Self, ()> {
-        let mut sdhc = Self {
-            sd_scr: [0, 0],
-            sd_ocr: 0,
-            sd_rca: 0,
-            sd_hv: 0,
-        };
+/// Forgets the flags of earlier commands
+fn clear_interrupts() {
+    let _irq_lock = irq_lock();
+    unsafe { mmio_write(EMMC_INTERRUPT, mmio_read(EMMC_INTERRUPT)) };
+    PENDING.store(0, Ordering::SeqCst);
+}
 
-        // GPIO_CD
-        mmio_write(GPFSEL4, mmio_read(GPFSEL4) & !(7 << (7 * 3)));
-        mmio_write(GPPUD, 2);
-        delay(150);
-        mmio_write(GPPUDCLK1, 1 << 15);
-        delay(150);
-        mmio_write(GPPUD, 0);
-        mmio_write(GPPUDCLK1, 0);
-        mmio_write(GPHEN1, mmio_read(GPHEN1) | 1 << 15);
-
-        // GPIO_CLK, GPIO_CMD
-        mmio_write(
-            GPFSEL4,
-            mmio_read(GPFSEL4) | (7 << (8 * 3)) | (7 << (9 * 3)),
-        );
-        mmio_write(GPPUD, 2);
-        delay(150);
-        mmio_write(GPPUDCLK1, (1 << 16) | (1 << 17));
-        delay(150);
-        mmio_write(GPPUD, 0);
-        mmio_write(GPPUDCLK1, 0);
-
-        // GPIO_DAT0, GPIO_DAT1, GPIO_DAT2, GPIO_DAT3
-        mmio_write(
-            GPFSEL5,
-            mmio_read(GPFSEL5) | 7 | (7 << 3) | (7 << (2 * 3)) | (7 << (3 * 3)),
-        );
-        mmio_write(GPPUD, 2);
-        delay(150);
-        mmio_write(GPPUDCLK1, (1 << 18) | (1 << 19) | (1 << 20) | (1 << 21));
-        delay(150);
-        mmio_write(GPPUD, 0);
-        mmio_write(GPPUDCLK1, 0);
-
-        sdhc.sd_hv = (mmio_read(EMMC_SLOTISR_VER) & HOST_SPEC_NUM) >> HOST_SPEC_NUM_SHIFT;
-        println!("[INFO] EMMC GPIO set up");
-
-        // Reset the card
-        mmio_write(EMMC_CONTROL0, 0);
-        mmio_write(EMMC_CONTROL1, mmio_read(EMMC_CONTROL1) | C1_SRST_HC);
-        let mut successful = false;
-        for _ in 0..10_000 {
-            if mmio_read(EMMC_CONTROL1) & C1_SRST_HC == 0 {
-                successful = true;
-                break;
-            }
-            delay_us_sync(10);
-        }
-        if successful {
-            println!("[INFO] EMMC: reset OK");
+/// Resolves to the pending flags once they include one of its mask or an error, and takes those.
+/// There's no software timeout, the controller raises its own command and data timeouts
+struct Interrupt(u32);
+
+impl Future for Interrupt {
+    type Output = u32;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let _irq_lock = irq_lock();
+        // Checked with the waker locked, so a handler on another core can't slip in between
+        let mut waker = WAKER.lock();
+        let flags = PENDING.load(Ordering::SeqCst);
+        if flags & (self.0 | INT_ERRORS) != 0 {
+            PENDING.fetch_and(!(self.0 | INT_ERRORS), Ordering::SeqCst);
+            Poll::Ready(flags)
         } else {
-            println!("[WARN] EMMC: reset failed");
+            *waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+/// Yields until `done`, or fails after `timeout_us`
+async fn poll_until(timeout_us: u64, done: impl Fn() -> bool) -> Result<(), ()> {
+    let deadline = get_uptime_us() + timeout_us;
+    while !done() {
+        if get_uptime_us() > deadline {
             return Err(());
         }
-        mmio_write(
-            EMMC_CONTROL1,
-            mmio_read(EMMC_CONTROL1) | C1_CLK_INTLEN | C1_TOUNIT_MAX,
-        );
-        delay_us_sync(10);
+        yield_now().await;
+    }
+    Ok(())
+}
 
-        // Set clock to setup frequency
-        sdhc.clk(400_000)?;
-        mmio_write(EMMC_INT_EN, 0xffffffff);
-        mmio_write(EMMC_INT_MASK, 0xffffffff);
-        sdhc.sd_scr[0] = 0;
-        sdhc.sd_scr[1] = 0;
-        sdhc.sd_rca = 0;
-        sdhc.cmd(CMD_GO_IDLE, 0)?;
-
-        sdhc.cmd(CMD_SEND_IF_COND, 0x000001AA)?;
-        delay(400);
-
-        let mut got_complete = false;
-        let mut got_voltage = false;
-        let mut got_ccs = false;
-        for _ in 0..6 {
-            let response = sdhc.cmd(CMD_SEND_OP_COND, ACMD41_ARG_HC)?;
-            print!("[DBUG] EMMC: CMD_SEND_OP_COND returned: 0x{:x} (", response);
-            if response & ACMD41_CMD_COMPLETE != 0 {
-                got_complete = true;
-                print!("COMPLETE ");
-            }
-            if response & ACMD41_VOLTAGE != 0 {
-                got_voltage = true;
-                print!("VOLTAGE ");
-            }
-            if response & ACMD41_CMD_CCS != 0 {
-                got_ccs = true;
-                print!("CCS ");
-            }
-            println!(")");
+/// Brings the controller's command and data state machines back after an error
+async fn reset_lines() {
+    const LINES: u32 = C1_SRST_CMD | C1_SRST_DATA;
+    unsafe { mmio_write(EMMC_CONTROL1, mmio_read(EMMC_CONTROL1) | LINES) };
+    let _ = poll_until(
+        STATUS_TIMEOUT_US,
+        || unsafe { mmio_read(EMMC_CONTROL1) } & LINES == 0,
+    )
+    .await;
+}
 
-            if got_complete {
-                break;
-            }
+/// The card's capacity in 512-byte blocks, from the CSD register in the last response
+unsafe fn csd_block_count() -> u64 {
+    let resp1 = mmio_read(EMMC_RESP1);
+    let resp2 = mmio_read(EMMC_RESP2);
+    let resp3 = mmio_read(EMMC_RESP3);
+    if (resp3 >> 22) & 0b11 == CSD_STRUCTURE_V2 {
+        // C_SIZE counts 512KiB
+        let c_size = ((resp1 >> 8) & 0x3fffff) as u64;
+        (c_size + 1) * 1024
+    } else {
+        let c_size = ((resp1 >> 22) | ((resp2 & 0b11) << 10)) as u64;
+        let c_size_mult = (resp1 >> 7) & 0b111;
+        let read_bl_len = (resp2 >> 8) & 0xf;
+        ((c_size + 1) << (c_size_mult + 2)) << read_bl_len >> 9
+    }
+}
 
-            delay(400);
-        }
+fn check_buffer(len: usize) -> Result<(), SdhcError> {
+    if len == 0 || len % BLOCK_SIZE != 0 {
+        Err(SdhcError::InvalidBuffer)
+    } else {
+        Ok(())
+    }
+}
 
-        if !got_complete || !got_voltage {
-            return Err(());
-        }
+// ----- Card -----
 
-        sdhc.cmd(CMD_ALL_SEND_CID, 0)?;
-        sdhc.sd_rca = sdhc.cmd(CMD_SEND_REL_ADDR, 0)?;
-        println!(
-            "[DBUG] EMMC: CMD_SEND_REL_ADDR returned 0x{:x}",
-            sdhc.sd_rca
-        );
+pub struct Sdhc {
+    scr: [u32; 2],
+    rca: u32,
+    /// Host controller spec version
+    hv: u32,
+    /// The card takes block numbers rather than byte offsets (SDHC/SDXC)
+    ccs: bool,
+    block_count: u64,
+}
 
-        sdhc.clk(25_000_000)?;
+impl Sdhc {
+    /// Resets the controller and brings the card up to 25MHz. Needs the IRQ handler
+    async fn init() -> Result<Self, SdhcError> {
+        let mut sdhc = Self {
+            scr: [0, 0],
+            rca: 0,
+            hv: 0,
+            ccs: false,
+            block_count: 0,
+        };
 
-        sdhc.cmd(CMD_CARD_SELECT, sdhc.sd_rca)?;
+        // GPIO_CD, then GPIO_CLK, GPIO_CMD and GPIO_DAT0-3
+        gpio::setup(&[47], b"SDHC", Function::Input, Pull::Up).map_err(|_| SdhcError::PinsTaken)?;
+        gpio::setup(&[48, 49, 50, 51, 52, 53], b"SDHC", Function::Alt3, Pull::Up)
+            .map_err(|_| SdhcError::PinsTaken)?;
 
-        sdhc.status(SR_DAT_INHIBIT)?;
-        mmio_write(EMMC_BLKSIZECNT, (1 << 16) | 8);
-        sdhc.cmd(CMD_SEND_SCR, 0)?;
-        sdhc.int(INT_READ_RDY)?;
+        sdhc.hv = (unsafe { mmio_read(EMMC_SLOTISR_VER) } & HOST_SPEC_NUM) >> HOST_SPEC_NUM_SHIFT;
 
-        let mut scr_part = 0;
-        for _ in 0..100_000 {
-            if mmio_read(EMMC_STATUS) & SR_READ_AVAILABLE != 0 {
-                sdhc.sd_scr[scr_part] = mmio_read(EMMC_DATA);
-                scr_part += 1;
-                if scr_part == 2 {
-                    break;
-                }
-            } else {
-                delay_us_sync(1);
-            }
-        }
-        if scr_part != 2 {
-            return Err(());
-        }
-        if (sdhc.sd_scr[0] & SCR_SD_BUS_WIDTH_4) != 0 {
-            sdhc.cmd(CMD_SET_BUS_WIDTH, sdhc.sd_rca | 2)?;
-            mmio_write(EMMC_CONTROL0, mmio_read(EMMC_CONTROL0) | C0_HCTL_DWITDH);
+        // Reset the controller
+        unsafe {
+            mmio_write(EMMC_CONTROL0, 0);
+            mmio_write(EMMC_CONTROL1, mmio_read(EMMC_CONTROL1) | C1_SRST_HC);
         }
+        poll_until(
+            STATUS_TIMEOUT_US,
+            || unsafe { mmio_read(EMMC_CONTROL1) } & C1_SRST_HC == 0,
+        )
+        .await
+        .map_err(|_| SdhcError::Reset)?;
+        unsafe {
+            mmio_write(
+                EMMC_CONTROL1,
+                mmio_read(EMMC_CONTROL1) | C1_CLK_INTLEN | C1_TOUNIT_MAX,
+            )
+        };
+        delay_us(10).await;
 
-        // Add software flag
-        print!("[DBUG] EMMC: supports: ");
-        if (sdhc.sd_scr[0] & SCR_SUPP_SET_BLKCNT) != 0 {
-            print!("SET_BLKCNT ");
+        // Set clock to setup frequency
+        sdhc.clk(400_000).await?;
+        unsafe {
+            mmio_write(EMMC_INT_MASK, 0xffffffff);
+            mmio_write(EMMC_INT_EN, INT_ENABLED);
         }
-        if got_ccs {
-            print!("CCS ");
+        sdhc.cmd(CMD_GO_IDLE, 0).await?;
+        sdhc.cmd(CMD_SEND_IF_COND, 0x000001AA).await?;
+
+        // The card answers busy until it's powered up
+        let deadline = get_uptime_us() + OP_COND_TIMEOUT_US;
+        let ocr = loop {
+            let ocr = sdhc.cmd(CMD_SEND_OP_COND, ACMD41_ARG_HC).await?;
+            if ocr & ACMD41_CMD_COMPLETE != 0 || get_uptime_us() > deadline {
+                break ocr;
+            }
+            sleep_us(10_000).await;
+        };
+        println!("[DBUG] EMMC: CMD_SEND_OP_COND returned 0x{:x}", ocr);
+        if ocr & ACMD41_CMD_COMPLETE == 0 || ocr & ACMD41_VOLTAGE == 0 {
+            return Err(SdhcError::Unsupported);
+        }
+        sdhc.ccs = ocr & ACMD41_CMD_CCS != 0;
+
+        sdhc.cmd(CMD_ALL_SEND_CID, 0).await?;
+        sdhc.rca = sdhc.cmd(CMD_SEND_REL_ADDR, 0).await?;
+        println!("[DBUG] EMMC: CMD_SEND_REL_ADDR returned 0x{:x}", sdhc.rca);
+
+        sdhc.cmd(CMD_SEND_CSD, sdhc.rca).await?;
+        sdhc.block_count = unsafe { csd_block_count() };
+        println!("[DBUG] EMMC: {} blocks", sdhc.block_count);
+
+        sdhc.clk(25_000_000).await?;
+        sdhc.cmd(CMD_CARD_SELECT, sdhc.rca).await?;
+
+        // The SCR has the bus widths and optional commands
+        sdhc.status(SR_DAT_INHIBIT).await?;
+        unsafe { mmio_write(EMMC_BLKSIZECNT, (1 << 16) | 8) };
+        sdhc.cmd(CMD_SEND_SCR, 0).await?;
+        sdhc.int(INT_READ_RDY).await?;
+        for word in &mut sdhc.scr {
+            *word = unsafe { mmio_read(EMMC_DATA) };
         }
-        println!();
+        sdhc.int(INT_DATA_DONE).await?;
 
-        sdhc.sd_scr[0] &= !SCR_SUPP_CCS;
-        if got_ccs {
-            sdhc.sd_scr[0] |= SCR_SUPP_CCS;
+        if (sdhc.scr[0] & SCR_SD_BUS_WIDTH_4) != 0 {
+            sdhc.cmd(CMD_SET_BUS_WIDTH, sdhc.rca | 2).await?;
+            unsafe { mmio_write(EMMC_CONTROL0, mmio_read(EMMC_CONTROL0) | C0_HCTL_DWITDH) };
         }
 
-        println!("[INFO] EMMC: Setup success!");
+        println!(
+            "[DBUG] EMMC: supports: {}{}",
+            if sdhc.supports_set_blkcnt() {
+                "SET_BLKCNT "
+            } else {
+                ""
+            },
+            if sdhc.ccs { "CCS" } else { "" }
+        );
         Ok(sdhc)
     }
 
-    /// Wait for data or command ready
-    pub unsafe fn status(&mut self, mask: u32) -> Result<(), ()> {
-        for _ in 0..500_000 {
-            if (mmio_read(EMMC_STATUS) & mask) == 0 {
-                return if mmio_read(EMMC_INTERRUPT) & INT_ERROR_MASK == 0 {
-                    Ok(())
-                } else {
-                    Err(()) // Error
-                };
-            } else {
-                delay_us_sync(1);
-            }
-        }
-        Err(()) // Error
+    pub fn block_count(&self) -> u64 {
+        self.block_count
     }
 
-    /// Wait for interrupt
-    pub unsafe fn int(&mut self, mask: u32) -> Result<(), ()> {
-        let mut done = false;
+    fn supports_set_blkcnt(&self) -> bool {
+        (self.scr[0] & SCR_SUPP_SET_BLKCNT) != 0
+    }
 
-        for _ in 0..1_000_000 {
-            if mmio_read(EMMC_INTERRUPT) & (mask | INT_ERROR_MASK) == 0 {
-                delay_us_sync(1);
-            } else {
-                done = true;
-                break;
-            }
+    /// Data commands take block numbers on SDHC/SDXC cards, and byte offsets on older ones
+    fn address(&self, lba: u32) -> Result<u32, SdhcError> {
+        if self.ccs {
+            Ok(lba)
+        } else {
+            lba.checked_mul(BLOCK_SIZE as u32)
+                .ok_or(SdhcError::InvalidBuffer)
         }
+    }
 
-        let flags = mmio_read(EMMC_INTERRUPT);
-        let result = if !done || (flags & INT_CMD_TIMEOUT) != 0 || (flags & INT_DATA_TIMEOUT) != 0 {
-            println!("[WARN] EMMC: int timeout");
-            Err(()) // Timeout
-        } else if (flags & INT_ERROR_MASK) != 0 {
-            println!("[WARN] EMMC: int error");
-            Err(()) // Error
+    /// Wait for the command or data lines in `mask` to be free
+    async fn status(&self, mask: u32) -> Result<(), SdhcError> {
+        poll_until(
+            STATUS_TIMEOUT_US,
+            || unsafe { mmio_read(EMMC_STATUS) } & mask == 0,
+        )
+        .await
+        .map_err(|_| SdhcError::Busy)
+    }
+
+    /// Wait for interrupt
+    async fn int(&self, mask: u32) -> Result<(), SdhcError> {
+        let flags = Interrupt(mask).await;
+        let result = if flags & INT_CMD_TIMEOUT != 0 {
+            Err(SdhcError::CommandTimeout)
+        } else if flags & INT_DATA_TIMEOUT != 0 {
+            Err(SdhcError::DataTimeout)
+        } else if flags & INT_ERRORS != 0 {
+            Err(SdhcError::Interrupt(flags & INT_ERRORS))
         } else {
             Ok(())
         };
 
-        mmio_write(EMMC_INTERRUPT, mask);
+        if result.is_err() {
+            reset_lines().await;
+        }
         result
     }
 
-    /// Send a command
-    pub unsafe fn cmd(&mut self, mut code: u32, arg: u32) -> Result<u32, SdhcCmdError> {
+    /// Send a command, after APP_CMD if it's an application command
+    async fn cmd(&self, code: u32, arg: u32) -> Result<u32, SdhcError> {
         if (code & CMD_NEED_APP) != 0 {
-            let new_code = CMD_APP_CMD | (if self.sd_rca != 0 { CMD_RSPNS_48 } else { 0 });
-            let result = self.cmd(new_code, self.sd_rca)?;
-            if self.sd_rca != 0 && result == 0 {
-                println!("[WARN] ERROR: failed to send SD APP command");
-                return Err(SdhcCmdError(0));
+            let app_code = CMD_APP_CMD | (if self.rca != 0 { CMD_RSPNS_48 } else { 0 });
+            let status = self.send(app_code, self.rca).await?;
+            if self.rca != 0 && status & SR_APP_CMD == 0 {
+                return Err(SdhcError::Card(status));
             }
-            code &= !CMD_NEED_APP;
         }
+        self.send(code & !CMD_NEED_APP, arg).await
+    }
 
-        if self.status(SR_CMD_INHIBIT).is_err() {
-            println!("[WARN] ERROR: EMMC busy");
-            return Err(SdhcCmdError(0));
-        }
+    async fn send(&self, code: u32, arg: u32) -> Result<u32, SdhcError> {
+        self.status(SR_CMD_INHIBIT).await?;
 
-        println!(
-            "[DBUG] EMMC: Sending (command: 0x{:x}, arg: 0x{:x})",
-            code, arg
-        );
-        mmio_write(EMMC_INTERRUPT, mmio_read(EMMC_INTERRUPT));
-        mmio_write(EMMC_ARG1, arg);
-        mmio_write(EMMC_CMDTM, code);
-
-        if code == (CMD_SEND_OP_COND & !CMD_NEED_APP) {
-            delay_us_sync(1000);
-        } else if code == CMD_SEND_IF_COND || code == CMD_APP_CMD {
-            delay_us_sync(100);
+        clear_interrupts();
+        unsafe {
+            mmio_write(EMMC_ARG1, arg);
+            mmio_write(EMMC_CMDTM, code);
         }
+        self.int(INT_CMD_DONE).await?;
 
-        self.int(INT_CMD_DONE).map_err(|_| {
-            println!("[WARN] ERROR: failed to send EMMC command");
-            SdhcCmdError(0)
-        })?;
+        let result = unsafe { mmio_read(EMMC_RESP0) };
 
-        let result = mmio_read(EMMC_RESP0);
-
-        const CMD_APP_CMD_RSPNS_48: u32 = CMD_APP_CMD | CMD_RSPNS_48;
         const CMD_SEND_OP_COND_NO_APP: u32 = CMD_SEND_OP_COND & !CMD_NEED_APP;
         match code {
             CMD_GO_IDLE | CMD_APP_CMD => Ok(0),
-            CMD_APP_CMD_RSPNS_48 => Ok(result & SR_APP_CMD),
             CMD_SEND_OP_COND_NO_APP => Ok(result),
-            CMD_SEND_IF_COND => {
-                if result == arg {
-                    Ok(result & CMD_ERRORS_MASK)
-                } else {
-                    Err(SdhcCmdError(result & CMD_ERRORS_MASK))
-                }
-            }
-            CMD_ALL_SEND_CID => {
+            CMD_SEND_IF_COND if result == arg => Ok(result),
+            CMD_SEND_IF_COND => Err(SdhcError::Unsupported),
+            CMD_SEND_CSD => Ok(result),
+            CMD_ALL_SEND_CID => unsafe {
                 Ok(result | mmio_read(EMMC_RESP3) | mmio_read(EMMC_RESP2) | mmio_read(EMMC_RESP1))
-            }
+            },
             CMD_SEND_REL_ADDR => {
+                // Some of the status bits, packed below the RCA
                 let err = ((result & 0x1fff)
                     | ((result & 0x2000) << 6)
                     | ((result & 0x4000) << 8)
                     | ((result & 0x8000) << 8))
                     & CMD_ERRORS_MASK;
                 if err != 0 {
-                    Err(SdhcCmdError(result & CMD_RCA_MASK))
+                    Err(SdhcError::Card(err))
                 } else {
                     Ok(result & CMD_RCA_MASK)
                 }
             }
-            _ => Ok(result & CMD_ERRORS_MASK),
+            _ if result & CMD_ERRORS_MASK != 0 => Err(SdhcError::Card(result & CMD_ERRORS_MASK)),
+            _ => Ok(result),
         }
     }
 
-    /// read blocks from the sd card
-    pub unsafe fn read_block(&mut self, lba: u32, mut buf: &mut [u32]) -> Result<(), ()> {
-        if buf.len() % (512 / 4) != 0 || buf.is_empty() {
-            return Err(());
+    /// Starts moving `blocks` blocks, with one command when there are several
+    async fn start_transfer(
+        &self,
+        lba: u32,
+        blocks: u32,
+        single: u32,
+        multi: u32,
+    ) -> Result<(), SdhcError> {
+        let address = self.address(lba)?;
+        self.status(SR_DAT_INHIBIT).await?;
+        if blocks > 1 && self.supports_set_blkcnt() {
+            self.cmd(CMD_SET_BLOCKCNT, blocks).await?;
         }
-        let block_count = (buf.len() / (512 / 4)) as u32;
-        println!(
-            "[DBUG] EMMC: read_block(lba: {}, blocks: {})",
-            lba, block_count
-        );
-
-        let ccs_support = (self.sd_scr[0] & SCR_SUPP_CCS) != 0;
-        let set_blkcnt_support = (self.sd_scr[0] & SCR_SUPP_SET_BLKCNT) != 0;
+        unsafe { mmio_write(EMMC_BLKSIZECNT, (blocks << 16) | BLOCK_SIZE as u32) };
+        self.cmd(if blocks > 1 { multi } else { single }, address)
+            .await?;
+        Ok(())
+    }
 
-        self.status(SR_DAT_INHIBIT)?;
-        if ccs_support {
-            if block_count > 1 && set_blkcnt_support {
-                self.cmd(CMD_SET_BLOCKCNT, block_count)?;
-            }
-            mmio_write(EMMC_BLKSIZECNT, (block_count << 16) | 512);
-            self.cmd(
-                if block_count == 1 {
-                    CMD_READ_SINGLE
-                } else {
-                    CMD_READ_MULTI
-                },
-                lba,
-            )?;
+    /// Waits for the end of a transfer, and stops it if the card wasn't told how many blocks
+    /// there were. The transfer is stopped even if `moved` failed
+    async fn finish_transfer(
+        &self,
+        blocks: u32,
+        moved: Result<(), SdhcError>,
+    ) -> Result<(), SdhcError> {
+        let result = match moved {
+            Ok(()) => self.int(INT_DATA_DONE).await,
+            err => err,
+        };
+        if blocks > 1 && !self.supports_set_blkcnt() {
+            let stopped = self.cmd(CMD_STOP_TRANS, 0).await;
+            result.and(stopped.map(|_| ()))
         } else {
-            mmio_write(EMMC_BLKSIZECNT, (1 << 16) | 512);
+            result
         }
+    }
 
-        for block in 0..block_count {
-            if !ccs_support {
-                self.cmd(CMD_READ_SINGLE, (lba + block) * 512)?;
-            }
-            self.int(INT_READ_RDY)?;
-            for chunk in &mut buf[..128] {
-                *chunk = mmio_read(EMMC_DATA);
+    /// Reads whole blocks starting at `lba`
+    pub async fn read_blocks(&mut self, lba: u32, buf: &mut [u8]) -> Result<(), SdhcError> {
+        check_buffer(buf.len())?;
+        for (i, chunk) in buf
+            .chunks_mut(MAX_BLOCKS_PER_TRANSFER * BLOCK_SIZE)
+            .enumerate()
+        {
+            let chunk_lba = lba
+                .checked_add((i * MAX_BLOCKS_PER_TRANSFER) as u32)
+                .ok_or(SdhcError::InvalidBuffer)?;
+            let blocks = (chunk.len() / BLOCK_SIZE) as u32;
+            self.start_transfer(chunk_lba, blocks, CMD_READ_SINGLE, CMD_READ_MULTI)
+                .await?;
+
+            let mut moved = Ok(());
+            for block in chunk.chunks_exact_mut(BLOCK_SIZE) {
+                moved = self.int(INT_READ_RDY).await;
+                if moved.is_err() {
+                    break;
+                }
+                for word in block.chunks_exact_mut(4) {
+                    word.copy_from_slice(&unsafe { mmio_read(EMMC_DATA) }.to_le_bytes());
+                }
             }
-            buf = &mut buf[(512 / 4)..];
-        }
-
-        if block_count > 1 && set_blkcnt_support && ccs_support {
-            self.cmd(CMD_STOP_TRANS, 0)?;
+            self.finish_transfer(blocks, moved).await?;
         }
-
         Ok(())
     }
 
-    /// write blocks from the sd card
-    pub unsafe fn write_block(&mut self, lba: u32, mut buf: &[u32]) -> Result<(), ()> {
-        if buf.len() % (512 / 4) != 0 || buf.is_empty() {
-            return Err(());
-        }
-        let block_count = (buf.len() / (512 / 4)) as u32;
-        println!(
-            "[DBUG] EMMC: write_block(lba: {}, blocks: {})",
-            lba, block_count
-        );
-
-        let ccs_support = (self.sd_scr[0] & SCR_SUPP_CCS) != 0;
-        let set_blkcnt_support = (self.sd_scr[0] & SCR_SUPP_SET_BLKCNT) != 0;
-
-        self.status(SR_DAT_INHIBIT | SR_WRITE_AVAILABLE)?;
-        if ccs_support {
-            if block_count > 1 && set_blkcnt_support {
-                self.cmd(CMD_SET_BLOCKCNT, block_count)?;
-            }
-            mmio_write(EMMC_BLKSIZECNT, (block_count << 16) | 512);
-            self.cmd(
-                if block_count == 1 {
-                    CMD_WRITE_SINGLE
-                } else {
-                    CMD_WRITE_MULTI
-                },
-                lba,
-            )?;
-        } else {
-            mmio_write(EMMC_BLKSIZECNT, (1 << 16) | 512);
-        }
-
-        for block in 0..block_count {
-            if !ccs_support {
-                self.cmd(CMD_WRITE_SINGLE, (lba + block) * 512)?;
-            }
-            self.int(INT_WRITE_RDY)?;
-            for chunk in &buf[..128] {
-                mmio_write(EMMC_DATA, *chunk);
+    /// Writes whole blocks starting at `lba`
+    pub async fn write_blocks(&mut self, lba: u32, buf: &[u8]) -> Result<(), SdhcError> {
+        check_buffer(buf.len())?;
+        for (i, chunk) in buf.chunks(MAX_BLOCKS_PER_TRANSFER * BLOCK_SIZE).enumerate() {
+            let chunk_lba = lba
+                .checked_add((i * MAX_BLOCKS_PER_TRANSFER) as u32)
+                .ok_or(SdhcError::InvalidBuffer)?;
+            let blocks = (chunk.len() / BLOCK_SIZE) as u32;
+            self.start_transfer(chunk_lba, blocks, CMD_WRITE_SINGLE, CMD_WRITE_MULTI)
+                .await?;
+
+            let mut moved = Ok(());
+            for block in chunk.chunks_exact(BLOCK_SIZE) {
+                moved = self.int(INT_WRITE_RDY).await;
+                if moved.is_err() {
+                    break;
+                }
+                for word in block.chunks_exact(4) {
+                    unsafe { mmio_write(EMMC_DATA, u32::from_le_bytes(word.try_into().unwrap())) };
+                }
             }
-            buf = &buf[(512 / 4)..];
-        }
-        self.int(INT_DATA_DONE)?;
-
-        if block_count > 1 && set_blkcnt_support && ccs_support {
-            self.cmd(CMD_STOP_TRANS, 0)?;
+            self.finish_transfer(blocks, moved).await?;
         }
-
         Ok(())
     }
 
-    /// set SD clock to frequency in Hz
-    pub unsafe fn clk(&mut self, freq: u32) -> Result<(), ()> {
+    /// Set SD clock to frequency in Hz
+    async fn clk(&self, freq: u32) -> Result<(), SdhcError> {
         if freq == 0 {
-            return Err(());
+            return Err(SdhcError::Clock);
         }
 
-        let mut done = false;
-        for _ in 0..100_000 {
-            if mmio_read(EMMC_STATUS) & (SR_CMD_INHIBIT | SR_DAT_INHIBIT) != 0 {
-                delay_us_sync(1);
-            } else {
-                done = true;
-                break;
-            }
-        }
-        if !done {
-            println!("[WARN] ERROR: timeout waiting for inhibit flag");
-            return Err(());
-        }
+        self.status(SR_CMD_INHIBIT | SR_DAT_INHIBIT).await?;
 
-        mmio_write(EMMC_CONTROL1, mmio_read(EMMC_CONTROL1) & !C1_CLK_EN);
-        delay_us_sync(10);
+        unsafe { mmio_write(EMMC_CONTROL1, mmio_read(EMMC_CONTROL1) & !C1_CLK_EN) };
+        delay_us(10).await;
 
         // WTF
         let c = 41666666 / freq;
@@ -555,7 +620,7 @@ impl Sdhc {
             }
         }
 
-        let mut divisor = if self.sd_hv > HOST_SPEC_V2 {
+        let mut divisor = if self.hv > HOST_SPEC_V2 {
             c
         } else {
             1 << shift
@@ -566,34 +631,151 @@ impl Sdhc {
         }
         println!("[DBUG] EMMC: sd_clk divisor {}, shift {}", divisor, shift);
 
-        let h = if self.sd_hv > HOST_SPEC_V2 {
+        let h = if self.hv > HOST_SPEC_V2 {
             (divisor & 0x300) >> 2
         } else {
             0
         };
         divisor = ((divisor & 0xff) << 8) | h;
-        mmio_write(
-            EMMC_CONTROL1,
-            (mmio_read(EMMC_CONTROL1) & 0xffff003f) | divisor,
-        );
-        delay_us_sync(10);
-        mmio_write(EMMC_CONTROL1, mmio_read(EMMC_CONTROL1) | C1_CLK_EN);
-        delay_us_sync(10);
-
-        let mut done = false;
-        for _ in 0..10_000 {
-            if mmio_read(EMMC_CONTROL1) & C1_CLK_STABLE == 0 {
-                delay_us_sync(10);
-            } else {
-                done = true;
-                break;
-            }
-        }
-        if !done {
-            println!("[WARN] ERROR: failed to get stable clock");
-            return Err(());
+        unsafe {
+            mmio_write(
+                EMMC_CONTROL1,
+                (mmio_read(EMMC_CONTROL1) & 0xffff003f) | divisor,
+            )
+        };
+        delay_us(10).await;
+        unsafe { mmio_write(EMMC_CONTROL1, mmio_read(EMMC_CONTROL1) | C1_CLK_EN) };
+        delay_us(10).await;
+
+        poll_until(
+            STATUS_TIMEOUT_US,
+            || unsafe { mmio_read(EMMC_CONTROL1) } & C1_CLK_STABLE != 0,
+        )
+        .await
+        .map_err(|_| SdhcError::Clock)
+    }
+}
+
+// ----- Driver -----
+
+/// The card, once the driver's task has initialized it
+static CARD: Mutex<Option<Sdhc>> = Mutex::new(None);
+
+/// Reads whole blocks from the SD card, starting at `lba`
+pub async fn read_blocks(lba: u32, buf: &mut [u8]) -> Result<(), SdhcError> {
+    ktask::lock(&CARD)
+        .await
+        .as_mut()
+        .ok_or(SdhcError::NoCard)?
+        .read_blocks(lba, buf)
+        .await
+}
+
+/// Writes whole blocks to the SD card, starting at `lba`
+pub async fn write_blocks(lba: u32, buf: &[u8]) -> Result<(), SdhcError> {
+    ktask::lock(&CARD)
+        .await
+        .as_mut()
+        .ok_or(SdhcError::NoCard)?
+        .write_blocks(lba, buf)
+        .await
+}
+
+/// The whole SD card, as a block device
+struct SdCard {
+    block_count: u64,
+}
+
+#[async_trait]
+impl BlockDevice for SdCard {
+    fn name(&self) -> &[u8] {
+        b"sd0"
+    }
+
+    fn sector_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.block_count
+    }
+
+    async fn read(&self, sector: u64, buf: &mut [u8]) -> IoResult<()> {
+        let lba = u32::try_from(sector).map_err(|_| ())?;
+        read_blocks(lba, buf).await.map_err(|e| {
+            println!("[WARN] EMMC: Reading block {} failed: {}", sector, e);
+        })
+    }
+
+    async fn write(&self, sector: u64, buf: &[u8]) -> IoResult<()> {
+        let lba = u32::try_from(sector).map_err(|_| ())?;
+        write_blocks(lba, buf).await.map_err(|e| {
+            println!("[WARN] EMMC: Writing block {} failed: {}", sector, e);
+        })
+    }
+
+    /// Writes are done once the card took the data
+    async fn flush(&self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct Driver {
+    info: UnsafeCell<DriverInfo>,
+}
+
+impl driver_manager::Driver for Driver {
+    fn init(&self) -> Result<(), ()> {
+        // FIXME: Vulnerability
+        unsafe {
+            (*self.info.get()).initialized = true;
         }
 
+        irq::register(IRQ_EMMC, b"EMMC", handle_irq)?;
+
+        spawn_task!(b"SDHC.init", {
+            match Sdhc::init().await {
+                Ok(card) => {
+                    println!("[INFO] EMMC: Setup success!");
+                    let block_count = card.block_count();
+                    *ktask::lock(&CARD).await = Some(card);
+                    block::register_disk(Arc::new(SdCard { block_count })).await;
+                }
+                Err(e) => println!("[WARN] EMMC: Setup failed: {}", e),
+            }
+        });
+
         Ok(())
     }
+
+    fn info(&'static self) -> &'static DriverInfo {
+        // FIXME: Vulnerability
+        unsafe { self.info.get().as_ref().unwrap() }
+    }
+
+    fn board(&self) -> Option<Board> {
+        Some(Board::RaspberryPi3)
+    }
 }
+
+static mut DRIVER: Driver = Driver {
+    info: UnsafeCell::new(DriverInfo {
+        name: b"Raspberry Pi 3 SDHC",
+        initialized: false,
+        devices: RwLock::new([driver_manager::Device {
+            device_type: DeviceType::Storage,
+            interface: fi::FileInterface {
+                sync_read: None,
+                read: None,
+                sync_write: None,
+                write: None,
+                ctrl: None,
+            },
+        }]),
+    }),
+};
+
+#[link_section = ".drivers"]
+#[used]
+static mut DRIVER_REF: &dyn driver_manager::Driver = unsafe { &DRIVER };
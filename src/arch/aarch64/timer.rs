@@ -0,0 +1,47 @@
+//! The EL1 physical timer of the ARM generic timer, every core has its own
+
+use crate::prelude::*;
+
+const CTL_ENABLE: u64 = 1 << 0;
+const CTL_IMASK: u64 = 1 << 1;
+
+/// Counter ticks per second
+pub fn frequency() -> u64 {
+    unsafe { get_msr!(cntfrq_el0) }
+}
+
+/// Ticks since boot, at `frequency()` per second
+pub fn counter() -> u64 {
+    unsafe { get_msr!(cntpct_el0) }
+}
+
+fn us_to_ticks(time_us: u64) -> u64 {
+    time_us.saturating_mul(frequency()) / (1000 * 1000)
+}
+
+/// Arms the current core's timer to go off in `time_us`, replacing whatever it was armed for
+pub fn set(time_us: u64) {
+    let deadline = counter().saturating_add(us_to_ticks(time_us));
+    unsafe {
+        set_msr!(cntp_cval_el0, deadline);
+        set_msr!(cntp_ctl_el0, CTL_ENABLE);
+    }
+}
+
+/// Arms the current core's timer to go off in `time_us`, unless it's armed to go off sooner
+pub fn set_if_earlier(time_us: u64) {
+    let deadline = counter().saturating_add(us_to_ticks(time_us));
+    unsafe {
+        let armed = get_msr!(cntp_ctl_el0) & (CTL_ENABLE | CTL_IMASK) == CTL_ENABLE;
+        if !armed || deadline < get_msr!(cntp_cval_el0) {
+            set_msr!(cntp_cval_el0, deadline);
+            set_msr!(cntp_ctl_el0, CTL_ENABLE);
+        }
+    }
+}
+
+/// Silences the current core's timer until it's set again. The interrupt is level triggered, so
+/// this has to happen before returning from it
+pub fn mask() {
+    unsafe { set_msr!(cntp_ctl_el0, CTL_ENABLE | CTL_IMASK) };
+}
@@ -1,15 +1,31 @@
+use crate::arch::aarch64::board::Board;
+use crate::arch::aarch64::exceptions::ExceptionContext;
+use crate::arch::aarch64::gpio::{self, Function, Pull};
 use crate::arch::aarch64::mmio::{
-    delay, mmio_read, mmio_write, GPFSEL1, GPPUD, GPPUDCLK0, UART1_ENABLE, UART1_MU_BAUD,
-    UART1_MU_CNTL, UART1_MU_IER, UART1_MU_IIR, UART1_MU_IO, UART1_MU_LCR, UART1_MU_LSR,
-    UART1_MU_MCR,
+    mmio_read, mmio_write, AUX_IRQ, AUX_IRQ_MINI_UART, IRQ_AUX, UART1_ENABLE, UART1_MU_BAUD,
+    UART1_MU_CNTL, UART1_MU_IER, UART1_MU_IER_REQUIRED, UART1_MU_IER_RX, UART1_MU_IER_TX,
+    UART1_MU_IIR, UART1_MU_IO, UART1_MU_LCR, UART1_MU_LSR, UART1_MU_LSR_DATA_READY,
+    UART1_MU_LSR_TX_EMPTY, UART1_MU_MCR,
 };
+use crate::arch::aarch64::{interrupts, irq};
 use crate::driver_manager::{DeviceType, DriverInfo};
 use crate::ipc;
+use crate::ipc::spsc_queue::SpscQueue;
 use crate::prelude::*;
 
 use crate::{driver_manager, fi, ktask};
 use core::cell::UnsafeCell;
-use spin::RwLock;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::{Mutex, Once, RwLock};
+
+const TX_BUFFER_SIZE: usize = 4096;
+
+/// Bytes waiting for the TX-empty interrupt, only locked with interrupts disabled
+static TX_BUFFER: Mutex<SpscQueue<TX_BUFFER_SIZE>> = Mutex::new(SpscQueue::new());
+/// Set once the IRQ handler is registered, writes are synchronous until then
+static TX_IRQ_READY: AtomicBool = AtomicBool::new(false);
+/// The IRQ handler pushes received bytes here
+static RX_QUEUE: Once<ipc::IpcRef> = Once::new();
 
 // ----- Driver -----
 
@@ -18,7 +34,14 @@ struct Driver {
     info: UnsafeCell<DriverInfo>,
 }
 
+/// Sets up UART1 and gives it the header's GPIO 14 and 15, as the early console
 pub fn init_uart1() {
+    setup_uart1();
+    // `claim` warns if they're taken
+    let _ = gpio::setup(&[14, 15], b"UART1", Function::Alt5, Pull::Off);
+}
+
+fn setup_uart1() {
     unsafe {
         // Initialize UART
         mmio_write(UART1_ENABLE, mmio_read(UART1_ENABLE) | 1);
@@ -28,32 +51,94 @@ pub fn init_uart1() {
         mmio_write(UART1_MU_IER, 0);
         mmio_write(UART1_MU_IIR, 0xc6); // disable interrupts
         mmio_write(UART1_MU_BAUD, 270); // 115200 baud
-
-        // Map UART1 to GPIO pins
-        mmio_write(GPFSEL1, {
-            let mut new_val = mmio_read(GPFSEL1);
-            new_val &= !((7 << 12) | (7 << 15)); // gpio14, gpio15
-            new_val |= (2 << 12) | (2 << 15); // alt5
-            new_val
-        });
-        mmio_write(GPPUD, 0); // enable pins 14 and 15
-        delay(1500);
-        mmio_write(GPPUDCLK0, (1 << 14) | (1 << 15));
-        delay(1500);
-        mmio_write(GPPUDCLK0, 0); // flush GPIO setup
         mmio_write(UART1_MU_CNTL, 3); // enable Tx, Rx
     }
 }
 
-pub fn write_uart1(buf: &[u8]) -> IoResult<usize> {
-    for c in buf {
-        unsafe {
-            // Wait for UART to become ready to transmit.
-            while mmio_read(UART1_MU_LSR) & 0x20 == 0 {}
-            mmio_write(UART1_MU_IO, *c as u32);
+fn can_write() -> bool {
+    unsafe { mmio_read(UART1_MU_LSR) & UART1_MU_LSR_TX_EMPTY != 0 }
+}
+
+fn can_read() -> bool {
+    unsafe { mmio_read(UART1_MU_LSR) & UART1_MU_LSR_DATA_READY != 0 }
+}
+
+/// Moves buffered bytes into the UART's FIFO until either is full or empty
+fn drain_tx(buffer: &mut SpscQueue<TX_BUFFER_SIZE>) {
+    while can_write() {
+        match buffer.read(1).first().copied() {
+            Some(c) => unsafe { mmio_write(UART1_MU_IO, c as u32) },
+            None => break,
+        }
+    }
+}
+
+/// Enables the RX interrupt once there's a queue for it, and the TX-empty interrupt if `tx`
+fn set_irqs(tx: bool) {
+    let mut ier = UART1_MU_IER_REQUIRED;
+    if RX_QUEUE.get().is_some() {
+        ier |= UART1_MU_IER_RX;
+    }
+    if tx {
+        ier |= UART1_MU_IER_TX;
+    }
+    unsafe { mmio_write(UART1_MU_IER, ier) };
+}
+
+/// Buffers `buf` for the TX-empty interrupt to send. Spins instead when nothing would drain the
+/// buffer: before the driver is up, in IRQ handlers and after panics
+pub fn write_uart1(mut buf: &[u8]) -> IoResult<usize> {
+    let len = buf.len();
+    let buffered = TX_IRQ_READY.load(Ordering::Relaxed) && interrupts::enabled();
+
+    let _irq_lock = irq_lock();
+    let mut buffer = TX_BUFFER.lock();
+
+    if !buffered {
+        // Keep the order, what's already buffered goes first
+        while buffer.is_readable() {
+            drain_tx(&mut buffer);
+        }
+        for c in buf {
+            while !can_write() {}
+            unsafe { mmio_write(UART1_MU_IO, *c as u32) };
+        }
+        return Ok(len);
+    }
+
+    loop {
+        drain_tx(&mut buffer);
+        let written = buffer.write(buf);
+        buf = &buf[written..];
+        if buf.is_empty() {
+            break;
         }
+        // Full, wait for the UART to make room
+        core::hint::spin_loop();
     }
-    Ok(buf.len())
+    set_irqs(buffer.is_readable());
+    Ok(len)
+}
+
+unsafe fn handle_irq(_e: &mut ExceptionContext) {
+    if mmio_read(AUX_IRQ) & AUX_IRQ_MINI_UART == 0 {
+        return;
+    }
+
+    // Empty the receive FIFO
+    let mut received = ArrayVec::<u8, 8>::new();
+    while can_read() && !received.is_full() {
+        received.push(mmio_read(UART1_MU_IO) as u8);
+    }
+    if let Some(queue) = RX_QUEUE.get() {
+        // Dropped if the reader is too slow
+        let _ = queue.queue_write(&received);
+    }
+
+    // Refill the transmit FIFO
+    let mut buffer = TX_BUFFER.lock();
+    drain_tx(&mut buffer);
+    set_irqs(buffer.is_readable());
 }
 
 impl driver_manager::Driver for Driver {
@@ -63,6 +148,13 @@ impl driver_manager::Driver for Driver {
             (*self.info.get()).initialized = true;
         }
 
+        // Another UART is the early console, it keeps the pins
+        if unsafe { mmio_read(UART1_ENABLE) } & 1 == 0 {
+            setup_uart1();
+        }
+        irq::register(IRQ_AUX, b"UART1", handle_irq)?;
+        TX_IRQ_READY.store(true, Ordering::Relaxed);
+
         spawn_task!(b"UART1.input", {
             // Create the input queue
             let root = ipc::ROOT.read().as_ref().unwrap().clone();
@@ -80,14 +172,10 @@ impl driver_manager::Driver for Driver {
                 .await
                 .unwrap();
 
-            // Write to it forever
-            let mut buf = [0u8; 1];
-            loop {
-                if let Ok(1) = fi::Read::read(&DEVICE, &mut buf).await {
-                    input_queue.queue_write(&buf).warn();
-                }
-                ktask::yield_now().await;
-            }
+            // The IRQ handler fills it from now on
+            RX_QUEUE.call_once(|| input_queue);
+            let _irq_lock = irq_lock();
+            set_irqs(TX_BUFFER.lock().is_readable());
         });
 
         spawn_task!(b"UART1.output", {
@@ -112,7 +200,6 @@ impl driver_manager::Driver for Driver {
             loop {
                 if let Some(count) = output_queue.queue_read(&mut buf).await {
                     if count != 0 {
-                        // fi::SyncWrite::write_all(&DEVICE, &buf).await.unwrap();
                         fi::SyncWrite::write_all(&DEVICE, &buf[0..count]).unwrap();
                     }
                 }
@@ -127,6 +214,10 @@ impl driver_manager::Driver for Driver {
         // FIXME: Vulnerability
         unsafe { self.info.get().as_ref().unwrap() }
     }
+
+    fn board(&self) -> Option<Board> {
+        Some(Board::RaspberryPi3)
+    }
 }
 
 static mut DRIVER: Driver = Driver {
@@ -158,63 +249,40 @@ struct Device;
 #[async_trait]
 impl fi::Write for Device {
     async fn write(&self, buf: &[u8]) -> IoResult<usize> {
-        for c in buf {
-            unsafe {
-                // Wait for UART to become ready to transmit.
-                while mmio_read(UART1_MU_LSR) & 0x20 == 0 {
-                    ktask::yield_now().await;
-                }
-                mmio_write(UART1_MU_IO, *c as u32);
-            }
-        }
-        Ok(buf.len())
+        write_uart1(buf)
     }
 }
 
 #[async_trait]
 impl fi::Read for Device {
+    /// Waits for the IRQ handler to receive something
     async fn read(&self, buf: &mut [u8]) -> IoResult<usize> {
         if buf.is_empty() {
             return Ok(0);
         }
-        unsafe {
-            // Wait for UART to become ready to receive.
-            while mmio_read(UART1_MU_LSR) & 0x1 == 0 {
-                // Poll UART1 at 120hz (1000×1000÷120 = 8333us)
-                wtfln!("(UART)");
-                crate::arch::aarch64::mmio::sleep_us(8333).await;
-                // crate::arch::aarch64::mmio::sleep_us(1010).await;
-                // yield_now().await;
+        loop {
+            if let Some(queue) = RX_QUEUE.get() {
+                return queue.queue_read(buf).await.ok_or(());
             }
-            buf[0] = mmio_read(UART1_MU_IO) as u8;
+            ktask::yield_now().await;
         }
-        Ok(1)
     }
 }
 
 impl fi::SyncWrite for Device {
     fn write(&self, buf: &[u8]) -> IoResult<usize> {
-        for c in buf {
-            unsafe {
-                // Wait for UART to become ready to transmit.
-                while mmio_read(UART1_MU_LSR) & 0x20 == 0 {}
-                mmio_write(UART1_MU_IO, *c as u32);
-            }
-        }
-        Ok(buf.len())
+        write_uart1(buf)
     }
 }
 
 impl fi::SyncRead for Device {
+    /// Polls the UART directly, only useful while the IRQ handler can't steal the bytes
     fn read(&self, buf: &mut [u8]) -> IoResult<usize> {
         if buf.is_empty() {
             return Ok(0);
         }
-        unsafe {
-            // Wait for UART to become ready to receive.
-            while mmio_read(UART1_MU_LSR) & 0x1 == 0 {}
-            buf[0] = mmio_read(UART1_MU_IO) as u8;
-        }
+        while !can_read() {}
+        buf[0] = unsafe { mmio_read(UART1_MU_IO) } as u8;
         Ok(1)
     }
 }
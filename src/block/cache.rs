@@ -0,0 +1,274 @@
+//! Write-back sector cache between a disk and everything on it, partitions included
+
+use crate::block::{check_range, BlockDevice};
+use crate::ktask;
+use crate::prelude::*;
+
+use alloc::collections::BTreeMap;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::{Mutex, RwLock};
+
+/// Sectors kept per disk
+const CAPACITY: usize = 1024;
+/// Sectors read past the end of a sequential read that missed
+const READ_AHEAD: u64 = 16;
+
+static HITS: AtomicU64 = AtomicU64::new(0);
+static MISSES: AtomicU64 = AtomicU64::new(0);
+static READ_AHEAD_SECTORS: AtomicU64 = AtomicU64::new(0);
+static WRITTEN_BACK: AtomicU64 = AtomicU64::new(0);
+static CACHED: AtomicU64 = AtomicU64::new(0);
+static DIRTY: AtomicU64 = AtomicU64::new(0);
+
+/// Sector counts, over every cache
+#[derive(Debug)]
+pub struct PerfReport {
+    hits: u64,
+    misses: u64,
+    read_ahead: u64,
+    written_back: u64,
+    cached: u64,
+    dirty: u64,
+}
+
+pub fn perf_report() -> PerfReport {
+    PerfReport {
+        hits: HITS.load(Ordering::Relaxed),
+        misses: MISSES.load(Ordering::Relaxed),
+        read_ahead: READ_AHEAD_SECTORS.load(Ordering::Relaxed),
+        written_back: WRITTEN_BACK.load(Ordering::Relaxed),
+        cached: CACHED.load(Ordering::Relaxed),
+        dirty: DIRTY.load(Ordering::Relaxed),
+    }
+}
+
+static CACHES: RwLock<Vec<Arc<CachedDevice>>> = RwLock::new(Vec::new());
+
+/// Writes every dirty sector of every disk
+pub async fn sync_all() -> IoResult<()> {
+    let caches = CACHES.read().clone();
+    let mut result = Ok(());
+    for cache in caches {
+        if cache.flush().await.is_err() {
+            println!("[WARN] Failed to sync \"{}\"", AsciiStr(cache.inner.name()));
+            result = Err(());
+        }
+    }
+    result
+}
+
+struct Entry {
+    data: Box<[u8]>,
+    dirty: bool,
+    /// Its key in `Cache::lru`
+    last_use: u64,
+}
+
+struct Cache {
+    entries: BTreeMap<u64, Entry>,
+    /// Sectors by last use, the oldest first
+    lru: BTreeMap<u64, u64>,
+    clock: u64,
+    /// Where a read would start if it carried on from the last one
+    next_read: u64,
+}
+
+impl Cache {
+    fn touch(&mut self, sector: u64) {
+        if let Some(entry) = self.entries.get_mut(&sector) {
+            self.lru.remove(&entry.last_use);
+            self.clock += 1;
+            entry.last_use = self.clock;
+            self.lru.insert(self.clock, sector);
+        }
+    }
+
+    fn get(&mut self, sector: u64) -> Option<&[u8]> {
+        self.touch(sector);
+        self.entries.get(&sector).map(|entry| &*entry.data)
+    }
+
+    fn contains(&self, sector: u64) -> bool {
+        self.entries.contains_key(&sector)
+    }
+}
+
+/// Caches whole sectors of `inner`. Writes stay in the cache until `flush`, or until they're
+/// evicted
+pub struct CachedDevice {
+    inner: Arc<dyn BlockDevice>,
+    cache: Mutex<Cache>,
+}
+
+impl CachedDevice {
+    pub fn new(inner: Arc<dyn BlockDevice>) -> Arc<Self> {
+        let device = Arc::new(CachedDevice {
+            inner,
+            cache: Mutex::new(Cache {
+                entries: BTreeMap::new(),
+                lru: BTreeMap::new(),
+                clock: 0,
+                next_read: 0,
+            }),
+        });
+        CACHES.write().push(device.clone());
+        device
+    }
+
+    /// Caches `data` for `sector`, evicting the least recently used sector if it's full
+    async fn insert(
+        &self,
+        cache: &mut Cache,
+        sector: u64,
+        data: &[u8],
+        dirty: bool,
+    ) -> IoResult<()> {
+        if let Some(entry) = cache.entries.get_mut(&sector) {
+            entry.data.copy_from_slice(data);
+            if dirty && !entry.dirty {
+                entry.dirty = true;
+                DIRTY.fetch_add(1, Ordering::Relaxed);
+            }
+            cache.touch(sector);
+            return Ok(());
+        }
+
+        if cache.entries.len() >= CAPACITY {
+            let (&last_use, &oldest) = cache.lru.iter().next().unwrap();
+            if cache.entries[&oldest].dirty {
+                self.inner
+                    .write(oldest, &cache.entries[&oldest].data)
+                    .await?;
+                WRITTEN_BACK.fetch_add(1, Ordering::Relaxed);
+                DIRTY.fetch_sub(1, Ordering::Relaxed);
+            }
+            cache.lru.remove(&last_use);
+            cache.entries.remove(&oldest);
+            CACHED.fetch_sub(1, Ordering::Relaxed);
+        }
+
+        cache.clock += 1;
+        cache.entries.insert(
+            sector,
+            Entry {
+                data: data.into(),
+                dirty,
+                last_use: cache.clock,
+            },
+        );
+        cache.lru.insert(cache.clock, sector);
+        CACHED.fetch_add(1, Ordering::Relaxed);
+        if dirty {
+            DIRTY.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BlockDevice for CachedDevice {
+    fn name(&self) -> &[u8] {
+        self.inner.name()
+    }
+
+    fn sector_size(&self) -> usize {
+        self.inner.sector_size()
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.inner.sector_count()
+    }
+
+    /// Misses next to each other are read in one go, and reads carrying on from the last one
+    /// read ahead
+    async fn read(&self, sector: u64, buf: &mut [u8]) -> IoResult<()> {
+        check_range(self, sector, buf.len())?;
+        let sector_size = self.sector_size();
+        let count = (buf.len() / sector_size) as u64;
+
+        let mut cache = ktask::lock(&self.cache).await;
+        let sequential = sector == cache.next_read;
+        cache.next_read = sector + count;
+
+        let mut done = 0;
+        while done < count {
+            let start = done as usize * sector_size;
+            if let Some(data) = cache.get(sector + done) {
+                buf[start..start + sector_size].copy_from_slice(data);
+                HITS.fetch_add(1, Ordering::Relaxed);
+                done += 1;
+                continue;
+            }
+
+            let mut missed = 1;
+            while done + missed < count && !cache.contains(sector + done + missed) {
+                missed += 1;
+            }
+            let mut fetched = missed;
+            if sequential && done + missed == count {
+                while fetched - missed < READ_AHEAD
+                    && sector + done + fetched < self.sector_count()
+                    && !cache.contains(sector + done + fetched)
+                {
+                    fetched += 1;
+                }
+            }
+
+            let mut data = vec![0; fetched as usize * sector_size];
+            self.inner.read(sector + done, &mut data).await?;
+            let len = missed as usize * sector_size;
+            buf[start..start + len].copy_from_slice(&data[..len]);
+            for (i, chunk) in (0..).zip(data.chunks_exact(sector_size)) {
+                self.insert(&mut cache, sector + done + i, chunk, false)
+                    .await?;
+            }
+            MISSES.fetch_add(missed, Ordering::Relaxed);
+            READ_AHEAD_SECTORS.fetch_add(fetched - missed, Ordering::Relaxed);
+            done += missed;
+        }
+        Ok(())
+    }
+
+    async fn write(&self, sector: u64, buf: &[u8]) -> IoResult<()> {
+        check_range(self, sector, buf.len())?;
+        let mut cache = ktask::lock(&self.cache).await;
+        for (i, chunk) in (0..).zip(buf.chunks_exact(self.sector_size())) {
+            self.insert(&mut cache, sector + i, chunk, true).await?;
+        }
+        Ok(())
+    }
+
+    /// Writes the dirty sectors, runs of them in one go, then flushes the disk
+    async fn flush(&self) -> IoResult<()> {
+        let mut cache = ktask::lock(&self.cache).await;
+        let dirty: Vec<u64> = cache
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.dirty)
+            .map(|(sector, _)| *sector)
+            .collect();
+
+        let mut run = Vec::new();
+        let mut run_start = 0;
+        for (i, &sector) in dirty.iter().enumerate() {
+            if run.is_empty() {
+                run_start = sector;
+            }
+            run.extend_from_slice(&cache.entries[&sector].data);
+            if dirty.get(i + 1) == Some(&(sector + 1)) {
+                continue;
+            }
+
+            self.inner.write(run_start, &run).await?;
+            for written in run_start..=sector {
+                cache.entries.get_mut(&written).unwrap().dirty = false;
+            }
+            let sectors = sector - run_start + 1;
+            WRITTEN_BACK.fetch_add(sectors, Ordering::Relaxed);
+            DIRTY.fetch_sub(sectors, Ordering::Relaxed);
+            run.clear();
+        }
+
+        self.inner.flush().await
+    }
+}
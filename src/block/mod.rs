@@ -0,0 +1,164 @@
+//! Disks and their partitions, as devices made of equally sized sectors
+
+pub(crate) mod cache;
+pub(crate) mod partition;
+
+use crate::ipc::{self, IpcNode, IpcRef};
+use crate::prelude::*;
+
+use futures::prelude::stream::BoxStream;
+use spin::RwLock;
+
+#[async_trait]
+pub trait BlockDevice: Send + Sync {
+    fn name(&self) -> &[u8];
+
+    fn sector_size(&self) -> usize;
+
+    fn sector_count(&self) -> u64;
+
+    /// Reads whole sectors starting at `sector`
+    async fn read(&self, sector: u64, buf: &mut [u8]) -> IoResult<()>;
+
+    /// Writes whole sectors starting at `sector`
+    async fn write(&self, sector: u64, buf: &[u8]) -> IoResult<()>;
+
+    /// Waits for earlier writes to reach the medium
+    async fn flush(&self) -> IoResult<()>;
+}
+
+/// Checks that `len` bytes are whole sectors of `device`, and that they're on it from `sector`
+pub fn check_range(device: &dyn BlockDevice, sector: u64, len: usize) -> IoResult<()> {
+    let sector_size = device.sector_size();
+    if len % sector_size != 0 {
+        return Err(());
+    }
+    let end = sector.checked_add((len / sector_size) as u64).ok_or(())?;
+    if end > device.sector_count() {
+        return Err(());
+    }
+    Ok(())
+}
+
+/// Every disk and partition, in the order they were found. Their id in the IPC tree is their
+/// index + 1
+static DEVICES: RwLock<Vec<Arc<dyn BlockDevice>>> = RwLock::new(Vec::new());
+
+pub fn devices() -> Vec<Arc<dyn BlockDevice>> {
+    DEVICES.read().clone()
+}
+
+pub fn by_name(name: &[u8]) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES
+        .read()
+        .iter()
+        .find(|device| device.name() == name)
+        .cloned()
+}
+
+/// Adds `disk` behind a cache, then each of its partitions on top of that
+pub async fn register_disk(disk: Arc<dyn BlockDevice>) {
+    let disk: Arc<dyn BlockDevice> = cache::CachedDevice::new(disk);
+    add(disk.clone()).await;
+    match partition::scan(&disk).await {
+        Ok(partitions) => {
+            for partition in partitions {
+                add(Arc::new(partition)).await;
+            }
+        }
+        Err(()) => println!(
+            "[WARN] Couldn't read the partitions of \"{}\"",
+            AsciiStr(disk.name())
+        ),
+    }
+}
+
+async fn add(device: Arc<dyn BlockDevice>) {
+    println!(
+        "[INFO] Block device \"{}\": {} sectors of {} bytes",
+        AsciiStr(device.name()),
+        device.sector_count(),
+        device.sector_size()
+    );
+    let id = {
+        let mut devices = DEVICES.write();
+        devices.push(device.clone());
+        devices.len() as u64
+    };
+
+    let root = ipc::ROOT.read().as_ref().unwrap().clone();
+    root.dir_get(ipc::well_known::ROOT_DEVICES)
+        .await
+        .unwrap()
+        .dir_get(ipc::well_known::DEVICES_BLOCK)
+        .await
+        .unwrap()
+        .dir_link(id, Arc::new(IpcBlockDevice(device.clone())))
+        .await
+        .unwrap();
+
+    crate::fs::probe(device).await;
+}
+
+// ----- IPC -----
+
+/// A block device in the IPC tree, readable at any byte offset
+pub struct IpcBlockDevice(Arc<dyn BlockDevice>);
+
+#[async_trait]
+impl IpcNode for IpcBlockDevice {
+    fn dir_list<'a>(self: Arc<Self>) -> Option<BoxStream<'a, IpcRef>> {
+        None
+    }
+
+    async fn dir_get(self: Arc<Self>, _id: u64) -> Option<IpcRef> {
+        None
+    }
+
+    async fn dir_create(self: Arc<Self>, _id: u64) -> Option<IpcRef> {
+        None
+    }
+
+    async fn dir_link(
+        self: Arc<Self>,
+        _id: u64,
+        _node: Arc<dyn IpcNode + Send + Sync>,
+    ) -> Option<IpcRef> {
+        None
+    }
+
+    fn queue_write(self: Arc<Self>, _data: &[u8]) -> Result<usize, ()> {
+        Err(())
+    }
+
+    async fn queue_read(self: Arc<Self>, _dest: &mut [u8]) -> Option<usize> {
+        None
+    }
+
+    /// Reads the sectors around `offset..offset + dest.len()`, returns 0 past the end
+    async fn read_at(self: Arc<Self>, offset: u64, dest: &mut [u8]) -> Option<usize> {
+        let sector_size = self.0.sector_size() as u64;
+        let size = self.0.sector_count() * sector_size;
+        if offset >= size || dest.is_empty() {
+            return Some(0);
+        }
+        let len = dest.len().min((size - offset) as usize);
+
+        let first = offset / sector_size;
+        let last = (offset + len as u64 - 1) / sector_size;
+        let mut sectors = vec![0; ((last - first + 1) * sector_size) as usize];
+        self.0.read(first, &mut sectors).await.ok()?;
+
+        let start = (offset % sector_size) as usize;
+        dest[..len].copy_from_slice(&sectors[start..start + len]);
+        Some(len)
+    }
+
+    fn describe(&self) -> [u8; 4] {
+        *b"BLK "
+    }
+
+    fn name(&self) -> Option<&[u8]> {
+        Some(self.0.name())
+    }
+}
@@ -0,0 +1,237 @@
+//! Finds partitions in a GPT, or else an MBR. Logical partitions inside an MBR extended
+//! partition aren't supported
+
+use crate::block::{check_range, BlockDevice};
+use crate::prelude::*;
+
+use core::convert::TryInto;
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const MBR_ENTRIES: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+/// The MBR partition that covers the disk when it has a GPT
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xee;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+/// More than anyone uses, bounds what we read
+const GPT_MAX_ENTRIES: usize = 256;
+/// Size of the header fields we know, the header can be bigger
+const GPT_MIN_HEADER_SIZE: usize = 92;
+/// Entries are 128 << n bytes
+const GPT_MIN_ENTRY_SIZE: usize = 128;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PartitionType {
+    Mbr(u8),
+    /// The type GUID, as stored
+    Gpt([u8; 16]),
+}
+
+/// A range of a disk's sectors
+pub struct Partition {
+    disk: Arc<dyn BlockDevice>,
+    name: Vec<u8>,
+    pub partition_type: PartitionType,
+    start: u64,
+    sector_count: u64,
+}
+
+impl Partition {
+    /// Named after the disk and its slot in the table, e.g. `sd0p1`
+    fn new(
+        disk: &Arc<dyn BlockDevice>,
+        slot: usize,
+        partition_type: PartitionType,
+        start: u64,
+        sector_count: u64,
+    ) -> Option<Self> {
+        let end = start.checked_add(sector_count)?;
+        if sector_count == 0 || end > disk.sector_count() {
+            println!(
+                "[WARN] Partition {} of \"{}\" is past its end",
+                slot,
+                AsciiStr(disk.name())
+            );
+            return None;
+        }
+        let mut name = disk.name().to_vec();
+        name.extend_from_slice(alloc::format!("p{}", slot).as_bytes());
+        Some(Partition {
+            disk: disk.clone(),
+            name,
+            partition_type,
+            start,
+            sector_count,
+        })
+    }
+}
+
+#[async_trait]
+impl BlockDevice for Partition {
+    fn name(&self) -> &[u8] {
+        &self.name
+    }
+
+    fn sector_size(&self) -> usize {
+        self.disk.sector_size()
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sector_count
+    }
+
+    async fn read(&self, sector: u64, buf: &mut [u8]) -> IoResult<()> {
+        check_range(self, sector, buf.len())?;
+        self.disk.read(self.start + sector, buf).await
+    }
+
+    async fn write(&self, sector: u64, buf: &[u8]) -> IoResult<()> {
+        check_range(self, sector, buf.len())?;
+        self.disk.write(self.start + sector, buf).await
+    }
+
+    async fn flush(&self) -> IoResult<()> {
+        self.disk.flush().await
+    }
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+/// CRC32 as used by GPTs (IEEE, reflected)
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb88320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// A filesystem's boot sector rather than an MBR, on disks formatted without partitions. Both end
+/// with the same signature, but boot sectors start with a jump and a valid sector size
+fn is_boot_sector(sector: &[u8]) -> bool {
+    matches!(sector[0], 0xeb | 0xe9)
+        && matches!(
+            u16::from_le_bytes([sector[11], sector[12]]),
+            512 | 1024 | 2048 | 4096
+        )
+}
+
+/// The partitions of `disk`, none if it has no partition table
+pub async fn scan(disk: &Arc<dyn BlockDevice>) -> IoResult<Vec<Partition>> {
+    let sector_size = disk.sector_size();
+    if sector_size < 512 {
+        return Err(());
+    }
+    let mut mbr = vec![0; sector_size];
+    disk.read(0, &mut mbr).await?;
+    if mbr[510..512] != MBR_SIGNATURE || is_boot_sector(&mbr) {
+        return Ok(Vec::new());
+    }
+
+    let entries = mbr[MBR_ENTRIES..MBR_ENTRIES + 4 * MBR_ENTRY_SIZE].chunks_exact(MBR_ENTRY_SIZE);
+    // The boot flag is the only sanity check MBRs have
+    if entries
+        .clone()
+        .any(|entry| !matches!(entry[0], 0x00 | 0x80))
+    {
+        return Ok(Vec::new());
+    }
+    if entries
+        .clone()
+        .any(|entry| entry[4] == MBR_TYPE_GPT_PROTECTIVE)
+    {
+        return scan_gpt(disk).await;
+    }
+
+    Ok(entries
+        .enumerate()
+        .filter(|(_, entry)| entry[4] != 0)
+        .filter_map(|(i, entry)| {
+            Partition::new(
+                disk,
+                i + 1,
+                PartitionType::Mbr(entry[4]),
+                u32_at(entry, 8) as u64,
+                u32_at(entry, 12) as u64,
+            )
+        })
+        .collect())
+}
+
+async fn scan_gpt(disk: &Arc<dyn BlockDevice>) -> IoResult<Vec<Partition>> {
+    let sector_size = disk.sector_size();
+    let mut header = vec![0; sector_size];
+    disk.read(1, &mut header).await?;
+    if &header[0..8] != GPT_SIGNATURE {
+        println!(
+            "[WARN] \"{}\" has a protective MBR, but no GPT",
+            AsciiStr(disk.name())
+        );
+        return Err(());
+    }
+
+    // Nothing in the header is trusted before its CRC is checked, computed with its field zeroed
+    let header_size = u32_at(&header, 12) as usize;
+    if !(GPT_MIN_HEADER_SIZE..=sector_size).contains(&header_size) {
+        return Err(());
+    }
+    let header_crc = u32_at(&header, 16);
+    header[16..20].fill(0);
+    if crc32(&header[..header_size]) != header_crc {
+        println!("[WARN] \"{}\" has a corrupt GPT", AsciiStr(disk.name()));
+        return Err(());
+    }
+
+    let entries_start = u64_at(&header, 72);
+    let entry_count = u32_at(&header, 80) as usize;
+    let entry_size = u32_at(&header, 84) as usize;
+    if entry_count > GPT_MAX_ENTRIES
+        || !entry_size.is_power_of_two()
+        || !(GPT_MIN_ENTRY_SIZE..=sector_size).contains(&entry_size)
+    {
+        return Err(());
+    }
+    let len = entry_count.checked_mul(entry_size).ok_or(())?;
+    let sectors = (len + sector_size - 1) / sector_size;
+    let mut entries = vec![0; sectors.checked_mul(sector_size).ok_or(())?];
+    disk.read(entries_start, &mut entries).await?;
+    if crc32(&entries[..len]) != u32_at(&header, 88) {
+        println!(
+            "[WARN] \"{}\" has a corrupt GPT partition array",
+            AsciiStr(disk.name())
+        );
+        return Err(());
+    }
+
+    Ok(entries
+        .chunks_exact(entry_size)
+        .take(entry_count)
+        .enumerate()
+        .filter(|(_, entry)| entry[0..16].iter().any(|b| *b != 0))
+        .filter_map(|(i, entry)| {
+            let first = u64_at(entry, 32);
+            // Inclusive
+            let last = u64_at(entry, 40);
+            Partition::new(
+                disk,
+                i + 1,
+                PartitionType::Gpt(entry[0..16].try_into().unwrap()),
+                first,
+                last.checked_sub(first)?.checked_add(1)?,
+            )
+        })
+        .collect())
+}
@@ -0,0 +1,164 @@
+use crate::arch::aarch64::board::{self, EarlyConsole, EARLY_CONSOLES};
+use crate::arch::aarch64::dtb;
+use crate::arch::aarch64::pl011::LineConfig;
+use crate::console::{self, LogLevel};
+use crate::prelude::*;
+
+use spin::Once;
+
+const MAX_CMDLINE_LEN: usize = 1024;
+/// 1GiB, far below the top of the lower half where the stack goes
+const MAX_INIT_STACK_KIB: usize = 1024 * 1024;
+
+static CMDLINE: Once<ArrayVec<u8, MAX_CMDLINE_LEN>> = Once::new();
+static OPTIONS: Once<BootOptions> = Once::new();
+
+/// Options parsed from the kernel command line, e.g. `earlycon=uart1 loglevel=info fbcon=off`.
+/// Unknown options are ignored, the bootloader may pass some of its own.
+#[derive(Debug)]
+pub struct BootOptions {
+    /// `earlycon=<uart0|uart1|pl011>`: Where kernel messages go, depends on the board
+    pub earlycon: &'static [u8],
+    /// `loglevel=<error|warn|info|debug>`: Hide messages above this level
+    pub log_level: LogLevel,
+    /// `fbcon=<on|off>`: Whether to start the framebuffer console, if the board has one
+    pub fbcon: bool,
+    /// `init=<PATH>`: VFS path of the program the `init` command runs, instead of the built-in one
+    pub init: Option<&'static [u8]>,
+    /// `init_stack=<KiB>`: Stack size of usermode programs, 32KiB by default, 1GiB at most
+    pub init_stack: usize,
+    /// `panic_reboot=<SECONDS>`: Reboot this long after a panic, instead of hanging
+    pub panic_reboot: Option<u64>,
+    /// `qemu_exit=<on|off>`: Exit QEMU with a status code on halt, poweroff and panic. Only for
+    /// QEMU with `-semihosting`
+    pub qemu_exit: bool,
+    /// `uart0=<BAUD>[,<DATA BITS><PARITY><STOP BITS>]`: Line settings of the PL011 UART0, e.g.
+    /// `uart0=9600,7e1`. 115200 8N1 by default
+    pub uart0: LineConfig,
+}
+
+impl Default for BootOptions {
+    fn default() -> Self {
+        BootOptions {
+            earlycon: EARLY_CONSOLES[0].name,
+            log_level: LogLevel::Debug,
+            fbcon: board::HAS_FRAMEBUFFER,
+            init: None,
+            init_stack: 32 * 1024,
+            panic_reboot: None,
+            qemu_exit: false,
+            uart0: LineConfig::DEFAULT,
+        }
+    }
+}
+
+impl BootOptions {
+    fn parse(cmdline: &'static [u8]) -> (BootOptions, ArrayVec<&'static [u8], 8>) {
+        let mut options = BootOptions::default();
+        let mut invalid = ArrayVec::new();
+
+        for arg in cmdline.split(|c| *c == b' ').filter(|arg| !arg.is_empty()) {
+            let mut parts = arg.splitn(2, |c| *c == b'=');
+            let key = parts.next().unwrap();
+            let value = parts.next().unwrap_or(b"");
+
+            let valid = match key {
+                b"earlycon" => EARLY_CONSOLES
+                    .iter()
+                    .find(|console| console.name == value)
+                    .map(|console| options.earlycon = console.name)
+                    .is_some(),
+                b"loglevel" => LogLevel::from_name(value)
+                    .map(|level| options.log_level = level)
+                    .is_some(),
+                b"fbcon" => parse_on_off(value)
+                    .map(|on| options.fbcon = on && board::HAS_FRAMEBUFFER)
+                    .is_some(),
+                b"init" if !value.is_empty() => {
+                    options.init = Some(value);
+                    true
+                }
+                b"init" => false,
+                b"init_stack" => core::str::from_utf8(value)
+                    .ok()
+                    .and_then(|value| value.parse::<usize>().ok())
+                    .filter(|kib| (1..=MAX_INIT_STACK_KIB).contains(kib))
+                    .map(|kib| options.init_stack = kib * 1024)
+                    .is_some(),
+                b"panic_reboot" => core::str::from_utf8(value)
+                    .ok()
+                    .and_then(|value| value.parse().ok())
+                    .map(|seconds| options.panic_reboot = Some(seconds))
+                    .is_some(),
+                b"qemu_exit" => parse_on_off(value)
+                    .map(|on| options.qemu_exit = on)
+                    .is_some(),
+                b"uart0" => LineConfig::parse(value)
+                    .map(|config| options.uart0 = config)
+                    .is_some(),
+                _ => true,
+            };
+            if !valid {
+                // Printed later, since there's no console yet
+                let _ = invalid.try_push(arg);
+            }
+        }
+
+        (options, invalid)
+    }
+}
+
+fn parse_on_off(value: &[u8]) -> Option<bool> {
+    match value {
+        b"on" => Some(true),
+        b"off" => Some(false),
+        _ => None,
+    }
+}
+
+/// Reads the command line from the device tree's `/chosen/bootargs`, or from the firmware
+fn read_cmdline() -> ArrayVec<u8, MAX_CMDLINE_LEN> {
+    let mut cmdline = ArrayVec::new();
+
+    match dtb::property(&["chosen"], "bootargs") {
+        Some(args) => {
+            let _ = cmdline.try_extend_from_slice(&args[..args.len().min(MAX_CMDLINE_LEN)]);
+        }
+        None => board::firmware_cmdline(&mut cmdline),
+    }
+
+    // Strip the NUL terminator(s)
+    while cmdline.last() == Some(&0) {
+        cmdline.pop();
+    }
+    cmdline
+}
+
+/// Parses the command line, then sets up the early console and log level accordingly
+pub fn init() {
+    let cmdline = CMDLINE.call_once(read_cmdline);
+    let (options, invalid) = BootOptions::parse(cmdline);
+    let options = OPTIONS.call_once(|| options);
+
+    console::set_log_level(options.log_level);
+    let early_console = early_console();
+    (early_console.init)();
+    console::set_main_console_by_name(early_console.driver_name);
+
+    println!("[INFO] Kernel command line: \"{}\"", AsciiStr(cmdline));
+    for arg in invalid {
+        println!("[WARN] Ignoring invalid boot option \"{}\"", AsciiStr(arg));
+    }
+}
+
+pub fn get() -> &'static BootOptions {
+    OPTIONS.call_once(BootOptions::default)
+}
+
+/// The console picked with `earlycon=`
+pub fn early_console() -> &'static EarlyConsole {
+    EARLY_CONSOLES
+        .iter()
+        .find(|console| console.name == get().earlycon)
+        .unwrap()
+}
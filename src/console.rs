@@ -3,14 +3,52 @@ use crate::fi;
 use crate::ipc;
 use crate::prelude::*;
 
-use crate::arch::aarch64::uart1::write_uart1;
+use crate::arch::aarch64::board;
 use alloc::string::ToString;
 use core::fmt;
 use core::fmt::Formatter;
 use core::mem::size_of;
+use core::sync::atomic::{AtomicU8, Ordering};
 use spin::RwLock;
 
 pub static MAIN_CONSOLE: RwLock<Option<&'static fi::FileInterface>> = RwLock::new(None);
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Debug as u8);
+
+/// Kernel messages are tagged by a prefix like `[INFO]`, lines without one are always printed
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+#[repr(u8)]
+pub enum LogLevel {
+    Error = 0,
+    Warn = 1,
+    Info = 2,
+    Debug = 3,
+}
+
+impl LogLevel {
+    pub fn from_name(name: &[u8]) -> Option<LogLevel> {
+        match name {
+            b"error" => Some(LogLevel::Error),
+            b"warn" => Some(LogLevel::Warn),
+            b"info" => Some(LogLevel::Info),
+            b"debug" => Some(LogLevel::Debug),
+            _ => None,
+        }
+    }
+
+    fn from_prefix(line: &str) -> Option<LogLevel> {
+        match line.get(..6)? {
+            "[EROR]" => Some(LogLevel::Error),
+            "[WARN]" => Some(LogLevel::Warn),
+            "[INFO]" => Some(LogLevel::Info),
+            "[DBUG]" => Some(LogLevel::Debug),
+            _ => None,
+        }
+    }
+}
+
+pub fn set_log_level(level: LogLevel) {
+    LOG_LEVEL.store(level as u8, Ordering::SeqCst);
+}
 
 pub fn set_main_console_by_name(name: &[u8]) {
     for driver in drivers() {
@@ -91,22 +129,54 @@ impl fmt::Write for FmtWriteAdapter<'_> {
 
 impl fmt::Write for FmtWriteAdapter2 {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        write_uart1(s.as_bytes())
-            .map(|_| ())
-            .map_err(|_| fmt::Error)
+        board::early_write(s.as_bytes());
+        Ok(())
     }
 }
 
-/// Prints the given formatted string to the UART.
+/// Drops the whole message if its first piece has a prefix above the current log level
+struct LogLevelFilter<W: fmt::Write> {
+    inner: W,
+    pass: Option<bool>,
+}
+
+impl<W: fmt::Write> fmt::Write for LogLevelFilter<W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let pass = *self
+            .pass
+            .get_or_insert_with(|| match LogLevel::from_prefix(s) {
+                Some(level) => level as u8 <= LOG_LEVEL.load(Ordering::Relaxed),
+                None => true,
+            });
+        if pass {
+            self.inner.write_str(s)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Prints the given formatted string to the main console, or to the UART if there's none yet.
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
 
-    let _ = FmtWriteAdapter2.write_fmt(args);
-    // if let Some(console) = MAIN_CONSOLE.read().as_deref() {
-    //     // Ignore return code
-    //     let _ = FmtWriteAdapter(console.sync_write.unwrap()).write_fmt(args);
-    // }
+    // Don't deadlock if we're printing while switching consoles
+    let main_console = MAIN_CONSOLE.try_read().and_then(|c| *c);
+    if let Some(sync_write) = main_console.and_then(|c| c.sync_write) {
+        // Ignore return code
+        let _ = LogLevelFilter {
+            inner: FmtWriteAdapter(sync_write),
+            pass: None,
+        }
+        .write_fmt(args);
+    } else {
+        let _ = LogLevelFilter {
+            inner: FmtWriteAdapter2,
+            pass: None,
+        }
+        .write_fmt(args);
+    }
 }
 
 struct FmtQueueWriteAdapter(ipc::IpcRef);
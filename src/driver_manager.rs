@@ -1,3 +1,4 @@
+use crate::arch::aarch64::board::{self, Board};
 use crate::fi;
 use crate::prelude::*;
 use core::fmt;
@@ -22,6 +23,17 @@ pub trait Driver {
 
     // FIXME: Once allocator works, change devices to be refcounted and remove 'static lifetime
     fn info(&'static self) -> &'static DriverInfo;
+
+    /// The board this driver's hardware is on, `None` if it works on any board
+    fn board(&self) -> Option<Board> {
+        None
+    }
+}
+
+fn for_this_board(driver: &dyn Driver) -> bool {
+    driver
+        .board()
+        .map_or(true, |driver_board| driver_board == board::BOARD)
 }
 
 impl Debug for &'static dyn Driver {
@@ -67,6 +79,9 @@ impl DriverInfo {
 pub enum DeviceType {
     Console,
     Framebuffer,
+    Entropy,
+    Gpio,
+    Storage,
 }
 
 pub struct Device {
@@ -80,18 +95,24 @@ impl Debug for DriverInfo {
     }
 }
 
-pub fn drivers() -> &'static [&'static dyn Driver] {
-    unsafe {
+/// The drivers for the board we're running on
+pub fn drivers() -> impl Iterator<Item = &'static &'static dyn Driver> {
+    let drivers: &'static [&'static dyn Driver] = unsafe {
         let start = &__drivers_start as *const u8 as *const &dyn Driver;
         let end = &__drivers_end as *const u8 as *const &dyn Driver;
         &*slice_from_raw_parts(start, end.offset_from(start) as usize)
-    }
+    };
+    drivers.iter().filter(|driver| for_this_board(**driver))
 }
 
-pub unsafe fn drivers_mut() -> &'static mut [&'static mut dyn Driver] {
+/// The drivers for the board we're running on
+pub unsafe fn drivers_mut() -> impl Iterator<Item = &'static mut &'static mut dyn Driver> {
     let start = &mut __drivers_start as *mut u8 as *mut &mut dyn Driver;
     let end = &mut __drivers_end as *mut u8 as *mut &mut dyn Driver;
-    &mut *slice_from_raw_parts_mut(start, end.offset_from(start) as usize)
+    let drivers = &mut *slice_from_raw_parts_mut(start, end.offset_from(start) as usize);
+    drivers
+        .iter_mut()
+        .filter(|driver| for_this_board(&***driver))
 }
 
 fn early_init_driver(driver: &'static dyn Driver) -> Result<(), ()> {
@@ -0,0 +1,171 @@
+//! ELF64 executables for usermode: checking the headers, and mapping the loadable segments
+
+use crate::arch::aarch64::mmu;
+use crate::arch::aarch64::phymem::PAGE_SIZE;
+use crate::prelude::*;
+use crate::process::AddressSpace;
+
+use alloc::collections::btree_map::{BTreeMap, Entry};
+use core::convert::TryInto;
+
+const MAGIC: &[u8] = b"\x7fELF";
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const TYPE_EXEC: u16 = 2;
+const MACHINE_AARCH64: u16 = 183;
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+const SEGMENT_LOAD: u32 = 1;
+const SEGMENT_EXECUTE: u32 = 1 << 0;
+const SEGMENT_WRITE: u32 = 1 << 1;
+
+/// ttbr0 covers the lower 512GiB
+pub const USER_END: usize = 0x80_0000_0000;
+
+const CACHE_LINE: usize = 64;
+
+fn u16_at(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(buf: &[u8], offset: usize) -> usize {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap()) as usize
+}
+
+#[derive(Debug)]
+pub struct Segment {
+    pub vaddr: usize,
+    /// The part past `file_size` is `.bss`, zeroed
+    pub mem_size: usize,
+    pub offset: usize,
+    pub file_size: usize,
+    pub writable: bool,
+    pub executable: bool,
+}
+
+impl Segment {
+    /// Whether it has anything in the pages of `start..end`
+    pub fn overlaps(&self, start: usize, end: usize) -> bool {
+        let first_page = self.vaddr & !(PAGE_SIZE - 1);
+        first_page < end && self.vaddr + self.mem_size > start
+    }
+}
+
+pub struct Executable<'a> {
+    image: &'a [u8],
+    pub entry: usize,
+    pub segments: Vec<Segment>,
+}
+
+impl<'a> Executable<'a> {
+    /// Only statically linked AArch64 executables, with every segment in usermode addresses
+    pub fn parse(image: &'a [u8]) -> Result<Self, ()> {
+        if image.len() < HEADER_SIZE
+            || &image[..4] != MAGIC
+            || image[4] != CLASS_64
+            || image[5] != DATA_LITTLE_ENDIAN
+            || u16_at(image, 16) != TYPE_EXEC
+            || u16_at(image, 18) != MACHINE_AARCH64
+            || u16_at(image, 54) as usize != PROGRAM_HEADER_SIZE
+        {
+            return Err(());
+        }
+        let entry = u64_at(image, 24);
+        let headers_start = u64_at(image, 32);
+        let headers_len = u16_at(image, 56) as usize * PROGRAM_HEADER_SIZE;
+        let headers = image
+            .get(headers_start..headers_start.checked_add(headers_len).ok_or(())?)
+            .ok_or(())?;
+
+        let mut segments = Vec::new();
+        for header in headers.chunks_exact(PROGRAM_HEADER_SIZE) {
+            if u32_at(header, 0) != SEGMENT_LOAD {
+                continue;
+            }
+            let flags = u32_at(header, 4);
+            let segment = Segment {
+                vaddr: u64_at(header, 16),
+                mem_size: u64_at(header, 40),
+                offset: u64_at(header, 8),
+                file_size: u64_at(header, 32),
+                writable: flags & SEGMENT_WRITE != 0,
+                executable: flags & SEGMENT_EXECUTE != 0,
+            };
+            let file_end = segment.offset.checked_add(segment.file_size);
+            let mem_end = segment.vaddr.checked_add(segment.mem_size);
+            if segment.file_size > segment.mem_size
+                || file_end.map_or(true, |end| end > image.len())
+                || mem_end.map_or(true, |end| end > USER_END)
+            {
+                return Err(());
+            }
+            if segment.mem_size > 0 {
+                segments.push(segment);
+            }
+        }
+
+        let entry_mapped = segments.iter().any(|segment| {
+            segment.executable && (segment.vaddr..segment.vaddr + segment.mem_size).contains(&entry)
+        });
+        if !entry_mapped {
+            return Err(());
+        }
+        Ok(Executable {
+            image,
+            entry,
+            segments,
+        })
+    }
+
+    /// Copies the segments into fresh pages of `address_space`. Pages shared by two segments get
+    /// the permissions of both
+    pub unsafe fn load(&self, address_space: &mut AddressSpace) -> Result<(), ()> {
+        // Page address -> (page, writable, executable)
+        let mut pages = BTreeMap::new();
+        for segment in &self.segments {
+            let first_page = segment.vaddr & !(PAGE_SIZE - 1);
+            let file_end = segment.vaddr + segment.file_size;
+            for page_vaddr in (first_page..segment.vaddr + segment.mem_size).step_by(PAGE_SIZE) {
+                let (page, writable, executable) = match pages.entry(page_vaddr) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => {
+                        // Zeroed, which covers `.bss`
+                        let page = address_space.alloc_page()?;
+                        entry.insert((page, false, false))
+                    }
+                };
+                *writable |= segment.writable;
+                *executable |= segment.executable;
+
+                let start = segment.vaddr.max(page_vaddr);
+                let end = file_end.min(page_vaddr + PAGE_SIZE);
+                if start < end {
+                    let offset = segment.offset + (start - segment.vaddr);
+                    page.virt_mut()[start - page_vaddr..end - page_vaddr]
+                        .copy_from_slice(&self.image[offset..offset + (end - start)]);
+                }
+            }
+        }
+
+        for (vaddr, (page, writable, executable)) in pages {
+            let mut flags = mmu::PT_USER | mmu::PT_ISH | mmu::PT_MEM;
+            flags |= if writable { mmu::PT_RW } else { mmu::PT_RO };
+            if executable {
+                // Instructions are fetched from memory, not from the data cache we wrote them to
+                for line in page.virt().chunks(CACHE_LINE) {
+                    asm!("dc cvau, {:x}", in(reg) line.as_ptr(), options(nostack));
+                }
+            } else {
+                flags |= mmu::PT_NX;
+            }
+            address_space.map(vaddr, page.base, flags)?;
+        }
+        asm!("dsb ish", "ic ialluis", "dsb ish", "isb", options(nostack));
+        Ok(())
+    }
+}
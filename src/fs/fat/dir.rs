@@ -0,0 +1,364 @@
+//! Directory entries: 8.3 short names, and the long names stored in the slots before them
+
+use crate::fs::fat::{u16_at, u32_at};
+use crate::prelude::*;
+
+use core::convert::TryInto;
+
+pub const SLOT_SIZE: usize = 32;
+/// Characters of a long name per slot
+const LFN_CHARS: usize = 13;
+/// Where those characters are in a long name slot
+const LFN_OFFSETS: [usize; LFN_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+const LFN_LAST: u8 = 0x40;
+const LFN_MAX_CHARS: usize = 255;
+
+pub const ATTR_READ_ONLY: u8 = 0x01;
+pub const ATTR_HIDDEN: u8 = 0x02;
+pub const ATTR_SYSTEM: u8 = 0x04;
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+pub const ATTR_LFN: u8 = ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_VOLUME_ID;
+
+/// The rest of the directory is unused
+const SLOT_END: u8 = 0x00;
+const SLOT_DELETED: u8 = 0xe5;
+/// Stands for a real 0xe5 as a short name's first character
+const SLOT_KANJI_E5: u8 = 0x05;
+
+/// Bits of the reserved byte Windows uses for all-lowercase 8.3 names
+const NTRES_LOWER_BASE: u8 = 0x08;
+const NTRES_LOWER_EXT: u8 = 0x10;
+
+/// 1980-01-01, we have no clock
+const DEFAULT_DATE: u16 = (1 << 5) | 1;
+
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    /// UTF-8, the long name if there's one
+    pub name: Vec<u8>,
+    short_name: [u8; 11],
+    pub attributes: u8,
+    pub first_cluster: u32,
+    pub size: u32,
+    /// The first cluster of the directory it's in
+    pub dir: u32,
+    /// Where the short name slot is, in slots from the start of the directory
+    pub slot: u32,
+    /// The first of its long name slots, or `slot`
+    first_slot: u32,
+}
+
+impl DirEntry {
+    pub fn is_dir(&self) -> bool {
+        self.attributes & ATTR_DIRECTORY != 0
+    }
+
+    /// `.` and `..`
+    pub fn is_dot(&self) -> bool {
+        self.short_name[0] == b'.'
+    }
+
+    pub fn short_name(&self) -> &[u8; 11] {
+        &self.short_name
+    }
+
+    /// The slots it takes, long name included
+    pub fn slots(&self) -> core::ops::RangeInclusive<u32> {
+        self.first_slot..=self.slot
+    }
+
+    /// Puts the cluster and size back into its short name slot
+    pub fn update_slot(&self, slot: &mut [u8]) {
+        slot[20..22].copy_from_slice(&((self.first_cluster >> 16) as u16).to_le_bytes());
+        slot[26..28].copy_from_slice(&(self.first_cluster as u16).to_le_bytes());
+        slot[28..32].copy_from_slice(&self.size.to_le_bytes());
+    }
+}
+
+pub fn is_free(slot: &[u8]) -> bool {
+    matches!(slot[0], SLOT_END | SLOT_DELETED)
+}
+
+pub fn mark_deleted(slot: &mut [u8]) {
+    slot[0] = SLOT_DELETED;
+}
+
+fn short_name_checksum(short_name: &[u8; 11]) -> u8 {
+    short_name
+        .iter()
+        .fold(0u8, |sum, c| sum.rotate_right(1).wrapping_add(*c))
+}
+
+/// `NAME.EXT` from the padded 8.3 form
+fn display_short_name(short_name: &[u8; 11], ntres: u8) -> Vec<u8> {
+    let mut base = short_name[..8].to_vec();
+    if base[0] == SLOT_KANJI_E5 {
+        base[0] = 0xe5;
+    }
+    while base.last() == Some(&b' ') {
+        base.pop();
+    }
+    if ntres & NTRES_LOWER_BASE != 0 {
+        base.make_ascii_lowercase();
+    }
+
+    let mut ext = short_name[8..].to_vec();
+    while ext.last() == Some(&b' ') {
+        ext.pop();
+    }
+    if ntres & NTRES_LOWER_EXT != 0 {
+        ext.make_ascii_lowercase();
+    }
+
+    if !ext.is_empty() {
+        base.push(b'.');
+        base.extend_from_slice(&ext);
+    }
+    base
+}
+
+struct LongName {
+    units: Vec<u16>,
+    checksum: u8,
+    first_slot: u32,
+    /// The order number the next slot must have, 0 once complete
+    next: u8,
+}
+
+/// The entries in the raw contents of the directory starting at cluster `dir`
+pub fn parse(dir: u32, raw: &[u8]) -> Vec<DirEntry> {
+    let mut entries = Vec::new();
+    let mut long_name: Option<LongName> = None;
+
+    for (slot, bytes) in raw.chunks_exact(SLOT_SIZE).enumerate() {
+        let slot = slot as u32;
+        match bytes[0] {
+            SLOT_END => break,
+            SLOT_DELETED => {
+                long_name = None;
+                continue;
+            }
+            _ => {}
+        }
+
+        let attributes = bytes[11];
+        if attributes & 0x3f == ATTR_LFN {
+            let order = bytes[0] & 0x1f;
+            if bytes[0] & LFN_LAST != 0 {
+                long_name = Some(LongName {
+                    units: vec![0xffff; order as usize * LFN_CHARS],
+                    checksum: bytes[13],
+                    first_slot: slot,
+                    next: order,
+                });
+            }
+            match &mut long_name {
+                Some(name) if order != 0 && name.next == order && name.checksum == bytes[13] => {
+                    let start = (order as usize - 1) * LFN_CHARS;
+                    for (i, offset) in LFN_OFFSETS.iter().enumerate() {
+                        name.units[start + i] = u16_at(bytes, *offset);
+                    }
+                    name.next -= 1;
+                }
+                _ => long_name = None,
+            }
+            continue;
+        }
+        if attributes & ATTR_VOLUME_ID != 0 {
+            long_name = None;
+            continue;
+        }
+
+        let short_name: [u8; 11] = bytes[..11].try_into().unwrap();
+        let (name, first_slot) = match long_name.take() {
+            Some(long) if long.next == 0 && long.checksum == short_name_checksum(&short_name) => {
+                let units = long.units.iter().copied().take_while(|c| *c != 0);
+                let mut name = Vec::new();
+                let mut buf = [0; 4];
+                for c in char::decode_utf16(units) {
+                    let c = c.unwrap_or(char::REPLACEMENT_CHARACTER);
+                    name.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                }
+                (name, long.first_slot)
+            }
+            _ => (display_short_name(&short_name, bytes[12]), slot),
+        };
+
+        entries.push(DirEntry {
+            name,
+            short_name,
+            attributes,
+            first_cluster: (u16_at(bytes, 20) as u32) << 16 | u16_at(bytes, 26) as u32,
+            size: u32_at(bytes, 28),
+            dir,
+            slot,
+            first_slot,
+        });
+    }
+    entries
+}
+
+// ----- New entries -----
+
+/// Names that can be stored and read back the same
+pub fn is_valid_name(name: &[u8]) -> bool {
+    let name = match core::str::from_utf8(name) {
+        Ok(name) => name,
+        Err(_) => return false,
+    };
+    // Trailing dots and spaces get lost on other systems, which covers `.` and `..` too
+    !name.is_empty()
+        && !name.ends_with('.')
+        && !name.ends_with(' ')
+        && name.encode_utf16().count() <= LFN_MAX_CHARS
+        && !name.chars().any(|c| c < ' ' || "\"*/:<>?\\|".contains(c))
+}
+
+fn is_short_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"$%'-_@~`!(){}^#&".contains(&c)
+}
+
+/// The 8.3 form of `name` and its reserved byte, if it has one without losing anything
+fn exact_short_name(name: &[u8]) -> Option<([u8; 11], u8)> {
+    let (base, ext) = match name.iter().rposition(|c| *c == b'.') {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, &b""[..]),
+    };
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 {
+        return None;
+    }
+    if !base.iter().chain(ext).all(|c| is_short_char(*c)) {
+        return None;
+    }
+
+    // Each part has to be in one case, which the reserved byte can tell
+    let mut ntres = 0;
+    for (part, lower_flag) in [(base, NTRES_LOWER_BASE), (ext, NTRES_LOWER_EXT)] {
+        let has_lower = part.iter().any(u8::is_ascii_lowercase);
+        let has_upper = part.iter().any(u8::is_ascii_uppercase);
+        match (has_lower, has_upper) {
+            (true, true) => return None,
+            (true, false) => ntres |= lower_flag,
+            _ => {}
+        }
+    }
+
+    let mut short_name = [b' '; 11];
+    short_name[..base.len()].copy_from_slice(base);
+    short_name[8..8 + ext.len()].copy_from_slice(ext);
+    short_name.make_ascii_uppercase();
+    Some((short_name, ntres))
+}
+
+/// A `BASE~N.EXT` name for `name` that isn't in `taken`
+fn generated_short_name(name: &[u8], taken: &[[u8; 11]]) -> Option<[u8; 11]> {
+    let clean = |part: &[u8]| -> Vec<u8> {
+        part.iter()
+            .filter(|c| **c != b' ' && **c != b'.')
+            .map(|c| {
+                if is_short_char(*c) {
+                    c.to_ascii_uppercase()
+                } else {
+                    b'_'
+                }
+            })
+            .collect()
+    };
+    let name = {
+        let start = name.iter().position(|c| *c != b'.').unwrap_or(name.len());
+        &name[start..]
+    };
+    let (base, ext) = match name.iter().rposition(|c| *c == b'.') {
+        Some(dot) => (clean(&name[..dot]), clean(&name[dot + 1..])),
+        None => (clean(name), Vec::new()),
+    };
+
+    for n in 1..1_000_000u32 {
+        let tail = alloc::format!("~{}", n);
+        let base_len = base.len().min(8 - tail.len());
+        let mut short_name = [b' '; 11];
+        short_name[..base_len].copy_from_slice(&base[..base_len]);
+        short_name[base_len..base_len + tail.len()].copy_from_slice(tail.as_bytes());
+        let ext_len = ext.len().min(3);
+        short_name[8..8 + ext_len].copy_from_slice(&ext[..ext_len]);
+        if !taken.contains(&short_name) {
+            return Some(short_name);
+        }
+    }
+    None
+}
+
+/// The slots for a new entry named `name`: its long name, if it needs one, then its short name.
+/// `name` must be valid
+pub fn new_slots(
+    name: &[u8],
+    attributes: u8,
+    first_cluster: u32,
+    taken: &[[u8; 11]],
+) -> Option<Vec<[u8; SLOT_SIZE]>> {
+    let (short_name, ntres, long_name) = match exact_short_name(name) {
+        Some((short_name, ntres)) if !taken.contains(&short_name) => (short_name, ntres, None),
+        _ => {
+            let units: Vec<u16> = core::str::from_utf8(name).ok()?.encode_utf16().collect();
+            (generated_short_name(name, taken)?, 0, Some(units))
+        }
+    };
+
+    let mut slots = Vec::new();
+    if let Some(units) = long_name {
+        let count = (units.len() + LFN_CHARS - 1) / LFN_CHARS;
+        let checksum = short_name_checksum(&short_name);
+        for order in (1..=count).rev() {
+            let mut slot = [0; SLOT_SIZE];
+            slot[0] = order as u8 | if order == count { LFN_LAST } else { 0 };
+            slot[11] = ATTR_LFN;
+            slot[13] = checksum;
+            for (i, offset) in LFN_OFFSETS.iter().enumerate() {
+                // Ends with a NUL, the rest is padding
+                let unit = match (order - 1) * LFN_CHARS + i {
+                    pos if pos < units.len() => units[pos],
+                    pos if pos == units.len() => 0,
+                    _ => 0xffff,
+                };
+                slot[*offset..*offset + 2].copy_from_slice(&unit.to_le_bytes());
+            }
+            slots.push(slot);
+        }
+    }
+
+    slots.push(short_slot(&short_name, attributes, ntres, first_cluster));
+    Some(slots)
+}
+
+/// The `.` or `..` slot of a new directory
+pub fn dot_slot(dots: &[u8], first_cluster: u32) -> [u8; SLOT_SIZE] {
+    let mut short_name = [b' '; 11];
+    short_name[..dots.len()].copy_from_slice(dots);
+    short_slot(&short_name, ATTR_DIRECTORY, 0, first_cluster)
+}
+
+fn short_slot(
+    short_name: &[u8; 11],
+    attributes: u8,
+    ntres: u8,
+    first_cluster: u32,
+) -> [u8; SLOT_SIZE] {
+    let mut slot = [0; SLOT_SIZE];
+    slot[..11].copy_from_slice(short_name);
+    slot[11] = attributes;
+    slot[12] = ntres;
+    // Creation, access and modification dates
+    for date in [16, 18, 24] {
+        slot[date..date + 2].copy_from_slice(&DEFAULT_DATE.to_le_bytes());
+    }
+    set_first_cluster(&mut slot, first_cluster);
+    slot
+}
+
+/// Points a short name slot at `first_cluster`
+pub fn set_first_cluster(slot: &mut [u8; SLOT_SIZE], first_cluster: u32) {
+    slot[20..22].copy_from_slice(&((first_cluster >> 16) as u16).to_le_bytes());
+    slot[26..28].copy_from_slice(&(first_cluster as u16).to_le_bytes());
+}
@@ -0,0 +1,147 @@
+//! FAT directories and files in the IPC tree, with the entry's slot in its directory as their id
+
+use crate::fs::fat::dir::DirEntry;
+use crate::fs::fat::FatVolume;
+use crate::ipc::{IpcNode, IpcRef};
+use crate::prelude::*;
+
+use futures::prelude::stream::BoxStream;
+use futures::{stream, StreamExt};
+
+pub struct IpcFatDir {
+    volume: Arc<FatVolume>,
+    cluster: u32,
+    name: Vec<u8>,
+}
+
+impl IpcFatDir {
+    pub fn new(volume: Arc<FatVolume>, cluster: u32, name: Vec<u8>) -> Arc<Self> {
+        Arc::new(IpcFatDir {
+            volume,
+            cluster,
+            name,
+        })
+    }
+}
+
+fn to_ipc_ref(volume: &Arc<FatVolume>, entry: DirEntry) -> IpcRef {
+    IpcRef {
+        id: entry.slot as u64,
+        inner: if entry.is_dir() {
+            IpcFatDir::new(volume.clone(), entry.first_cluster, entry.name)
+        } else {
+            Arc::new(IpcFatFile {
+                volume: volume.clone(),
+                entry,
+            })
+        },
+    }
+}
+
+#[async_trait]
+impl IpcNode for IpcFatDir {
+    fn dir_list<'a>(self: Arc<Self>) -> Option<BoxStream<'a, IpcRef>> {
+        let volume = self.volume.clone();
+        let entries = async move { self.volume.list(self.cluster).await.unwrap_or_default() };
+        Some(Box::pin(stream::once(entries).flat_map(move |entries| {
+            let volume = volume.clone();
+            stream::iter(
+                entries
+                    .into_iter()
+                    .map(move |entry| to_ipc_ref(&volume, entry)),
+            )
+        })))
+    }
+
+    async fn dir_get(self: Arc<Self>, id: u64) -> Option<IpcRef> {
+        let entry = self
+            .volume
+            .list(self.cluster)
+            .await
+            .ok()?
+            .into_iter()
+            .find(|entry| entry.slot as u64 == id)?;
+        Some(to_ipc_ref(&self.volume, entry))
+    }
+
+    async fn dir_create(self: Arc<Self>, _id: u64) -> Option<IpcRef> {
+        None
+    }
+
+    async fn dir_link(
+        self: Arc<Self>,
+        _id: u64,
+        _node: Arc<dyn IpcNode + Send + Sync>,
+    ) -> Option<IpcRef> {
+        None
+    }
+
+    fn queue_write(self: Arc<Self>, _data: &[u8]) -> Result<usize, ()> {
+        Err(())
+    }
+
+    async fn queue_read(self: Arc<Self>, _dest: &mut [u8]) -> Option<usize> {
+        None
+    }
+
+    async fn read_at(self: Arc<Self>, _offset: u64, _dest: &mut [u8]) -> Option<usize> {
+        None
+    }
+
+    fn describe(&self) -> [u8; 4] {
+        *b"DIR "
+    }
+
+    fn name(&self) -> Option<&[u8]> {
+        Some(&self.name)
+    }
+}
+
+/// Reads what's in the file when asked, the entry is looked up again each time
+pub struct IpcFatFile {
+    volume: Arc<FatVolume>,
+    entry: DirEntry,
+}
+
+#[async_trait]
+impl IpcNode for IpcFatFile {
+    fn dir_list<'a>(self: Arc<Self>) -> Option<BoxStream<'a, IpcRef>> {
+        None
+    }
+
+    async fn dir_get(self: Arc<Self>, _id: u64) -> Option<IpcRef> {
+        None
+    }
+
+    async fn dir_create(self: Arc<Self>, _id: u64) -> Option<IpcRef> {
+        None
+    }
+
+    async fn dir_link(
+        self: Arc<Self>,
+        _id: u64,
+        _node: Arc<dyn IpcNode + Send + Sync>,
+    ) -> Option<IpcRef> {
+        None
+    }
+
+    fn queue_write(self: Arc<Self>, _data: &[u8]) -> Result<usize, ()> {
+        Err(())
+    }
+
+    async fn queue_read(self: Arc<Self>, _dest: &mut [u8]) -> Option<usize> {
+        None
+    }
+
+    async fn read_at(self: Arc<Self>, offset: u64, dest: &mut [u8]) -> Option<usize> {
+        self.volume.read(&self.entry, offset, dest).await.ok()
+    }
+
+    fn describe(&self) -> [u8; 4] {
+        *b"FILE"
+    }
+
+    fn name(&self) -> Option<&[u8]> {
+        Some(&self.entry.name)
+    }
+}
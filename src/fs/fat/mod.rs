@@ -0,0 +1,662 @@
+//! FAT32 volumes: files and directories with long names, read and written in place
+
+pub(crate) mod dir;
+pub(crate) mod ipc;
+pub(crate) mod vfs;
+
+use crate::block::BlockDevice;
+use crate::ktask;
+use crate::prelude::*;
+
+use core::convert::TryInto;
+use dir::{DirEntry, ATTR_ARCHIVE, ATTR_DIRECTORY, SLOT_SIZE};
+use spin::Mutex;
+
+const FAT_ENTRY_MASK: u32 = 0x0fff_ffff;
+const FAT_FREE: u32 = 0;
+/// Chains end with any entry from here up
+const FAT_END_OF_CHAIN: u32 = 0x0fff_fff8;
+/// What we end chains with
+const FAT_END_MARK: u32 = 0x0fff_ffff;
+/// Clusters 0 and 1 only exist in the FAT
+const FIRST_CLUSTER: u32 = 2;
+
+const FS_INFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FS_INFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+const FS_INFO_FREE_COUNT: usize = 488;
+const FS_INFO_UNKNOWN: u32 = 0xffff_ffff;
+
+pub(crate) fn u16_at(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+pub(crate) fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+/// Held for the whole of every operation, they span many awaits
+struct State {
+    /// Where to start looking for a free cluster
+    next_free: u32,
+    /// FSInfo's free cluster count was marked unknown
+    fs_info_stale: bool,
+}
+
+pub struct FatVolume {
+    device: Arc<dyn BlockDevice>,
+    label: Vec<u8>,
+    sector_size: usize,
+    sectors_per_cluster: u64,
+    fat_start: u64,
+    fat_sectors: u64,
+    fat_count: u64,
+    data_start: u64,
+    cluster_count: u32,
+    root_cluster: u32,
+    /// 0 if there's none
+    fs_info_sector: u64,
+    state: Mutex<State>,
+}
+
+impl FatVolume {
+    /// Fails if `device` doesn't start with a FAT32 boot sector
+    pub async fn mount(device: Arc<dyn BlockDevice>) -> IoResult<Arc<Self>> {
+        let sector_size = device.sector_size();
+        if sector_size < 512 {
+            return Err(());
+        }
+        let mut boot = vec![0; sector_size];
+        device.read(0, &mut boot).await?;
+        if boot[510..512] != [0x55, 0xaa] {
+            return Err(());
+        }
+
+        let sectors_per_cluster = boot[13] as u64;
+        let reserved_sectors = u16_at(&boot, 14) as u64;
+        let fat_count = boot[16] as u64;
+        let total_sectors = match u16_at(&boot, 19) {
+            0 => u32_at(&boot, 32) as u64,
+            count => count as u64,
+        };
+        let fat_sectors = u32_at(&boot, 36) as u64;
+        // FAT12 and FAT16 have a fixed size root directory and 16-bit FAT sizes instead
+        if u16_at(&boot, 11) as usize != sector_size
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved_sectors == 0
+            || fat_count == 0
+            || u16_at(&boot, 17) != 0
+            || u16_at(&boot, 22) != 0
+            || fat_sectors == 0
+            || total_sectors > device.sector_count()
+        {
+            return Err(());
+        }
+
+        let data_start = reserved_sectors + fat_count * fat_sectors;
+        if data_start >= total_sectors {
+            return Err(());
+        }
+        let cluster_count = ((total_sectors - data_start) / sectors_per_cluster)
+            .min(fat_sectors * sector_size as u64 / 4 - FIRST_CLUSTER as u64)
+            as u32;
+
+        let mut label = boot[71..82].to_vec();
+        while label.last() == Some(&b' ') {
+            label.pop();
+        }
+
+        let volume = FatVolume {
+            device,
+            label,
+            sector_size,
+            sectors_per_cluster,
+            fat_start: reserved_sectors,
+            fat_sectors,
+            fat_count,
+            data_start,
+            cluster_count,
+            root_cluster: u32_at(&boot, 44),
+            fs_info_sector: match u16_at(&boot, 48) {
+                0xffff => 0,
+                sector => sector as u64,
+            },
+            state: Mutex::new(State {
+                next_free: FIRST_CLUSTER,
+                fs_info_stale: false,
+            }),
+        };
+        if !volume.is_valid_cluster(volume.root_cluster) {
+            return Err(());
+        }
+        Ok(Arc::new(volume))
+    }
+
+    pub fn label(&self) -> &[u8] {
+        &self.label
+    }
+
+    /// The root directory's first cluster
+    pub fn root(&self) -> u32 {
+        self.root_cluster
+    }
+
+    fn cluster_size(&self) -> usize {
+        self.sector_size * self.sectors_per_cluster as usize
+    }
+
+    fn is_valid_cluster(&self, cluster: u32) -> bool {
+        (FIRST_CLUSTER..FIRST_CLUSTER + self.cluster_count).contains(&cluster)
+    }
+
+    fn cluster_sector(&self, cluster: u32) -> u64 {
+        self.data_start + (cluster - FIRST_CLUSTER) as u64 * self.sectors_per_cluster
+    }
+
+    // ----- FAT -----
+
+    /// The sector of `cluster`'s entry, counted from the start of a FAT, and its offset in it
+    fn fat_position(&self, cluster: u32) -> (u64, usize) {
+        let offset = cluster as u64 * 4;
+        (
+            offset / self.sector_size as u64,
+            (offset % self.sector_size as u64) as usize,
+        )
+    }
+
+    async fn fat_get(&self, cluster: u32) -> IoResult<u32> {
+        let (sector, offset) = self.fat_position(cluster);
+        let mut buf = vec![0; self.sector_size];
+        self.device.read(self.fat_start + sector, &mut buf).await?;
+        Ok(u32_at(&buf, offset) & FAT_ENTRY_MASK)
+    }
+
+    /// Sets `cluster`'s entry in every FAT
+    async fn fat_set(&self, cluster: u32, value: u32) -> IoResult<()> {
+        let (sector, offset) = self.fat_position(cluster);
+        let mut buf = vec![0; self.sector_size];
+        for fat in 0..self.fat_count {
+            let sector = self.fat_start + fat * self.fat_sectors + sector;
+            self.device.read(sector, &mut buf).await?;
+            // The top 4 bits are reserved
+            let entry = u32_at(&buf, offset) & !FAT_ENTRY_MASK | value & FAT_ENTRY_MASK;
+            buf[offset..offset + 4].copy_from_slice(&entry.to_le_bytes());
+            self.device.write(sector, &buf).await?;
+        }
+        Ok(())
+    }
+
+    /// The clusters of the chain starting at `first`, none if it's 0
+    async fn chain(&self, first: u32) -> IoResult<Vec<u32>> {
+        let mut chain = Vec::new();
+        let mut cluster = first;
+        while self.is_valid_cluster(cluster) {
+            if chain.len() >= self.cluster_count as usize {
+                println!("[WARN] FAT: Cluster chain from {} loops", first);
+                return Err(());
+            }
+            chain.push(cluster);
+            cluster = self.fat_get(cluster).await?;
+        }
+        if (chain.is_empty() && cluster == FAT_FREE) || cluster >= FAT_END_OF_CHAIN {
+            Ok(chain)
+        } else {
+            println!(
+                "[WARN] FAT: Cluster chain from {} is broken at {}",
+                first, cluster
+            );
+            Err(())
+        }
+    }
+
+    /// Other systems would trust FSInfo's free cluster count, it's marked unknown before the first
+    /// change instead of being kept up to date
+    async fn mark_fs_info_stale(&self, state: &mut State) -> IoResult<()> {
+        if state.fs_info_stale || self.fs_info_sector == 0 {
+            return Ok(());
+        }
+        let mut buf = vec![0; self.sector_size];
+        self.device.read(self.fs_info_sector, &mut buf).await?;
+        if u32_at(&buf, 0) == FS_INFO_LEAD_SIGNATURE
+            && u32_at(&buf, 484) == FS_INFO_STRUCT_SIGNATURE
+        {
+            buf[FS_INFO_FREE_COUNT..FS_INFO_FREE_COUNT + 4]
+                .copy_from_slice(&FS_INFO_UNKNOWN.to_le_bytes());
+            self.device.write(self.fs_info_sector, &buf).await?;
+        }
+        state.fs_info_stale = true;
+        Ok(())
+    }
+
+    /// Takes a free cluster and links it after `last`, or starts a new chain with it
+    async fn alloc_cluster(&self, state: &mut State, last: Option<u32>) -> IoResult<u32> {
+        self.mark_fs_info_stale(state).await?;
+
+        let per_sector = (self.sector_size / 4) as u32;
+        let end = FIRST_CLUSTER + self.cluster_count;
+        let mut buf = vec![0; self.sector_size];
+        let mut cluster = if self.is_valid_cluster(state.next_free) {
+            state.next_free
+        } else {
+            FIRST_CLUSTER
+        };
+        let mut checked = 0;
+        while checked < self.cluster_count {
+            // Whole FAT sectors at a time
+            let (sector, _) = self.fat_position(cluster);
+            self.device.read(self.fat_start + sector, &mut buf).await?;
+            let sector_end = ((cluster / per_sector + 1) * per_sector).min(end);
+            for candidate in cluster..sector_end {
+                let offset = (candidate % per_sector * 4) as usize;
+                if u32_at(&buf, offset) & FAT_ENTRY_MASK == FAT_FREE {
+                    self.fat_set(candidate, FAT_END_MARK).await?;
+                    if let Some(last) = last {
+                        self.fat_set(last, candidate).await?;
+                    }
+                    state.next_free = candidate + 1;
+                    return Ok(candidate);
+                }
+            }
+            checked += sector_end - cluster;
+            cluster = if sector_end == end {
+                FIRST_CLUSTER
+            } else {
+                sector_end
+            };
+        }
+
+        println!("[WARN] FAT: \"{}\" is full", AsciiStr(&self.label));
+        Err(())
+    }
+
+    async fn free_clusters(&self, state: &mut State, clusters: &[u32]) -> IoResult<()> {
+        if clusters.is_empty() {
+            return Ok(());
+        }
+        self.mark_fs_info_stale(state).await?;
+        for cluster in clusters {
+            self.fat_set(*cluster, FAT_FREE).await?;
+        }
+        Ok(())
+    }
+
+    // ----- Clusters -----
+
+    /// Reads `buf` from `offset` in `cluster`, whole sectors at a time
+    async fn read_in_cluster(&self, cluster: u32, offset: usize, buf: &mut [u8]) -> IoResult<()> {
+        let first = offset / self.sector_size;
+        let last = (offset + buf.len() - 1) / self.sector_size;
+        let sector = self.cluster_sector(cluster) + first as u64;
+        if offset % self.sector_size == 0 && buf.len() % self.sector_size == 0 {
+            return self.device.read(sector, buf).await;
+        }
+
+        let mut sectors = vec![0; (last - first + 1) * self.sector_size];
+        self.device.read(sector, &mut sectors).await?;
+        let start = offset - first * self.sector_size;
+        buf.copy_from_slice(&sectors[start..start + buf.len()]);
+        Ok(())
+    }
+
+    /// Writes `data` at `offset` in `cluster`, keeping the rest of the sectors it touches
+    async fn write_in_cluster(&self, cluster: u32, offset: usize, data: &[u8]) -> IoResult<()> {
+        let first = offset / self.sector_size;
+        let last = (offset + data.len() - 1) / self.sector_size;
+        let sector = self.cluster_sector(cluster) + first as u64;
+        if offset % self.sector_size == 0 && data.len() % self.sector_size == 0 {
+            return self.device.write(sector, data).await;
+        }
+
+        let mut sectors = vec![0; (last - first + 1) * self.sector_size];
+        self.device.read(sector, &mut sectors).await?;
+        let start = offset - first * self.sector_size;
+        sectors[start..start + data.len()].copy_from_slice(data);
+        self.device.write(sector, &sectors).await
+    }
+
+    // ----- Directories -----
+
+    /// The clusters of directory `dir`, and what's in them
+    async fn read_dir(&self, dir: u32) -> IoResult<(Vec<u32>, Vec<u8>)> {
+        let chain = self.chain(dir).await?;
+        let cluster_size = self.cluster_size();
+        let mut raw = vec![0; chain.len() * cluster_size];
+        for (cluster, buf) in chain.iter().zip(raw.chunks_exact_mut(cluster_size)) {
+            self.device.read(self.cluster_sector(*cluster), buf).await?;
+        }
+        Ok((chain, raw))
+    }
+
+    async fn entries(&self, dir: u32) -> IoResult<Vec<DirEntry>> {
+        let (_, raw) = self.read_dir(dir).await?;
+        let mut entries = dir::parse(dir, &raw);
+        for entry in &mut entries {
+            // `..` in the root's children says 0
+            if entry.is_dir() && entry.first_cluster == 0 {
+                entry.first_cluster = self.root_cluster;
+            }
+        }
+        Ok(entries)
+    }
+
+    /// The cluster with `slot` of the directory made of `chain`, and the slot's offset in it
+    fn slot_position(&self, chain: &[u32], slot: u32) -> IoResult<(u32, usize)> {
+        let offset = slot as usize * SLOT_SIZE;
+        let cluster = *chain.get(offset / self.cluster_size()).ok_or(())?;
+        Ok((cluster, offset % self.cluster_size()))
+    }
+
+    /// Applies `change` to each of `slots` in directory `dir`
+    async fn change_slots(
+        &self,
+        dir: u32,
+        slots: impl Iterator<Item = u32>,
+        change: impl Fn(&mut [u8]),
+    ) -> IoResult<()> {
+        let chain = self.chain(dir).await?;
+        let mut bytes = [0; SLOT_SIZE];
+        for slot in slots {
+            let (cluster, offset) = self.slot_position(&chain, slot)?;
+            self.read_in_cluster(cluster, offset, &mut bytes).await?;
+            change(&mut bytes);
+            self.write_in_cluster(cluster, offset, &bytes).await?;
+        }
+        Ok(())
+    }
+
+    /// Writes `entry`'s cluster and size back
+    async fn write_entry(&self, entry: &DirEntry) -> IoResult<()> {
+        self.change_slots(entry.dir, core::iter::once(entry.slot), |slot| {
+            entry.update_slot(slot)
+        })
+        .await
+    }
+
+    /// `entry` as it is now, if it's still there
+    async fn refresh(&self, entry: &DirEntry) -> IoResult<DirEntry> {
+        self.entries(entry.dir)
+            .await?
+            .into_iter()
+            .find(|e| e.slot == entry.slot && e.short_name() == entry.short_name())
+            .ok_or(())
+    }
+
+    /// `entry` as it is now, if it's still there
+    pub async fn reload(&self, entry: &DirEntry) -> IoResult<DirEntry> {
+        let _state = ktask::lock(&self.state).await;
+        self.refresh(entry).await
+    }
+
+    /// What's in directory `dir`, without `.` and `..`
+    pub async fn list(&self, dir: u32) -> IoResult<Vec<DirEntry>> {
+        let _state = ktask::lock(&self.state).await;
+        Ok(self
+            .entries(dir)
+            .await?
+            .into_iter()
+            .filter(|entry| !entry.is_dot())
+            .collect())
+    }
+
+    /// Looks `name` up in directory `dir`, ignoring ASCII case like everyone else
+    pub async fn find(&self, dir: u32, name: &[u8]) -> IoResult<Option<DirEntry>> {
+        let _state = ktask::lock(&self.state).await;
+        Ok(self
+            .entries(dir)
+            .await?
+            .into_iter()
+            .find(|entry| entry.name.eq_ignore_ascii_case(name)))
+    }
+
+    /// Creates an empty file or directory called `name` in directory `dir`
+    pub async fn create(&self, dir: u32, name: &[u8], directory: bool) -> IoResult<DirEntry> {
+        let mut state = ktask::lock(&self.state).await;
+        if !dir::is_valid_name(name) {
+            return Err(());
+        }
+        let (mut chain, raw) = self.read_dir(dir).await?;
+        let entries = dir::parse(dir, &raw);
+        if entries
+            .iter()
+            .any(|entry| entry.name.eq_ignore_ascii_case(name))
+        {
+            return Err(());
+        }
+        let taken: Vec<[u8; 11]> = entries.iter().map(|entry| *entry.short_name()).collect();
+
+        let attributes = if directory {
+            ATTR_DIRECTORY
+        } else {
+            ATTR_ARCHIVE
+        };
+        // Built before allocating anything, a directory's cluster is filled in once it has one
+        let mut slots = dir::new_slots(name, attributes, 0, &taken).ok_or(())?;
+
+        // The first run of free slots long enough, the free slots at the end carry on into new
+        // clusters
+        let mut run_start = 0;
+        let mut run = 0;
+        for (slot, bytes) in raw.chunks_exact(SLOT_SIZE).enumerate() {
+            if !dir::is_free(bytes) {
+                run = 0;
+                continue;
+            }
+            if run == 0 {
+                run_start = slot;
+            }
+            run += 1;
+            if run == slots.len() {
+                break;
+            }
+        }
+        if run == 0 {
+            run_start = raw.len() / SLOT_SIZE;
+        }
+        let slots_per_cluster = self.cluster_size() / SLOT_SIZE;
+        let old_len = chain.len();
+        let mut allocated = Vec::new();
+        let written: IoResult<()> = async {
+            // Directories start with `.` and `..`, `..` says 0 for the root
+            if directory {
+                let cluster = self.alloc_cluster(&mut state, None).await?;
+                allocated.push(cluster);
+                let mut contents = vec![0; self.cluster_size()];
+                contents[..SLOT_SIZE].copy_from_slice(&dir::dot_slot(b".", cluster));
+                let parent = if dir == self.root_cluster { 0 } else { dir };
+                contents[SLOT_SIZE..2 * SLOT_SIZE].copy_from_slice(&dir::dot_slot(b"..", parent));
+                self.device
+                    .write(self.cluster_sector(cluster), &contents)
+                    .await?;
+                dir::set_first_cluster(slots.last_mut().unwrap(), cluster);
+            }
+
+            while chain.len() * slots_per_cluster < run_start + slots.len() {
+                let cluster = self
+                    .alloc_cluster(&mut state, chain.last().copied())
+                    .await?;
+                allocated.push(cluster);
+                chain.push(cluster);
+                self.device
+                    .write(self.cluster_sector(cluster), &vec![0; self.cluster_size()])
+                    .await?;
+            }
+
+            for (slot, bytes) in (run_start as u32..).zip(&slots) {
+                let (cluster, offset) = self.slot_position(&chain, slot)?;
+                self.write_in_cluster(cluster, offset, bytes).await?;
+            }
+            Ok(())
+        }
+        .await;
+        if written.is_err() {
+            // Nothing points at what was allocated once the directory's chain ends where it did
+            if chain.len() > old_len {
+                self.fat_set(chain[old_len - 1], FAT_END_MARK).await?;
+            }
+            self.free_clusters(&mut state, &allocated).await?;
+            self.device.flush().await?;
+            return Err(());
+        }
+        self.device.flush().await?;
+
+        let slot = (run_start + slots.len() - 1) as u32;
+        self.entries(dir)
+            .await?
+            .into_iter()
+            .find(|entry| entry.slot == slot)
+            .ok_or(())
+    }
+
+    /// Deletes a file, or a directory if it's empty
+    pub async fn remove(&self, entry: &DirEntry) -> IoResult<()> {
+        let mut state = ktask::lock(&self.state).await;
+        let entry = self.refresh(entry).await?;
+        if entry.is_dot() {
+            return Err(());
+        }
+        if entry.is_dir()
+            && self
+                .entries(entry.first_cluster)
+                .await?
+                .iter()
+                .any(|child| !child.is_dot())
+        {
+            return Err(());
+        }
+
+        self.change_slots(entry.dir, entry.slots(), dir::mark_deleted)
+            .await?;
+        let chain = self.chain(entry.first_cluster).await?;
+        self.free_clusters(&mut state, &chain).await?;
+        self.device.flush().await
+    }
+
+    // ----- Files -----
+
+    /// Reads from `offset` in a file, returns how much there was until its end
+    pub async fn read(&self, entry: &DirEntry, offset: u64, buf: &mut [u8]) -> IoResult<usize> {
+        let _state = ktask::lock(&self.state).await;
+        let entry = self.refresh(entry).await?;
+        if entry.is_dir() {
+            return Err(());
+        }
+        let size = entry.size as u64;
+        if offset >= size || buf.is_empty() {
+            return Ok(0);
+        }
+        let len = buf.len().min((size - offset) as usize);
+
+        let chain = self.chain(entry.first_cluster).await?;
+        let cluster_size = self.cluster_size();
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let cluster = *chain
+                .get((position / cluster_size as u64) as usize)
+                .ok_or(())?;
+            let within = (position % cluster_size as u64) as usize;
+            let count = (len - done).min(cluster_size - within);
+            self.read_in_cluster(cluster, within, &mut buf[done..done + count])
+                .await?;
+            done += count;
+        }
+        Ok(len)
+    }
+
+    /// Writes `data` from `offset`, which can be up to the file's end. Updates `entry`
+    pub async fn write(&self, entry: &mut DirEntry, offset: u64, data: &[u8]) -> IoResult<()> {
+        let mut state = ktask::lock(&self.state).await;
+        let mut current = self.refresh(entry).await?;
+        self.write_locked(&mut state, &mut current, offset, data)
+            .await?;
+        *entry = current;
+        Ok(())
+    }
+
+    /// Writes `data` at the end of a file. Updates `entry`
+    pub async fn append(&self, entry: &mut DirEntry, data: &[u8]) -> IoResult<()> {
+        let mut state = ktask::lock(&self.state).await;
+        let mut current = self.refresh(entry).await?;
+        let end = current.size as u64;
+        self.write_locked(&mut state, &mut current, end, data)
+            .await?;
+        *entry = current;
+        Ok(())
+    }
+
+    /// Cuts a file down to `size` bytes, or pads it with zeros up to it. Updates `entry`
+    pub async fn truncate(&self, entry: &mut DirEntry, size: u32) -> IoResult<()> {
+        let mut state = ktask::lock(&self.state).await;
+        let mut current = self.refresh(entry).await?;
+        if current.is_dir() {
+            return Err(());
+        }
+
+        if size > current.size {
+            let zeros = vec![0; (size - current.size) as usize];
+            let end = current.size as u64;
+            self.write_locked(&mut state, &mut current, end, &zeros)
+                .await?;
+        } else {
+            let chain = self.chain(current.first_cluster).await?;
+            let cluster_size = self.cluster_size() as u64;
+            let keep = ((size as u64 + cluster_size - 1) / cluster_size) as usize;
+            if keep == 0 {
+                current.first_cluster = 0;
+            } else if keep < chain.len() {
+                self.fat_set(chain[keep - 1], FAT_END_MARK).await?;
+            }
+            current.size = size;
+            self.write_entry(&current).await?;
+            self.free_clusters(&mut state, &chain[keep.min(chain.len())..])
+                .await?;
+            self.device.flush().await?;
+        }
+        *entry = current;
+        Ok(())
+    }
+
+    async fn write_locked(
+        &self,
+        state: &mut State,
+        entry: &mut DirEntry,
+        offset: u64,
+        data: &[u8],
+    ) -> IoResult<()> {
+        if entry.is_dir() || offset > entry.size as u64 {
+            return Err(());
+        }
+        let end = offset
+            .checked_add(data.len() as u64)
+            .filter(|end| *end <= u32::MAX as u64)
+            .ok_or(())?;
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        let cluster_size = self.cluster_size();
+        let mut chain = self.chain(entry.first_cluster).await?;
+        let needed = ((end + cluster_size as u64 - 1) / cluster_size as u64) as usize;
+        while chain.len() < needed {
+            let cluster = self.alloc_cluster(state, chain.last().copied()).await?;
+            if chain.is_empty() {
+                entry.first_cluster = cluster;
+            }
+            chain.push(cluster);
+        }
+
+        let mut done = 0;
+        while done < data.len() {
+            let position = offset + done as u64;
+            let cluster = chain[(position / cluster_size as u64) as usize];
+            let within = (position % cluster_size as u64) as usize;
+            let count = (data.len() - done).min(cluster_size - within);
+            self.write_in_cluster(cluster, within, &data[done..done + count])
+                .await?;
+            done += count;
+        }
+
+        entry.size = entry.size.max(end as u32);
+        self.write_entry(entry).await?;
+        self.device.flush().await
+    }
+}
@@ -0,0 +1,156 @@
+//! FAT directories and files as VFS nodes
+
+use crate::fs::fat::dir::DirEntry;
+use crate::fs::fat::FatVolume;
+use crate::prelude::*;
+use crate::vfs::{self, Node, NodeKind, Stat};
+
+use core::convert::TryFrom;
+
+pub struct FatDirNode {
+    volume: Arc<FatVolume>,
+    cluster: u32,
+}
+
+impl FatDirNode {
+    pub fn new(volume: Arc<FatVolume>, cluster: u32) -> Arc<Self> {
+        Arc::new(FatDirNode { volume, cluster })
+    }
+}
+
+fn to_node(volume: &Arc<FatVolume>, entry: DirEntry) -> Arc<dyn Node> {
+    if entry.is_dir() {
+        FatDirNode::new(volume.clone(), entry.first_cluster)
+    } else {
+        Arc::new(FatFileNode {
+            volume: volume.clone(),
+            entry: spin::Mutex::new(entry),
+        })
+    }
+}
+
+fn entry_kind(entry: &DirEntry) -> NodeKind {
+    if entry.is_dir() {
+        NodeKind::Dir
+    } else {
+        NodeKind::File
+    }
+}
+
+#[async_trait]
+impl Node for FatDirNode {
+    fn kind(&self) -> NodeKind {
+        NodeKind::Dir
+    }
+
+    async fn stat(&self) -> IoResult<Stat> {
+        Ok(Stat {
+            kind: NodeKind::Dir,
+            size: 0,
+        })
+    }
+
+    async fn lookup(&self, name: &[u8]) -> IoResult<Option<Arc<dyn Node>>> {
+        Ok(self
+            .volume
+            .find(self.cluster, name)
+            .await?
+            .map(|entry| to_node(&self.volume, entry)))
+    }
+
+    async fn readdir(&self) -> IoResult<Vec<vfs::DirEntry>> {
+        Ok(self
+            .volume
+            .list(self.cluster)
+            .await?
+            .into_iter()
+            .map(|entry| vfs::DirEntry {
+                kind: entry_kind(&entry),
+                name: entry.name,
+            })
+            .collect())
+    }
+
+    async fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> IoResult<usize> {
+        Err(())
+    }
+
+    async fn write_at(&self, _offset: u64, _data: &[u8]) -> IoResult<usize> {
+        Err(())
+    }
+
+    async fn create(&self, name: &[u8], kind: NodeKind) -> IoResult<Arc<dyn Node>> {
+        let entry = match kind {
+            NodeKind::File => self.volume.create(self.cluster, name, false).await?,
+            NodeKind::Dir => self.volume.create(self.cluster, name, true).await?,
+            NodeKind::Device => return Err(()),
+        };
+        Ok(to_node(&self.volume, entry))
+    }
+
+    async fn remove(&self, name: &[u8]) -> IoResult<()> {
+        let entry = self.volume.find(self.cluster, name).await?.ok_or(())?;
+        self.volume.remove(&entry).await
+    }
+}
+
+/// Keeps the entry it was opened with, the volume finds it again for every operation
+pub struct FatFileNode {
+    volume: Arc<FatVolume>,
+    entry: spin::Mutex<DirEntry>,
+}
+
+impl FatFileNode {
+    fn entry(&self) -> DirEntry {
+        self.entry.lock().clone()
+    }
+}
+
+#[async_trait]
+impl Node for FatFileNode {
+    fn kind(&self) -> NodeKind {
+        NodeKind::File
+    }
+
+    async fn stat(&self) -> IoResult<Stat> {
+        let entry = self.volume.reload(&self.entry()).await?;
+        Ok(Stat {
+            kind: NodeKind::File,
+            size: entry.size as u64,
+        })
+    }
+
+    async fn lookup(&self, _name: &[u8]) -> IoResult<Option<Arc<dyn Node>>> {
+        Err(())
+    }
+
+    async fn readdir(&self) -> IoResult<Vec<vfs::DirEntry>> {
+        Err(())
+    }
+
+    async fn read_at(&self, offset: u64, buf: &mut [u8]) -> IoResult<usize> {
+        self.volume.read(&self.entry(), offset, buf).await
+    }
+
+    /// Writing past the end fills the gap with zeros
+    async fn write_at(&self, offset: u64, data: &[u8]) -> IoResult<usize> {
+        let mut entry = self.volume.reload(&self.entry()).await?;
+        if offset > entry.size as u64 {
+            self.volume
+                .truncate(&mut entry, u32::try_from(offset).map_err(|_| ())?)
+                .await?;
+        }
+        self.volume.write(&mut entry, offset, data).await?;
+        *self.entry.lock() = entry;
+        Ok(data.len())
+    }
+
+    async fn truncate(&self, size: u64) -> IoResult<()> {
+        let mut entry = self.entry();
+        self.volume
+            .truncate(&mut entry, u32::try_from(size).map_err(|_| ())?)
+            .await?;
+        *self.entry.lock() = entry;
+        Ok(())
+    }
+}
@@ -0,0 +1,55 @@
+//! Filesystems on block devices, mounted at `/fs/<device>` in the VFS and the IPC tree
+
+pub(crate) mod fat;
+pub(crate) mod tmpfs;
+
+use crate::block::BlockDevice;
+use crate::prelude::*;
+use crate::{ipc, vfs};
+
+use fat::ipc::IpcFatDir;
+use fat::vfs::FatDirNode;
+use fat::FatVolume;
+use spin::RwLock;
+
+/// Every mounted volume. Their id in the IPC tree is their index + 1
+static VOLUMES: RwLock<Vec<Arc<FatVolume>>> = RwLock::new(Vec::new());
+
+pub fn volumes() -> Vec<Arc<FatVolume>> {
+    VOLUMES.read().clone()
+}
+
+/// Mounts `device` if it holds a filesystem we know, and does nothing otherwise
+pub async fn probe(device: Arc<dyn BlockDevice>) {
+    let volume = match FatVolume::mount(device.clone()).await {
+        Ok(volume) => volume,
+        Err(()) => return,
+    };
+    println!(
+        "[INFO] Mounted FAT32 volume \"{}\" from \"{}\"",
+        AsciiStr(volume.label()),
+        AsciiStr(device.name())
+    );
+    let id = {
+        let mut volumes = VOLUMES.write();
+        volumes.push(volume.clone());
+        volumes.len() as u64
+    };
+
+    let root = ipc::ROOT.read().as_ref().unwrap().clone();
+    root.dir_get(ipc::well_known::ROOT_FS)
+        .await
+        .unwrap()
+        .dir_link(
+            id,
+            IpcFatDir::new(volume.clone(), volume.root(), device.name().to_vec()),
+        )
+        .await
+        .unwrap();
+
+    let mut path = b"/fs/".to_vec();
+    path.extend_from_slice(device.name());
+    if vfs::mount(&path, FatDirNode::new(volume.clone(), volume.root())).is_err() {
+        println!("[WARN] \"{}\" is already mounted", AsciiStr(&path));
+    }
+}
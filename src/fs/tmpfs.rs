@@ -0,0 +1,234 @@
+//! Files and directories kept on the kernel heap, gone at reboot
+
+use crate::arch::aarch64::virtmem;
+use crate::prelude::*;
+use crate::vfs::{self, Node, NodeKind, Stat};
+
+use core::convert::TryFrom;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::RwLock;
+
+/// Part of the kernel heap each tmpfs can fill with file contents, by default
+const HEAP_SHARE: usize = 4;
+
+/// File contents of one tmpfs, counted by capacity against its quota
+struct Usage {
+    used: AtomicUsize,
+    quota: usize,
+}
+
+impl Usage {
+    /// Fails if `bytes` more would go over the quota, or leave the heap without room for them
+    fn charge(&self, bytes: usize) -> IoResult<()> {
+        if bytes > virtmem::get_free() {
+            return Err(());
+        }
+        self.used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                used.checked_add(bytes).filter(|used| *used <= self.quota)
+            })
+            .map(|_| ())
+            .map_err(|_| ())
+    }
+
+    fn release(&self, bytes: usize) {
+        self.used.fetch_sub(bytes, Ordering::SeqCst);
+    }
+}
+
+enum Contents {
+    File(Vec<u8>),
+    /// In creation order
+    Dir(Vec<(Vec<u8>, Arc<TmpNode>)>),
+}
+
+pub struct TmpNode {
+    usage: Arc<Usage>,
+    contents: RwLock<Contents>,
+}
+
+impl TmpNode {
+    /// The root directory of a new tmpfs, which can hold `quota` bytes of file contents
+    pub fn new_root(quota: usize) -> Arc<Self> {
+        let usage = Arc::new(Usage {
+            used: AtomicUsize::new(0),
+            quota,
+        });
+        TmpNode::new(usage, NodeKind::Dir)
+    }
+
+    /// A tmpfs with a quota of a share of the kernel heap
+    pub fn new_default() -> Arc<Self> {
+        TmpNode::new_root(virtmem::get_size() / HEAP_SHARE)
+    }
+
+    fn new(usage: Arc<Usage>, kind: NodeKind) -> Arc<Self> {
+        let contents = match kind {
+            NodeKind::Dir => Contents::Dir(Vec::new()),
+            _ => Contents::File(Vec::new()),
+        };
+        Arc::new(TmpNode {
+            usage,
+            contents: RwLock::new(contents),
+        })
+    }
+
+    /// Grows or shrinks a file's contents to `size`, zero filled. Growing doubles the capacity if
+    /// the quota allows, or else reserves just enough, without ever letting the heap run out
+    fn resize(&self, data: &mut Vec<u8>, size: usize) -> IoResult<()> {
+        let capacity = data.capacity();
+        if size > capacity {
+            let doubled = size.max(capacity.saturating_mul(2));
+            let new_capacity = if self.usage.charge(doubled - capacity).is_ok() {
+                doubled
+            } else {
+                self.usage.charge(size - capacity)?;
+                size
+            };
+            if data.try_reserve_exact(new_capacity - data.len()).is_err() {
+                self.usage.release(new_capacity - capacity);
+                return Err(());
+            }
+        }
+        data.resize(size, 0);
+        if size < data.capacity() / 2 {
+            data.shrink_to_fit();
+        }
+        // Only shrinking lowers it
+        let new_capacity = data.capacity();
+        if new_capacity < capacity {
+            self.usage.release(capacity - new_capacity);
+        }
+        Ok(())
+    }
+}
+
+/// Removed files still open elsewhere keep their contents until they're closed
+impl Drop for TmpNode {
+    fn drop(&mut self) {
+        if let Contents::File(data) = self.contents.get_mut() {
+            self.usage.release(data.capacity());
+        }
+    }
+}
+
+#[async_trait]
+impl Node for TmpNode {
+    fn kind(&self) -> NodeKind {
+        match &*self.contents.read() {
+            Contents::File(_) => NodeKind::File,
+            Contents::Dir(_) => NodeKind::Dir,
+        }
+    }
+
+    async fn stat(&self) -> IoResult<Stat> {
+        Ok(match &*self.contents.read() {
+            Contents::File(data) => Stat {
+                kind: NodeKind::File,
+                size: data.len() as u64,
+            },
+            Contents::Dir(_) => Stat {
+                kind: NodeKind::Dir,
+                size: 0,
+            },
+        })
+    }
+
+    async fn lookup(&self, name: &[u8]) -> IoResult<Option<Arc<dyn Node>>> {
+        match &*self.contents.read() {
+            Contents::Dir(entries) => Ok(entries
+                .iter()
+                .find(|(entry_name, _)| entry_name == name)
+                .map(|(_, node)| node.clone() as Arc<dyn Node>)),
+            Contents::File(_) => Err(()),
+        }
+    }
+
+    async fn readdir(&self) -> IoResult<Vec<vfs::DirEntry>> {
+        match &*self.contents.read() {
+            Contents::Dir(entries) => Ok(entries
+                .iter()
+                .map(|(name, node)| vfs::DirEntry {
+                    name: name.clone(),
+                    kind: node.kind(),
+                })
+                .collect()),
+            Contents::File(_) => Err(()),
+        }
+    }
+
+    async fn read_at(&self, offset: u64, buf: &mut [u8]) -> IoResult<usize> {
+        match &*self.contents.read() {
+            Contents::File(data) => {
+                let start = usize::try_from(offset)
+                    .unwrap_or(usize::MAX)
+                    .min(data.len());
+                let len = buf.len().min(data.len() - start);
+                buf[..len].copy_from_slice(&data[start..start + len]);
+                Ok(len)
+            }
+            Contents::Dir(_) => Err(()),
+        }
+    }
+
+    /// Writing past the end fills the gap with zeros
+    async fn write_at(&self, offset: u64, data: &[u8]) -> IoResult<usize> {
+        let mut contents = self.contents.write();
+        let file = match &mut *contents {
+            Contents::File(file) => file,
+            Contents::Dir(_) => return Err(()),
+        };
+        let start = usize::try_from(offset).map_err(|_| ())?;
+        let end = start.checked_add(data.len()).ok_or(())?;
+        if end > file.len() {
+            self.resize(file, end)?;
+        }
+        file[start..end].copy_from_slice(data);
+        Ok(data.len())
+    }
+
+    async fn create(&self, name: &[u8], kind: NodeKind) -> IoResult<Arc<dyn Node>> {
+        if kind == NodeKind::Device || name.is_empty() || name.contains(&b'/') {
+            return Err(());
+        }
+        let mut contents = self.contents.write();
+        let entries = match &mut *contents {
+            Contents::Dir(entries) => entries,
+            Contents::File(_) => return Err(()),
+        };
+        if entries.iter().any(|(entry_name, _)| entry_name == name) {
+            return Err(());
+        }
+        let node = TmpNode::new(self.usage.clone(), kind);
+        entries.push((name.to_vec(), node.clone()));
+        Ok(node)
+    }
+
+    /// Directories have to be empty
+    async fn remove(&self, name: &[u8]) -> IoResult<()> {
+        let mut contents = self.contents.write();
+        let entries = match &mut *contents {
+            Contents::Dir(entries) => entries,
+            Contents::File(_) => return Err(()),
+        };
+        let index = entries
+            .iter()
+            .position(|(entry_name, _)| entry_name == name)
+            .ok_or(())?;
+        if let Contents::Dir(children) = &*entries[index].1.contents.read() {
+            if !children.is_empty() {
+                return Err(());
+            }
+        }
+        entries.remove(index);
+        Ok(())
+    }
+
+    async fn truncate(&self, size: u64) -> IoResult<()> {
+        let mut contents = self.contents.write();
+        match &mut *contents {
+            Contents::File(file) => self.resize(file, usize::try_from(size).map_err(|_| ())?),
+            Contents::Dir(_) => Err(()),
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use crate::arch::aarch64::dtb;
+use crate::ipc;
+use crate::prelude::*;
+
+use tar_parser::TypeFlag;
+
+/// Directory tree built from the flat list of tar entries, before turning it into IPC nodes
+struct DirBuilder {
+    name: &'static [u8],
+    dirs: Vec<DirBuilder>,
+    files: Vec<(&'static [u8], &'static [u8])>,
+}
+
+impl DirBuilder {
+    fn new(name: &'static [u8]) -> Self {
+        DirBuilder {
+            name,
+            dirs: vec![],
+            files: vec![],
+        }
+    }
+
+    fn get_dir(&mut self, name: &'static [u8]) -> &mut DirBuilder {
+        let idx = match self.dirs.iter().position(|d| d.name == name) {
+            Some(idx) => idx,
+            None => {
+                self.dirs.push(DirBuilder::new(name));
+                self.dirs.len() - 1
+            }
+        };
+        &mut self.dirs[idx]
+    }
+
+    /// Adds a file or directory by its full path, creating parent directories as needed
+    fn add(&mut self, path: &'static [u8], contents: Option<&'static [u8]>) {
+        let mut parts = path
+            .split(|c| *c == b'/')
+            .filter(|part| *part != b"" && *part != b".")
+            .peekable();
+        let mut dir = self;
+        while let Some(part) = parts.next() {
+            if parts.peek().is_some() {
+                dir = dir.get_dir(part);
+            } else if let Some(contents) = contents {
+                dir.files.push((part, contents));
+            } else {
+                dir.get_dir(part);
+            }
+        }
+    }
+
+    /// Entries are numbered from 1, directories first
+    fn into_ipc(self) -> Arc<ipc::IpcDir> {
+        let mut entries = vec![];
+        for dir in self.dirs {
+            entries.push(ipc::IpcRef {
+                id: entries.len() as u64 + 1,
+                inner: dir.into_ipc(),
+            });
+        }
+        for (name, contents) in self.files {
+            entries.push(ipc::IpcRef {
+                id: entries.len() as u64 + 1,
+                inner: ipc::IpcRoFile::new(name, contents),
+            });
+        }
+        ipc::IpcDir::new_named(self.name, entries)
+    }
+}
+
+fn parse(data: &'static [u8]) -> Result<Arc<ipc::IpcDir>, ()> {
+    let (_, entries) = tar_parser::parse_tar(data).map_err(|_| ())?;
+
+    let mut root = DirBuilder::new(b"initrd");
+    for entry in entries {
+        match entry.header.typeflag {
+            TypeFlag::NormalFile | TypeFlag::ContiguousFile => {
+                root.add(entry.header.name.as_bytes(), Some(entry.contents))
+            }
+            TypeFlag::Directory => root.add(entry.header.name.as_bytes(), None),
+            _ => println!(
+                "[WARN] initrd: Skipping unsupported entry \"{}\"",
+                entry.header.name
+            ),
+        }
+    }
+
+    Ok(root.into_ipc())
+}
+
+/// Parses the initrd given by the bootloader, and mounts it at `well_known::ROOT_INITRD`
+pub fn init() {
+    let initrd = match dtb::initrd() {
+        Some(initrd) => initrd,
+        None => {
+            println!("[INFO] No initrd given");
+            return;
+        }
+    };
+    println!("[INFO] initrd @ {:?}", initrd);
+
+    // SAFETY: The initrd range is reserved, and nobody writes to it
+    let data = unsafe { initrd.virt() };
+    let root = match parse(data) {
+        Ok(root) => root,
+        Err(()) => {
+            println!("[WARN] Failed to parse initrd");
+            return;
+        }
+    };
+
+    spawn_task!(b"initrd.mount", {
+        let ipc_root = ipc::ROOT.read().as_ref().unwrap().clone();
+        if ipc_root
+            .dir_link(ipc::well_known::ROOT_INITRD, root)
+            .await
+            .is_none()
+        {
+            println!("[WARN] Failed to mount initrd");
+        }
+    });
+}
@@ -5,6 +5,7 @@ use futures::stream;
 use spin::RwLock;
 
 pub struct IpcDir {
+    name: Option<&'static [u8]>,
     entries: RwLock<Vec<IpcRef>>,
 }
 
@@ -14,6 +15,13 @@ impl IpcDir {
     }
     pub fn new_filled(entries: Vec<IpcRef>) -> Arc<Self> {
         Arc::new(IpcDir {
+            name: None,
+            entries: RwLock::new(entries),
+        })
+    }
+    pub fn new_named(name: &'static [u8], entries: Vec<IpcRef>) -> Arc<Self> {
+        Arc::new(IpcDir {
+            name: Some(name),
             entries: RwLock::new(entries),
         })
     }
@@ -76,7 +84,15 @@ impl IpcNode for IpcDir {
         None
     }
 
+    async fn read_at(self: Arc<Self>, _offset: u64, _dest: &mut [u8]) -> Option<usize> {
+        None
+    }
+
     fn describe(&self) -> [u8; 4] {
         *b"DIR "
     }
+
+    fn name(&self) -> Option<&[u8]> {
+        self.name
+    }
 }
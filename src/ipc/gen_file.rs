@@ -0,0 +1,109 @@
+use crate::ipc::{IpcNode, IpcRef};
+use crate::prelude::*;
+
+use futures::future::BoxFuture;
+use futures::prelude::stream::BoxStream;
+use spin::Mutex;
+
+/// A read-only file whose contents are generated when it's read (e.g. a sensor reading)
+pub struct IpcGenFile {
+    name: &'static [u8],
+    generate: fn() -> BoxFuture<'static, Vec<u8>>,
+    /// The contents being read through `queue_read`, and the read head
+    snapshot: Mutex<(Vec<u8>, usize)>,
+    /// The contents being read through `read_at`, generated again by each read at offset 0
+    read_at_snapshot: Mutex<Option<Arc<Vec<u8>>>>,
+}
+
+impl IpcGenFile {
+    pub fn new(name: &'static [u8], generate: fn() -> BoxFuture<'static, Vec<u8>>) -> Arc<Self> {
+        Arc::new(Self {
+            name,
+            generate,
+            snapshot: Mutex::new((Vec::new(), 0)),
+            read_at_snapshot: Mutex::new(None),
+        })
+    }
+}
+
+fn copy_from(contents: &[u8], offset: usize, dest: &mut [u8]) -> usize {
+    if offset >= contents.len() {
+        return 0;
+    }
+    let len = dest.len().min(contents.len() - offset);
+    dest[..len].copy_from_slice(&contents[offset..offset + len]);
+    len
+}
+
+#[async_trait]
+impl IpcNode for IpcGenFile {
+    fn dir_list<'a>(self: Arc<Self>) -> Option<BoxStream<'a, IpcRef>> {
+        None
+    }
+
+    async fn dir_get(self: Arc<Self>, _id: u64) -> Option<IpcRef> {
+        None
+    }
+
+    async fn dir_create(self: Arc<Self>, _id: u64) -> Option<IpcRef> {
+        None
+    }
+
+    async fn dir_link(
+        self: Arc<Self>,
+        _id: u64,
+        _node: Arc<dyn IpcNode + Send + Sync>,
+    ) -> Option<IpcRef> {
+        None
+    }
+
+    fn queue_write(self: Arc<Self>, _data: &[u8]) -> Result<usize, ()> {
+        Err(())
+    }
+
+    /// Reads from a cursor shared by all readers, returns 0 at EOF. The next read after that
+    /// starts over with fresh contents
+    async fn queue_read(self: Arc<Self>, dest: &mut [u8]) -> Option<usize> {
+        let fresh = if self.snapshot.lock().1 == 0 {
+            Some((self.generate)().await)
+        } else {
+            None
+        };
+
+        let mut snapshot = self.snapshot.lock();
+        let (contents, read_head) = &mut *snapshot;
+        if let Some(fresh) = fresh {
+            *contents = fresh;
+        }
+        let len = copy_from(contents, *read_head, dest);
+        *read_head = if len == 0 { 0 } else { *read_head + len };
+        Some(len)
+    }
+
+    /// A read at offset 0 generates the contents, the reads after it see the same ones, so a file
+    /// read in chunks stays consistent
+    async fn read_at(self: Arc<Self>, offset: u64, dest: &mut [u8]) -> Option<usize> {
+        let cached = if offset == 0 {
+            None
+        } else {
+            self.read_at_snapshot.lock().clone()
+        };
+        let contents = match cached {
+            Some(contents) => contents,
+            None => {
+                let fresh = Arc::new((self.generate)().await);
+                *self.read_at_snapshot.lock() = Some(fresh.clone());
+                fresh
+            }
+        };
+        Some(copy_from(&contents, offset as usize, dest))
+    }
+
+    fn describe(&self) -> [u8; 4] {
+        *b"FILE"
+    }
+
+    fn name(&self) -> Option<&[u8]> {
+        Some(self.name)
+    }
+}
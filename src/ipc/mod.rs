@@ -1,15 +1,21 @@
 // pub(crate) mod condvar;
 pub(crate) mod dir;
+pub(crate) mod gen_file;
+pub(crate) mod ro_file;
 pub(crate) mod signal;
 pub(crate) mod spsc_mux;
 pub(crate) mod spsc_queue;
 pub(crate) mod well_known;
 
+use crate::arch::aarch64::board;
 use crate::prelude::*;
+use crate::random::IpcRandom;
 use core::fmt::{Debug, Formatter};
 pub use dir::IpcDir;
 use futures::stream::BoxStream;
 use futures::StreamExt;
+pub use gen_file::IpcGenFile;
+pub use ro_file::IpcRoFile;
 use spin::RwLock;
 pub use spsc_queue::IpcSpscQueue;
 
@@ -22,7 +28,11 @@ pub struct IpcRef {
 impl Debug for IpcRef {
     fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
         display_bstr(f, &self.inner.describe())?;
-        write!(f, " {:x}", self.id)
+        write!(f, " {:x}", self.id)?;
+        if let Some(name) = self.inner.name() {
+            write!(f, " \"{}\"", AsciiStr(name))?;
+        }
+        Ok(())
     }
 }
 
@@ -38,7 +48,9 @@ pub trait IpcNode {
     ) -> Option<IpcRef>;
     fn queue_write(self: Arc<Self>, data: &[u8]) -> Result<usize, ()>;
     async fn queue_read(self: Arc<Self>, dest: &mut [u8]) -> Option<usize>;
+    async fn read_at(self: Arc<Self>, offset: u64, dest: &mut [u8]) -> Option<usize>;
     fn describe(&self) -> [u8; 4];
+    fn name(&self) -> Option<&[u8]>;
 }
 
 impl IpcRef {
@@ -58,6 +70,24 @@ impl IpcRef {
         self.inner.clone().dir_link(id, node).await
     }
 
+    /// The entry called `name` in this directory, by name first, then by hex id
+    pub async fn dir_lookup(&self, name: &[u8]) -> Option<IpcRef> {
+        let mut stream = self.dir_list()?;
+        let mut by_id = None;
+        let id = core::str::from_utf8(name)
+            .ok()
+            .and_then(|name| u64::from_str_radix(name, 16).ok());
+        while let Some(entry) = stream.next().await {
+            if entry.name() == Some(name) {
+                return Some(entry);
+            }
+            if by_id.is_none() && Some(entry.id) == id {
+                by_id = Some(entry);
+            }
+        }
+        by_id
+    }
+
     pub fn queue_write(&self, data: &[u8]) -> Result<usize, ()> {
         self.inner.clone().queue_write(data)
     }
@@ -80,9 +110,17 @@ impl IpcRef {
         self.inner.clone().queue_read(dest).await
     }
 
+    pub async fn read_at(&self, offset: u64, dest: &mut [u8]) -> Option<usize> {
+        self.inner.clone().read_at(offset, dest).await
+    }
+
     pub fn describe(&self) -> [u8; 4] {
         self.inner.describe()
     }
+
+    pub fn name(&self) -> Option<&[u8]> {
+        self.inner.name()
+    }
 }
 
 pub fn init() {
@@ -108,27 +146,93 @@ pub fn init() {
             },
             IpcRef {
                 id: well_known::ROOT_DEVICES,
-                inner: IpcDir::new_filled(vec![
-                    IpcRef {
-                        id: well_known::DEVICES_RPI_UART,
-                        inner: IpcDir::new_filled(vec![IpcRef {
-                            id: well_known::RPI_UART1,
-                            inner: IpcDir::new_empty(),
-                        }]),
-                    },
-                    IpcRef {
-                        id: well_known::DEVICES_RPI_FB_CON,
-                        inner: IpcDir::new_filled(vec![IpcRef {
-                            id: well_known::RPI_FB_CON0,
-                            inner: IpcDir::new_empty(),
-                        }]),
-                    },
-                ]),
+                inner: IpcDir::new_named(
+                    b"devices",
+                    vec![
+                        IpcRef {
+                            id: well_known::DEVICES_RPI_UART,
+                            inner: IpcDir::new_filled(vec![
+                                IpcRef {
+                                    id: well_known::RPI_UART0,
+                                    inner: IpcDir::new_empty(),
+                                },
+                                IpcRef {
+                                    id: well_known::RPI_UART1,
+                                    inner: IpcDir::new_empty(),
+                                },
+                            ]),
+                        },
+                        IpcRef {
+                            id: well_known::DEVICES_RPI_FB_CON,
+                            inner: IpcDir::new_filled(vec![IpcRef {
+                                id: well_known::RPI_FB_CON0,
+                                inner: IpcDir::new_empty(),
+                            }]),
+                        },
+                        IpcRef {
+                            id: well_known::DEVICES_PL011,
+                            inner: IpcDir::new_filled(vec![IpcRef {
+                                id: well_known::PL011_UART0,
+                                inner: IpcDir::new_empty(),
+                            }]),
+                        },
+                        IpcRef {
+                            id: well_known::DEVICES_RANDOM,
+                            inner: IpcDir::new_filled(vec![
+                                IpcRef {
+                                    id: well_known::RANDOM_RANDOM,
+                                    inner: IpcRandom::new(true),
+                                },
+                                IpcRef {
+                                    id: well_known::RANDOM_URANDOM,
+                                    inner: IpcRandom::new(false),
+                                },
+                            ]),
+                        },
+                        IpcRef {
+                            id: well_known::DEVICES_BLOCK,
+                            inner: IpcDir::new_named(b"block", vec![]),
+                        },
+                    ],
+                ),
+            },
+            IpcRef {
+                id: well_known::ROOT_FS,
+                inner: IpcDir::new_named(b"fs", vec![]),
+            },
+            IpcRef {
+                id: well_known::ROOT_SYS,
+                inner: IpcDir::new_named(
+                    b"sys",
+                    vec![IpcRef {
+                        id: well_known::SYS_BOARD,
+                        inner: IpcDir::new_named(
+                            b"board",
+                            board::INFO_FILES
+                                .iter()
+                                .zip(1..)
+                                .map(|(file, id)| IpcRef {
+                                    id,
+                                    inner: IpcGenFile::new(file.name, file.read),
+                                })
+                                .collect(),
+                        ),
+                    }],
+                ),
             },
         ]),
     });
 }
 
+/// The node at `path` from the root, like its path in the VFS minus the mounts
+pub async fn lookup(path: &[u8]) -> Option<IpcRef> {
+    let mut node = ROOT.read().as_ref()?.clone();
+    for name in crate::vfs::components(path) {
+        node = node.dir_lookup(name).await?;
+    }
+    Some(node)
+}
+
 pub async fn test() {
     let ipc_dir = ROOT.read().as_ref().unwrap().clone();
 
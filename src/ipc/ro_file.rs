@@ -0,0 +1,78 @@
+use crate::ipc::{IpcNode, IpcRef};
+use crate::prelude::*;
+
+use futures::prelude::stream::BoxStream;
+use spin::Mutex;
+
+/// A read-only file backed by static memory (e.g. the initrd)
+pub struct IpcRoFile {
+    name: &'static [u8],
+    contents: &'static [u8],
+    read_head: Mutex<usize>,
+}
+
+impl IpcRoFile {
+    pub fn new(name: &'static [u8], contents: &'static [u8]) -> Arc<Self> {
+        Arc::new(Self {
+            name,
+            contents,
+            read_head: Mutex::new(0),
+        })
+    }
+
+    fn copy_from(&self, offset: usize, dest: &mut [u8]) -> usize {
+        if offset >= self.contents.len() {
+            return 0;
+        }
+        let len = dest.len().min(self.contents.len() - offset);
+        dest[..len].copy_from_slice(&self.contents[offset..offset + len]);
+        len
+    }
+}
+
+#[async_trait]
+impl IpcNode for IpcRoFile {
+    fn dir_list<'a>(self: Arc<Self>) -> Option<BoxStream<'a, IpcRef>> {
+        None
+    }
+
+    async fn dir_get(self: Arc<Self>, _id: u64) -> Option<IpcRef> {
+        None
+    }
+
+    async fn dir_create(self: Arc<Self>, _id: u64) -> Option<IpcRef> {
+        None
+    }
+
+    async fn dir_link(
+        self: Arc<Self>,
+        _id: u64,
+        _node: Arc<dyn IpcNode + Send + Sync>,
+    ) -> Option<IpcRef> {
+        None
+    }
+
+    fn queue_write(self: Arc<Self>, _data: &[u8]) -> Result<usize, ()> {
+        Err(())
+    }
+
+    /// Reads from a cursor shared by all readers, returns 0 at EOF
+    async fn queue_read(self: Arc<Self>, dest: &mut [u8]) -> Option<usize> {
+        let mut read_head = self.read_head.lock();
+        let len = self.copy_from(*read_head, dest);
+        *read_head += len;
+        Some(len)
+    }
+
+    async fn read_at(self: Arc<Self>, offset: u64, dest: &mut [u8]) -> Option<usize> {
+        Some(self.copy_from(offset as usize, dest))
+    }
+
+    fn describe(&self) -> [u8; 4] {
+        *b"FILE"
+    }
+
+    fn name(&self) -> Option<&[u8]> {
+        Some(self.name)
+    }
+}
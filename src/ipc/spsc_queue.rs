@@ -13,6 +13,7 @@ impl<'a> Future for SpscReadableWaiter<'a> {
     type Output = ();
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let _irq_lock = irq_lock();
         let mut spsc = self.0.lock();
         if spsc.is_readable() {
             Poll::Ready(())
@@ -33,7 +34,7 @@ pub struct SpscQueue<const S: usize> {
 
 // FIXME: Can be made atomic I think?
 impl<const S: usize> SpscQueue<S> {
-    pub fn new() -> Self {
+    pub const fn new() -> Self {
         Self {
             buffer: [0; S],
             read_head: 0,
@@ -104,6 +105,7 @@ impl<const S: usize> SpscQueue<S> {
     }
 }
 
+/// Can be written from IRQ handlers, so the queue is only locked with interrupts disabled
 pub struct IpcSpscQueue {
     queue: Mutex<Box<SpscQueue<512>>>,
 }
@@ -139,12 +141,14 @@ impl IpcNode for IpcSpscQueue {
     }
 
     fn queue_write(self: Arc<Self>, data: &[u8]) -> Result<usize, ()> {
+        let _irq_lock = irq_lock();
         Ok(self.queue.lock().write(data))
     }
 
     async fn queue_read(self: Arc<Self>, dest: &mut [u8]) -> Option<usize> {
         loop {
             {
+                let _irq_lock = irq_lock();
                 let mut queue = self.queue.lock();
                 let result = queue.read(dest.len());
                 (&mut dest[..result.len()]).copy_from_slice(result);
@@ -158,7 +162,15 @@ impl IpcNode for IpcSpscQueue {
         }
     }
 
+    async fn read_at(self: Arc<Self>, _offset: u64, _dest: &mut [u8]) -> Option<usize> {
+        None
+    }
+
     fn describe(&self) -> [u8; 4] {
         *b"SPSC"
     }
+
+    fn name(&self) -> Option<&[u8]> {
+        None
+    }
 }
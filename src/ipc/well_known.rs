@@ -7,8 +7,16 @@ pub const EXAMPLE_3: u64 = 3;
 
 pub const ROOT_DEVICES: u64 = 0x123;
 
+pub const ROOT_INITRD: u64 = 0x1d;
+
+/// Its entries are the mounted volumes, in the order they're mounted from 1
+pub const ROOT_FS: u64 = 0xf5;
+
+pub const ROOT_SYS: u64 = 0x5;
+pub const SYS_BOARD: u64 = 0xb;
+
 pub const DEVICES_RPI_UART: u64 = 1;
-// pub const RPI_UART0: u64 = 1;
+pub const RPI_UART0: u64 = 1;
 pub const RPI_UART1: u64 = 2;
 pub const RPI_UART_IN: u64 = 1;
 pub const RPI_UART_OUT: u64 = 2;
@@ -17,3 +25,18 @@ pub const DEVICES_RPI_FB_CON: u64 = 2;
 pub const RPI_FB_CON0: u64 = 1;
 pub const RPI_FB_CON_IN: u64 = 1;
 pub const RPI_FB_CON_OUT: u64 = 2;
+
+pub const DEVICES_PL011: u64 = 3;
+pub const PL011_UART0: u64 = 1;
+pub const PL011_IN: u64 = 1;
+pub const PL011_OUT: u64 = 2;
+
+pub const DEVICES_RANDOM: u64 = 4;
+pub const RANDOM_RANDOM: u64 = 1;
+pub const RANDOM_URANDOM: u64 = 2;
+
+/// Its entries are the pins, by number
+pub const DEVICES_GPIO: u64 = 5;
+
+/// Its entries are the disks and partitions, in the order they're found from 1
+pub const DEVICES_BLOCK: u64 = 6;
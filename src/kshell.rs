@@ -1,21 +1,23 @@
 #![allow(clippy::never_loop)]
 use crate::arch::aarch64::mmio::get_uptime_us;
 use crate::arch::aarch64::mmio::sleep_us;
+use crate::arch::aarch64::{gpio, irq, phymem, power, virtmem};
 use crate::driver_manager::DeviceType;
 use crate::framebuffer::FramebufferCM;
+use crate::ipc::well_known::{DEVICES_GPIO, ROOT_DEVICES, ROOT_SYS, SYS_BOARD};
 use crate::ktask;
 use crate::prelude::*;
-use crate::{driver_manager, framebuffer_console, syscalls};
+use crate::vfs::{self, NodeKind};
+use crate::{block, driver_manager, framebuffer_console, process, syscalls, threads};
 use crate::{fonts, ipc};
-use futures::future::BoxFuture;
-use futures::stream;
+use futures::future::{self, BoxFuture, Either};
 use futures::StreamExt;
 
 struct KShell {
     pub input: ipc::IpcRef,
     pub output: ipc::IpcRef,
     pub root: ipc::IpcRef,
-    pub cwd: Vec<u64>,
+    pub cwd: Vec<u8>,
     pub colors: bool,
 }
 
@@ -209,27 +211,6 @@ impl KShell {
         Some(root)
     }
 
-    fn parse_path(cwd: &[u64], path: &[u8]) -> Result<Vec<u64>, ()> {
-        let mut root = if path.starts_with(b"/") {
-            vec![]
-        } else {
-            cwd.to_vec()
-        };
-
-        for part in path.split(|c| *c == b'/') {
-            if part == b"." || part == b"" {
-                // Do nothing
-            } else if part == b".." {
-                root.pop();
-            } else {
-                let as_str = core::str::from_utf8(part).map_err(|_| ())?;
-                root.push(u64::from_str_radix(as_str, 16).map_err(|_| ())?);
-            }
-        }
-
-        Ok(root)
-    }
-
     pub async fn run(&mut self) {
         queue_write!(self.output.clone(), "--- Bold KShell ---\n");
         queue_write!(
@@ -249,10 +230,7 @@ impl KShell {
             if self.colors {
                 queue_write!(self.output.clone(), "\x1b[32m");
             }
-            queue_write!(self.output.clone(), "/");
-            for dir in &self.cwd {
-                queue_write!(self.output.clone(), "{:x}/", *dir);
-            }
+            queue_write!(self.output.clone(), "{}", AsciiStr(&self.cwd));
             if self.colors {
                 queue_write!(self.output.clone(), "\x1b[0m");
             }
@@ -273,21 +251,59 @@ impl KShell {
                              ls <PATH>   : List directory\n\
                              tree <PATH> : List directory recursively\n\
                              cd <PATH>   : Change directory\n\
+                             cat <PATH>  : Print a file\n\
+                             mkdir <PATH>: Create a directory\n\
+                             touch <PATH>: Create an empty file\n\
+                             rm <PATH>   : Remove a file or an empty directory\n\
+                             echo <TEXT> [>|>> <PATH>]\n\
+                             \x20           : Print text, or write or append it to a file\n\
+                             sync        : Write cached disk blocks back\n\
                              info        : Display system info\n\
+                             mem         : Display free memory\n\
+                             sysinfo     : Display board info\n\
+                             irqs        : Display interrupt statistics\n\
+                             gpio        : List GPIO pins\n\
+                             gpio <PIN> [0|1|in|up|down|off|watch]\n\
+                             \x20           : Read, drive, configure or watch a GPIO pin\n\
                              ps          : Process list\n\
-                             init        : Start usermode\n\
+                             init [PATH] : Start a usermode program, the built-in one by default\n\
                              gfx         : Benchmark graphics\n\
-                             font <FONT> : Change framebuffer font"
+                             font <FONT> : Change framebuffer font\n\
+                             reboot      : Restart the machine\n\
+                             halt        : Stop the machine\n\
+                             poweroff    : Turn the machine off"
                         );
                     }
                     b"ls" => self.handle_cmd_ls(&words).await,
                     b"tree" => self.handle_cmd_tree(&words).await,
                     b"cd" => self.handle_cmd_cd(&words).await,
+                    b"cat" => self.handle_cmd_cat(&words).await,
+                    b"mkdir" => self.handle_cmd_mkdir(&words).await,
+                    b"touch" => self.handle_cmd_touch(&words).await,
+                    b"rm" => self.handle_cmd_rm(&words).await,
+                    b"echo" => self.handle_cmd_echo(&words).await,
                     b"font" => self.handle_cmd_font(&words).await,
                     b"info" => self.handle_cmd_info(&words).await,
+                    b"mem" => self.handle_cmd_mem(&words).await,
+                    b"sysinfo" => self.handle_cmd_sysinfo(&words).await,
+                    b"irqs" => self.handle_cmd_irqs(&words).await,
+                    b"gpio" => self.handle_cmd_gpio(&words).await,
                     b"ps" => self.handle_cmd_ps(&words).await,
                     b"init" => self.handle_cmd_init(&words).await,
                     b"gfx" => self.handle_cmd_gfx(&words).await,
+                    b"sync" => self.handle_cmd_sync(&words).await,
+                    b"reboot" => {
+                        block::cache::sync_all().await.ok();
+                        power::reboot()
+                    }
+                    b"halt" => {
+                        block::cache::sync_all().await.ok();
+                        power::halt()
+                    }
+                    b"poweroff" => {
+                        block::cache::sync_all().await.ok();
+                        power::poweroff()
+                    }
                     _ => {
                         queue_writeln!(
                             self.output.clone(),
@@ -303,80 +319,80 @@ impl KShell {
     async fn handle_cmd_ls(&mut self, words: &[&[u8]]) {
         let path = match words.len() {
             1 => self.cwd.clone(),
-            2 => match KShell::parse_path(&self.cwd, words[1]) {
-                Ok(path) => path,
-                Err(_) => {
-                    queue_writeln!(self.output.clone(), "Error: Invalid Path");
-                    return;
-                }
-            },
+            2 => vfs::join(&self.cwd, words[1]),
             _ => {
                 queue_writeln!(self.output.clone(), "Error: Invalid Usage, see `help`");
                 return;
             }
         };
 
-        if let Some(cwd) = self.navigate_to_path(&path).await {
-            if cwd.describe() == *b"DIR " {
-                if let Some(mut stream) = cwd.dir_list() {
-                    while let Some(item) = stream.next().await {
-                        queue_writeln!(self.output.clone(), "{:?}", item);
+        match vfs::stat(&path).await {
+            Ok(stat) if stat.kind == NodeKind::Dir => match vfs::readdir(&path).await {
+                Ok(entries) => {
+                    for entry in entries {
+                        queue_writeln!(
+                            self.output.clone(),
+                            "{} {}",
+                            entry.kind.describe(),
+                            AsciiStr(&entry.name)
+                        );
                     }
-                } else {
+                }
+                Err(()) => {
                     queue_writeln!(self.output.clone(), "Error: Failed to list given path");
                 }
-            } else {
-                queue_writeln!(self.output.clone(), "Error: Not a directory");
-            }
-        } else {
-            queue_writeln!(self.output.clone(), "Error: Directory doesn't exist");
+            },
+            Ok(_) => queue_writeln!(self.output.clone(), "Error: Not a directory"),
+            Err(()) => queue_writeln!(self.output.clone(), "Error: Directory doesn't exist"),
         }
     }
 
     async fn handle_cmd_tree(&mut self, words: &[&[u8]]) {
         let path = match words.len() {
             1 => self.cwd.clone(),
-            2 => match KShell::parse_path(&self.cwd, words[1]) {
-                Ok(path) => path,
-                Err(_) => {
-                    queue_writeln!(self.output.clone(), "Error: Invalid Path");
-                    return;
-                }
-            },
+            2 => vfs::join(&self.cwd, words[1]),
             _ => {
                 queue_writeln!(self.output.clone(), "Error: Invalid Usage, see `help`");
                 return;
             }
         };
 
-        if let Some(cwd) = self.navigate_to_path(&path).await {
-            if cwd.describe() == *b"DIR " {
-                if let Some(stream) = cwd.dir_list() {
-                    fn rec_print(
-                        output: ipc::IpcRef,
-                        mut stream: stream::BoxStream<ipc::IpcRef>,
-                        depth: usize,
-                    ) -> BoxFuture<()> {
-                        Box::pin(async move {
-                            while let Some(item) = stream.next().await {
+        match vfs::stat(&path).await {
+            Ok(stat) if stat.kind == NodeKind::Dir => {
+                fn rec_print(
+                    output: ipc::IpcRef,
+                    path: Vec<u8>,
+                    depth: usize,
+                ) -> BoxFuture<'static, ()> {
+                    Box::pin(async move {
+                        let entries = match vfs::readdir(&path).await {
+                            Ok(entries) => entries,
+                            Err(()) => {
                                 queue_write!(output.clone(), "{}", "| ".repeat(depth));
-                                queue_writeln!(output.clone(), "{:?}", item);
-                                if let Some(stream) = item.dir_list() {
-                                    rec_print(output.clone(), stream, depth + 1).await;
-                                }
+                                queue_writeln!(output.clone(), "Error: Failed to list");
+                                return;
                             }
-                        })
-                    }
-
-                    rec_print(self.output.clone(), stream, 0).await;
-                } else {
-                    queue_writeln!(self.output.clone(), "Error: Failed to list given path");
+                        };
+                        for entry in entries {
+                            queue_write!(output.clone(), "{}", "| ".repeat(depth));
+                            queue_writeln!(
+                                output.clone(),
+                                "{} {}",
+                                entry.kind.describe(),
+                                AsciiStr(&entry.name)
+                            );
+                            if entry.kind == NodeKind::Dir {
+                                rec_print(output.clone(), vfs::join(&path, &entry.name), depth + 1)
+                                    .await;
+                            }
+                        }
+                    })
                 }
-            } else {
-                queue_writeln!(self.output.clone(), "Error: Not a directory");
+
+                rec_print(self.output.clone(), path, 0).await;
             }
-        } else {
-            queue_writeln!(self.output.clone(), "Error: Directory doesn't exist");
+            Ok(_) => queue_writeln!(self.output.clone(), "Error: Not a directory"),
+            Err(()) => queue_writeln!(self.output.clone(), "Error: Directory doesn't exist"),
         }
     }
 
@@ -406,28 +422,138 @@ impl KShell {
 
     async fn handle_cmd_cd(&mut self, words: &[&[u8]]) {
         let path = match words.len() {
-            1 => vec![],
-            2 => match KShell::parse_path(&self.cwd, words[1]) {
-                Ok(path) => path,
-                Err(_) => {
-                    queue_writeln!(self.output.clone(), "Error: Invalid Path");
+            1 => b"/".to_vec(),
+            2 => vfs::join(&self.cwd, words[1]),
+            _ => {
+                queue_writeln!(self.output.clone(), "Error: Invalid Usage, see `help`");
+                return;
+            }
+        };
+
+        match vfs::stat(&path).await {
+            Ok(stat) if stat.kind == NodeKind::Dir => self.cwd = path,
+            Ok(_) => queue_writeln!(self.output.clone(), "Error: Not a directory"),
+            Err(()) => queue_writeln!(self.output.clone(), "Error: Directory doesn't exist"),
+        }
+    }
+
+    async fn handle_cmd_cat(&mut self, words: &[&[u8]]) {
+        let path = match words.len() {
+            2 => vfs::join(&self.cwd, words[1]),
+            _ => {
+                queue_writeln!(self.output.clone(), "Error: Invalid Usage, see `help`");
+                return;
+            }
+        };
+
+        let file = match vfs::open(&path, vfs::OPEN_READ).await {
+            Ok(file) => file,
+            Err(()) => {
+                queue_writeln!(self.output.clone(), "Error: File doesn't exist");
+                return;
+            }
+        };
+        match file.kind() {
+            NodeKind::File => {}
+            NodeKind::Dir => {
+                queue_writeln!(self.output.clone(), "Error: Is a directory");
+                return;
+            }
+            NodeKind::Device => {
+                queue_writeln!(self.output.clone(), "Error: Not a file");
+                return;
+            }
+        }
+
+        let mut buf = [0; 512];
+        loop {
+            let len = match file.read(&mut buf).await {
+                Ok(0) => return,
+                Ok(len) => len,
+                Err(()) => {
+                    queue_writeln!(self.output.clone(), "Error: Failed to read file");
                     return;
                 }
-            },
-            _ => {
+            };
+
+            // The output queue is small, wait for it to drain
+            let mut data = &buf[..len];
+            while !data.is_empty() {
+                match self.output.queue_write(data) {
+                    Ok(0) => yield_now().await,
+                    Ok(written) => data = &data[written..],
+                    Err(()) => return,
+                }
+            }
+        }
+    }
+
+    async fn handle_cmd_mkdir(&mut self, words: &[&[u8]]) {
+        if words.len() != 2 {
+            queue_writeln!(self.output.clone(), "Error: Invalid Usage, see `help`");
+            return;
+        }
+        if vfs::mkdir(&vfs::join(&self.cwd, words[1])).await.is_err() {
+            queue_writeln!(self.output.clone(), "Error: Couldn't create directory");
+        }
+    }
+
+    async fn handle_cmd_touch(&mut self, words: &[&[u8]]) {
+        if words.len() != 2 {
+            queue_writeln!(self.output.clone(), "Error: Invalid Usage, see `help`");
+            return;
+        }
+        let path = vfs::join(&self.cwd, words[1]);
+        if vfs::open(&path, vfs::OPEN_CREATE).await.is_err() {
+            queue_writeln!(self.output.clone(), "Error: Couldn't create file");
+        }
+    }
+
+    async fn handle_cmd_rm(&mut self, words: &[&[u8]]) {
+        if words.len() != 2 {
+            queue_writeln!(self.output.clone(), "Error: Invalid Usage, see `help`");
+            return;
+        }
+        if vfs::remove(&vfs::join(&self.cwd, words[1])).await.is_err() {
+            queue_writeln!(
+                self.output.clone(),
+                "Error: Couldn't remove, directories have to be empty"
+            );
+        }
+    }
+
+    async fn handle_cmd_echo(&mut self, words: &[&[u8]]) {
+        let (text, redirect) = match words.iter().position(|w| *w == b">" || *w == b">>") {
+            Some(at) if at + 2 == words.len() => (&words[1..at], Some((words[at], words[at + 1]))),
+            Some(_) => {
                 queue_writeln!(self.output.clone(), "Error: Invalid Usage, see `help`");
                 return;
             }
+            None => (&words[1..], None),
         };
+        let mut line = text.join(&b' ');
+        line.push(b'\n');
 
-        if let Some(given_dir) = self.navigate_to_path(&path).await {
-            if given_dir.describe() == *b"DIR " {
-                self.cwd = path;
-            } else {
-                queue_writeln!(self.output.clone(), "Error: Not a directory");
+        let (operator, path) = match redirect {
+            Some(redirect) => redirect,
+            None => {
+                queue_write!(self.output.clone(), "{}", AsciiStr(&line));
+                return;
             }
-        } else {
-            queue_writeln!(self.output.clone(), "Error: Directory doesn't exist");
+        };
+        let flags = vfs::OPEN_WRITE
+            | vfs::OPEN_CREATE
+            | if operator == b">>" {
+                vfs::OPEN_APPEND
+            } else {
+                vfs::OPEN_TRUNCATE
+            };
+        let result = match vfs::open(&vfs::join(&self.cwd, path), flags).await {
+            Ok(file) => file.write_all(&line).await,
+            Err(()) => Err(()),
+        };
+        if result.is_err() {
+            queue_writeln!(self.output.clone(), "Error: Couldn't write file");
         }
     }
 
@@ -442,8 +568,182 @@ impl KShell {
             "Scheduler:\n  {:?}",
             ktask::perf_report()
         );
+        queue_writeln!(
+            self.output.clone(),
+            "Block Cache:\n  {:?}",
+            block::cache::perf_report()
+        );
+    }
+
+    async fn handle_cmd_mem(&mut self, _words: &[&[u8]]) {
+        let free_pages = phymem::PHYMEM_FREE_LIST.lock().free_count();
+        queue_writeln!(
+            self.output.clone(),
+            "Physical: {} pages free ({} KiB)",
+            free_pages,
+            free_pages * phymem::PAGE_SIZE / 1024
+        );
+        queue_writeln!(
+            self.output.clone(),
+            "Heap:     {} KiB used, {} KiB free",
+            virtmem::get_used() / 1024,
+            virtmem::get_free() / 1024
+        );
+        let processes = process::list();
+        queue_writeln!(
+            self.output.clone(),
+            "Usermode: {} processes, {} pages, {} page tables",
+            processes.len(),
+            processes.iter().map(|process| process.pages).sum::<usize>(),
+            processes
+                .iter()
+                .map(|process| process.tables)
+                .sum::<usize>()
+        );
+    }
+
+    async fn handle_cmd_sync(&mut self, _words: &[&[u8]]) {
+        if block::cache::sync_all().await.is_err() {
+            queue_writeln!(self.output.clone(), "Error: Failed to write some blocks");
+        }
+    }
+
+    async fn handle_cmd_sysinfo(&mut self, _words: &[&[u8]]) {
+        let board_dir = match self.navigate_to_path(&[ROOT_SYS, SYS_BOARD]).await {
+            Some(board_dir) => board_dir,
+            None => {
+                queue_writeln!(self.output.clone(), "Error: No board info");
+                return;
+            }
+        };
+        let mut stream = board_dir.dir_list().unwrap();
+        while let Some(file) = stream.next().await {
+            let mut contents = Vec::new();
+            let mut buf = [0u8; 512];
+            while let Some(len) = file.read_at(contents.len() as u64, &mut buf).await {
+                if len == 0 {
+                    break;
+                }
+                contents.extend_from_slice(&buf[..len]);
+            }
+
+            // Indent multi-line values under their name
+            let name = AsciiStr(file.name().unwrap_or(b"?"));
+            let contents = contents.strip_suffix(b"\n").unwrap_or(&contents);
+            if contents.contains(&b'\n') {
+                queue_writeln!(self.output.clone(), "{}:", name);
+                for line in contents.split(|c| *c == b'\n') {
+                    queue_writeln!(self.output.clone(), "  {}", AsciiStr(line));
+                }
+            } else {
+                queue_writeln!(self.output.clone(), "{}: {}", name, AsciiStr(contents));
+            }
+        }
     }
+    async fn handle_cmd_irqs(&mut self, _words: &[&[u8]]) {
+        queue_writeln!(self.output.clone(), "     IRQ    Count Name");
+        for stats in irq::stats() {
+            queue_writeln!(
+                self.output.clone(),
+                "{: >8} {: >8} {}",
+                stats.irq,
+                stats.count,
+                AsciiStr(stats.name),
+            );
+        }
+        queue_writeln!(self.output.clone(), "Spurious: {}", irq::spurious_count());
+    }
+    async fn handle_cmd_gpio(&mut self, words: &[&[u8]]) {
+        let gpio_dir = match self.navigate_to_path(&[ROOT_DEVICES, DEVICES_GPIO]).await {
+            Some(gpio_dir) => gpio_dir,
+            None => {
+                queue_writeln!(self.output.clone(), "Error: No GPIO");
+                return;
+            }
+        };
 
+        if words.len() == 1 {
+            queue_writeln!(self.output.clone(), " PIN Func Level Owner");
+            for pin in 0..gpio::PIN_COUNT {
+                queue_writeln!(
+                    self.output.clone(),
+                    "{: >4} {: <4} {: >5} {}",
+                    pin,
+                    gpio::function(pin).name(),
+                    gpio::read(pin) as u8,
+                    AsciiStr(gpio::owner(pin).unwrap_or(b"-")),
+                );
+            }
+            return;
+        }
+        if words.len() > 3 {
+            queue_writeln!(self.output.clone(), "Error: Invalid Usage, see `help`");
+            return;
+        }
+
+        let pin_number = core::str::from_utf8(words[1])
+            .ok()
+            .and_then(|pin| pin.parse::<u64>().ok());
+        let pin = match pin_number {
+            Some(pin_number) => gpio_dir.dir_get(pin_number).await,
+            None => None,
+        };
+        let pin = match pin {
+            Some(pin) => pin,
+            None => {
+                queue_writeln!(self.output.clone(), "Error: No such pin");
+                return;
+            }
+        };
+        let name = AsciiStr(pin.name().unwrap_or(b"?"));
+
+        match words.get(2) {
+            None => {
+                let mut level = [0u8; 2];
+                let len = pin.read_at(0, &mut level).await.unwrap_or(0);
+                queue_write!(self.output.clone(), "{}: {}", name, AsciiStr(&level[..len]));
+            }
+            Some(&b"watch") => {
+                queue_writeln!(
+                    self.output.clone(),
+                    "Watching {}, press any key to stop",
+                    name
+                );
+                loop {
+                    let mut level = [0u8; 2];
+                    let mut key = [0u8; 1];
+                    let change = Box::pin(pin.queue_read(&mut level));
+                    let stop = Box::pin(self.input.queue_read(&mut key));
+                    let changed = match future::select(change, stop).await {
+                        Either::Left((len, _)) => Some(len),
+                        Either::Right(_) => None,
+                    };
+                    match changed {
+                        Some(Some(len)) => queue_write!(
+                            self.output.clone(),
+                            "{} {}: {}",
+                            DurationFmt(get_uptime_us()),
+                            name,
+                            AsciiStr(&level[..len])
+                        ),
+                        Some(None) => {
+                            queue_writeln!(self.output.clone(), "Error: Already being watched");
+                            break;
+                        }
+                        None => break,
+                    }
+                }
+            }
+            Some(command) => {
+                if pin.queue_write(command).is_err() {
+                    queue_writeln!(
+                        self.output.clone(),
+                        "Error: Can't do that, the pin may belong to a driver"
+                    );
+                }
+            }
+        }
+    }
     async fn handle_cmd_gfx(&mut self, _words: &[&[u8]]) {
         let framebuffer = driver_manager::device_by_type(DeviceType::Framebuffer)
             .unwrap()
@@ -478,25 +778,79 @@ impl KShell {
     async fn handle_cmd_ps(&mut self, _words: &[&[u8]]) {
         queue_writeln!(
             self.output.clone(),
-            "     PID   Uptime  CPUTime   Yields Name"
+            "     PID Core   Uptime  CPUTime   Yields Name"
         );
         for task in ktask::proc_list() {
             queue_writeln!(
                 self.output.clone(),
-                "{: >8} {: >8} {: >8} {: >8} {}",
+                "{: >8} {: >4} {: >8} {: >8} {: >8} {}",
                 task.id,
+                task.last_core,
                 DurationFmt(task.uptime_us),
                 DurationFmt(task.cpu_time_us),
                 task.total_yields,
                 AsciiStr(task.name),
             );
         }
+
+        queue_writeln!(
+            self.output.clone(),
+            "\n     TID Core   Uptime   Yields      PID Name"
+        );
+        for thread in threads::proc_list() {
+            let process = match thread.process {
+                Some(id) => alloc::format!("{}", id),
+                None => "-".into(),
+            };
+            queue_writeln!(
+                self.output.clone(),
+                "{: >8} {: >4} {: >8} {: >8} {: >8} {}",
+                thread.id,
+                thread.last_core,
+                DurationFmt(thread.uptime_us),
+                thread.total_yields,
+                process,
+                AsciiStr(thread.name),
+            );
+        }
+
+        queue_writeln!(
+            self.output.clone(),
+            "\n     PID  Threads  Handles    Pages   Tables Name"
+        );
+        for process in process::list() {
+            queue_writeln!(
+                self.output.clone(),
+                "{: >8} {: >8} {: >8} {: >8} {: >8} {}",
+                process.id,
+                process.threads,
+                process.handles,
+                process.pages,
+                process.tables,
+                AsciiStr(&process.name),
+            );
+        }
     }
 
-    async fn handle_cmd_init(&mut self, _words: &[&[u8]]) {
+    async fn handle_cmd_init(&mut self, words: &[&[u8]]) {
+        let path = match words.len() {
+            1 => None,
+            2 => Some(vfs::join(&self.cwd, words[1])),
+            _ => {
+                queue_writeln!(self.output.clone(), "Error: Invalid Usage, see `help`");
+                return;
+            }
+        };
+
         queue_writeln!(self.output.clone(), "Starting usermode...");
         sleep_us(100000).await;
-        syscalls::usermode().await;
+        if let Err(error) = syscalls::usermode(path.as_deref()).await {
+            queue_writeln!(
+                self.output.clone(),
+                "Error: Couldn't start program: {}",
+                error
+            );
+        }
     }
 }
 
@@ -510,7 +864,7 @@ pub fn launch(input_queue: ipc::IpcRef, output_queue: ipc::IpcRef, colors: bool)
             input: input_queue,
             output: output_queue,
             root,
-            cwd: vec![],
+            cwd: b"/".to_vec(),
             colors,
         }
         .run()
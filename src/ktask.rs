@@ -6,7 +6,7 @@ use core::ptr::null;
 use core::sync::atomic::{AtomicUsize, Ordering};
 use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
 use core::{future::Future, pin::Pin};
-use spin::{Mutex, Once, RwLock};
+use spin::{Mutex, MutexGuard, Once, RwLock};
 
 pub(crate) static EXECUTOR: Once<SimpleExecutor> = Once::new();
 static PERF_INFO: Mutex<PerfInfo> = Mutex::new(PerfInfo::new());
@@ -61,6 +61,7 @@ pub struct TaskPerfInfo {
     pub uptime_us: u64,
     pub cpu_time_us: u64,
     pub total_yields: u64,
+    pub last_core: usize,
 }
 
 pub struct Task {
@@ -69,7 +70,8 @@ pub struct Task {
     start_time_us: u64,
     cpu_time_us: u64,
     total_yields: u64,
-    future: Mutex<Pin<Box<(dyn Future<Output = ()> + Send)>>>,
+    last_core: usize,
+    future: Mutex<Option<Pin<Box<(dyn Future<Output = ()> + Send)>>>>,
 }
 
 impl Task {
@@ -80,7 +82,8 @@ impl Task {
             start_time_us: get_uptime_us(),
             cpu_time_us: 0,
             total_yields: 0,
-            future: Mutex::new(Box::pin(future)),
+            last_core: 0,
+            future: Mutex::new(Some(Box::pin(future))),
         }
     }
 
@@ -94,12 +97,24 @@ impl Task {
             start_time_us: get_uptime_us(),
             cpu_time_us: 0,
             total_yields: 0,
-            future: Mutex::new(future),
+            last_core: 0,
+            future: Mutex::new(Some(future)),
         }
     }
 
     fn poll(&self, context: &mut Context) -> Poll<()> {
-        self.future.lock().as_mut().poll(context)
+        let mut future = self.future.lock();
+        if let Some(inner) = future.as_mut() {
+            let result = inner.as_mut().poll(context);
+            if result.is_ready() {
+                // Another core may still be holding a waker for this task, don't poll it again
+                *future = None;
+            }
+            result
+        } else {
+            // Finished on another core
+            Poll::Pending
+        }
     }
 }
 
@@ -140,17 +155,13 @@ impl SimpleExecutor {
                 let waker = task_waker(task_id);
                 let mut context = Context::from_waker(&waker);
 
-                let found_task;
-                if let Some(task) = self
+                // The task may have finished on another core since it was woken
+                let found_task = self
                     .tasks
                     .lock()
-                    .iter_mut()
+                    .iter()
                     .find(|t| t.read().id == task_id)
-                {
-                    found_task = Some(task.clone());
-                } else {
-                    panic!("no task?");
-                }
+                    .cloned();
 
                 if let Some(found_task) = found_task {
                     // Run the task
@@ -164,6 +175,7 @@ impl SimpleExecutor {
                     let mut locked_found_task = found_task.write();
                     locked_found_task.total_yields += 1;
                     locked_found_task.cpu_time_us += uptime_after - uptime_before;
+                    locked_found_task.last_core = unsafe { threads::current_core() };
                     let mut perf_info = PERF_INFO.lock();
                     perf_info.total_yields += 1;
                     perf_info.cpu_time_us += uptime_after - uptime_before;
@@ -189,8 +201,6 @@ impl SimpleExecutor {
                             // task still needs to run
                         }
                     }
-                } else {
-                    panic!("no task? 2");
                 }
             } else {
                 let _locked = irq_lock();
@@ -218,6 +228,7 @@ impl SimpleExecutor {
                     uptime_us: uptime - t.start_time_us,
                     cpu_time_us: t.cpu_time_us,
                     total_yields: t.total_yields,
+                    last_core: t.last_core,
                 }
             })
             .collect()
@@ -238,12 +249,10 @@ fn task_raw_waker(task_id: usize) -> RawWaker {
         task_raw_waker(task_id as usize)
     }
     fn wake(task_id: *const ()) {
-        let _locked = irq_lock();
-        EXECUTOR.wait().run_queue.lock().push_back(task_id as usize);
+        EXECUTOR.wait().wake(task_id as usize);
     }
     fn wake_by_ref(task_id: *const ()) {
-        let _locked = irq_lock();
-        EXECUTOR.wait().run_queue.lock().push_back(task_id as usize);
+        EXECUTOR.wait().wake(task_id as usize);
     }
 
     let vtable = &RawWakerVTable::new(clone, wake, wake_by_ref, no_op);
@@ -261,11 +270,11 @@ fn thread_raw_waker(thread_id: usize) -> RawWaker {
     }
     fn wake(thread_id: *const ()) {
         let _locked = irq_lock();
-        threads::EXECUTORS.get().unwrap()[0].wake(thread_id as usize);
+        threads::wake(thread_id as usize);
     }
     fn wake_by_ref(thread_id: *const ()) {
         let _locked = irq_lock();
-        threads::EXECUTORS.get().unwrap()[0].wake(thread_id as usize);
+        threads::wake(thread_id as usize);
     }
 
     let vtable = &RawWakerVTable::new(clone, wake, wake_by_ref, no_op);
@@ -295,6 +304,16 @@ pub async fn yield_now() {
     YieldNow(false).await
 }
 
+/// Locks `mutex` without spinning while another task holds it, for locks held across awaits
+pub async fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    loop {
+        if let Some(guard) = mutex.try_lock() {
+            return guard;
+        }
+        yield_now().await;
+    }
+}
+
 struct YieldNow(bool);
 
 impl Future for YieldNow {
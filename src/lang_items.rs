@@ -1,3 +1,4 @@
+use crate::arch::aarch64::{backtrace, power};
 use crate::prelude::*;
 use core::fmt;
 
@@ -29,43 +30,25 @@ unsafe fn panic_handler(info: &PanicInfo) -> ! {
     if let Some(location) = info.location() {
         println!("at {}", location);
     }
-    println!("--- Stack:");
-    let sp: u64;
-    let lr: u64;
-    asm!(
-        "mov {:x}, sp",
-        out(reg) sp,
-        options(nomem, nostack)
-    );
-    asm!(
-        "mov {:x}, lr",
-        out(reg) lr,
-        options(nomem, nostack)
-    );
-    println!("- LR: 0x{:x} ", lr);
-    for i in 0..128 {
-        let val = *(sp as *const u64).offset(i);
-        if (0xffffff8000080000..=0xffffff8000080000 + 0x03f400).contains(&val) {
-            println!("- {}: 0x{:x} ", i, val);
-        }
-    }
+    let fp = backtrace::current_fp();
+    println!("--- Backtrace:");
+    backtrace::print_backtrace(fp);
     println!("--- Bold Kernel v{} Panic! ---", env!("CARGO_PKG_VERSION"));
 
-    let mut message_buf = ArrayVec::<u8, 512>::new();
-    let _ = write!(FmtWriteAdapter(&mut message_buf, 0), "KERNEL PANIC: ");
-    if let Some(message) = info.message() {
-        let _ = writeln!(FmtWriteAdapter(&mut message_buf, 0), "{}", message);
+    let mut message_buf = ArrayVec::<u8, 4096>::new();
+    let mut message = FmtWriteAdapter(&mut message_buf, 0);
+    let _ = write!(message, "KERNEL PANIC: ");
+    if let Some(args) = info.message() {
+        let _ = writeln!(message, "{}", args);
     }
     if let Some(location) = info.location() {
-        let _ = write!(FmtWriteAdapter(&mut message_buf, 0), "at {}", location);
+        let _ = writeln!(message, "at {}", location);
     }
+    let _ = writeln!(message, "\nBacktrace:");
+    let _ = backtrace::write_backtrace(&mut message, fp);
     crate::arch::aarch64::framebuffer::panic(message_buf.as_slice());
 
-    loop {
-        asm!("wfi");
-    }
-    // qemu_exit::AArch64::new().exit(1)
-    // poweroff(false);
+    power::after_panic()
 }
 
 #[alloc_error_handler]
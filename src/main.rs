@@ -9,34 +9,42 @@
 #![no_std]
 #![no_main]
 #![feature(naked_functions)]
+#![feature(const_fn_fn_ptr_basics)]
+#![feature(const_mut_refs)]
 #![allow(dead_code)]
 
 extern crate alloc;
 
-use crate::arch::aarch64::{mmu, phymem, virtmem};
+use crate::arch::aarch64::{board, dtb, mmio, mmu, phymem, virtmem};
 use alloc::boxed::Box;
 
 pub(crate) mod arch;
+pub(crate) mod block;
+pub(crate) mod boot_options;
 pub(crate) mod console;
 pub(crate) mod driver_manager;
+pub(crate) mod elf;
 mod file_interface;
 pub(crate) mod fonts;
 pub(crate) mod framebuffer;
 pub(crate) mod framebuffer_console;
+pub(crate) mod fs;
+pub(crate) mod initrd;
 pub(crate) mod ipc;
 mod kshell;
 pub(crate) mod ktask;
 mod lang_items;
 pub(crate) mod prelude;
+pub(crate) mod process;
+pub(crate) mod random;
 pub(crate) mod sleep_queue;
 pub(crate) mod syscalls;
 pub(crate) mod threads;
 pub(crate) mod utils;
+pub(crate) mod vfs;
 
-use crate::arch::aarch64::uart1::init_uart1;
 use crate::prelude::*;
 
-use core::ptr::slice_from_raw_parts;
 pub(crate) use file_interface as fi;
 
 /// # Safety
@@ -65,10 +73,20 @@ pub unsafe extern "C" fn kmain(dtb_addr: PhyAddr) -> ! {
 /// Don't do complex things (e.g like printing) that might leave lowmem pointers dangling
 #[no_mangle]
 pub unsafe extern "C" fn kmain_mmu(dtb_addr: PhyAddr) -> ! {
+    // Find out where RAM and the peripherals are
+    let memory_layout = match dtb::init(dtb_addr) {
+        Ok(()) => dtb::memory_layout().ok(),
+        Err(()) => None,
+    };
+    board::map_peripherals(memory_layout.as_ref());
+
     // Physical Memory allocator
     {
         let mut phymem = phymem::PHYMEM_FREE_LIST.lock();
-        phymem.init();
+        match &memory_layout {
+            Some(layout) => phymem.init(&layout.memory, &layout.reserved),
+            None => phymem.init(&[board::FALLBACK_MEMORY], &[]),
+        }
     }
 
     // Create new stack for ourselves
@@ -82,10 +100,8 @@ pub unsafe extern "C" fn kmain_mmu(dtb_addr: PhyAddr) -> ! {
     // println!("[DBUG] core 0 stack at: {:?}", core0_stack);
     asm!(
         "mov sp, {:x}",
-        "mov x0, {:x}",
         "b kmain_on_stack",
         in(reg) (core0_stack.base.virt_mut() as usize) + core0_stack.len,
-        in(reg) dtb_addr.0,
         options(noreturn)
     )
 }
@@ -94,18 +110,17 @@ pub unsafe extern "C" fn kmain_mmu(dtb_addr: PhyAddr) -> ! {
 ///
 /// This function assumes:
 /// - It runs only once, on a clean machine
-/// - `dtb_addr` is either a valid dtb or null
 /// - The MMU is initialized with both low-half and high-half pointing to kernel memory
 /// - The stack was changed to the main stack of the kinit thread
 #[no_mangle]
-unsafe extern "C" fn kmain_on_stack(dtb_addr: PhyAddr) -> ! {
+unsafe extern "C" fn kmain_on_stack() -> ! {
     println!("[DBUG] Ejecting lowmem...");
     mmu::eject_lowmem();
     println!("[DBUG] Eject success!");
 
-    // Early console
-    init_uart1();
-    console::set_main_console_by_name(b"Raspberry Pi 3 UART1");
+    // Early console and log level
+    boot_options::init();
+    board::check_device_tree();
 
     driver_manager::early_init_all_drivers();
 
@@ -118,15 +133,17 @@ unsafe extern "C" fn kmain_on_stack(dtb_addr: PhyAddr) -> ! {
         virtmem::init(kernel_virtmem);
     }
 
-    if dtb_addr.0 != 0 {
-        let dtb_addr = dtb_addr.virt() as *const u8;
-        println!("[DBUG] DTB @ {:p} snippet:", dtb_addr);
-        let something = &*slice_from_raw_parts(dtb_addr, 128);
-        dump_hex_slice(something);
-        println!("[DBUG] Parsed:");
-        arch::aarch64::dtb::parse(dtb_addr);
+    if let Some(blob) = dtb::blob() {
+        println!("[DBUG] DTB @ {:?}", blob);
+        println!("[DBUG] {:?}", dtb::memory_layout());
+        if board::BOARD == board::Board::RaspberryPi3 {
+            println!("[DBUG] MMIO @ {:?}", mmio::mmio_base());
+        }
     } else {
-        println!("[DBUG] No DTB given");
+        println!(
+            "[WARN] No DTB given, assuming RAM is {:?}",
+            board::FALLBACK_MEMORY
+        );
     }
 
     // let mac = mailbox_methods::get_nic_mac().unwrap();
@@ -137,14 +154,20 @@ unsafe extern "C" fn kmain_on_stack(dtb_addr: PhyAddr) -> ! {
 
     // IPC
     ipc::init();
+    vfs::init();
+    random::init();
 
     // Start kernel tasks
     ktask::init();
+    syscalls::init();
 
     // Early console
-    driver_manager::init_driver_by_name(b"Raspberry Pi 3 UART1").warn();
+    driver_manager::init_driver_by_name(boot_options::early_console().driver_name).warn();
     println!("--- Bold Kernel v{} ---", env!("CARGO_PKG_VERSION"));
 
+    // Initial ramdisk
+    initrd::init();
+
     arch::aarch64::init::init_multicore();
 
     // // IPC test
@@ -191,7 +214,10 @@ unsafe extern "C" fn kmain_on_stack(dtb_addr: PhyAddr) -> ! {
     //     println!("[DBUG] Boxed val2: {} (at &{:p})", heap_val2, &heap_val2);
     // }
 
-    println!("[INFO] Loaded drivers: {:?}", driver_manager::drivers());
+    println!(
+        "[INFO] Loaded drivers: {:?}",
+        driver_manager::drivers().collect::<Vec<_>>()
+    );
 
     // Initialize main console, currently same as early-con
     // println!("[INFO] Initializing main console");
@@ -199,23 +225,16 @@ unsafe extern "C" fn kmain_on_stack(dtb_addr: PhyAddr) -> ! {
     // console::set_main_console_by_name(b"Raspberry Pi 3 UART1");
     // println!("[INFO] Main console working");
 
-    // Get kernel command line
-    // let args = mailbox_methods::get_kernel_args().unwrap();
-    // println!("[INFO] Kernel command line: {:?}", args.deref());
-
     driver_manager::init_all_drivers();
 
     // Get root clock
     // let rate = mailbox_methods::get_clock_rate(0).unwrap();
     // println!("[INFO] Root clock = {}", rate);
 
-    // Generate a random number
-    // entropy::init();
-    // let lucky_number = entropy::get();
-    // println!("[INFO] Today's lucky number: {}", lucky_number);
-
     // Draw something
-    framebuffer_console::init();
+    if boot_options::get().fbcon {
+        framebuffer_console::init();
+    }
 
     // Spawn some more tasks
     // async fn example_task(id: usize) {
@@ -241,19 +260,6 @@ unsafe extern "C" fn kmain_on_stack(dtb_addr: PhyAddr) -> ! {
     //     }
     // });
 
-    // let mut sdhc = arch::aarch64::sdhc::Sdhc::init().unwrap();
-    // let mut buf = [0; 512 / 4];
-    // sdhc.read_block(0, &mut buf).unwrap();
-    //
-    // println!("[INFO] EMMC: First block: ");
-    // dump_hex(&buf);
-
-    // Modify first dword to demonstrate writing
-    // buf[0] = 0xdeadbeef;
-    // sdhc.write_block(0, &buf).unwrap();
-    // sdhc.read_block(0, &mut buf).unwrap();
-    // assert_eq!(buf[0], 0xdeadbeef);
-
     spawn_task!(b"KShell.launcher", {
         let root = ipc::ROOT.read().as_ref().unwrap().clone();
 
@@ -276,24 +282,13 @@ unsafe extern "C" fn kmain_on_stack(dtb_addr: PhyAddr) -> ! {
         // )
         // .await;
 
-        let fb_shell_out = navigate(
-            root.clone(),
-            &[
-                ipc::well_known::ROOT_DEVICES,
-                ipc::well_known::DEVICES_RPI_FB_CON,
-                ipc::well_known::RPI_FB_CON0,
-                ipc::well_known::RPI_FB_CON_OUT,
-            ],
-        )
-        .await;
-
         let uart_shell_in = navigate(
             root.clone(),
             &[
-                ipc::well_known::ROOT_DEVICES,
-                ipc::well_known::DEVICES_RPI_UART,
-                ipc::well_known::RPI_UART1,
-                ipc::well_known::RPI_UART_IN,
+                board::SHELL_UART[0],
+                board::SHELL_UART[1],
+                board::SHELL_UART[2],
+                board::SHELL_UART_IN,
             ],
         )
         .await;
@@ -301,24 +296,36 @@ unsafe extern "C" fn kmain_on_stack(dtb_addr: PhyAddr) -> ! {
         let uart_shell_out = navigate(
             root.clone(),
             &[
-                ipc::well_known::ROOT_DEVICES,
-                ipc::well_known::DEVICES_RPI_UART,
-                ipc::well_known::RPI_UART1,
-                ipc::well_known::RPI_UART_OUT,
+                board::SHELL_UART[0],
+                board::SHELL_UART[1],
+                board::SHELL_UART[2],
+                board::SHELL_UART_OUT,
             ],
         )
         .await;
 
-        kshell::launch(
-            uart_shell_in,
-            ipc::spsc_mux::mux_into_outputs(uart_shell_out, fb_shell_out),
-            false,
-        );
+        let shell_out = if boot_options::get().fbcon {
+            let fb_shell_out = navigate(
+                root.clone(),
+                &[
+                    ipc::well_known::ROOT_DEVICES,
+                    ipc::well_known::DEVICES_RPI_FB_CON,
+                    ipc::well_known::RPI_FB_CON0,
+                    ipc::well_known::RPI_FB_CON_OUT,
+                ],
+            )
+            .await;
+            ipc::spsc_mux::mux_into_outputs(uart_shell_out, fb_shell_out)
+        } else {
+            uart_shell_out
+        };
+
+        kshell::launch(uart_shell_in, shell_out, false);
     });
 
     threads::init();
     arch::aarch64::interrupts::init();
-    // Now waiting for timer calibration interrupt to begin the scheduler
+    // Now waiting for the first timer interrupt to begin the scheduler
 
     loop {
         asm!("wfi");
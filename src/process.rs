@@ -0,0 +1,266 @@
+//! Usermode programs: the address space they run in, the IPC nodes they opened and their threads
+
+use crate::arch::aarch64::exceptions::ExceptionContext;
+use crate::arch::aarch64::mmu::{self, PageTable};
+use crate::arch::aarch64::phymem::{PAGE_SIZE, PHYMEM_FREE_LIST};
+use crate::elf;
+use crate::ipc::IpcRef;
+use crate::prelude::*;
+use crate::threads::{self, Thread};
+
+use core::pin::Pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::{Mutex, MutexGuard, RwLock};
+
+static PROCESS_COUNTER: AtomicUsize = AtomicUsize::new(1);
+/// Bitmap of the 8-bit ASIDs in use. ASID 0 is kept for kernel threads, which have no lower half
+static ASIDS: Mutex<[u64; 4]> = Mutex::new([1, 0, 0, 0]);
+/// Processes with threads left
+static PROCESSES: RwLock<Vec<Arc<Process>>> = RwLock::new(Vec::new());
+
+fn alloc_asid() -> Result<u16, ()> {
+    let _locked = irq_lock();
+    let mut asids = ASIDS.lock();
+    let (word, bits) = asids
+        .iter_mut()
+        .enumerate()
+        .find(|(_, bits)| **bits != u64::MAX)
+        .ok_or(())?;
+    let bit = (!*bits).trailing_zeros();
+    *bits |= 1 << bit;
+    Ok((word * 64) as u16 + bit as u16)
+}
+
+/// Flushes the TLB entries tagged with `asid` on every core, so it can be handed out again
+unsafe fn free_asid(asid: u16) {
+    asm!(
+        "dsb ishst",
+        "tlbi aside1is, {}",
+        "dsb ish",
+        "isb",
+        in(reg) (asid as u64) << 48,
+        options(nostack)
+    );
+    let _locked = irq_lock();
+    ASIDS.lock()[asid as usize / 64] &= !(1 << (asid % 64));
+}
+
+/// Page tables for the lower half, and the pages mapped in them. Everything goes back to the
+/// allocators when it's dropped. Its pages are mapped non-global, tagged with its own ASID, so
+/// switching to it doesn't need a TLB flush
+pub struct AddressSpace {
+    root: Pin<Box<PageTable>>,
+    asid: u16,
+    frames: Vec<PhyAddr>,
+}
+
+impl AddressSpace {
+    /// Fails if every ASID is taken
+    pub fn new() -> Result<Self, ()> {
+        Ok(AddressSpace {
+            root: Box::pin(unsafe { PageTable::new() }),
+            asid: alloc_asid()?,
+            frames: Vec::new(),
+        })
+    }
+
+    /// A zeroed page, freed with the address space
+    pub unsafe fn alloc_page(&mut self) -> Result<PhySlice, ()> {
+        let page = PHYMEM_FREE_LIST.lock().alloc_pages(1).ok_or(())?;
+        self.frames.push(page.base);
+        page.virt_mut().fill(0);
+        Ok(page)
+    }
+
+    pub unsafe fn map(&mut self, vaddr: usize, paddr: PhyAddr, attrs: u64) -> Result<(), ()> {
+        mmu::vmap_to(&mut self.root, vaddr, paddr, attrs | mmu::PT_NG)
+    }
+
+    /// The value of `ttbr0_el1` for running in it: The ASID, and the root table
+    pub fn ttbr0(&self) -> u64 {
+        ((self.asid as u64) << 48) | ((self.root.0.as_ptr() as u64) & 0x7FFFFFFFFF)
+    }
+
+    /// Copies `data` to `vaddr`, going through its page tables so it works whatever ttbr0 is.
+    /// Fails if usermode couldn't write there
+    pub unsafe fn copy_to(&self, vaddr: usize, data: &[u8]) -> Result<(), ()> {
+        match vaddr.checked_add(data.len()) {
+            Some(end) if end <= elf::USER_END => {}
+            _ => return Err(()),
+        }
+        let mut copied = 0;
+        while copied < data.len() {
+            let addr = vaddr + copied;
+            let pte = mmu::page_entry(&self.root, addr).ok_or(())?;
+            if pte & mmu::PT_USER == 0 || pte & mmu::PT_RO != 0 {
+                return Err(());
+            }
+            let offset = addr % PAGE_SIZE;
+            let len = (PAGE_SIZE - offset).min(data.len() - copied);
+            let dest = PhyAddr((pte & 0x7FFFFFF000) as usize + offset).virt() as *mut u8;
+            core::ptr::copy_nonoverlapping(data[copied..].as_ptr(), dest, len);
+            copied += len;
+        }
+        Ok(())
+    }
+
+    /// Pages from `alloc_page`
+    pub fn page_count(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Page tables, the lvl1 one included
+    pub fn table_count(&self) -> usize {
+        unsafe { mmu::count_tables(&self.root) + 1 }
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        unsafe {
+            free_asid(self.asid);
+            mmu::free_tables(&mut self.root);
+            let mut phymem = PHYMEM_FREE_LIST.lock();
+            for frame in self.frames.drain(..) {
+                phymem.free_page(frame);
+            }
+        }
+    }
+}
+
+pub struct Process {
+    id: usize,
+    /// The program's path, or `init` for the built-in one
+    name: Vec<u8>,
+    ttbr0: u64,
+    address_space: Mutex<AddressSpace>,
+    /// Indexed by handle, closed ones are `None`
+    handles: Mutex<Vec<Option<IpcRef>>>,
+    /// Thread ids
+    threads: Mutex<Vec<usize>>,
+}
+
+impl Process {
+    /// An empty process, with nothing mapped and no threads yet
+    pub fn new(name: &[u8]) -> Result<Arc<Process>, ()> {
+        let address_space = AddressSpace::new()?;
+        Ok(Arc::new(Process {
+            id: PROCESS_COUNTER.fetch_add(1, Ordering::SeqCst),
+            name: name.to_vec(),
+            ttbr0: address_space.ttbr0(),
+            address_space: Mutex::new(address_space),
+            handles: Mutex::new(Vec::new()),
+            threads: Mutex::new(Vec::new()),
+        }))
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn name(&self) -> &[u8] {
+        &self.name
+    }
+
+    /// Doesn't change over the life of the process, so switching threads never takes a lock
+    pub fn ttbr0(&self) -> u64 {
+        self.ttbr0
+    }
+
+    pub fn address_space(&self) -> MutexGuard<AddressSpace> {
+        self.address_space.lock()
+    }
+
+    /// Runs a new thread of this process from `state`
+    pub fn spawn_thread(self: &Arc<Self>, name: &'static [u8], state: ExceptionContext) {
+        let thread = Thread::new(name, state, Some(self.clone()));
+        {
+            let _locked = irq_lock();
+            let mut threads = self.threads.lock();
+            if threads.is_empty() {
+                PROCESSES.write().push(self.clone());
+            }
+            threads.push(thread.id());
+        }
+        threads::spawn(thread);
+    }
+
+    /// Called by `Thread::kill`. The process exits with its last thread: its handles are closed
+    /// right away, and its address space is freed once the last reference to it is gone
+    pub(crate) fn thread_exited(&self, tid: usize) {
+        let _locked = irq_lock();
+        let mut threads = self.threads.lock();
+        threads.retain(|t| *t != tid);
+        if threads.is_empty() {
+            println!(
+                "[INFO] Process #{} \"{}\" exited",
+                self.id,
+                AsciiStr(&self.name)
+            );
+            PROCESSES.write().retain(|process| process.id != self.id);
+            self.handles.lock().clear();
+        }
+    }
+
+    /// Returns the new handle, the lowest free one
+    pub fn open_handle(&self, node: IpcRef) -> usize {
+        let _locked = irq_lock();
+        let mut handles = self.handles.lock();
+        match handles.iter().position(|handle| handle.is_none()) {
+            Some(handle) => {
+                handles[handle] = Some(node);
+                handle
+            }
+            None => {
+                handles.push(Some(node));
+                handles.len() - 1
+            }
+        }
+    }
+
+    pub fn handle(&self, handle: usize) -> Option<IpcRef> {
+        let _locked = irq_lock();
+        self.handles.lock().get(handle).cloned().flatten()
+    }
+
+    pub fn close_handle(&self, handle: usize) -> Result<(), ()> {
+        let _locked = irq_lock();
+        let mut handles = self.handles.lock();
+        handles
+            .get_mut(handle)
+            .and_then(|handle| handle.take())
+            .ok_or(())?;
+        while let Some(None) = handles.last() {
+            handles.pop();
+        }
+        Ok(())
+    }
+}
+
+pub struct ProcessInfo {
+    pub id: usize,
+    pub name: Vec<u8>,
+    pub threads: usize,
+    pub handles: usize,
+    pub pages: usize,
+    pub tables: usize,
+}
+
+pub fn list() -> Vec<ProcessInfo> {
+    let _locked = irq_lock();
+    let processes = PROCESSES.read().clone();
+    processes
+        .iter()
+        .map(|process| {
+            let address_space = process.address_space.lock();
+            ProcessInfo {
+                id: process.id,
+                name: process.name.clone(),
+                threads: process.threads.lock().len(),
+                handles: process.handles.lock().iter().flatten().count(),
+                pages: address_space.page_count(),
+                tables: address_space.table_count(),
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,227 @@
+//! The kernel's CSPRNG: ChaCha20 with fast key erasure, seeded by entropy drivers, timer jitter
+//! and the bootloader's `/chosen/rng-seed`
+
+use crate::arch::aarch64::mmio::sleep_us;
+use crate::arch::aarch64::{dtb, timer};
+use crate::ipc::{IpcNode, IpcRef};
+use crate::prelude::*;
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use futures::prelude::stream::BoxStream;
+use spin::Mutex;
+
+/// Credited entropy needed before `random` stops blocking
+const SEED_BITS: u64 = 256;
+/// Timer jitter samples taken by `init`, each credited as 1/16 of a bit
+const JITTER_SAMPLES: usize = 1024;
+
+static CSPRNG: Mutex<ChaCha20Rng> = Mutex::new(ChaCha20Rng::new());
+static ENTROPY_BITS: AtomicU64 = AtomicU64::new(0);
+static SEEDED: AtomicBool = AtomicBool::new(false);
+
+// ----- ChaCha20 -----
+
+/// "expand 32-byte k"
+const CHACHA_CONSTANTS: [u32; 4] = [0x61707865, 0x3320646e, 0x79622d32, 0x6b206574];
+const CHACHA_BLOCK_LEN: usize = 64;
+const KEY_LEN: usize = 32;
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+/// The ChaCha20 block function (RFC 8439), with a 64-bit counter and nonce
+fn chacha20_block(key: &[u8; KEY_LEN], counter: u64, nonce: u64) -> [u8; CHACHA_BLOCK_LEN] {
+    let mut input = [0u32; 16];
+    input[..4].copy_from_slice(&CHACHA_CONSTANTS);
+    for (word, bytes) in input[4..12].iter_mut().zip(key.chunks_exact(4)) {
+        *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    }
+    input[12] = counter as u32;
+    input[13] = (counter >> 32) as u32;
+    input[14] = nonce as u32;
+    input[15] = (nonce >> 32) as u32;
+
+    let mut state = input;
+    for _ in 0..10 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+
+    let mut block = [0u8; CHACHA_BLOCK_LEN];
+    for ((bytes, word), input) in block.chunks_exact_mut(4).zip(state).zip(input) {
+        bytes.copy_from_slice(&word.wrapping_add(input).to_le_bytes());
+    }
+    block
+}
+
+/// Nonces keep the keystream, rekeying and mixing uses of a key apart
+const NONCE_OUTPUT: u64 = 0;
+const NONCE_REKEY: u64 = 1;
+const NONCE_MIX: u64 = 2;
+
+struct ChaCha20Rng {
+    key: [u8; KEY_LEN],
+}
+
+impl ChaCha20Rng {
+    const fn new() -> Self {
+        ChaCha20Rng { key: [0; KEY_LEN] }
+    }
+
+    /// Replaces the key with one derived from it, so earlier output can't be recovered
+    fn rekey(&mut self, nonce: u64) {
+        let block = chacha20_block(&self.key, 0, nonce);
+        self.key.copy_from_slice(&block[..KEY_LEN]);
+    }
+
+    fn mix(&mut self, entropy: &[u8]) {
+        for chunk in entropy.chunks(KEY_LEN) {
+            for (key_byte, byte) in self.key.iter_mut().zip(chunk) {
+                *key_byte ^= byte;
+            }
+            self.rekey(NONCE_MIX);
+        }
+    }
+
+    fn fill(&mut self, dest: &mut [u8]) {
+        for (counter, chunk) in dest.chunks_mut(CHACHA_BLOCK_LEN).enumerate() {
+            let block = chacha20_block(&self.key, counter as u64, NONCE_OUTPUT);
+            chunk.copy_from_slice(&block[..chunk.len()]);
+        }
+        self.rekey(NONCE_REKEY);
+    }
+}
+
+// ----- Kernel API -----
+
+/// Mixes `entropy` into the CSPRNG, crediting it with `bits` of actual randomness
+pub fn add_entropy(entropy: &[u8], bits: u64) {
+    CSPRNG.lock().mix(entropy);
+    let total = ENTROPY_BITS.fetch_add(bits, Ordering::Relaxed) + bits;
+    if total >= SEED_BITS && !SEEDED.swap(true, Ordering::Relaxed) {
+        println!("[INFO] Random number generator seeded");
+    }
+}
+
+/// Whether enough entropy was collected for the output to be unpredictable
+pub fn is_seeded() -> bool {
+    SEEDED.load(Ordering::Relaxed)
+}
+
+/// Fills `dest` with random bytes, even if the CSPRNG isn't seeded yet
+pub fn fill(dest: &mut [u8]) {
+    CSPRNG.lock().fill(dest);
+}
+
+/// Fills `dest` with random bytes once the CSPRNG is seeded
+pub async fn fill_seeded(dest: &mut [u8]) {
+    while !is_seeded() {
+        sleep_us(10_000).await;
+    }
+    fill(dest);
+}
+
+pub fn get_u64() -> u64 {
+    let mut bytes = [0u8; 8];
+    fill(&mut bytes);
+    u64::from_le_bytes(bytes)
+}
+
+/// Mixes in timer jitter and the bootloader's seed, entropy drivers add more later
+pub fn init() {
+    // The time an uncached loop takes varies a little, keep the lowest bits
+    let mut jitter = [0u8; JITTER_SAMPLES];
+    let mut last = timer::counter();
+    for sample in jitter.iter_mut() {
+        let mut spin = 0u64;
+        for i in 0..64 {
+            spin = unsafe { core::ptr::read_volatile(&spin) }.wrapping_add(i);
+        }
+        let now = timer::counter();
+        *sample = now.wrapping_sub(last) as u8 ^ spin as u8;
+        last = now;
+    }
+    add_entropy(&jitter, (JITTER_SAMPLES / 16) as u64);
+
+    if let Some(seed) = dtb::property(&["chosen"], "rng-seed") {
+        add_entropy(seed, seed.len() as u64 * 8);
+    }
+}
+
+// ----- IPC -----
+
+/// A queue node that reads random bytes. The blocking one (`random`) waits until the CSPRNG is
+/// seeded, the other (`urandom`) never waits
+pub struct IpcRandom {
+    blocking: bool,
+}
+
+impl IpcRandom {
+    pub fn new(blocking: bool) -> Arc<Self> {
+        Arc::new(Self { blocking })
+    }
+}
+
+#[async_trait]
+impl IpcNode for IpcRandom {
+    fn dir_list<'a>(self: Arc<Self>) -> Option<BoxStream<'a, IpcRef>> {
+        None
+    }
+
+    async fn dir_get(self: Arc<Self>, _id: u64) -> Option<IpcRef> {
+        None
+    }
+
+    async fn dir_create(self: Arc<Self>, _id: u64) -> Option<IpcRef> {
+        None
+    }
+
+    async fn dir_link(
+        self: Arc<Self>,
+        _id: u64,
+        _node: Arc<dyn IpcNode + Send + Sync>,
+    ) -> Option<IpcRef> {
+        None
+    }
+
+    /// Writes are mixed in, but not credited
+    fn queue_write(self: Arc<Self>, data: &[u8]) -> Result<usize, ()> {
+        add_entropy(data, 0);
+        Ok(data.len())
+    }
+
+    async fn queue_read(self: Arc<Self>, dest: &mut [u8]) -> Option<usize> {
+        if self.blocking {
+            fill_seeded(dest).await;
+        } else {
+            fill(dest);
+        }
+        Some(dest.len())
+    }
+
+    async fn read_at(self: Arc<Self>, _offset: u64, _dest: &mut [u8]) -> Option<usize> {
+        None
+    }
+
+    fn describe(&self) -> [u8; 4] {
+        *b"RAND"
+    }
+
+    fn name(&self) -> Option<&[u8]> {
+        Some(if self.blocking { b"random" } else { b"urandom" })
+    }
+}
@@ -8,26 +8,21 @@ use spin::{Mutex, Once};
 static SLEEP_QUEUE: Once<Mutex<VecDeque<(u64, Waker)>>> = Once::new();
 const EARLY_WAKE_MARGIN_US: u64 = 3000; // 3ms resolution
 
-pub fn pop() -> (u64, Option<Waker>) {
+/// Wakes everything that's due, returns the time until the next wake-up
+pub fn wake_expired() -> u64 {
     let _locked = irq_lock();
 
     let current_time = get_uptime_us();
     let mut sleep_queue = SLEEP_QUEUE.call_once(|| Mutex::new(VecDeque::new())).lock();
-    loop {
-        let wake_time = sleep_queue.pop_front();
-        if let Some((wake_time, waker)) = wake_time {
-            if wake_time <= current_time + EARLY_WAKE_MARGIN_US {
-                // Wake immediately and continue
-                waker.wake_by_ref();
-                continue;
-            } else {
-                return (wake_time - current_time, Some(waker));
-            }
-        } else {
-            // Default to 1 second wake-ups
-            return (1000 * 1000, None);
+    while let Some((wake_time, _)) = sleep_queue.front() {
+        if *wake_time > current_time + EARLY_WAKE_MARGIN_US {
+            return *wake_time - current_time;
         }
+        let (_, waker) = sleep_queue.pop_front().unwrap();
+        waker.wake();
     }
+    // Default to 1 second wake-ups
+    1000 * 1000
 }
 
 pub fn push(wake_time: u64, waker: Waker) {
@@ -1,14 +1,22 @@
 use crate::arch::aarch64::exceptions::ExceptionContext;
 use crate::arch::aarch64::mmio::get_uptime_us;
 use crate::arch::aarch64::mmu;
-use crate::arch::aarch64::mmu::PageTable;
-use crate::ktask::thread_waker;
+use crate::arch::aarch64::phymem::PAGE_SIZE;
+use crate::ktask::{null_waker, thread_waker};
 use crate::prelude::*;
-use crate::threads::{current_core, Thread};
-use crate::{sleep_queue, threads};
+use crate::process::Process;
+use crate::threads::current_core;
+use crate::{boot_options, elf, ipc, sleep_queue, threads, vfs};
+use core::fmt;
+use core::future::Future;
 use core::ops::Deref;
-use core::ptr::slice_from_raw_parts;
+use core::pin::Pin;
+use core::ptr::{slice_from_raw_parts, slice_from_raw_parts_mut};
+use core::task::{Context, Poll, Waker};
+use futures::future::poll_fn;
+use futures::StreamExt;
 use num_enum::TryFromPrimitive;
+use spin::Mutex;
 
 #[repr(u64)]
 #[derive(TryFromPrimitive)]
@@ -18,19 +26,220 @@ pub enum Syscall {
     KLogWriteInt = 2,
     USleep = 3,
     GetTid = 4,
+    /// (path, path length) -> handle
+    IpcOpen = 5,
+    /// (handle)
+    IpcClose = 6,
+    /// (handle, ids, max ids) -> entries in the directory, up to `max ids` of their ids are
+    /// written
+    IpcDirList = 7,
+    /// (handle, id) -> handle
+    IpcDirGet = 8,
+    /// (directory handle, id, handle)
+    IpcDirLink = 9,
+    /// (handle, buffer, length) -> bytes read, blocks until there are some
+    IpcQueueRead = 10,
+    /// (handle, data, length) -> bytes written
+    IpcQueueWrite = 11,
 }
 
-unsafe fn cstr_ptr_to_asciistr(ptr: *const u8) -> AsciiStr<'static> {
-    let mut length = 0;
-    for i in 0.. {
-        if *ptr.offset(i) == 0 {
-            length = i;
-            break;
+/// Returned in x0 by failed syscalls
+const SYSCALL_ERROR: u64 = u64::MAX;
+
+/// `len` bytes of usermode memory, if usermode can read them. Empty whatever `ptr` is when `len`
+/// is 0
+unsafe fn user_slice<'a>(ptr: u64, len: u64) -> Result<&'a [u8], ()> {
+    if len == 0 {
+        return Ok(&[]);
+    }
+    if !mmu::user_accessible(ptr as usize, len as usize, false) {
+        return Err(());
+    }
+    Ok(&*slice_from_raw_parts(ptr as *const u8, len as usize))
+}
+
+/// The NUL-terminated string at `ptr` in usermode memory, checked a page at a time while looking
+/// for the NUL
+unsafe fn user_cstr<'a>(ptr: u64) -> Result<&'a [u8], ()> {
+    let mut len = 0;
+    loop {
+        let start = ptr.checked_add(len).ok_or(())?;
+        let page_end = (start | (PAGE_SIZE as u64 - 1)).checked_add(1).ok_or(())?;
+        let chunk = user_slice(start, page_end - start)?;
+        match chunk.iter().position(|c| *c == 0) {
+            Some(nul) => return user_slice(ptr, len + nul as u64),
+            None => len += chunk.len() as u64,
+        }
+    }
+}
+
+/// `len` bytes of usermode memory, if usermode can write them. Empty whatever `ptr` is when `len`
+/// is 0
+unsafe fn user_slice_mut<'a>(ptr: u64, len: u64) -> Result<&'a mut [u8], ()> {
+    if len == 0 {
+        return Ok(&mut []);
+    }
+    if !mmu::user_accessible(ptr as usize, len as usize, true) {
+        return Err(());
+    }
+    Ok(&mut *slice_from_raw_parts_mut(ptr as *mut u8, len as usize))
+}
+
+// ----- Blocking syscalls -----
+
+/// A syscall that was pending when its thread was switched away from, with what resumes the
+/// thread once it's done
+type BlockedSyscall = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Handed from the syscall handler to the `Syscalls` task
+static BLOCKED_SYSCALLS: Mutex<Vec<BlockedSyscall>> = Mutex::new(Vec::new());
+/// Wakes the `Syscalls` task, `None` until it first ran
+static SYSCALLS_WAKER: Mutex<Option<Waker>> = Mutex::new(None);
+
+/// Starts the task that finishes blocked syscalls. Every one of them is polled each time it's
+/// woken up, there's only ever a few
+pub fn init() {
+    spawn_task!(b"Syscalls", {
+        let mut blocked: Vec<BlockedSyscall> = Vec::new();
+        poll_fn(|cx| {
+            {
+                let _locked = irq_lock();
+                *SYSCALLS_WAKER.lock() = Some(cx.waker().clone());
+                blocked.append(&mut BLOCKED_SYSCALLS.lock());
+            }
+            let mut i = 0;
+            while i < blocked.len() {
+                if blocked[i].as_mut().poll(cx).is_ready() {
+                    blocked.swap_remove(i);
+                } else {
+                    i += 1;
+                }
+            }
+            Poll::<()>::Pending
+        })
+        .await
+    });
+}
+
+/// Runs a syscall that may have to wait. It's polled once right away; if it's pending, the thread
+/// is switched away from and the `Syscalls` task keeps polling the same future, then puts its
+/// result in the thread's x0 and wakes the thread up. `None` if the thread was switched away from
+///
+/// The future can't borrow usermode memory, it may finish while another address space is mapped
+unsafe fn run_blocking(
+    e: &mut ExceptionContext,
+    future: impl Future<Output = Result<u64, ()>> + Send + 'static,
+) -> Option<u64> {
+    let waker = SYSCALLS_WAKER.lock().clone().unwrap_or_else(null_waker);
+    let mut future = Box::pin(future);
+    if let Poll::Ready(result) = future.as_mut().poll(&mut Context::from_waker(&waker)) {
+        return Some(result.unwrap_or(SYSCALL_ERROR));
+    }
+
+    let executor = &threads::EXECUTORS.get().unwrap()[current_core()];
+    let thread = executor.current_thread().unwrap();
+    // Saves the thread's state first, so the result can't be overwritten
+    executor.switch(e);
+    BLOCKED_SYSCALLS.lock().push(Box::pin(async move {
+        let result = future.await.unwrap_or(SYSCALL_ERROR);
+        let _locked = irq_lock();
+        let tid = {
+            let mut thread = thread.write();
+            thread.set_syscall_result(result);
+            thread.id()
+        };
+        threads::wake(tid);
+    }));
+    waker.wake();
+    None
+}
+
+/// `Ok(None)` if the thread blocked
+unsafe fn handle_ipc_syscall(
+    e: &mut ExceptionContext,
+    syscall_no: Syscall,
+) -> Result<Option<u64>, ()> {
+    let executor = &threads::EXECUTORS.get().unwrap()[current_core()];
+    let process = executor
+        .current_thread()
+        .unwrap()
+        .read()
+        .process()
+        .cloned()
+        .ok_or(())?;
+    let [arg0, arg1, arg2] = [e.gpr[0], e.gpr[1], e.gpr[2]];
+    let node = || process.handle(arg0 as usize).ok_or(());
+
+    match syscall_no {
+        Syscall::IpcOpen => {
+            let path = user_slice(arg0, arg1)?.to_vec();
+            Ok(run_blocking(e, async move {
+                let node = ipc::lookup(&path).await.ok_or(())?;
+                Ok(process.open_handle(node) as u64)
+            }))
+        }
+        Syscall::IpcClose => {
+            process.close_handle(arg0 as usize)?;
+            Ok(Some(0))
+        }
+        Syscall::IpcDirList => {
+            let dir = node()?;
+            let max_ids = user_slice_mut(arg1, arg2.checked_mul(8).ok_or(())?)?.len() / 8;
+            Ok(run_blocking(e, async move {
+                let mut stream = dir.dir_list().ok_or(())?;
+                let mut entries = Vec::new();
+                while let Some(entry) = stream.next().await {
+                    entries.push(entry.id);
+                }
+                let ids = entries
+                    .iter()
+                    .take(max_ids)
+                    .flat_map(|id| id.to_le_bytes())
+                    .collect::<Vec<u8>>();
+                copy_to_process(&process, arg1, &ids)?;
+                Ok(entries.len() as u64)
+            }))
+        }
+        Syscall::IpcDirGet => {
+            let dir = node()?;
+            Ok(run_blocking(e, async move {
+                let node = dir.dir_get(arg1).await.ok_or(())?;
+                Ok(process.open_handle(node) as u64)
+            }))
         }
+        Syscall::IpcDirLink => {
+            let dir = node()?;
+            let linked = process.handle(arg2 as usize).ok_or(())?;
+            Ok(run_blocking(e, async move {
+                dir.dir_link(arg1, linked.inner).await.ok_or(())?;
+                Ok(0)
+            }))
+        }
+        Syscall::IpcQueueRead => {
+            let queue = node()?;
+            let mut buf = vec![0; user_slice_mut(arg1, arg2)?.len()];
+            // It could wait forever for nothing
+            if buf.is_empty() {
+                return Ok(Some(0));
+            }
+            Ok(run_blocking(e, async move {
+                let len = queue.queue_read(&mut buf).await.ok_or(())?;
+                copy_to_process(&process, arg1, &buf[..len])?;
+                Ok(len as u64)
+            }))
+        }
+        Syscall::IpcQueueWrite => {
+            let data = user_slice(arg1, arg2)?;
+            Ok(Some(node()?.queue_write(data)? as u64))
+        }
+        _ => Err(()),
     }
+}
 
-    let slice = &*slice_from_raw_parts(ptr, length as usize);
-    AsciiStr(slice)
+/// Copies `data` to `ptr` in `process`, from any thread
+fn copy_to_process(process: &Process, ptr: u64, data: &[u8]) -> Result<(), ()> {
+    let _locked = irq_lock();
+    unsafe { process.address_space().copy_to(ptr as usize, data) }
 }
 
 pub unsafe fn handle_syscall(e: &mut ExceptionContext, syscall_no: Syscall) {
@@ -42,10 +251,10 @@ pub unsafe fn handle_syscall(e: &mut ExceptionContext, syscall_no: Syscall) {
             current_thread.read().kill();
             executor.switch(e);
         }
-        Syscall::KLogWrite => {
-            let format = cstr_ptr_to_asciistr(e.gpr[0] as *const u8);
-            println!("[UM] {}", format);
-        }
+        Syscall::KLogWrite => match user_cstr(e.gpr[0]) {
+            Ok(message) => println!("[UM] {}", AsciiStr(message)),
+            Err(()) => e.gpr[0] = SYSCALL_ERROR,
+        },
         Syscall::KLogWriteInt => {
             println!("[UM] 0x{:x}", e.gpr[0]);
         }
@@ -65,6 +274,17 @@ pub unsafe fn handle_syscall(e: &mut ExceptionContext, syscall_no: Syscall) {
             let executor = &threads::EXECUTORS.get().unwrap()[current_core];
             e.gpr[0] = executor.current_thread().unwrap().read().id() as u64;
         }
+        Syscall::IpcOpen
+        | Syscall::IpcClose
+        | Syscall::IpcDirList
+        | Syscall::IpcDirGet
+        | Syscall::IpcDirLink
+        | Syscall::IpcQueueRead
+        | Syscall::IpcQueueWrite => match handle_ipc_syscall(e, syscall_no) {
+            Ok(Some(result)) => e.gpr[0] = result,
+            Ok(None) => {}
+            Err(()) => e.gpr[0] = SYSCALL_ERROR,
+        },
     }
 }
 
@@ -79,59 +299,119 @@ impl<const LEN: usize> Deref for PageAligned<LEN> {
     }
 }
 
-pub async fn usermode() {
-    // Prepare code
-    const CODE_BYTES: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/example_app.bin"));
-    const CODE_LENGTH: usize = CODE_BYTES.len();
-
-    #[no_mangle]
-    #[link_section = ".text"]
-    static CODE: PageAligned<CODE_LENGTH> = PageAligned(*include_bytes!(concat!(
-        env!("OUT_DIR"),
-        "/example_app.bin"
-    )));
-
-    let code: extern "C" fn() = unsafe { core::mem::transmute(&*CODE as *const _) };
-
-    // Prepare stack
-    let stack = Box::pin(PageAligned([0u8; 4096 * 8]));
-
-    unsafe {
-        // Prepare page tables
-        let mut page_tables = Box::pin(PageTable::new());
-        set_msr!(ttbr0_el1, (page_tables.0.as_ptr() as u64) & 0x7FFFFFFFFF);
-        const PAGE_FLAGS: u64 = mmu::PT_USER | // non-privileged
-            mmu::PT_ISH | // inner shareable
-            mmu::PT_MEM; // normal memory
-        mmu::vmap_to(
-            &mut page_tables,
-            0x10000,
-            PhyAddr(stack.0.as_ptr() as usize & 0x7FFFFFFFFF),
-            PAGE_FLAGS | mmu::PT_RW | mmu::PT_NX | mmu::PT_USER,
-        )
-        .unwrap();
-        mmu::vmap_to(
-            &mut page_tables,
-            0x20000,
-            PhyAddr(code as usize & 0x7FFFFFFFFF),
-            PAGE_FLAGS | mmu::PT_RO | mmu::PT_USER,
-        )
-        .unwrap();
-
-        let thread = Thread::new(
-            b"Usermode Runner",
-            ExceptionContext {
-                gpr: [
-                    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-                    0, 0, 0, 0,
-                ],
-                lr: 0,
-                pc: 0x20000,
-                sp: 0x20000,
-                spsr: 0x340,
-            },
-            Some(page_tables),
-        );
-        threads::EXECUTORS.get().unwrap()[0].spawn(thread);
+/// The example program, built with the kernel
+const BUILTIN_PROGRAM: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/example_app"));
+
+/// Top of usermode stacks, the page above is left unmapped as a guard
+const STACK_TOP: usize = elf::USER_END - PAGE_SIZE;
+
+/// Why a program couldn't be started
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ProgramError {
+    /// Its path couldn't be opened or read
+    NotFound,
+    /// Not a static AArch64 ELF executable, or its segments are in the way of the stack
+    InvalidExecutable,
+    /// Out of memory or ASIDs while setting up its process
+    OutOfMemory,
+}
+
+impl fmt::Display for ProgramError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProgramError::NotFound => write!(f, "No such file"),
+            ProgramError::InvalidExecutable => {
+                write!(f, "It has to be a static AArch64 ELF executable")
+            }
+            ProgramError::OutOfMemory => write!(f, "Out of memory"),
+        }
+    }
+}
+
+async fn read_program(path: &[u8]) -> Result<Vec<u8>, ProgramError> {
+    let file = vfs::open(path, vfs::OPEN_READ)
+        .await
+        .map_err(|_| ProgramError::NotFound)?;
+    file.read_to_end().await.map_err(|_| ProgramError::NotFound)
+}
+
+/// Maps `image`'s segments and a stack into a new process called `name`, then runs it in a new
+/// thread
+unsafe fn spawn_program(name: &[u8], image: &[u8]) -> Result<(), ProgramError> {
+    let executable = elf::Executable::parse(image).map_err(|_| ProgramError::InvalidExecutable)?;
+
+    // Nothing is mapped in the guard pages around the stack either
+    let stack_pages = boot_options::get()
+        .init_stack
+        .checked_add(PAGE_SIZE - 1)
+        .ok_or(ProgramError::OutOfMemory)?
+        / PAGE_SIZE;
+    let stack_bottom = (stack_pages + 1)
+        .checked_mul(PAGE_SIZE)
+        .and_then(|len| STACK_TOP.checked_sub(len))
+        .ok_or(ProgramError::OutOfMemory)?
+        + PAGE_SIZE;
+    if executable
+        .segments
+        .iter()
+        .any(|segment| segment.overlaps(stack_bottom - PAGE_SIZE, elf::USER_END))
+    {
+        return Err(ProgramError::InvalidExecutable);
+    }
+
+    // Whatever was allocated goes back with the process if this fails
+    let process = Process::new(name).map_err(|_| ProgramError::OutOfMemory)?;
+    {
+        let mut address_space = process.address_space();
+        executable
+            .load(&mut address_space)
+            .map_err(|_| ProgramError::OutOfMemory)?;
+        for page in 0..stack_pages {
+            let frame = address_space
+                .alloc_page()
+                .map_err(|_| ProgramError::OutOfMemory)?;
+            address_space
+                .map(
+                    stack_bottom + page * PAGE_SIZE,
+                    frame.base,
+                    mmu::PT_USER | mmu::PT_ISH | mmu::PT_MEM | mmu::PT_RW | mmu::PT_NX,
+                )
+                .map_err(|_| ProgramError::OutOfMemory)?;
+        }
+    }
+
+    process.spawn_thread(
+        b"main",
+        ExceptionContext {
+            gpr: [0; 30],
+            lr: 0,
+            pc: executable.entry as u64,
+            sp: STACK_TOP as u64,
+            spsr: 0x340,
+        },
+    );
+    Ok(())
+}
+
+async fn start_program(path: &[u8]) -> Result<(), ProgramError> {
+    let image = read_program(path).await?;
+    unsafe { spawn_program(path, &image) }
+}
+
+/// Runs the program at `path`, or else the `init=` one, or else the built-in one
+pub async fn usermode(path: Option<&[u8]>) -> Result<(), ProgramError> {
+    if let Some(path) = path {
+        return start_program(path).await;
+    }
+    if let Some(path) = boot_options::get().init {
+        match start_program(path).await {
+            Ok(()) => return Ok(()),
+            Err(error) => println!(
+                "[WARN] Failed to start init program \"{}\" ({}), using the built-in one",
+                AsciiStr(path),
+                error
+            ),
+        }
     }
+    unsafe { spawn_program(b"init", BUILTIN_PROGRAM) }
 }
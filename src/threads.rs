@@ -1,15 +1,14 @@
 use crate::arch::aarch64::exceptions::ExceptionContext;
 use crate::arch::aarch64::mmio::{delay_us_sync, get_uptime_us};
-use crate::arch::aarch64::mmu::PageTable;
 use crate::arch::aarch64::phymem;
 use crate::ktask;
 use crate::prelude::*;
-use core::pin::Pin;
+use crate::process::Process;
 use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use spin::{Mutex, Once, RwLock};
 
 pub(crate) const THREAD_TIMEOUT_US: u64 = 10_000;
-pub(crate) const CORE_COUNT: usize = 1;
+pub(crate) const CORE_COUNT: usize = 4;
 pub(crate) static EXECUTORS: Once<[SimpleThreadExecutor; CORE_COUNT]> = Once::new();
 // static PERF_INFO: Mutex<PerfInfo> = Mutex::new(PerfInfo::new());
 static PID_COUNTER: AtomicUsize = AtomicUsize::new(1);
@@ -26,6 +25,16 @@ pub unsafe fn current_core() -> usize {
     get_msr!(mpidr_el1) as usize & 0x3
 }
 
+pub struct ThreadPerfInfo {
+    pub id: usize,
+    pub name: &'static [u8],
+    /// Id of its process, `None` for kernel threads
+    pub process: Option<usize>,
+    pub uptime_us: u64,
+    pub total_yields: u64,
+    pub last_core: usize,
+}
+
 #[derive(Debug)]
 pub struct PerfReport {
     uptime_us: u64,
@@ -43,29 +52,27 @@ pub struct Thread {
     start_time_us: u64,
     cpu_time_us: u64,
     total_yields: u64,
+    last_core: usize,
     state: ExceptionContext,
-    page_tables: Option<Pin<Box<PageTable>>>,
-    // kernel_stack: Pin<Box<PageAligned<{ 4096 * 32 }>>>,
-    kernel_stack: &'static [u8],
+    /// `None` for kernel threads
+    process: Option<Arc<Process>>,
+    kernel_stack: PhySlice,
 }
 
 impl Thread {
     pub fn new(
         name: &'static [u8],
         state: ExceptionContext,
-        page_tables: Option<Pin<Box<PageTable>>>,
+        process: Option<Arc<Process>>,
     ) -> Thread {
         let id = PID_COUNTER.fetch_add(1, Ordering::SeqCst);
         println!("Creating thread #{}", id);
         let stack = unsafe {
             let mut phymem = phymem::PHYMEM_FREE_LIST.lock();
-            let kernel_virtmem = phymem
+            phymem
                 .alloc_pages(32)
                 // 128KiB
-                .expect("Failed to allocate thread stack");
-            kernel_virtmem.virt()
-
-            // Box::pin(PageAligned([0u8; 4096 * 32])) // This didn't work for some reason
+                .expect("Failed to allocate thread stack")
         };
 
         let mut thread = Thread {
@@ -74,36 +81,66 @@ impl Thread {
             start_time_us: get_uptime_us(),
             cpu_time_us: 0,
             total_yields: 0,
+            last_core: 0,
             state,
-            page_tables,
-            kernel_stack: stack, // TODO: Use this
+            process,
+            kernel_stack: stack,
         };
 
         println!(
-            "Allocated kernel stack for \"{}\" at {:p}",
+            "Allocated kernel stack for \"{}\" at {:?}",
             AsciiStr(name),
-            thread.kernel_stack.as_ptr()
+            thread.kernel_stack
         );
 
         if thread.state.sp == 0 {
             thread.state.sp =
-                (thread.kernel_stack.as_ptr() as usize + thread.kernel_stack.len()) as u64;
+                unsafe { thread.kernel_stack.base.virt() as usize + thread.kernel_stack.len }
+                    as u64;
         }
 
         thread
     }
 
+    /// Its kernel stack is freed once the last reference to it is gone, its process exits if it
+    /// was the last thread
     pub fn kill(&self) {
         println!("Killing thread #{}", self.id);
         for executor in EXECUTORS.get().unwrap() {
             executor.unregister_thread(self.id);
         }
+        if let Some(process) = &self.process {
+            process.thread_exited(self.id);
+        }
         println!("Killed!");
     }
 
     pub fn id(&self) -> usize {
         self.id
     }
+
+    pub fn name(&self) -> &'static [u8] {
+        self.name
+    }
+
+    pub fn process(&self) -> Option<&Arc<Process>> {
+        self.process.as_ref()
+    }
+
+    /// Sets x0 in its saved state, for a syscall that finished while it was switched away
+    pub fn set_syscall_result(&mut self, value: u64) {
+        self.state.gpr[0] = value;
+    }
+}
+
+impl Drop for Thread {
+    fn drop(&mut self) {
+        unsafe {
+            phymem::PHYMEM_FREE_LIST
+                .lock()
+                .free_pages(self.kernel_stack)
+        };
+    }
 }
 
 pub struct SimpleThreadExecutor {
@@ -166,13 +203,23 @@ impl SimpleThreadExecutor {
                     .map(|t| t.clone())
                     .expect("no thread?");
 
+                // Saved first, the thread might be switching to itself if it was woken up while
+                // switching away
+                let last_thread = self.current_thread.lock().replace(next_thread.clone());
+                if let Some(last_thread) = &last_thread {
+                    let mut last_thread = last_thread.write();
+                    last_thread.state = *current_state;
+                    last_thread.total_yields += 1;
+                }
+
                 let next_state = {
-                    let next_thread = next_thread.read();
+                    let mut next_thread = next_thread.write();
+                    next_thread.last_core = unsafe { current_core() };
 
                     // Switch to next page tables
                     unsafe {
-                        if let Some(page_tables) = &next_thread.page_tables {
-                            set_msr!(ttbr0_el1, (page_tables.0.as_ptr() as u64) & 0x7FFFFFFFFF)
+                        if let Some(process) = &next_thread.process {
+                            set_msr!(ttbr0_el1, process.ttbr0())
                         } else {
                             set_msr!(ttbr0_el1, 0);
                         }
@@ -182,12 +229,6 @@ impl SimpleThreadExecutor {
                     next_thread.state
                 };
 
-                let last_thread = self.current_thread.lock().replace(next_thread);
-                if let Some(last_thread) = &last_thread {
-                    let mut last_thread = last_thread.write();
-                    last_thread.state = *current_state;
-                }
-
                 *current_state = next_state;
                 break last_thread.map(|t| t.read().id).unwrap_or(0);
             } else {
@@ -202,7 +243,18 @@ impl SimpleThreadExecutor {
     }
 
     pub fn wake(&self, thread_id: usize) {
-        self.run_queue.lock().push_back(thread_id)
+        let mut run_queue = self.run_queue.lock();
+        if !run_queue.contains(&thread_id) {
+            run_queue.push_back(thread_id);
+        }
+    }
+
+    pub fn has_thread(&self, thread_id: usize) -> bool {
+        self.threads.lock().iter().any(|t| t.read().id == thread_id)
+    }
+
+    pub fn thread_count(&self) -> usize {
+        self.threads.lock().len()
     }
 
     pub fn current_thread(&self) -> Option<Arc<RwLock<Thread>>> {
@@ -224,48 +276,90 @@ impl SimpleThreadExecutor {
         }
     }
 
-    // pub fn proc_list(&self) -> Vec<ThreadPerfInfo> {
-    //     let uptime = get_uptime_us();
-    //     self.threads
-    //         .lock()
-    //         .iter()
-    //         .map(|t| {
-    //             let t = t.read();
-    //             ThreadPerfInfo {
-    //                 id: t.id,
-    //                 name: t.name,
-    //                 uptime_us: uptime - t.start_time_us,
-    //                 cpu_time_us: t.cpu_time_us,
-    //                 total_yields: t.total_yields,
-    //             }
-    //         })
-    //         .collect()
-    // }
+    pub fn proc_list(&self) -> Vec<ThreadPerfInfo> {
+        let uptime = get_uptime_us();
+        self.threads
+            .lock()
+            .iter()
+            .map(|t| {
+                let t = t.read();
+                ThreadPerfInfo {
+                    id: t.id,
+                    name: t.name,
+                    process: t.process.as_ref().map(|process| process.id()),
+                    uptime_us: uptime - t.start_time_us,
+                    total_yields: t.total_yields,
+                    last_core: t.last_core,
+                }
+            })
+            .collect()
+    }
 }
 
 pub(crate) unsafe fn init() {
+    const KTASK_THREAD_NAMES: [&[u8]; CORE_COUNT] =
+        [b"ktask/0", b"ktask/1", b"ktask/2", b"ktask/3"];
+
     EXECUTORS.call_once(|| {
-        let executor = SimpleThreadExecutor::new();
-
-        executor.spawn(Thread::new(
-            b"ktask/0",
-            ExceptionContext {
-                gpr: [
-                    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-                    0, 0, 0, 0,
-                ],
-                lr: 0,
-                pc: ktask_thread as unsafe extern "C" fn() as *const () as u64,
-                sp: 0,
-                spsr: 0x344,
-            },
-            None,
-        ));
-
-        [executor]
+        let executors = [
+            SimpleThreadExecutor::new(),
+            SimpleThreadExecutor::new(),
+            SimpleThreadExecutor::new(),
+            SimpleThreadExecutor::new(),
+        ];
+
+        // Every core runs the kernel task executor when it has nothing else to do
+        for (executor, name) in executors.iter().zip(KTASK_THREAD_NAMES) {
+            executor.spawn(Thread::new(
+                name,
+                ExceptionContext {
+                    gpr: [
+                        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+                        0, 0, 0, 0, 0,
+                    ],
+                    lr: 0,
+                    pc: ktask_thread as unsafe extern "C" fn() as *const () as u64,
+                    sp: 0,
+                    spsr: 0x344,
+                },
+                None,
+            ));
+        }
+
+        executors
     });
 }
 
+/// Spawn a thread on the least loaded core
+pub fn spawn(thread: Thread) {
+    let executor = EXECUTORS
+        .get()
+        .unwrap()
+        .iter()
+        .min_by_key(|executor| executor.thread_count())
+        .unwrap();
+    executor.spawn(thread);
+}
+
+/// Wake a thread on whichever core it belongs to
+pub fn wake(thread_id: usize) {
+    for executor in EXECUTORS.get().unwrap() {
+        if executor.has_thread(thread_id) {
+            executor.wake(thread_id);
+            return;
+        }
+    }
+}
+
+pub fn proc_list() -> Vec<ThreadPerfInfo> {
+    EXECUTORS
+        .get()
+        .unwrap()
+        .iter()
+        .flat_map(|executor| executor.proc_list())
+        .collect()
+}
+
 pub unsafe fn yield_thread() {}
 
 unsafe extern "C" fn ktask_thread() {
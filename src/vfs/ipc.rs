@@ -0,0 +1,91 @@
+//! The IPC tree as a filesystem. Queues and other nodes that aren't files or directories are devices
+
+use crate::ipc::IpcRef;
+use crate::prelude::*;
+use crate::vfs::{DirEntry, Node, NodeKind, Stat};
+
+use alloc::format;
+use futures::StreamExt;
+
+pub struct IpcVfsNode(IpcRef);
+
+impl IpcVfsNode {
+    pub fn new(node: IpcRef) -> Arc<Self> {
+        Arc::new(IpcVfsNode(node))
+    }
+}
+
+fn kind(node: &IpcRef) -> NodeKind {
+    match &node.describe() {
+        b"DIR " => NodeKind::Dir,
+        b"FILE" => NodeKind::File,
+        _ => NodeKind::Device,
+    }
+}
+
+/// Unnamed nodes go by their id in hex
+fn entry_name(node: &IpcRef) -> Vec<u8> {
+    match node.name() {
+        Some(name) => name.to_vec(),
+        None => format!("{:x}", node.id).into_bytes(),
+    }
+}
+
+#[async_trait]
+impl Node for IpcVfsNode {
+    fn kind(&self) -> NodeKind {
+        kind(&self.0)
+    }
+
+    /// IPC files don't know their size, they're read through to find it
+    async fn stat(&self) -> IoResult<Stat> {
+        let kind = kind(&self.0);
+        let mut size = 0;
+        if kind == NodeKind::File {
+            let mut buf = [0u8; 512];
+            loop {
+                match self.0.read_at(size, &mut buf).await.ok_or(())? {
+                    0 => break,
+                    len => size += len as u64,
+                }
+            }
+        }
+        Ok(Stat { kind, size })
+    }
+
+    /// By name first, then by hex id
+    async fn lookup(&self, name: &[u8]) -> IoResult<Option<Arc<dyn Node>>> {
+        Ok(self
+            .0
+            .dir_lookup(name)
+            .await
+            .map(|entry| IpcVfsNode::new(entry) as Arc<dyn Node>))
+    }
+
+    async fn readdir(&self) -> IoResult<Vec<DirEntry>> {
+        let mut stream = self.0.dir_list().ok_or(())?;
+        let mut entries = Vec::new();
+        while let Some(entry) = stream.next().await {
+            entries.push(DirEntry {
+                name: entry_name(&entry),
+                kind: kind(&entry),
+            });
+        }
+        Ok(entries)
+    }
+
+    async fn read_at(&self, offset: u64, buf: &mut [u8]) -> IoResult<usize> {
+        match kind(&self.0) {
+            NodeKind::File => self.0.read_at(offset, buf).await.ok_or(()),
+            NodeKind::Device => self.0.queue_read(buf).await.ok_or(()),
+            NodeKind::Dir => Err(()),
+        }
+    }
+
+    async fn write_at(&self, _offset: u64, data: &[u8]) -> IoResult<usize> {
+        match kind(&self.0) {
+            NodeKind::Device => self.0.queue_write(data),
+            _ => Err(()),
+        }
+    }
+}
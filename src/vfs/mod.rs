@@ -0,0 +1,377 @@
+//! Files and directories by string path, with filesystems mounted at points of a single tree
+//!
+//! The IPC tree is mounted at `/`, its entries go by their name or else their hex id. Filesystems
+//! mounted deeper take over everything below their mount point.
+
+pub(crate) mod ipc;
+
+use crate::fs::tmpfs::TmpNode;
+use crate::ktask;
+use crate::prelude::*;
+
+use spin::{Mutex, RwLock};
+
+/// Open for reading
+pub const OPEN_READ: u32 = 1 << 0;
+/// Open for writing
+pub const OPEN_WRITE: u32 = 1 << 1;
+/// Create the file if it doesn't exist
+pub const OPEN_CREATE: u32 = 1 << 2;
+/// Empty the file when opening it
+pub const OPEN_TRUNCATE: u32 = 1 << 3;
+/// Every write goes to the end of the file
+pub const OPEN_APPEND: u32 = 1 << 4;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum NodeKind {
+    File,
+    Dir,
+    /// Reads and writes are a stream, offsets are ignored
+    Device,
+}
+
+impl NodeKind {
+    /// 4 characters, like `IpcNode::describe`
+    pub fn describe(&self) -> &'static str {
+        match self {
+            NodeKind::File => "FILE",
+            NodeKind::Dir => "DIR ",
+            NodeKind::Device => "DEV ",
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Stat {
+    pub kind: NodeKind,
+    /// 0 for directories and devices
+    pub size: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub name: Vec<u8>,
+    pub kind: NodeKind,
+}
+
+/// A file, directory or device of a filesystem. Nodes are looked up again for every `open`, and
+/// shouldn't cache what another node for the same file could change
+#[async_trait]
+pub trait Node: Send + Sync {
+    fn kind(&self) -> NodeKind;
+
+    async fn stat(&self) -> IoResult<Stat>;
+
+    /// The entry called `name` in this directory, `None` if there's none
+    async fn lookup(&self, name: &[u8]) -> IoResult<Option<Arc<dyn Node>>>;
+
+    async fn readdir(&self) -> IoResult<Vec<DirEntry>>;
+
+    /// Returns 0 at the end of a file
+    async fn read_at(&self, offset: u64, buf: &mut [u8]) -> IoResult<usize>;
+
+    async fn write_at(&self, offset: u64, data: &[u8]) -> IoResult<usize>;
+
+    /// Creates an empty file or directory in this directory
+    async fn create(&self, _name: &[u8], _kind: NodeKind) -> IoResult<Arc<dyn Node>> {
+        Err(())
+    }
+
+    /// Removes the entry called `name` from this directory
+    async fn remove(&self, _name: &[u8]) -> IoResult<()> {
+        Err(())
+    }
+
+    async fn truncate(&self, _size: u64) -> IoResult<()> {
+        Err(())
+    }
+}
+
+// ----- Paths -----
+
+/// The components of `path` relative to `/`, with `.` and `..` resolved
+pub fn components(path: &[u8]) -> Vec<&[u8]> {
+    let mut components = Vec::new();
+    for part in path.split(|c| *c == b'/') {
+        match part {
+            b"" | b"." => {}
+            b".." => {
+                components.pop();
+            }
+            _ => components.push(part),
+        }
+    }
+    components
+}
+
+/// `path` made absolute from `cwd` if it's relative, then normalized
+pub fn join(cwd: &[u8], path: &[u8]) -> Vec<u8> {
+    let mut joined = if path.starts_with(b"/") {
+        Vec::new()
+    } else {
+        cwd.to_vec()
+    };
+    joined.push(b'/');
+    joined.extend_from_slice(path);
+
+    let mut normalized = Vec::new();
+    for component in components(&joined) {
+        normalized.push(b'/');
+        normalized.extend_from_slice(component);
+    }
+    if normalized.is_empty() {
+        normalized.push(b'/');
+    }
+    normalized
+}
+
+/// The parent directory of `path` and the last component, `None` for `/`
+fn split_last(path: &[u8]) -> Option<(Vec<u8>, &[u8])> {
+    let mut components = components(path);
+    let name = components.pop()?;
+    let mut parent = vec![b'/'];
+    parent.extend_from_slice(&components.join(&b'/'));
+    Some((parent, name))
+}
+
+// ----- Mounts -----
+
+struct Mount {
+    components: Vec<Vec<u8>>,
+    root: Arc<dyn Node>,
+}
+
+static MOUNTS: RwLock<Vec<Mount>> = RwLock::new(Vec::new());
+
+/// Mounts the IPC tree at `/`, and a tmpfs at `/tmp`
+pub fn init() {
+    let root = crate::ipc::ROOT.read().as_ref().unwrap().clone();
+    mount(b"/", ipc::IpcVfsNode::new(root)).unwrap();
+    mount(b"/tmp", TmpNode::new_default()).unwrap();
+}
+
+/// Fails if something is already mounted at `path`
+pub fn mount(path: &[u8], root: Arc<dyn Node>) -> IoResult<()> {
+    let components: Vec<Vec<u8>> = components(path).iter().map(|c| c.to_vec()).collect();
+    let mut mounts = MOUNTS.write();
+    if mounts.iter().any(|mount| mount.components == components) {
+        return Err(());
+    }
+    mounts.push(Mount { components, root });
+    Ok(())
+}
+
+/// The root of the deepest mount containing `components`, and how many of them it covers
+fn find_mount(components: &[&[u8]]) -> IoResult<(Arc<dyn Node>, usize)> {
+    let mounts = MOUNTS.read();
+    let mount = mounts
+        .iter()
+        .filter(|mount| {
+            mount.components.len() <= components.len()
+                && mount
+                    .components
+                    .iter()
+                    .zip(components)
+                    .all(|(a, b)| a.as_slice() == *b)
+        })
+        .max_by_key(|mount| mount.components.len())
+        .ok_or(())?;
+    Ok((mount.root.clone(), mount.components.len()))
+}
+
+/// The node at absolute `path`, `None` if there's nothing there
+pub async fn lookup(path: &[u8]) -> IoResult<Option<Arc<dyn Node>>> {
+    let components = components(path);
+    let (mut node, covered) = find_mount(&components)?;
+    for name in &components[covered..] {
+        node = match node.lookup(name).await? {
+            Some(node) => node,
+            None => return Ok(None),
+        };
+    }
+    Ok(Some(node))
+}
+
+async fn lookup_existing(path: &[u8]) -> IoResult<Arc<dyn Node>> {
+    lookup(path).await?.ok_or(())
+}
+
+// ----- Operations -----
+
+pub async fn stat(path: &[u8]) -> IoResult<Stat> {
+    lookup_existing(path).await?.stat().await
+}
+
+/// What's in directory `path`, mount points in it included
+pub async fn readdir(path: &[u8]) -> IoResult<Vec<DirEntry>> {
+    let mut entries = lookup_existing(path).await?.readdir().await?;
+
+    let components = components(path);
+    for mount in MOUNTS.read().iter() {
+        if let Some((name, parent)) = mount.components.split_last() {
+            if parent
+                .iter()
+                .map(|c| c.as_slice())
+                .eq(components.iter().copied())
+                && !entries.iter().any(|entry| entry.name == *name)
+            {
+                entries.push(DirEntry {
+                    name: name.clone(),
+                    kind: NodeKind::Dir,
+                });
+            }
+        }
+    }
+    Ok(entries)
+}
+
+pub async fn mkdir(path: &[u8]) -> IoResult<()> {
+    let (parent, name) = split_last(path).ok_or(())?;
+    lookup_existing(&parent)
+        .await?
+        .create(name, NodeKind::Dir)
+        .await?;
+    Ok(())
+}
+
+/// Removes a file, or an empty directory
+pub async fn remove(path: &[u8]) -> IoResult<()> {
+    let (parent, name) = split_last(path).ok_or(())?;
+    let components = components(path);
+    let (_, covered) = find_mount(&components)?;
+    if covered == components.len() {
+        // A mount point
+        return Err(());
+    }
+    lookup_existing(&parent).await?.remove(name).await
+}
+
+/// Opens the file, directory or device at absolute `path` with `OPEN_*` `flags`
+pub async fn open(path: &[u8], flags: u32) -> IoResult<File> {
+    let node = match lookup(path).await? {
+        Some(node) => node,
+        None if flags & OPEN_CREATE != 0 => {
+            let (parent, name) = split_last(path).ok_or(())?;
+            lookup_existing(&parent)
+                .await?
+                .create(name, NodeKind::File)
+                .await?
+        }
+        None => return Err(()),
+    };
+    if flags & OPEN_TRUNCATE != 0 && node.kind() == NodeKind::File {
+        node.truncate(0).await?;
+    }
+    Ok(File {
+        node,
+        path: join(b"/", path),
+        flags,
+        offset: Mutex::new(0),
+    })
+}
+
+// ----- Open files -----
+
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+pub struct File {
+    node: Arc<dyn Node>,
+    path: Vec<u8>,
+    flags: u32,
+    offset: Mutex<u64>,
+}
+
+impl File {
+    pub fn path(&self) -> &[u8] {
+        &self.path
+    }
+
+    pub fn kind(&self) -> NodeKind {
+        self.node.kind()
+    }
+
+    pub async fn stat(&self) -> IoResult<Stat> {
+        self.node.stat().await
+    }
+
+    /// Reads from the current offset and moves it past what was read
+    pub async fn read(&self, buf: &mut [u8]) -> IoResult<usize> {
+        if self.flags & OPEN_READ == 0 {
+            return Err(());
+        }
+        let mut offset = ktask::lock(&self.offset).await;
+        let len = self.node.read_at(*offset, buf).await?;
+        *offset += len as u64;
+        Ok(len)
+    }
+
+    /// Writes at the current offset, or at the end with `OPEN_APPEND`, and moves the offset past
+    /// what was written
+    pub async fn write(&self, data: &[u8]) -> IoResult<usize> {
+        if self.flags & OPEN_WRITE == 0 {
+            return Err(());
+        }
+        let mut offset = ktask::lock(&self.offset).await;
+        if self.flags & OPEN_APPEND != 0 {
+            *offset = self.node.stat().await?.size;
+        }
+        let len = self.node.write_at(*offset, data).await?;
+        *offset += len as u64;
+        Ok(len)
+    }
+
+    /// Writes all of `data`, failing if the node stops taking it
+    pub async fn write_all(&self, mut data: &[u8]) -> IoResult<()> {
+        while !data.is_empty() {
+            match self.write(data).await? {
+                0 => return Err(()),
+                len => data = &data[len..],
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads everything from the current offset up to the end
+    pub async fn read_to_end(&self) -> IoResult<Vec<u8>> {
+        let mut contents = Vec::new();
+        let mut buf = [0u8; 512];
+        loop {
+            match self.read(&mut buf).await? {
+                0 => return Ok(contents),
+                len => contents.extend_from_slice(&buf[..len]),
+            }
+        }
+    }
+
+    /// Moves the offset, returns where it is now
+    pub async fn seek(&self, position: SeekFrom) -> IoResult<u64> {
+        let mut offset = ktask::lock(&self.offset).await;
+        let (base, delta) = match position {
+            SeekFrom::Start(start) => (start, 0),
+            SeekFrom::Current(delta) => (*offset, delta),
+            SeekFrom::End(delta) => (self.node.stat().await?.size, delta),
+        };
+        *offset = if delta < 0 {
+            base.checked_sub(delta.unsigned_abs()).ok_or(())?
+        } else {
+            base.checked_add(delta as u64).ok_or(())?
+        };
+        Ok(*offset)
+    }
+
+    pub async fn truncate(&self, size: u64) -> IoResult<()> {
+        if self.flags & OPEN_WRITE == 0 {
+            return Err(());
+        }
+        self.node.truncate(size).await
+    }
+
+    /// What's in the directory, mount points in it included
+    pub async fn readdir(&self) -> IoResult<Vec<DirEntry>> {
+        readdir(&self.path).await
+    }
+}
@@ -16,11 +16,67 @@ static inline long __syscall1(long syscall_no, unsigned long arg1) {
     return r0;
 }
 
+__attribute__((always_inline))
+static inline long __syscall2(long syscall_no, unsigned long arg1, unsigned long arg2) {
+    register unsigned long r8 __asm("x8") = syscall_no;
+    register unsigned long r0 __asm("x0") = arg1;
+    register unsigned long r1 __asm("x1") = arg2;
+    __asm__ __volatile__ ("svc #0" : "+r"(r0) : "r"(r8), "r"(r0), "r"(r1) : "memory");
+    return r0;
+}
+
+__attribute__((always_inline))
+static inline long __syscall3(long syscall_no, unsigned long arg1, unsigned long arg2, unsigned long arg3) {
+    register unsigned long r8 __asm("x8") = syscall_no;
+    register unsigned long r0 __asm("x0") = arg1;
+    register unsigned long r1 __asm("x1") = arg2;
+    register unsigned long r2 __asm("x2") = arg3;
+    __asm__ __volatile__ ("svc #0" : "+r"(r0) : "r"(r8), "r"(r0), "r"(r1), "r"(r2) : "memory");
+    return r0;
+}
+
 #define SYS_EXIT 0
 #define SYS_KLOG_WRITE 1
 #define SYS_KLOG_WRITE_INT 2
 #define SYS_USLEEP 3
 #define SYS_GET_TID 4
+#define SYS_IPC_OPEN 5
+#define SYS_IPC_CLOSE 6
+#define SYS_IPC_DIR_LIST 7
+#define SYS_IPC_DIR_GET 8
+#define SYS_IPC_DIR_LINK 9
+#define SYS_IPC_QUEUE_READ 10
+#define SYS_IPC_QUEUE_WRITE 11
+
+#define SYS_ERROR ((unsigned long) -1)
+
+static void ipc_example() {
+    static const char path[] = "/1";
+    unsigned long dir = __syscall2(SYS_IPC_OPEN, (unsigned long) path, sizeof(path) - 1);
+    if (dir == SYS_ERROR) {
+        __syscall1(SYS_KLOG_WRITE, (unsigned long) "Couldn't open the example IPC directory");
+        return;
+    }
+
+    unsigned long ids[8];
+    unsigned long count = __syscall3(SYS_IPC_DIR_LIST, dir, (unsigned long) ids, 8);
+    __syscall1(SYS_KLOG_WRITE, (unsigned long) "Entries in the example IPC directory:");
+    for (size_t i = 0; i < count && i < 8; i++) {
+        __syscall1(SYS_KLOG_WRITE_INT, ids[i]);
+    }
+
+    // Write to the example queue, then read it back
+    unsigned long queue = __syscall2(SYS_IPC_DIR_GET, dir, 3);
+    if (queue != SYS_ERROR) {
+        static const char message[] = "Hello through a queue!";
+        static char buf[sizeof(message)];
+        __syscall3(SYS_IPC_QUEUE_WRITE, queue, (unsigned long) message, sizeof(message) - 1);
+        __syscall3(SYS_IPC_QUEUE_READ, queue, (unsigned long) buf, sizeof(buf) - 1);
+        __syscall1(SYS_KLOG_WRITE, (unsigned long) buf);
+        __syscall1(SYS_IPC_CLOSE, queue);
+    }
+    __syscall1(SYS_IPC_CLOSE, dir);
+}
 
 
 void _start() {
@@ -28,6 +84,8 @@ void _start() {
     __syscall1(SYS_KLOG_WRITE, (unsigned long) "Hello from usermode! &start =");
     __syscall1(SYS_KLOG_WRITE_INT, (unsigned long) &_start);
 
+    ipc_example();
+
     // __syscall1(SYS_KLOG_WRITE, (unsigned long) "Trying to read kernel memory:");
     // __syscall1(SYS_KLOG_WRITE_INT, *(unsigned long*) 0xffffff8000080000);
 